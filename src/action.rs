@@ -1,16 +1,83 @@
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD as BASE64};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
 use bytes::Bytes;
 use serde::Serialize;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 //use serde::de::DeserializeOwned;
 use serde::de::Deserialize;
 
-use crate::error::ActionError;
+use crate::auth::{AuthContext, Authenticator};
+use crate::budget::Budget;
+use crate::cache::{cache_key, Lookup, ResponseCache};
+use crate::clock::{unix_ms, Clock, SystemClock};
+use crate::concurrency::Concurrency;
+use crate::correlation::CorrelationId;
+use crate::ctx::{ActionCtx, BatchCache, SubDispatch, DEFAULT_MAX_DISPATCH_DEPTH};
+use crate::dead_letter::{DeadLetter, DeadLetterSink};
+use crate::dedupe::DedupeCache;
+use crate::deprecation::{Deprecation, WarnThrottle, DEFAULT_WARN_INTERVAL, WARN_CACHE_CAPACITY};
+use crate::error::{ActionError, FromActionError};
+use crate::examples::Example;
+use crate::health::{ResourceProbe, HEALTH_ACTION};
+use crate::history::{ReplyLog, DEFAULT_REPLY_LOG_BYTES};
+use crate::idempotency::Idempotency;
+use crate::inflight::{InFlight, DEFAULT_INFLIGHT_CAPACITY};
+use crate::keymap::{rename_payload, rename_result, KeyMaps, KeyRename};
+use crate::logger::{ActionLogger, LogBuffer, LogEntry, MAX_REPLY_LOG_BYTES};
+use crate::maintenance::{instant_at, Maintenance, SweepStats};
+use crate::outcome::DispatchOutcome;
+use crate::panics::{panic_message, scrub_panic_message, PanicScrubber};
+use crate::pool::ResourcePool;
+use crate::protocol::{Hello, Negotiated, ProtocolFeatures, HANDSHAKE_ACTION};
+use crate::rate_limit::RateLimiter;
+use crate::routes::Routes;
+use crate::schema::SchemaInference;
+use crate::sizes::{json_len, SizeMetric, SizeStats, SizeTracker};
+use crate::source::PolicyOverrides;
+use crate::stamp::ReplyStamp;
+use crate::stats::Stats;
+use crate::timeout::Timeouts;
+use crate::token::{Plain, TokenCodec};
+use crate::trace::{DispatchTrace, TraceBuffer, Tracer, DEFAULT_TRACE_CAPACITY};
+#[cfg(feature = "crypto")]
+use crate::two_phase::SharedConfirmations;
+use crate::validate::Validator;
+use crate::verbosity::{ErrorVerbosity, Incident, Incidents};
 
-pub type ActionHandler<R> =
-    dyn Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>> + 'static;
-pub type ManagerInitHandler<R> = dyn Fn(&R) -> Result<(), Box<dyn std::error::Error>>;
+pub type ActionHandler<R> = dyn Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+    + Send
+    + Sync
+    + 'static;
+/// a handler which also gets the dispatch context, see `Manager::on_ctx`
+pub type CtxHandler<R> = dyn Fn(&R, &Action, &ActionCtx<'_>) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+    + Send
+    + Sync
+    + 'static;
+/// handlers registered with `Manager::on_mut`, see `Manager::do_action_mut`
+pub type MutHandler<R> = dyn FnMut(&mut R, &Action) -> Result<Value, ActionError> + Send + Sync;
+/// runs before every handler, an error skips the handler, see `Manager::use_before`
+pub type BeforeHook<R> = dyn Fn(&R, &mut Action) -> Result<(), ActionError> + Send + Sync;
+/// a condition one action must meet before its handler runs, see `Manager::guard`
+pub type Guard<R> = dyn Fn(&R, &Action) -> Result<(), ActionError> + Send + Sync;
+/// runs after every action, see `Manager::use_after`
+pub type AfterHook<R> = dyn Fn(&R, &mut Action) + Send + Sync;
+/// answers actions no handler is registered for, see `Manager::on_unknown`
+pub type UnknownHandler<R> = dyn Fn(&R, &Action) -> Result<Value, ActionError> + Send + Sync;
+/// told about failed actions, see `Manager::on_error`
+pub type ErrorCallback = dyn Fn(&str, &Action, &ActionError) + Send + Sync;
+/// makes a resource for every action, see `Manager::try_with`
+pub type ResourceGen<R> = dyn Fn() -> Result<R, ActionError> + Send + Sync;
+/// the closures `init` took before it accepted any `FnOnce`
+pub type ManagerInitHandler<R> = dyn Fn(&R) -> Result<(), Box<dyn std::error::Error>> + Send + Sync;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Action {
@@ -23,26 +90,280 @@ pub struct Action {
     pub id: u64,
     /// unique token attributable to a specific user
     pub token: Option<String>,
+    /// where the action came from (see the `source` module), set by the receiving side;
+    /// never read from the client
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// id of the external request this action belongs to, echoed on the reply
+    /// (see the `correlation` module)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
     /// arbitrary binary data if not using binary
     pub base64: Option<String>,
+    /// raw binary data, compact under binary formats and an array of numbers under JSON;
+    /// clients which only know `base64` leave it out
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub binary: Option<Vec<u8>>,
+    /// HMAC-SHA256 over the action, see the `signature` module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// named binary attachments, independent of `base64`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
     pub payload: HashMap<String, Value>,
     // the output of the action
     pub result: Option<Value>,
     // the error message, setting this thing sets is_ok to false
     pub errors: Option<Vec<ActionError>>,
+    /// non fatal notices for the client, e.g. that the action is deprecated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<ActionError>,
+    /// asks what the action would do without doing it, see `Manager::on_with_dry_run`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    /// units the handler consumed when the action has a budget, see `Manager::budget`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_used: Option<u64>,
+    /// what the handler logged through `ActionCtx::log`, carried over to the reply when
+    /// `Manager::attach_logs_to_reply` is on; never sent
+    #[serde(skip)]
+    pub logs: Vec<LogEntry>,
+    /// the result was served from the cache past its ttl, see `CacheMode`; never sent
+    #[serde(skip)]
+    pub stale: bool,
+    /// position of a follow-up frame of a duplex stream, see the `duplex` module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// `false` on the last frame of a duplex stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub more: Option<bool>,
+    /// unix time in milliseconds after which the action is not worth running, see
+    /// `Action::with_ttl`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// unix time in milliseconds the dispatch started, see `Manager::record_timings`;
+    /// never sent
+    #[serde(skip)]
+    pub received_at: Option<u64>,
+    /// how long the dispatch took, see `Manager::record_timings`; never sent
+    #[serde(skip)]
+    pub duration_ms: Option<u64>,
+    /// out of band values for the reply, see `Action::set_meta`; never sent
+    #[serde(skip)]
+    pub meta: HashMap<String, Value>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ActionReply {
     pub id: u64,
     //#[serde(borrow)]
     pub name: String,
-    //pub payload: HashMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// the request payload, only filled in when the manager echoes it (see `EchoMode`)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub payload: HashMap<String, Value>,
     pub result: Option<Value>,
     // this should always be available in the action
     pub errors: Vec<ActionError>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<ActionError>,
+    /// the request attachments, only filled in when the manager carries them through
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// the result is a simulation, nothing was changed
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dry_run: bool,
+    /// how expensive the action was, see `Manager::budget`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_used: Option<u64>,
+    /// server time in milliseconds since the unix epoch, see `Manager::stamp_replies`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_ts_ms: Option<i64>,
+    /// increases with every reply the manager emits, see `Manager::stamp_replies`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_seq: Option<u64>,
+    /// what the handler logged, see `Manager::attach_logs_to_reply`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<LogEntry>,
+    /// the result came from the cache past its ttl and is being refreshed, see `CacheMode`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub stale: bool,
+    /// the `seq` of the action answered, or of the reply within a duplex stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// `false` on the last reply of a duplex stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub more: Option<bool>,
+    /// unix time in milliseconds the server started on the action, see
+    /// `Manager::record_timings`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<u64>,
+    /// how long the server spent on the action, see `Manager::record_timings`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// out of band values next to the result, like pagination cursors or cache hints
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub meta: HashMap<String, Value>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// decoders for the `base64` field, padding is accepted but not required
+const BASE64_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const BASE64_URL_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// a named piece of binary data travelling with an action
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: String,
+    /// the data, base64 encoded with the standard alphabet
+    pub b64: String,
+    /// size of the decoded data in bytes
+    pub size: u64,
+}
+
+impl Attachment {
+    pub fn new(name: &str, content_type: &str, data: &[u8]) -> Self {
+        Attachment {
+            name: name.to_owned(),
+            content_type: content_type.to_owned(),
+            b64: BASE64.encode(data),
+            size: data.len() as u64,
+        }
+    }
+
+    /// decoded size worked out from the encoded length, without decoding
+    pub fn decoded_len(&self) -> u64 {
+        let padding = self.b64.bytes().rev().take_while(|b| *b == b'=').count();
+        ((self.b64.len() / 4 * 3).saturating_sub(padding)) as u64
+    }
+
+    pub fn decode(&self) -> Result<Vec<u8>, ActionError> {
+        BASE64.decode(&self.b64).map_err(|e| {
+            ActionError::new(
+                "AttachmentError",
+                &format!("attachment {} is not valid base64: {}", self.name, e),
+            )
+        })
+    }
+}
+
+/// limits checked while parsing an action off the wire
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// maximum decoded size of any single attachment
+    pub max_attachment_bytes: Option<u64>,
+    /// maximum decoded size of all attachments together
+    pub max_total_attachment_bytes: Option<u64>,
+}
+
+impl ParseOptions {
+    pub fn check(&self, action: &Action) -> Result<(), ActionError> {
+        let mut total = 0u64;
+        for a in &action.attachments {
+            let size = a.decoded_len();
+            if let Some(max) = self.max_attachment_bytes {
+                if size > max {
+                    return Err(ActionError::new(
+                        "AttachmentTooLarge",
+                        &format!("attachment {} is {} bytes, limit is {}", a.name, size, max),
+                    ));
+                }
+            }
+            total += size;
+        }
+        if let Some(max) = self.max_total_attachment_bytes {
+            if total > max {
+                return Err(ActionError::new(
+                    "AttachmentTooLarge",
+                    &format!("attachments total {} bytes, limit is {}", total, max),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// controls whether `Manager::reply` copies the request payload into the reply
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EchoMode {
+    /// the reply payload is always empty
+    #[default]
+    Never,
+    /// every reply carries the (redacted) request payload
+    Always,
+    /// only replies carrying errors get the (redacted) request payload
+    OnError,
+}
+
+/// what happens to a handler result over `Manager::max_result_bytes`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResultPolicy {
+    /// drop the result and reply with a `ResultTooLarge` error carrying the measured size
+    Reject,
+    /// keep only these top level keys of the result and add `"_truncated": true`
+    Truncate { keep_keys: Vec<String> },
 }
 
+/// options for `Manager::do_batch_with`
+#[derive(Debug, Clone, Default)]
+pub struct BatchOptions {
+    /// no new action is started once this has passed, the rest get a retryable
+    /// `BatchDeadlineExceeded` error
+    pub deadline: Option<Instant>,
+}
+
+/// replies of a batch along with how far it got
+#[derive(Serialize, Deserialize)]
+pub struct BatchReply {
+    pub replies: Vec<ActionReply>,
+    /// how many actions were actually dispatched
+    pub completed: usize,
+    /// the outcome of each action, in the order of `replies`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outcomes: Vec<DispatchOutcome>,
+}
+
+/// a registered action as reported by `Manager::list_actions_detailed`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActionInfo {
+    pub name: String,
+    pub deprecated: Option<Deprecation>,
+    /// old payload keys still accepted, see `Manager::payload_key_map`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub payload_keys: Vec<KeyRename>,
+    /// result keys renamed on the way out, see `Manager::result_key_map`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub result_keys: Vec<KeyRename>,
+    /// see `Manager::example`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<Example>,
+}
+
+/// name of the built-in action every manager answers with its name and actions, see
+/// `Manager::describe_action`
+pub const DESCRIBE_ACTION: &str = "__describe";
+
+/// value that replaces redacted payload entries
+pub const REDACTED: &str = "[redacted]";
+
+/// payload key a forwarded reply's result goes under, see `ActionReply::into_action`
+pub const FORWARDED_RESULT_KEY: &str = "result";
+/// payload keys reserved for the rest of a forwarded reply
+pub const FORWARDED_ERRORS_KEY: &str = "__errors";
+pub const FORWARDED_WARNINGS_KEY: &str = "__warnings";
+pub const FORWARDED_REQUEST_KEY: &str = "__request";
+
 /*
 pub fn try_action<V, E>(v: Result<V, E>) -> Result<serde_json::Value, ActionError>
 where
@@ -51,31 +372,196 @@ where
 {
     match v {
         Ok(val) => {
-            let v = serde_json::to_value(&val).expect("try_action, serde_json::to_value blew up");
-            Ok(v)
+            serde_json::to_value(&val).map_err(serialize_error)
         }
         Err(e) => Err(ActionError::from(("TryAction", format!("{}", e).as_ref()))),
     }
 }
 */
 
+/// failures of turning a value into JSON, which would otherwise take the request down
+pub(crate) fn serialize_error(e: serde_json::Error) -> ActionError {
+    ActionError::new("SerializeError", &e.to_string())
+}
+
 pub fn value_ok<V>(v: V) -> Result<serde_json::Value, Box<dyn std::error::Error>>
 where
     V: Serialize,
 {
-    Ok(serde_json::to_value(&v)?)
+    serde_json::to_value(&v).map_err(|e| serialize_error(e).into())
 }
 
 pub fn value_err<E: std::error::Error>(name: &str, e: E) -> Result<serde_json::Value, ActionError> {
     Err(ActionError::new(name, &e.to_string()))
 }
 
+/// the error of an action whose resource could not be made
+fn resource_error(cause: ActionError) -> ActionError {
+    ActionError::new(
+        "ResourceError",
+        &format!("the resource could not be made: {}", cause.message),
+    )
+    .with_details(json!({ "cause": cause }))
+    .retryable()
+}
+
+/// errors returned by handlers keep their code when they are an `ActionError`, anything
+/// else is reported as a `RunAction` error
+fn handler_error(e: Box<dyn std::error::Error>) -> ActionError {
+    match e.downcast::<ActionError>() {
+        Ok(e) => *e,
+        Err(e) => ActionError::from(("RunAction".to_owned(), format!("{}", e))),
+    }
+}
+
 pub fn action_ok() -> Result<serde_json::Value, Box<dyn std::error::Error>> {
     let v = json!({"success": true});
     Ok(v)
 }
 
 impl Action {
+    /// an action with an empty payload, to fill in with the `with_` methods
+    pub fn new(name: impl Into<String>, id: u64) -> Self {
+        Action {
+            id,
+            token: None,
+            source: None,
+            correlation_id: None,
+            name: name.into(),
+            base64: None,
+            binary: None,
+            signature: None,
+            expires_at: None,
+            received_at: None,
+            duration_ms: None,
+            meta: HashMap::new(),
+            attachments: Vec::new(),
+            payload: HashMap::new(),
+            errors: None,
+            warnings: Vec::new(),
+            dry_run: None,
+            budget_used: None,
+            logs: Vec::new(),
+            stale: false,
+            seq: None,
+            more: None,
+            result: None,
+        }
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// expires `ttl` from now, managers refuse it with `Expired` afterwards
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expires_at = Some(unix_ms().saturating_add(ttl.as_millis() as u64));
+        self
+    }
+
+    /// whether `expires_at` passed at unix time `now_ms`
+    pub fn is_expired_at(&self, now_ms: u64) -> bool {
+        self.expires_at.is_some_and(|at| now_ms >= at)
+    }
+
+    /// sets `key` of the payload, a value which does not serialize goes in as null
+    pub fn with_payload_value<V: Serialize>(mut self, key: &str, value: V) -> Self {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.payload.insert(key.to_owned(), value);
+        self
+    }
+
+    /// adds every field of `value` to the payload, failing with `PayloadError` when it
+    /// does not serialize to a JSON object
+    pub fn with_payload_struct<V: Serialize>(mut self, value: V) -> Result<Self, ActionError> {
+        match serde_json::to_value(value) {
+            Ok(Value::Object(fields)) => {
+                self.payload.extend(fields);
+                Ok(self)
+            }
+            Ok(other) => Err(ActionError::new(
+                "PayloadError",
+                "the payload must serialize to a JSON object",
+            )
+            .with_details(json!({ "got": other }))),
+            Err(e) => Err(ActionError::new("PayloadError", &e.to_string())),
+        }
+    }
+
+    pub fn with_base64(mut self, base64: impl Into<String>) -> Self {
+        self.base64 = Some(base64.into());
+        self
+    }
+
+    /// `key` of the payload, failing with `MissingField` naming it
+    pub fn payload_value(&self, key: &str) -> Result<&Value, ActionError> {
+        self.payload.get(key).ok_or_else(|| {
+            ActionError::new("MissingField", &format!("payload has no {}", key))
+                .with_details(json!({ "field": key }))
+        })
+    }
+
+    fn payload_as<'a, T>(
+        &'a self,
+        key: &str,
+        expected: &str,
+        get: impl Fn(&'a Value) -> Option<T>,
+    ) -> Result<T, ActionError> {
+        let value = self.payload_value(key)?;
+        get(value).ok_or_else(|| {
+            ActionError::new(
+                "TypeMismatch",
+                &format!("payload {} must be {}", key, expected),
+            )
+            .with_details(json!({ "field": key, "expected": expected }))
+        })
+    }
+
+    /// `key` of the payload as a string, failing with `MissingField` or `TypeMismatch`
+    pub fn payload_str(&self, key: &str) -> Result<&str, ActionError> {
+        self.payload_as(key, "a string", Value::as_str)
+    }
+
+    /// `key` of the payload as a whole number of at least 0, floats are rejected
+    pub fn payload_u64(&self, key: &str) -> Result<u64, ActionError> {
+        self.payload_as(key, "an unsigned integer", Value::as_u64)
+    }
+
+    pub fn payload_bool(&self, key: &str) -> Result<bool, ActionError> {
+        self.payload_as(key, "a boolean", Value::as_bool)
+    }
+
+    /// `key` of the payload as any JSON number
+    pub fn payload_f64(&self, key: &str) -> Result<f64, ActionError> {
+        self.payload_as(key, "a number", Value::as_f64)
+    }
+
+    /// deserializes the payload entry `key` alone, failing with `MissingField` when it is
+    /// not there and `PayloadError` naming it when it does not fit `Q`
+    pub fn from_payload_field<Q>(&self, key: &str) -> Result<Q, ActionError>
+    where
+        for<'de> Q: Deserialize<'de>,
+    {
+        let value = self.payload_value(key)?;
+        Q::deserialize(value).map_err(|e| {
+            ActionError::new("PayloadError", &format!("payload {}: {}", key, e))
+                .with_details(json!({ "field": key }))
+        })
+    }
+
+    /// sets the payload entry `key` to `value`, failing with `SerializeError`
+    pub fn set_payload_field<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: V,
+    ) -> Result<(), ActionError> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| ActionError::new("SerializeError", &format!("payload {}: {}", key, e)))?;
+        self.payload.insert(key.to_owned(), value);
+        Ok(())
+    }
+
     pub fn set_result(&mut self, res: Value) {
         //println!("Action.set_result {:?}", res);
         self.result = Some(res);
@@ -92,7 +578,7 @@ impl Action {
     where
         for<'de> Q: Deserialize<'de>,
     {
-        let o = serde_json::to_value(&self.payload).unwrap();
+        let o = serde_json::to_value(&self.payload).map_err(serialize_error)?;
         match serde_json::from_value::<Q>(o) {
             Ok(v) => Ok(v),
             Err(e) => Err(ActionError::new("PayloadError", &e.to_string())),
@@ -103,246 +589,3924 @@ impl Action {
     where
         for<'de> Q: Deserialize<'de>,
     {
-        let o = serde_json::to_value(&self.result).unwrap();
+        let o = serde_json::to_value(&self.result).map_err(serialize_error)?;
         match serde_json::from_value::<Q>(o) {
             Ok(v) => Ok(v),
             Err(e) => Err(ActionError::new("PayloadError", &e.to_string())),
         }
     }
 
-    pub fn from_bytes(buf: Bytes) -> Result<Self, String> {
-        // TODO: this can panic, so need to handle it
-        let jsonstr = std::str::from_utf8(&buf).unwrap();
-        let action: Result<Action, String> = match serde_json::from_str(jsonstr) {
-            Ok(a) => Ok(a),
-            Err(e) => Err(e.to_string()),
+    pub fn from_bytes(buf: Bytes) -> Result<Self, ActionError> {
+        Action::from_slice(&buf)
+    }
+
+    /// parses the action straight from the bytes, failing with `Utf8Error` (and the offset
+    /// of the first bad byte) when they are not UTF-8 and `JsonError` otherwise
+    pub fn from_slice(buf: &[u8]) -> Result<Self, ActionError> {
+        serde_json::from_slice(buf).map_err(|e| match std::str::from_utf8(buf) {
+            Err(utf8) => utf8.into(),
+            Ok(_) => e.into(),
+        })
+    }
+
+    /// parses the action and enforces the limits in `opts`
+    pub fn from_bytes_with(buf: Bytes, opts: &ParseOptions) -> Result<Self, ActionError> {
+        let action = Action::from_slice(&buf)?;
+        opts.check(&action)?;
+        Ok(action)
+    }
+
+    /// the JSON encoding of the action, failing with `SerializeError`
+    pub fn to_bytes(&self) -> Result<Bytes, ActionError> {
+        encode(self)
+    }
+
+    pub fn into_bytes(self) -> Result<Bytes, ActionError> {
+        encode(&self)
+    }
+
+    /// the decoded `base64` field, `None` when it is unset; the standard alphabet is tried
+    /// first, then the URL-safe one, failing with `Base64Error` when neither fits
+    pub fn decode_base64(&self) -> Result<Option<Vec<u8>>, ActionError> {
+        let encoded = match &self.base64 {
+            Some(s) => s,
+            None => return Ok(None),
         };
-        action
+        BASE64_ANY_PAD
+            .decode(encoded)
+            .or_else(|_| BASE64_URL_ANY_PAD.decode(encoded))
+            .map(Some)
+            .map_err(|e| ActionError::new("Base64Error", &format!("base64 is not valid: {}", e)))
+    }
+
+    /// sets the `base64` field to `data` encoded with the standard alphabet
+    pub fn set_base64_bytes(&mut self, data: &[u8]) {
+        self.base64 = Some(BASE64.encode(data));
+    }
+
+    /// decoded size of the `base64` field worked out from its length, without decoding,
+    /// so oversized data can be turned away first
+    pub fn base64_len_hint(&self) -> Option<u64> {
+        self.base64.as_ref().map(|s| {
+            let unpadded = s.trim_end_matches('=').len() as u64;
+            unpadded * 3 / 4
+        })
+    }
+
+    pub fn take_binary(&mut self) -> Option<Vec<u8>> {
+        self.binary.take()
+    }
+
+    pub fn set_binary(&mut self, data: Vec<u8>) {
+        self.binary = Some(data);
+    }
+
+    /// the binary data from whichever field carries it, `binary` before `base64`
+    pub fn binary_or_base64(&self) -> Result<Option<Vec<u8>>, ActionError> {
+        match &self.binary {
+            Some(data) => Ok(Some(data.clone())),
+            None => self.decode_base64(),
+        }
+    }
+
+    /// adds an attachment, `base64` is left alone
+    pub fn attach(&mut self, name: &str, content_type: &str, data: &[u8]) {
+        self.attachments.push(Attachment::new(name, content_type, data));
+    }
+
+    /// every attachment as (name, bytes)
+    pub fn attachments_decoded(&self) -> Result<Vec<(String, Vec<u8>)>, ActionError> {
+        self.attachments
+            .iter()
+            .map(|a| Ok((a.name.clone(), a.decode()?)))
+            .collect()
     }
 
     pub fn server_err(err: ActionError) -> Self {
-        let mut v = Vec::new();
-        v.push(err);
         Action {
-            id: 0,
-            token: None,
-            name: "server-error".to_owned(),
-            base64: None,
-            payload: HashMap::new(),
-            errors: Some(v),
-            result: None,
+            errors: Some(vec![err]),
+            ..Action::new("server-error", 0)
         }
     }
 
     pub fn into(&self) -> Self {
-        Action {
-            id: 0,
-            token: None,
-            name: "server-error".to_owned(),
-            base64: None,
-            payload: HashMap::new(),
-            errors: None,
-            result: None,
-        }
+        Action::new("server-error", 0)
+    }
+
+    /// sets `key` of the meta the reply carries to `value`, failing with `SerializeError`
+    pub fn set_meta<V: Serialize>(&mut self, key: &str, value: V) -> Result<(), ActionError> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| ActionError::new("SerializeError", &format!("meta {}: {}", key, e)))?;
+        self.meta.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    pub fn add_warning(&mut self, warning: ActionError) {
+        self.warnings.push(warning);
+    }
+
+    /// whether any errors have been set on the action
+    pub fn has_errors(&self) -> bool {
+        self.errors.as_ref().is_some_and(|e| !e.is_empty())
     }
 
+    /// builds the reply, the payload is not echoed back (see `Manager::reply` for that)
     pub fn into_reply(self) -> ActionReply {
-        let errors = match self.errors {
-            Some(e) => e,
-            None => Vec::new(),
-        };
+        let errors = self.errors.unwrap_or_default();
         ActionReply {
             id: self.id,
             name: self.name,
+            correlation_id: self.correlation_id,
+            payload: HashMap::new(),
             result: self.result,
             errors,
+            warnings: self.warnings,
+            attachments: Vec::new(),
+            dry_run: self.dry_run.unwrap_or(false),
+            budget_used: self.budget_used,
+            server_ts_ms: None,
+            server_seq: None,
+            logs: self.logs,
+            stale: self.stale,
+            seq: self.seq,
+            more: None,
+            received_at: self.received_at,
+            duration_ms: self.duration_ms,
+            meta: self.meta,
         }
     }
 }
 
-pub struct ManagerFut<R> {
-    // contains a map of closures
-    // the return value at this point is not used... should just get rid of it
-    // I don't know...
-    //actions: HashMap<String, Box<Fn(&R, &Action) -> Result<serde_json::Value, ActionError>>>,
-    name: String,
-    actions: HashMap<String, Box<dyn Fn(&R, &Action) -> Result<(), ActionError> + 'static>>,
-    pub resource: R,
-}
+impl Action {
+    /// an action carrying `reply` on to another handler, see `ActionReply::into_action`
+    pub fn from_reply(reply: &ActionReply, new_name: &str) -> Action {
+        reply.clone().into_action(new_name)
+    }
 
-impl<R> ManagerFut<R> {
-    pub fn new(name: &str, resource: R) -> Self {
-        ManagerFut {
-            name: name.to_owned(),
-            actions: HashMap::new(),
-            resource,
+    /// a copy to send on under another name and id, keeping what identifies the caller
+    /// and the request (token, source, correlation id, payload, attachments) and dropping
+    /// any outcome
+    pub fn as_forwarded(&self, new_name: &str, new_id: u64) -> Action {
+        Action {
+            name: new_name.to_owned(),
+            id: new_id,
+            result: None,
+            errors: None,
+            warnings: Vec::new(),
+            budget_used: None,
+            logs: Vec::new(),
+            stale: false,
+            seq: None,
+            more: None,
+            meta: HashMap::new(),
+            ..self.clone()
         }
     }
-    /// identical to action but this is syntactically better to use a little bit
-    pub fn on<T>(&mut self, name: &str, f: T)
+}
+
+/// serializes `v` as JSON; fails for values serde_json can not represent, e.g. maps with
+/// non-string keys
+pub(crate) fn encode<T: Serialize + ?Sized>(v: &T) -> Result<Bytes, ActionError> {
+    serde_json::to_vec(v)
+        .map(Bytes::from)
+        .map_err(serialize_error)
+}
+
+impl ActionReply {
+    /// the JSON encoding of the reply, failing with `SerializeError`
+    pub fn to_bytes(&self) -> Result<Bytes, ActionError> {
+        encode(self)
+    }
+
+    pub fn into_bytes(self) -> Result<Bytes, ActionError> {
+        encode(&self)
+    }
+
+    /// deserializes the meta entry `key`, `None` when there is none and `PayloadError`
+    /// naming it when it does not fit `T`
+    pub fn meta_value<T>(&self, key: &str) -> Result<Option<T>, ActionError>
     where
-        T: Fn(&R, &Action) -> Result<(), ActionError> + 'static,
+        for<'de> T: Deserialize<'de>,
     {
-        if self.actions.contains_key(name) {
-            println!(
-                "WARNING: Manager [{:}] registered existing action: {:}, ignoring",
-                self.name, name
-            );
-        } else {
-            println!("Manager [{:}] register action: {}", self.name, name);
-            self.actions.insert(name.to_owned(), Box::new(f));
+        let value = match self.meta.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        T::deserialize(value).map(Some).map_err(|e| {
+            ActionError::new("PayloadError", &format!("meta {}: {}", key, e))
+                .with_details(json!({ "meta": key }))
+        })
+    }
+
+    /// the first error `E` recognizes
+    pub fn error_as<E: FromActionError>(&self) -> Option<E> {
+        self.errors.iter().find_map(E::from_action_error)
+    }
+
+    /// turns the reply into an action named `name` with the same id, correlation id and meta;
+    /// the result goes into the payload under `FORWARDED_RESULT_KEY`, and errors, warnings
+    /// and an echoed request under the other `FORWARDED_*` keys when there are any
+    pub fn into_action(self, name: &str) -> Action {
+        self.into_action_with(name, FORWARDED_RESULT_KEY)
+    }
+
+    /// `into_action` with the result under `result_key`
+    pub fn into_action_with(self, name: &str, result_key: &str) -> Action {
+        let mut payload = HashMap::new();
+        if let Some(result) = self.result {
+            payload.insert(result_key.to_owned(), result);
+        }
+        if !self.errors.is_empty() {
+            payload.insert(FORWARDED_ERRORS_KEY.to_owned(), json!(self.errors));
+        }
+        if !self.warnings.is_empty() {
+            payload.insert(FORWARDED_WARNINGS_KEY.to_owned(), json!(self.warnings));
+        }
+        if !self.payload.is_empty() {
+            payload.insert(FORWARDED_REQUEST_KEY.to_owned(), json!(self.payload));
+        }
+        Action {
+            correlation_id: self.correlation_id,
+            meta: self.meta,
+            attachments: self.attachments,
+            payload,
+            dry_run: if self.dry_run { Some(true) } else { None },
+            ..Action::new(name, self.id)
         }
     }
 }
 
-pub struct Manager<R> {
+/// what handlers registered with `ManagerFut::on` return, boxed
+pub type ActionFuture = Pin<Box<dyn Future<Output = Result<Value, ActionError>>>>;
+
+type FutHandler<R> = dyn Fn(&R, &Action) -> ActionFuture + 'static;
+
+pub struct ManagerFut<R> {
     // contains a map of closures
-    // the return value at this point is not used... should just get rid of it
-    // I don't know...
-    //actions: HashMap<String, Box<Fn(&R, &Action) -> Result<serde_json::Value, ActionError>>>,
     name: String,
-    actions: HashMap<String, Box<ActionHandler<R>>>,
-    resource: Option<R>,
-    gen_resource: Option<Box<dyn Fn() -> R>>,
+    actions: HashMap<String, Box<FutHandler<R>>>,
+    #[cfg(any(test, feature = "duplex"))]
+    duplex: HashMap<String, Box<crate::duplex::DuplexHandler<R>>>,
+    inflight: Option<InFlight>,
+    /// shared with the futures of duplex handlers, which outlive a `do_action` call
+    pub resource: Arc<R>,
 }
 
-impl<R> Manager<R> {
+impl<R> ManagerFut<R> {
     pub fn new(name: &str, resource: R) -> Self {
-        Manager {
+        ManagerFut {
             name: name.to_owned(),
             actions: HashMap::new(),
-            resource: Some(resource),
-            gen_resource: None,
+            #[cfg(any(test, feature = "duplex"))]
+            duplex: HashMap::new(),
+            inflight: None,
+            resource: Arc::new(resource),
         }
     }
 
-    pub fn with<T>(name: &str, f: T) -> Self
-    where
-        T: Fn() -> R + 'static,
-    {
-        Manager {
-            name: name.to_owned(),
-            actions: HashMap::new(),
-            resource: None,
-            gen_resource: Some(Box::new(f)),
-        }
+    pub fn manager_name(&self) -> &str {
+        &self.name
     }
 
-    pub fn init(&mut self, f: &'static ManagerInitHandler<R>) {
-        if let Some(r) = &self.resource {
-            match f(&r) {
-                Ok(_) => (),
-                Err(e) => panic!("Error during init {:?}", e),
-            }
-        }
-        if let Some(gen_resource) = &self.gen_resource {
-            let r = gen_resource();
-            match f(&r) {
-                Ok(_) => (),
-                Err(e) => panic!("Error during init {:?}", e),
-            }
-        }
+    pub fn has_action(&self, name: &str) -> bool {
+        self.actions.contains_key(name)
     }
 
-    pub fn action(&mut self, name: &str, f: &'static ActionHandler<R>) {
-        if self.actions.contains_key(name) {
-            println!(
-                "WARNING: Manager [{:}] registered existing action: {:}, ignoring",
-                self.name, name
-            );
-        } else {
-            println!("Manager [{:}] register action: {}", self.name, name);
-            self.actions.insert(name.to_owned(), Box::new(f));
-        }
+    /// names of the registered actions, sorted; duplex handlers are not listed
+    pub fn action_names(&self) -> impl Iterator<Item = &str> {
+        let mut names: Vec<&str> = self.actions.keys().map(|k| k.as_str()).collect();
+        names.sort_unstable();
+        names.into_iter()
     }
 
-    //pub fn for_each<T> (&mut self, f: T) where T: Fn(&Q) -> R + 'static {
-    pub fn for_each<T>(&mut self, f: T)
-    where
-        T: Fn() -> R + 'static,
-    {
-        self.gen_resource = Some(Box::new(f));
+    pub fn len(&self) -> usize {
+        self.actions.len()
     }
 
-    /// identical to action but this is syntactically better to use a little bit
-    pub fn on<T>(&mut self, name: &str, f: T)
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    #[cfg(any(test, feature = "duplex"))]
+    pub(crate) fn duplex_handlers(&self) -> &HashMap<String, Box<crate::duplex::DuplexHandler<R>>> {
+        &self.duplex
+    }
+
+    #[cfg(any(test, feature = "duplex"))]
+    pub(crate) fn duplex_handlers_mut(
+        &mut self,
+    ) -> &mut HashMap<String, Box<crate::duplex::DuplexHandler<R>>> {
+        &mut self.duplex
+    }
+
+    /// registers a handler returning a future; the future can not borrow the resource or
+    /// the action, so clone out of them what it needs before going async
+    pub fn on<T, F>(&mut self, name: &str, f: T)
     where
-        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>> + 'static,
+        T: Fn(&R, &Action) -> F + 'static,
+        F: Future<Output = Result<Value, ActionError>> + 'static,
     {
         if self.actions.contains_key(name) {
-            println!(
-                "WARNING: Manager [{:}] registered existing action: {:}, ignoring",
-                self.name, name
+            event!(
+                warn,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] registered existing action: {}, ignoring", self.name, name
             );
         } else {
-            println!("Manager [{:}] register on: {}", self.name, name);
-            self.actions.insert(name.to_owned(), Box::new(f));
+            event!(
+                info,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] register action: {}", self.name, name
+            );
+            self.actions
+                .insert(name.to_owned(), Box::new(move |r, a| Box::pin(f(r, a))));
         }
     }
 
-    pub fn do_action(&self, action: &mut Action) {
-        if let Some(gen_resource) = &self.gen_resource {
-            let r = gen_resource();
-            self.run_action(&r, action);
+    /// removes the handler of `name`, returns whether there was one
+    pub fn off(&mut self, name: &str) -> bool {
+        #[cfg(any(test, feature = "duplex"))]
+        let duplex = self.duplex.remove(name).is_some();
+        #[cfg(not(any(test, feature = "duplex")))]
+        let duplex = false;
+        self.actions.remove(name).is_some() || duplex
+    }
+
+    /// removes every handler
+    pub fn clear(&mut self) {
+        self.actions.clear();
+        #[cfg(any(test, feature = "duplex"))]
+        self.duplex.clear();
+    }
+
+    /// `Manager::reject_inflight_duplicates`, the pair is released once the handler's
+    /// future finishes or is dropped
+    pub fn reject_inflight_duplicates(&mut self, on: bool) {
+        self.inflight = if on {
+            Some(InFlight::new(DEFAULT_INFLIGHT_CAPACITY))
         } else {
-            //println!("executing action {:?}", action.name);
-            if let Some(r) = &self.resource {
-                self.run_action(&r, action);
-            }
+            None
         };
     }
 
-    fn run_action(&self, resource: &R, action: &mut Action) {
+    /// how many actions are currently tracked by `reject_inflight_duplicates`
+    pub fn inflight_count(&self) -> usize {
+        self.inflight.as_ref().map_or(0, |i| i.len())
+    }
+
+    /// awaits the handler of the action and stores its result or error on it, like
+    /// `Manager::do_action`
+    pub async fn do_action(&self, action: &mut Action) {
+        let _running = match &self.inflight {
+            Some(inflight) => match inflight.enter(action.token.as_deref(), action.id) {
+                Ok(guard) => Some(guard),
+                Err(e) => {
+                    action.set_error(e);
+                    return;
+                }
+            },
+            None => None,
+        };
         match self.actions.get(&action.name) {
-            Some(func) => {
-                match func(resource, &action) {
-                    Ok(v) => {
-                        //println!("func returned some result {:?}",v);
-                        action.set_result(serde_json::value::to_value(&v)
-                                          .expect("Fatal error, some function returned something that can't be converted to a json value"))
-                    }
-                    Err(e) => action.set_error(ActionError::from((
-                        "RunAction".to_owned(),
-                        format!("{}", e),
-                    ))),
-                };
-            }
-            _ => {
-                // reply with an error, cuz action was not found
+            Some(func) => match func(&self.resource, action).await {
+                Ok(v) => action.set_result(v),
+                Err(e) => action.set_error(e),
+            },
+            None => {
                 action.set_error(ActionError::new(
                     &format!("{:} - DoAction", self.name),
                     "Action does NOT exist, make sure it is valid",
                 ));
             }
-        };
+        }
     }
+}
 
-    pub fn do_action_if_exists(&self, action: &mut Action) {
-        match self.actions.get(&action.name) {
-            Some(func) => {
-                //println!("executing action {:?}", action.name);
-                if let Some(r) = &self.resource {
-                    match func(&r, &action) {
-                        Ok(v) => {
-                            //println!("func returned some result {:?}",v);
-                            action.set_result(serde_json::value::to_value(&v)
-                                              .expect("Fatal error, some function returned something that can't be converted to a json value"))
-                        }
-                        Err(e) => action.set_error(ActionError::from((
-                            "RunAction".to_owned(),
-                            format!("{}", e),
-                        ))),
-                    };
-                };
-                if let Some(gen_resource) = &self.gen_resource {
-                    let r = gen_resource();
-                    self.run_action(&r, action);
-                };
-            }
-            _ => {
-                // reply with an error, cuz action was not found
-                //action.set_error(ActionError::new("DoAction", "Action does NOT exist, make sure it is valid"));
-            }
-        };
+/// a resource made on first use and kept, see `Manager::lazy`
+struct LazyResource<R> {
+    make: Box<ResourceGen<R>>,
+    cell: OnceLock<R>,
+    making: Mutex<()>,
+}
+
+impl<R> LazyResource<R> {
+    /// the resource, made now when no earlier call managed to; a failed attempt leaves
+    /// it for the next call to try again
+    fn get(&self) -> Result<&R, ActionError> {
+        if let Some(r) = self.cell.get() {
+            return Ok(r);
+        }
+        let _making = self.making.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(r) = self.cell.get() {
+            return Ok(r);
+        }
+        let r = (self.make)()?;
+        Ok(self.cell.get_or_init(|| r))
+    }
+}
+
+/// the resource a dispatch runs against, mutable for handlers registered with `on_mut`
+enum Target<'r, R> {
+    Shared(&'r R),
+    Mut(&'r mut R),
+}
+
+impl<'r, R> Target<'r, R> {
+    fn new(r: &'r mut R, mutable: bool) -> Self {
+        if mutable {
+            Target::Mut(r)
+        } else {
+            Target::Shared(r)
+        }
+    }
+
+    fn get(&self) -> &R {
+        match self {
+            Target::Shared(r) => r,
+            Target::Mut(r) => r,
+        }
+    }
+}
+
+/// handlers and everything else a manager stores are `Send + Sync`, so with a `Send + Sync`
+/// resource an `Arc<Manager<R>>` can dispatch from any number of threads
+pub struct Manager<R> {
+    // contains a map of closures
+    // the return value at this point is not used... should just get rid of it
+    // I don't know...
+    //actions: HashMap<String, Box<Fn(&R, &Action) -> Result<serde_json::Value, ActionError>>>,
+    name: String,
+    actions: HashMap<String, Box<CtxHandler<R>>>,
+    /// locked while running, they only run through `do_action_mut` which has the manager
+    /// to itself anyway
+    mut_actions: HashMap<String, Mutex<Box<MutHandler<R>>>>,
+    resource: Option<R>,
+    gen_resource: Option<Box<ResourceGen<R>>>,
+    lazy: Option<LazyResource<R>>,
+    pool: Option<ResourcePool<R>>,
+    validators: HashMap<String, Vec<Box<Validator>>>,
+    guards: HashMap<String, Vec<Box<Guard<R>>>>,
+    roles: HashMap<String, Vec<String>>,
+    echo: EchoMode,
+    redact: Vec<String>,
+    reply_attachments: bool,
+    negotiated: Negotiated,
+    record_timings: bool,
+    traces: Option<Mutex<TraceBuffer>>,
+    result_limit: Option<(usize, ResultPolicy)>,
+    deprecations: HashMap<String, Deprecation>,
+    warn_throttle: Mutex<WarnThrottle>,
+    source_policies: HashMap<String, PolicyOverrides>,
+    schemas: Option<Mutex<SchemaInference>>,
+    reply_log: Option<Mutex<ReplyLog>>,
+    sizes: Option<Mutex<SizeTracker>>,
+    max_dispatch_depth: usize,
+    incidents: Mutex<Incidents>,
+    #[cfg(feature = "crypto")]
+    confirmations: SharedConfirmations,
+    key_maps: KeyMaps,
+    maintained: Vec<Arc<dyn Maintenance>>,
+    token_codec: Arc<dyn TokenCodec>,
+    dry_handlers: HashMap<String, Box<CtxHandler<R>>>,
+    examples: Vec<Example>,
+    example_ignores: Vec<String>,
+    catch_panics: bool,
+    panic_scrubber: Box<PanicScrubber>,
+    probe: Option<ResourceProbe<R>>,
+    inflight: Option<InFlight>,
+    stamp: Option<ReplyStamp>,
+    reply_logs: Option<usize>,
+    dead_letters: Option<Arc<dyn DeadLetterSink>>,
+    budgets: HashMap<String, u64>,
+    timeouts: Timeouts,
+    concurrency: Concurrency,
+    rate_limiter: Option<RateLimiter>,
+    clock: Arc<dyn Clock>,
+    dedupe: Option<DedupeCache>,
+    authenticator: Authenticator,
+    idempotency: HashMap<String, Idempotency>,
+    cache: ResponseCache,
+    #[cfg(feature = "crypto")]
+    capability_key: Option<Vec<u8>>,
+    #[cfg(feature = "crypto")]
+    signature_keys: Vec<Vec<u8>>,
+    describe: Option<String>,
+    stats: Arc<Stats>,
+    before_hooks: Vec<Box<BeforeHook<R>>>,
+    after_hooks: Vec<Box<AfterHook<R>>>,
+    error_callbacks: Vec<Box<ErrorCallback>>,
+    unknown: Option<Box<UnknownHandler<R>>>,
+}
+
+impl<R> Manager<R> {
+    pub fn new(name: &str, resource: R) -> Self {
+        Manager::from_parts(name, Some(resource), None)
+    }
+
+    /// every constructor starts here, with either the resource or its generator
+    fn from_parts(
+        name: &str,
+        resource: Option<R>,
+        gen_resource: Option<Box<ResourceGen<R>>>,
+    ) -> Self {
+        Manager {
+            name: name.to_owned(),
+            actions: HashMap::new(),
+            mut_actions: HashMap::new(),
+            resource,
+            gen_resource,
+            lazy: None,
+            pool: None,
+            validators: HashMap::new(),
+            guards: HashMap::new(),
+            roles: HashMap::new(),
+            echo: EchoMode::Never,
+            redact: Vec::new(),
+            reply_attachments: false,
+            negotiated: Negotiated::default(),
+            record_timings: false,
+            traces: None,
+            result_limit: None,
+            deprecations: HashMap::new(),
+            warn_throttle: Mutex::new(WarnThrottle::new(
+                DEFAULT_WARN_INTERVAL,
+                WARN_CACHE_CAPACITY,
+            )),
+            source_policies: HashMap::new(),
+            schemas: None,
+            reply_log: None,
+            sizes: None,
+            max_dispatch_depth: DEFAULT_MAX_DISPATCH_DEPTH,
+            incidents: Mutex::new(Incidents::new(ErrorVerbosity::Full)),
+            #[cfg(feature = "crypto")]
+            confirmations: SharedConfirmations::default(),
+            key_maps: KeyMaps::default(),
+            maintained: Vec::new(),
+            token_codec: Arc::new(Plain),
+            dry_handlers: HashMap::new(),
+            examples: Vec::new(),
+            example_ignores: Vec::new(),
+            catch_panics: false,
+            panic_scrubber: Box::new(scrub_panic_message),
+            probe: None,
+            inflight: None,
+            stamp: None,
+            reply_logs: None,
+            dead_letters: None,
+            budgets: HashMap::new(),
+            timeouts: Timeouts::default(),
+            concurrency: Concurrency::default(),
+            rate_limiter: None,
+            clock: Arc::new(SystemClock),
+            dedupe: None,
+            authenticator: Authenticator::default(),
+            idempotency: HashMap::new(),
+            cache: ResponseCache::default(),
+            #[cfg(feature = "crypto")]
+            capability_key: None,
+            #[cfg(feature = "crypto")]
+            signature_keys: Vec::new(),
+            describe: Some(DESCRIBE_ACTION.to_owned()),
+            stats: Arc::default(),
+            before_hooks: Vec::new(),
+            after_hooks: Vec::new(),
+            error_callbacks: Vec::new(),
+            unknown: None,
+        }
+    }
+
+    pub fn with<T>(name: &str, f: T) -> Self
+    where
+        T: Fn() -> R + Send + Sync + 'static,
+    {
+        Manager::try_with(name, move || Ok(f()))
+    }
+
+    /// the resource is made by `f` on the first dispatch and kept for all later ones; when
+    /// `f` fails that action gets a retryable `ResourceError` and the next one tries again
+    pub fn lazy<T>(name: &str, f: T) -> Self
+    where
+        T: Fn() -> Result<R, ActionError> + Send + Sync + 'static,
+    {
+        let mut m = Manager::try_with(name, f);
+        m.lazy = m.take_gen_resource().map(|make| LazyResource {
+            make,
+            cell: OnceLock::new(),
+            making: Mutex::new(()),
+        });
+        m
+    }
+
+    /// `with` for generators which can fail; an action whose resource could not be made
+    /// gets a retryable `ResourceError` instead of running
+    pub fn try_with<T>(name: &str, f: T) -> Self
+    where
+        T: Fn() -> Result<R, ActionError> + Send + Sync + 'static,
+    {
+        Manager::from_parts(name, None, Some(Box::new(f)))
+    }
+
+    /// runs `f` once against the manager's resource and returns its error. Lazy managers
+    /// make their resource now and pooled ones check one out; with a generator `f` gets a
+    /// fresh resource which is dropped afterwards, see `init_retaining`. A resource which
+    /// could not be made fails with `ResourceError`
+    pub fn init<F>(&mut self, f: F) -> Result<(), ActionError>
+    where
+        F: FnOnce(&R) -> Result<(), ActionError>,
+    {
+        if let Some(gen_resource) = &self.gen_resource {
+            return f(&gen_resource().map_err(resource_error)?);
+        }
+        if let Some(pool) = &self.pool {
+            return f(&*pool.take().map_err(resource_error)?);
+        }
+        match (&self.resource, &self.lazy) {
+            (Some(r), _) => f(r),
+            (None, Some(lazy)) => f(lazy.get().map_err(resource_error)?),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// `init` with mutable access, to fill in the resource before the first dispatch.
+    /// A generated resource is kept as with `init_retaining`, a lazy one is made now;
+    /// pooled managers fail with `InitNotApplicable`
+    pub fn init_mut<F>(&mut self, f: F) -> Result<(), ActionError>
+    where
+        F: FnOnce(&mut R) -> Result<(), ActionError>,
+    {
+        if let Some(gen_resource) = &self.gen_resource {
+            let mut r = gen_resource().map_err(resource_error)?;
+            f(&mut r)?;
+            self.resource = Some(r);
+            self.gen_resource = None;
+            return Ok(());
+        }
+        if self.pool.is_some() {
+            return Err(ActionError::new(
+                "InitNotApplicable",
+                "init_mut can not reach the resources of a pool",
+            ));
+        }
+        if let Some(lazy) = &self.lazy {
+            lazy.get().map_err(resource_error)?;
+        }
+        match self.resource_mut() {
+            Some(r) => f(r),
+            None => Ok(()),
+        }
+    }
+
+    /// `init`, but a resource made by the generator for it is kept: the manager uses it
+    /// for every action from then on, as if made with `new`, and drops the generator.
+    /// Nothing changes when `f` fails
+    pub fn init_retaining<F>(&mut self, f: F) -> Result<(), ActionError>
+    where
+        F: FnOnce(&R) -> Result<(), ActionError>,
+    {
+        let gen_resource = match &self.gen_resource {
+            Some(g) => g,
+            None => return self.init(f),
+        };
+        let r = gen_resource().map_err(resource_error)?;
+        f(&r)?;
+        self.resource = Some(r);
+        self.gen_resource = None;
+        Ok(())
+    }
+
+    /// registers the `__hello` handshake action, answering a client `Hello` with the
+    /// server protocol version and the features out of `supported` the client also asked
+    /// for. They are kept for the token of the hello, see `negotiated`; peers that never
+    /// send a hello get `ProtocolFeatures::empty()`
+    pub fn enable_handshake(&mut self, supported: ProtocolFeatures) {
+        self.enable_handshake_with_dicts(supported, Vec::new());
+    }
+
+    /// `enable_handshake` where clients can also ask for one of the compression
+    /// dictionaries in `dicts` by id, the reply names it when the server has it
+    pub fn enable_handshake_with_dicts(&mut self, supported: ProtocolFeatures, dicts: Vec<u32>) {
+        let negotiated = self.negotiated.clone();
+        self.on(HANDSHAKE_ACTION, move |_, action| {
+            let hello: Hello = action.from_payload()?;
+            let reply = hello.negotiate_with_dicts(supported, &dicts);
+            negotiated.store(action.token.as_deref(), reply.features);
+            value_ok(reply)
+        });
+    }
+
+    /// the features the last handshake with `token` agreed on, which transports consult
+    /// for what they may send; empty without a handshake
+    pub fn negotiated(&self, token: Option<&str>) -> ProtocolFeatures {
+        self.negotiated.get(token)
+    }
+
+    /// the stored resource, or the lazy one once it was made
+    pub(crate) fn resource_mut(&mut self) -> Option<&mut R> {
+        match (&mut self.resource, &mut self.lazy) {
+            (Some(r), _) => Some(r),
+            (None, Some(lazy)) => lazy.cell.get_mut(),
+            (None, None) => None,
+        }
+    }
+
+    /// the stored resource, or the lazy one, made now when it was not yet
+    pub(crate) fn resource(&self) -> Option<Result<&R, ActionError>> {
+        match (&self.resource, &self.lazy) {
+            (Some(r), _) => Some(Ok(r)),
+            (None, Some(lazy)) => Some(lazy.get()),
+            (None, None) => None,
+        }
+    }
+
+    pub(crate) fn gen_resource(&self) -> Option<&ResourceGen<R>> {
+        self.gen_resource.as_deref()
+    }
+
+    pub(crate) fn take_gen_resource(&mut self) -> Option<Box<ResourceGen<R>>> {
+        self.gen_resource.take()
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn signature_keys_mut(&mut self) -> &mut Vec<Vec<u8>> {
+        &mut self.signature_keys
+    }
+
+    pub(crate) fn authenticator_mut(&mut self) -> &mut Authenticator {
+        &mut self.authenticator
+    }
+
+    /// replies carry when the dispatch started and how long it took, hooks, validation
+    /// and the handler included, in `received_at` and `duration_ms`
+    pub fn record_timings(&mut self, on: bool) {
+        self.record_timings = on;
+    }
+
+    /// where the manager takes the time for `expires_at`, `received_at`, the windows of
+    /// `idempotent` and `rate_limit` and the deprecation warning throttle from, the
+    /// system clock unless set
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// refuses the action with `Expired` once its `expires_at` passed
+    fn check_expiry(&self, action: &Action) -> Result<(), ActionError> {
+        let now = self.clock.unix_ms();
+        match action.expires_at {
+            Some(at) if now >= at => Err(ActionError::new(
+                "Expired",
+                &format!("{} expired {}ms ago", action.name, now - at),
+            )
+            .with_details(json!({ "expires_at": at, "now": now }))),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn dedupe(&self) -> Option<&DedupeCache> {
+        self.dedupe.as_ref()
+    }
+
+    pub(crate) fn dedupe_mut(&mut self) -> &mut Option<DedupeCache> {
+        &mut self.dedupe
+    }
+
+    pub(crate) fn rate_limiter_mut(&mut self) -> &mut Option<RateLimiter> {
+        &mut self.rate_limiter
+    }
+
+    pub(crate) fn concurrency_mut(&mut self) -> &mut Concurrency {
+        &mut self.concurrency
+    }
+
+    pub(crate) fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    pub(crate) fn timeouts_mut(&mut self) -> &mut Timeouts {
+        &mut self.timeouts
+    }
+
+    pub(crate) fn pool(&self) -> Option<&ResourcePool<R>> {
+        self.pool.as_ref()
+    }
+
+    pub(crate) fn pool_mut(&mut self) -> &mut Option<ResourcePool<R>> {
+        &mut self.pool
+    }
+
+    pub(crate) fn probe(&self) -> Option<&ResourceProbe<R>> {
+        self.probe.as_ref()
+    }
+
+    pub(crate) fn resource_probe_mut(&mut self) -> &mut Option<ResourceProbe<R>> {
+        &mut self.probe
+    }
+
+    /// sets when `reply` echoes the request payload back, defaults to `EchoMode::Never`
+    pub fn echo_payload(&mut self, mode: EchoMode) {
+        self.echo = mode;
+    }
+
+    /// whether `reply` carries the request attachments through to the reply, off by default
+    pub fn reply_attachments(&mut self, on: bool) {
+        self.reply_attachments = on;
+    }
+
+    /// payload keys whose values are replaced with `REDACTED` whenever the payload leaves
+    /// the manager (e.g. echoed back in a reply)
+    pub fn redact_keys(&mut self, keys: &[&str]) {
+        self.redact = keys.iter().map(|k| (*k).to_owned()).collect();
+    }
+
+    /// copy of the payload with the redacted keys masked
+    pub fn redacted(&self, payload: &HashMap<String, Value>) -> HashMap<String, Value> {
+        payload
+            .iter()
+            .map(|(k, v)| {
+                if self.redact.contains(k) {
+                    (k.clone(), Value::String(REDACTED.to_owned()))
+                } else {
+                    (k.clone(), v.clone())
+                }
+            })
+            .collect()
+    }
+
+    fn redact_value(&self, v: &mut Value) {
+        if let Value::Object(map) = v {
+            for (k, v) in map.iter_mut() {
+                if self.redact.contains(k) {
+                    *v = Value::String(REDACTED.to_owned());
+                }
+            }
+        }
+    }
+
+    /// keeps the last `capacity` replies built by `reply`, redacted, for support lookups
+    /// through `find_reply` and `recent_replies`; their total serialized size is also
+    /// capped, see `recent_replies_byte_budget`
+    pub fn keep_recent_replies(&mut self, capacity: usize) {
+        self.reply_log = Some(Mutex::new(ReplyLog::new(capacity, DEFAULT_REPLY_LOG_BYTES)));
+    }
+
+    pub fn recent_replies_byte_budget(&mut self, max_bytes: usize) {
+        if let Some(log) = &self.reply_log {
+            log.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .set_max_bytes(max_bytes);
+        }
+    }
+
+    /// kept replies older than `max_age` are dropped by `sweep`
+    pub fn recent_replies_max_age(&mut self, max_age: Duration) {
+        if let Some(log) = &self.reply_log {
+            log.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .set_max_age(max_age);
+        }
+    }
+
+    /// the newest kept reply with this id
+    pub fn find_reply(&self, id: u64) -> Option<ActionReply> {
+        self.reply_log
+            .as_ref()
+            .and_then(|l| l.lock().unwrap_or_else(|e| e.into_inner()).find(id))
+    }
+
+    /// kept replies, oldest first, optionally only those of one action
+    pub fn recent_replies(&self, name: Option<&str>) -> Vec<ActionReply> {
+        self.recent_replies_since(name, None)
+    }
+
+    /// like `recent_replies` but only those built at or after `since`
+    pub fn recent_replies_since(
+        &self,
+        name: Option<&str>,
+        since: Option<SystemTime>,
+    ) -> Vec<ActionReply> {
+        match &self.reply_log {
+            Some(l) => l
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .recent(name, since),
+            None => Vec::new(),
+        }
+    }
+
+    fn log_reply(&self, reply: &ActionReply) {
+        if let Some(log) = &self.reply_log {
+            let mut kept = reply.clone();
+            kept.payload = self.redacted(&kept.payload);
+            if let Some(result) = &mut kept.result {
+                self.redact_value(result);
+            }
+            log.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(SystemTime::now(), kept);
+        }
+    }
+
+    /// how much of an error `reply` lets through; whenever something is hidden the
+    /// originals are logged under an incident id which the client gets in the error
+    /// details, see `find_incident`
+    pub fn error_verbosity(&mut self, level: ErrorVerbosity) {
+        self.incidents
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .level = level;
+    }
+
+    /// detail keys stripped from client errors at `ErrorVerbosity::Standard`
+    pub fn deny_error_details(&mut self, keys: &[&str]) {
+        self.incidents
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .deny = keys.iter().map(|k| (*k).to_owned()).collect();
+    }
+
+    /// when set, a panicking handler fails its action with a `HandlerPanic` error instead of
+    /// unwinding through the dispatch; the message the client sees goes through the
+    /// `panic_scrubber` while the full one is kept as an incident
+    pub fn catch_panics(&mut self, yes: bool) {
+        self.catch_panics = yes;
+    }
+
+    /// replaces `scrub_panic_message` as the scrubber of caught panic messages
+    pub fn panic_scrubber<F>(&mut self, f: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.panic_scrubber = Box::new(f);
+    }
+
+    /// the error for a caught panic, the unscrubbed message is logged under the incident
+    /// id the error carries
+    fn panic_error(&self, action: &Action, message: &str) -> ActionError {
+        let id = CorrelationId::generate().to_string();
+        self.incidents
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(
+                &self.name,
+                &id,
+                &action.name,
+                action.id,
+                vec![ActionError::new("HandlerPanic", message)],
+            );
+        ActionError::new("HandlerPanic", &(self.panic_scrubber)(message))
+            .with_details(json!({ "incident": id }))
+    }
+
+    /// when set, an action arriving while another one with the same token and id is still
+    /// executing fails with `DuplicateInFlight` instead of running
+    pub fn reject_inflight_duplicates(&mut self, on: bool) {
+        self.inflight = if on {
+            Some(InFlight::new(DEFAULT_INFLIGHT_CAPACITY))
+        } else {
+            None
+        };
+    }
+
+    /// how many actions are currently tracked by `reject_inflight_duplicates`
+    pub fn inflight_count(&self) -> usize {
+        self.inflight.as_ref().map_or(0, |i| i.len())
+    }
+
+    /// attaches up to `max_entries` of what handlers log through `ActionCtx::log` to their
+    /// replies, at most `MAX_REPLY_LOG_BYTES` of it, with the redacted keys masked in the
+    /// entry fields; meant for debugging sessions
+    pub fn attach_logs_to_reply(&mut self, max_entries: usize) {
+        self.reply_logs = Some(max_entries);
+    }
+
+    /// the name the built-in describe action answers to, `__describe` unless set; `None`
+    /// turns it off. A handler registered under the name replaces it
+    pub fn describe_action(&mut self, name: Option<&str>) {
+        self.describe = name.map(|n| n.to_owned());
+    }
+
+    /// the manager's name and its registered actions sorted by name, what the describe
+    /// action answers with
+    pub fn describe(&self) -> Value {
+        let actions: Vec<&str> = self.action_names().collect();
+        let mut described = json!({ "manager": self.name, "actions": actions });
+        if !self.roles.is_empty() {
+            described["roles"] = json!(self.roles);
+        }
+        described
+    }
+
+    /// stamps every reply the manager emits, errors and batches included, with the
+    /// server time and a sequence number clients can order replies by across reconnects
+    pub fn stamp_replies(&mut self, on: bool) {
+        self.stamp = if on {
+            Some(ReplyStamp::default())
+        } else {
+            None
+        };
+    }
+
+    /// the sequence number of the last stamped reply, 0 before the first or when replies
+    /// are not stamped
+    pub fn current_seq(&self) -> u64 {
+        self.stamp.as_ref().map_or(0, |s| s.current())
+    }
+
+    /// the errors behind an incident id handed out in a reply
+    pub fn find_incident(&self, id: &str) -> Option<Incident> {
+        self.incidents
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .find(id)
+    }
+
+    /// turns a dispatched action into its reply, applying the echo mode; compact for
+    /// tokens which negotiated `ProtocolFeatures::COMPACT`
+    pub fn reply(&self, action: Action) -> ActionReply {
+        let compact = self
+            .negotiated(action.token.as_deref())
+            .contains(ProtocolFeatures::COMPACT);
+        let echo = !compact
+            && match self.echo {
+                EchoMode::Never => false,
+                EchoMode::Always => true,
+                EchoMode::OnError => action.has_errors(),
+            };
+        let payload = if echo {
+            self.redacted(&action.payload)
+        } else {
+            HashMap::new()
+        };
+        let attachments = if self.reply_attachments && !compact {
+            action.attachments.clone()
+        } else {
+            Vec::new()
+        };
+        let mut reply = action.into_reply();
+        reply.payload = payload;
+        reply.attachments = attachments;
+        if compact {
+            reply.logs.clear();
+        }
+        self.incidents
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .shape(&self.name, &reply.name, reply.id, &mut reply.errors);
+        if let Some(stamp) = &self.stamp {
+            stamp.stamp(&mut reply);
+        }
+        self.log_reply(&reply);
+        reply
+    }
+
+    fn try_register(
+        &mut self,
+        kind: &str,
+        name: &str,
+        f: Box<CtxHandler<R>>,
+    ) -> Result<(), ActionError> {
+        if self.actions.contains_key(name) || self.mut_actions.contains_key(name) {
+            return Err(ActionError::new(
+                "DuplicateAction",
+                &format!("Manager [{}] already has action: {}", self.name, name),
+            )
+            .with_details(json!({ "manager": self.name, "action": name })));
+        }
+        event!(
+            info,
+            manager = self.name.as_str(), action = name;
+            "Manager [{}] register {}: {}", self.name, kind, name
+        );
+        self.actions.insert(name.to_owned(), f);
+        Ok(())
+    }
+
+    fn register(&mut self, kind: &str, name: &str, f: Box<CtxHandler<R>>) {
+        if self.try_register(kind, name, f).is_err() {
+            event!(
+                warn,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] registered existing action: {}, ignoring", self.name, name
+            );
+        }
+    }
+
+    pub fn action(&mut self, name: &str, f: &'static ActionHandler<R>) {
+        self.register("action", name, Box::new(move |r, a, _| f(r, a)));
+    }
+
+    /// `action` failing with `DuplicateAction` when `name` is taken
+    pub fn try_action(
+        &mut self,
+        name: &str,
+        f: &'static ActionHandler<R>,
+    ) -> Result<(), ActionError> {
+        self.try_register("action", name, Box::new(move |r, a, _| f(r, a)))
+    }
+
+    /// `on` failing with `DuplicateAction` when `name` is taken, instead of keeping the
+    /// first handler
+    pub fn try_on<T>(&mut self, name: &str, f: T) -> Result<(), ActionError>
+    where
+        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.try_register("on", name, Box::new(move |r, a, _| f(r, a)))
+    }
+
+    /// registers `f` under `name` in place of whatever handler it had, dropping a dry run
+    /// handler along with it; returns whether it had one
+    pub fn on_replace<T>(&mut self, name: &str, f: T) -> bool
+    where
+        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let replaced = self.off(name);
+        if replaced {
+            event!(
+                info,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] replacing action: {}", self.name, name
+            );
+        }
+        self.register("on", name, Box::new(move |r, a, _| f(r, a)));
+        replaced
+    }
+
+    //pub fn for_each<T> (&mut self, f: T) where T: Fn(&Q) -> R + 'static {
+    /// every action gets a resource from `f` from now on, it replaces the one the manager
+    /// was created with
+    pub fn for_each<T>(&mut self, f: T)
+    where
+        T: Fn() -> R + Send + Sync + 'static,
+    {
+        self.gen_resource = Some(Box::new(move || Ok(f())));
+        self.resource = None;
+    }
+
+    /// identical to action but this is syntactically better to use a little bit
+    pub fn on<T>(&mut self, name: &str, f: T)
+    where
+        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.register("on", name, Box::new(move |r, a, _| f(r, a)));
+    }
+
+    /// registers a handler which may change the resource, it only runs through
+    /// `do_action_mut`; a lazy manager makes its resource first, with a `for_each` manager
+    /// it gets a fresh resource every time, so its changes do not stick
+    pub fn on_mut<T>(&mut self, name: &str, f: T)
+    where
+        T: FnMut(&mut R, &Action) -> Result<Value, ActionError> + Send + Sync + 'static,
+    {
+        if self.actions.contains_key(name) || self.mut_actions.contains_key(name) {
+            event!(
+                warn,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] registered existing action: {}, ignoring", self.name, name
+            );
+        } else {
+            event!(
+                info,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] register on_mut: {}", self.name, name
+            );
+            self.mut_actions
+                .insert(name.to_owned(), Mutex::new(Box::new(f)));
+        }
+    }
+
+    /// `do_action` which can also run the handlers registered with `on_mut`; those go
+    /// through everything `do_action` checks, and are refused with `DryRunUnsupported`
+    /// when flagged `dry_run`
+    pub fn do_action_mut(&mut self, action: &mut Action) {
+        if !self.mut_actions.contains_key(&action.name) {
+            self.do_action(action);
+            return;
+        }
+        // taken out for the dispatch, so the rest of the manager can be shared meanwhile
+        let mut own = match &mut self.lazy {
+            Some(lazy) => lazy.cell.take(),
+            None => self.resource.take(),
+        };
+        let this = &*self;
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let ctx = ActionCtx::for_action(None, action);
+            this.dispatch_on(action, &ctx, Some(&mut own));
+        }));
+        match (&mut self.lazy, own) {
+            (Some(lazy), Some(r)) => {
+                let _ = lazy.cell.set(r);
+            }
+            (_, own) => self.resource = own,
+        }
+        if let Err(p) = res {
+            std::panic::resume_unwind(p);
+        }
+    }
+
+    /// registers `handler` along with `dry_handler`, which runs instead for actions
+    /// flagged `dry_run` and describes what `handler` would do; actions flagged `dry_run`
+    /// are refused with `DryRunUnsupported` by handlers registered without one
+    pub fn on_with_dry_run<T, D>(&mut self, name: &str, handler: T, dry_handler: D)
+    where
+        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+        D: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let taken = self.has_action(name);
+        self.register("on", name, Box::new(move |r, a, _| handler(r, a)));
+        if !taken {
+            self.dry_handlers
+                .insert(name.to_owned(), Box::new(move |r, a, _| dry_handler(r, a)));
+        }
+    }
+
+    /// registers every entry of the table, nothing is registered when any name is
+    /// already taken and the error lists those names
+    pub fn mount(&mut self, routes: Routes<R>) -> Result<(), ActionError> {
+        let duplicates: Vec<&str> = routes
+            .names()
+            .into_iter()
+            .filter(|n| self.has_action(n))
+            .collect();
+        if !duplicates.is_empty() {
+            return Err(ActionError::new(
+                "DuplicateAction",
+                &format!(
+                    "Manager [{}] already has actions: {}",
+                    self.name,
+                    duplicates.join(", ")
+                ),
+            )
+            .with_details(json!({ "duplicates": duplicates })));
+        }
+        for (name, f) in routes.entries {
+            self.register("on", &name, f);
+        }
+        Ok(())
+    }
+
+    /// like `on` but the handler also gets the `ActionCtx` of the dispatch
+    pub fn on_ctx<T>(&mut self, name: &str, f: T)
+    where
+        T: Fn(&R, &Action, &ActionCtx<'_>) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.register("on", name, Box::new(f));
+    }
+
+    /// `on` for callers holding one of `roles`, as told by the `AuthContext` of
+    /// `set_token_validator`; others get `Forbidden`, and without a validator nobody
+    /// holds a role. The roles are listed by `describe` under `"roles"`
+    pub fn on_with_roles<T>(&mut self, name: &str, roles: &[&str], f: T)
+    where
+        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        match self.try_register("on", name, Box::new(move |r, a, _| f(r, a))) {
+            Ok(()) => {
+                let roles = roles.iter().map(|r| (*r).to_owned()).collect();
+                self.roles.insert(name.to_owned(), roles);
+            }
+            Err(_) => event!(
+                warn,
+                manager = self.name.as_str(), action = name;
+                "Manager [{}] registered existing action: {}, ignoring", self.name, name
+            ),
+        }
+    }
+
+    /// the roles `on_with_roles` requires for `name`
+    pub fn roles_of(&self, name: &str) -> Option<&[String]> {
+        self.roles.get(name).map(|r| r.as_slice())
+    }
+
+    /// who sent the action: the caller for sub-actions, nobody for capability tokens and
+    /// public actions, else whoever the token validator says
+    fn authenticate(
+        &self,
+        action: &Action,
+        ctx: &ActionCtx<'_>,
+    ) -> Result<Option<AuthContext>, ActionError> {
+        if ctx.auth().is_some() || ctx.is_authenticated() {
+            return Ok(ctx.auth().cloned());
+        }
+        #[cfg(feature = "crypto")]
+        if let Some(key) = &self.capability_key {
+            if crate::capability::check_action(action, key)? {
+                return Ok(None);
+            }
+        }
+        self.authenticator.check(&action.name, &action.token)
+    }
+
+    fn check_roles(&self, action: &Action, auth: Option<&AuthContext>) -> Result<(), ActionError> {
+        let required = match self.roles.get(&action.name) {
+            Some(required) => required,
+            None => return Ok(()),
+        };
+        if auth.is_some_and(|a| required.iter().any(|r| a.has_role(r))) {
+            return Ok(());
+        }
+        Err(ActionError::new(
+            "Forbidden",
+            &format!(
+                "{} requires the role {}",
+                action.name,
+                required.join(" or ")
+            ),
+        )
+        .with_details(json!({ "action": action.name, "required": required })))
+    }
+
+    /// adds a hook run before the handler of every action, unknown ones included, in the
+    /// order they were added; the first one failing skips the handler and the hooks after
+    /// it, its error is set on the action
+    pub fn use_before<T>(&mut self, hook: T)
+    where
+        T: Fn(&R, &mut Action) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        self.before_hooks.push(Box::new(hook));
+    }
+
+    /// adds a hook run after every action in the order they were added, also when the
+    /// handler failed, a before hook stopped it or no handler is registered
+    pub fn use_after<T>(&mut self, hook: T)
+    where
+        T: Fn(&R, &mut Action) + Send + Sync + 'static,
+    {
+        self.after_hooks.push(Box::new(hook));
+    }
+
+    pub fn manager_name(&self) -> &str {
+        &self.name
+    }
+
+    /// whether a handler is registered under `name`, through `on_mut` as well, without
+    /// dispatching anything
+    pub fn has_action(&self, name: &str) -> bool {
+        self.actions.contains_key(name) || self.mut_actions.contains_key(name)
+    }
+
+    /// names of the registered actions, those of `on_mut` included, sorted
+    pub fn action_names(&self) -> impl Iterator<Item = &str> {
+        let mut names: Vec<&str> = self
+            .actions
+            .keys()
+            .chain(self.mut_actions.keys())
+            .map(|k| k.as_str())
+            .collect();
+        names.sort_unstable();
+        names.into_iter()
+    }
+
+    /// how many actions are registered
+    pub fn len(&self) -> usize {
+        self.actions.len() + self.mut_actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty() && self.mut_actions.is_empty()
+    }
+
+    /// removes the handler of `name`, its dry run handler included, so it is dispatched as
+    /// if it was never registered; returns whether there was one. Validators and other
+    /// per-action settings stay for a handler registered later
+    pub fn off(&mut self, name: &str) -> bool {
+        self.dry_handlers.remove(name);
+        self.guards.remove(name);
+        self.roles.remove(name);
+        let mutating = self.mut_actions.remove(name).is_some();
+        self.actions.remove(name).is_some() || mutating
+    }
+
+    /// removes every handler
+    pub fn clear(&mut self) {
+        self.actions.clear();
+        self.mut_actions.clear();
+        self.dry_handlers.clear();
+        self.guards.clear();
+        self.roles.clear();
+    }
+
+    /// runs `f` with the resource for actions no handler is registered for, in place of
+    /// the built-in not found error; `do_action_if_exists` still skips them
+    pub fn on_unknown<T>(&mut self, f: T)
+    where
+        T: Fn(&R, &Action) -> Result<Value, ActionError> + Send + Sync + 'static,
+    {
+        self.unknown = Some(Box::new(f));
+    }
+
+    /// adds a callback told the action name, the action and the error whenever a handler
+    /// fails or no handler is registered; every callback runs, after the error is set on
+    /// the action, and one panicking does not stop the others
+    pub fn on_error<T>(&mut self, callback: T)
+    where
+        T: Fn(&str, &Action, &ActionError) + Send + Sync + 'static,
+    {
+        self.error_callbacks.push(Box::new(callback));
+    }
+
+    fn report_error(&self, action: &Action) {
+        if let Some(error) = action.errors.as_ref().and_then(|e| e.last()) {
+            for callback in &self.error_callbacks {
+                let called = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    callback(&action.name, action, error)
+                }));
+                if called.is_err() {
+                    event!(warn, "on_error callback of manager {} panicked", self.name);
+                }
+            }
+        }
+    }
+
+    /// checks run before the handler of `name`, when any of them fails every error is set
+    /// on the action and the handler is skipped
+    pub fn validate_action<T>(&mut self, name: &str, rules: T)
+    where
+        T: Fn(&Action) -> Result<(), Vec<ActionError>> + Send + Sync + 'static,
+    {
+        self.validators
+            .entry(name.to_owned())
+            .or_default()
+            .push(Box::new(rules));
+    }
+
+    /// a condition the registered action `name` must meet, checked with the resource after
+    /// the validators; guards run in the order they were added and the first one failing
+    /// sets its error and skips the handler. Guarding a name no handler is registered
+    /// under fails with `UnknownAction`, and `off` drops the guards of the action
+    pub fn guard<T>(&mut self, name: &str, f: T) -> Result<(), ActionError>
+    where
+        T: Fn(&R, &Action) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        if !self.actions.contains_key(name) && !self.mut_actions.contains_key(name) {
+            return Err(ActionError::new(
+                "UnknownAction",
+                &format!("can not guard {}, no handler is registered for it", name),
+            )
+            .with_details(json!({ "action": name })));
+        }
+        self.guards
+            .entry(name.to_owned())
+            .or_default()
+            .push(Box::new(f));
+        Ok(())
+    }
+
+    fn check_guards(&self, resource: &R, action: &Action) -> Result<(), ActionError> {
+        match self.guards.get(&action.name) {
+            Some(guards) => guards.iter().try_for_each(|g| g(resource, action)),
+            None => Ok(()),
+        }
+    }
+
+    fn validate(&self, action: &mut Action) -> bool {
+        let mut valid = true;
+        if let Some(rules) = self.validators.get(&action.name) {
+            for rule in rules {
+                if let Err(errors) = rule(action) {
+                    valid = false;
+                    for e in errors {
+                        action.set_error(e);
+                    }
+                }
+            }
+        }
+        valid
+    }
+
+    /// gives every `name` action `units` of work to spend through `ActionCtx::budget`,
+    /// the units consumed are reported on the reply as `budget_used`
+    pub fn budget(&mut self, name: &str, units: u64) {
+        self.budgets.insert(name.to_owned(), units);
+    }
+
+    /// marks `name` deprecated, it keeps working but its replies carry a `Deprecated`
+    /// warning, on every dispatch unless `deprecation_warn_interval` throttles it
+    pub fn deprecate(&mut self, name: &str, note: &str, sunset: Option<&str>) {
+        self.deprecations.insert(
+            name.to_owned(),
+            Deprecation {
+                note: note.to_owned(),
+                sunset: sunset.map(|s| s.to_owned()),
+            },
+        );
+    }
+
+    /// warns each token about each deprecated action at most once per `interval`, measured
+    /// with the clock of the manager; `Duration::ZERO`, the default, warns on every dispatch
+    pub fn deprecation_warn_interval(&mut self, interval: Duration) {
+        self.warn_throttle = Mutex::new(WarnThrottle::new(interval, WARN_CACHE_CAPACITY));
+    }
+
+    /// starts summarizing the payloads of registered actions, up to `sample_limit`
+    /// payloads per action, see `inferred_schemas`
+    pub fn infer_schemas(&mut self, sample_limit: u64) {
+        self.schemas = Some(Mutex::new(SchemaInference::new(sample_limit)));
+    }
+
+    /// schema-ish documents inferred from the payloads seen so far, a starting point for
+    /// hand written schemas rather than something to validate against
+    pub fn inferred_schemas(&self) -> HashMap<String, Value> {
+        match &self.schemas {
+            Some(s) => s.lock().unwrap_or_else(|e| e.into_inner()).schemas(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// rules for actions arriving from `source`, see `do_action_from`
+    pub fn source_policy(&mut self, source: &str, overrides: PolicyOverrides) {
+        self.source_policies.insert(source.to_owned(), overrides);
+    }
+
+    /// whether the source policy of the action exempts it from `rate_limit`
+    fn skips_rate_limit(&self, action: &Action) -> bool {
+        action
+            .source
+            .as_ref()
+            .and_then(|s| self.source_policies.get(s))
+            .is_some_and(|p| p.skip_rate_limit)
+    }
+
+    fn check_source(&self, action: &Action) -> Result<(), ActionError> {
+        let source = match &action.source {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        match self.source_policies.get(source) {
+            Some(p) if !p.allows(&action.name) => Err(ActionError::new(
+                "SourceNotAllowed",
+                &format!("{} may not be called from {}", action.name, source),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn warn_deprecated(&self, action: &mut Action) {
+        if let Some(d) = self.deprecations.get(&action.name) {
+            let warn = self
+                .warn_throttle
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .should_warn(action.token.as_deref(), &action.name, self.clock.now());
+            if warn {
+                action.add_warning(d.warning(&action.name));
+            }
+        }
+    }
+
+    /// every registered action with its metadata, sorted by name
+    pub fn list_actions_detailed(&self) -> Vec<ActionInfo> {
+        let mut info: Vec<ActionInfo> = self
+            .action_names()
+            .map(|name| ActionInfo {
+                name: name.to_owned(),
+                deprecated: self.deprecations.get(name).cloned(),
+                payload_keys: self.key_maps.payload.get(name).cloned().unwrap_or_default(),
+                result_keys: self.key_maps.result.get(name).cloned().unwrap_or_default(),
+                examples: self
+                    .examples
+                    .iter()
+                    .filter(|e| e.action == *name)
+                    .cloned()
+                    .collect(),
+            })
+            .collect();
+        info.sort_by(|a, b| a.name.cmp(&b.name));
+        info
+    }
+
+    /// caps the serialized size of handler results, anything larger is handled by `policy`
+    pub fn max_result_bytes(&mut self, limit: usize, policy: ResultPolicy) {
+        self.result_limit = Some((limit, policy));
+    }
+
+    fn limit_result(&self, v: Value, size: Option<usize>) -> Result<Value, ActionError> {
+        let (limit, policy) = match &self.result_limit {
+            Some(l) => l,
+            None => return Ok(v),
+        };
+        let size = match size {
+            Some(size) => size,
+            None => json_len(&v)?,
+        };
+        if size <= *limit {
+            return Ok(v);
+        }
+        match policy {
+            ResultPolicy::Reject => Err(ActionError::new(
+                "ResultTooLarge",
+                &format!("{} result is {} bytes, limit is {}", self.name, size, limit),
+            )
+            .with_details(json!({"size": size, "limit": limit}))),
+            ResultPolicy::Truncate { keep_keys } => {
+                let mut kept = serde_json::Map::new();
+                if let Value::Object(mut map) = v {
+                    for k in keep_keys {
+                        if let Some(v) = map.remove(k) {
+                            kept.insert(k.clone(), v);
+                        }
+                    }
+                }
+                kept.insert("_truncated".to_owned(), Value::Bool(true));
+                Ok(Value::Object(kept))
+            }
+        }
+    }
+
+    pub(crate) fn key_maps_mut(&mut self) -> &mut KeyMaps {
+        &mut self.key_maps
+    }
+
+    pub(crate) fn action_stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    pub(crate) fn response_cache(&self) -> &ResponseCache {
+        &self.cache
+    }
+
+    pub(crate) fn response_cache_mut(&mut self) -> &mut ResponseCache {
+        &mut self.cache
+    }
+
+    pub(crate) fn idempotency_mut(&mut self) -> &mut HashMap<String, Idempotency> {
+        &mut self.idempotency
+    }
+
+    pub(crate) fn idempotency_of(&self, name: &str) -> Idempotency {
+        self.idempotency.get(name).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn examples(&self) -> &[Example] {
+        &self.examples
+    }
+
+    pub(crate) fn examples_mut(&mut self) -> &mut Vec<Example> {
+        &mut self.examples
+    }
+
+    pub(crate) fn example_ignores(&self) -> &[String] {
+        &self.example_ignores
+    }
+
+    pub(crate) fn example_ignores_mut(&mut self) -> &mut Vec<String> {
+        &mut self.example_ignores
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn confirmations_shared(&self) -> &SharedConfirmations {
+        &self.confirmations
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn capability_key_mut(&mut self) -> &mut Option<Vec<u8>> {
+        &mut self.capability_key
+    }
+
+    /// how tokens are written by `for_storage`, `Plain` unless set
+    pub fn token_codec(&mut self, codec: Arc<dyn TokenCodec>) {
+        self.token_codec = codec;
+    }
+
+    /// copy of the action for anything that outlives the dispatch, e.g. a log or a
+    /// record of failed actions, with its token encoded by the token codec
+    pub fn for_storage(&self, action: &Action) -> Action {
+        let mut stored = action.clone();
+        stored.token = action.token.as_deref().map(|t| self.token_codec.encode(t));
+        stored
+    }
+
+    /// undoes `for_storage`, e.g. to replay stored actions, fails with `TokenCodecError`
+    /// when the codec can not recover tokens
+    pub fn restore_token(&self, stored: &Action) -> Result<Action, ActionError> {
+        let mut action = stored.clone();
+        action.token = match stored.token.as_deref() {
+            Some(t) => Some(self.token_codec.decode(t)?),
+            None => None,
+        };
+        Ok(action)
+    }
+
+    /// keeps every action whose handler fails in `sink`, token encoded by the
+    /// `TokenCodec`, see `dead_letter::replay_dead_letters`
+    pub fn dead_letter_to(&mut self, sink: Arc<dyn DeadLetterSink>) {
+        self.dead_letters = Some(sink);
+    }
+
+    /// has `sweep` also sweep `component`, e.g. a send queue or a cache of the application
+    pub fn maintain(&mut self, component: Arc<dyn Maintenance>) {
+        self.maintained.push(component);
+    }
+
+    /// drops expired state of the manager and of every component given to `maintain`
+    pub fn sweep(&self) -> SweepStats {
+        self.sweep_at(SystemTime::now())
+    }
+
+    /// `sweep` as if it was `now`
+    pub fn sweep_at(&self, now: SystemTime) -> SweepStats {
+        let mut stats = self
+            .warn_throttle
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .sweep(instant_at(now));
+        if let Some(log) = &self.reply_log {
+            stats += log.lock().unwrap_or_else(|e| e.into_inner()).sweep(now);
+        }
+        for component in &self.maintained {
+            stats += component.sweep(now);
+        }
+        stats
+    }
+
+    /// how deep handlers may nest `ActionCtx::dispatch` calls
+    pub fn max_dispatch_depth(&mut self, depth: usize) {
+        self.max_dispatch_depth = depth;
+    }
+
+    /// keeps per action statistics of payload and result sizes, see `size_stats`
+    pub fn track_sizes(&mut self, on: bool) {
+        self.sizes = if on {
+            Some(Mutex::new(SizeTracker::default()))
+        } else {
+            None
+        };
+    }
+
+    /// serialized payload and result sizes seen so far, by action name
+    pub fn size_stats(&self) -> BTreeMap<String, SizeStats> {
+        match &self.sizes {
+            Some(s) => s.lock().unwrap_or_else(|e| e.into_inner()).snapshot(),
+            None => BTreeMap::new(),
+        }
+    }
+
+    /// the `n` actions with the largest payloads or results, largest first
+    pub fn top_sizes(&self, n: usize, by: SizeMetric) -> Vec<(String, SizeStats)> {
+        match &self.sizes {
+            Some(s) => s.lock().unwrap_or_else(|e| e.into_inner()).top(n, by),
+            None => Vec::new(),
+        }
+    }
+
+    fn track_size(&self, f: impl FnOnce(&mut SizeTracker)) {
+        if let Some(s) = &self.sizes {
+            f(&mut s.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+
+    /// keeps timing traces of the most recent dispatches, see `recent_traces`
+    pub fn trace_dispatches(&mut self, on: bool) {
+        self.traces = if on {
+            Some(Mutex::new(TraceBuffer::new(DEFAULT_TRACE_CAPACITY)))
+        } else {
+            None
+        };
+    }
+
+    /// turns tracing on keeping at most `capacity` traces
+    pub fn trace_capacity(&mut self, capacity: usize) {
+        self.traces = Some(Mutex::new(TraceBuffer::new(capacity)));
+    }
+
+    /// the last `n` dispatch traces, oldest first
+    pub fn recent_traces(&self, n: usize) -> Vec<DispatchTrace> {
+        match &self.traces {
+            Some(t) => t.lock().unwrap_or_else(|e| e.into_inner()).recent(n),
+            None => Vec::new(),
+        }
+    }
+
+    fn tracer(&self) -> Tracer {
+        Tracer::new(self.traces.is_some())
+    }
+
+    fn record_trace(&self, trace: Tracer, action: &Action) {
+        if let (Some(traces), Some(t)) = (&self.traces, trace.finish(&self.name, action)) {
+            traces.lock().unwrap_or_else(|e| e.into_inner()).push(t);
+        }
+    }
+
+    /// dispatches `action`, leaving the result or errors on it, and tells how it went
+    pub fn do_action(&self, action: &mut Action) -> DispatchOutcome {
+        let ctx = ActionCtx::for_action(None, action);
+        self.dispatch(action, &ctx)
+    }
+
+    /// dispatches `action` and returns its reply along with why it looks the way it does
+    pub fn handle_with_outcome(&self, mut action: Action) -> (ActionReply, DispatchOutcome) {
+        let ctx = ActionCtx::for_action(None, &action);
+        let outcome = self.dispatch(&mut action, &ctx);
+        (self.reply(action), outcome)
+    }
+
+    /// `handle_with_outcome` for actions registered here; others are handed back
+    /// untouched, so they can be tried on the next manager
+    pub fn handle_if_exists(
+        &self,
+        action: Action,
+    ) -> Result<(ActionReply, DispatchOutcome), Box<Action>> {
+        if self.has_action(&action.name) {
+            Ok(self.handle_with_outcome(action))
+        } else {
+            Err(Box::new(action))
+        }
+    }
+
+    /// dispatches with `resource` instead of the manager's own
+    pub(crate) fn do_action_with(&self, resource: &R, action: &mut Action) {
+        let ctx = ActionCtx::for_action(None, action);
+        let mut trace = self.tracer();
+        self.run_action(Target::Shared(resource), action, &mut trace, &ctx);
+        self.record_trace(trace, action);
+    }
+
+    /// the entry point for transports: tags the action with `source`, overwriting
+    /// whatever the client claimed, and dispatches it under that source's policy
+    pub fn do_action_from(&self, source: &str, action: &mut Action) {
+        action.source = Some(source.to_owned());
+        self.do_action(action);
+    }
+
+    /// dispatches every action in order and returns their replies; handlers share a
+    /// `BatchCache` through their `ActionCtx` that is dropped once the batch is done
+    pub fn do_batch(&self, actions: Vec<Action>) -> Vec<ActionReply> {
+        self.do_batch_with(actions, BatchOptions::default()).replies
+    }
+
+    /// `do_batch` with options, once the deadline passes the remaining actions are not run
+    /// and get a retryable `BatchDeadlineExceeded` error instead
+    pub fn do_batch_with(&self, actions: Vec<Action>, opts: BatchOptions) -> BatchReply {
+        let cache = BatchCache::new();
+        let mut completed = 0;
+        let mut outcomes = Vec::with_capacity(actions.len());
+        let replies = actions
+            .into_iter()
+            .map(|mut action| {
+                let outcome = if opts.deadline.is_some_and(|d| Instant::now() >= d) {
+                    action.set_error(
+                        ActionError::new(
+                            "BatchDeadlineExceeded",
+                            &format!("batch deadline passed before {} could run", action.name),
+                        )
+                        .retryable(),
+                    );
+                    DispatchOutcome::Shed
+                } else {
+                    let ctx = ActionCtx::for_action(Some(&cache), &action);
+                    completed += 1;
+                    self.dispatch(&mut action, &ctx)
+                };
+                outcomes.push(outcome);
+                self.reply(action)
+            })
+            .collect();
+        BatchReply {
+            replies,
+            completed,
+            outcomes,
+        }
+    }
+
+    fn dispatch(&self, action: &mut Action, ctx: &ActionCtx<'_>) -> DispatchOutcome {
+        self.dispatch_on(action, ctx, None)
+    }
+
+    /// `dispatch`, with `mutating` holding the resource taken out of the manager when the
+    /// action is for a handler registered with `on_mut`, see `do_action_mut`
+    fn dispatch_on(
+        &self,
+        action: &mut Action,
+        ctx: &ActionCtx<'_>,
+        mutating: Option<&mut Option<R>>,
+    ) -> DispatchOutcome {
+        let sink = match &self.dead_letters {
+            Some(sink) => sink,
+            None => return self.dispatch_unrecorded(action, ctx, mutating),
+        };
+        let arrived = self.for_storage(action);
+        let outcome = self.dispatch_unrecorded(action, ctx, mutating);
+        if outcome == DispatchOutcome::HandlerError {
+            let errors = action.errors.clone().unwrap_or_default();
+            sink.push(DeadLetter::new(arrived, errors));
+        }
+        outcome
+    }
+
+    /// dispatches a dead letter again without recording it a second time
+    pub(crate) fn redispatch(&self, action: &mut Action) -> DispatchOutcome {
+        action.errors = None;
+        action.result = None;
+        let ctx = ActionCtx::for_action(None, action);
+        self.dispatch_unrecorded(action, &ctx, None)
+    }
+
+    fn dispatch_unrecorded(
+        &self,
+        action: &mut Action,
+        ctx: &ActionCtx<'_>,
+        mutating: Option<&mut Option<R>>,
+    ) -> DispatchOutcome {
+        if let Err(e) = self.check_expiry(action) {
+            action.set_error(e);
+            return DispatchOutcome::Shed;
+        }
+        #[cfg(feature = "crypto")]
+        if let Err(e) = crate::signature::check_signature(action, &self.signature_keys) {
+            action.set_error(e);
+            return DispatchOutcome::Rejected;
+        }
+        // like authentication, before the hooks, the builtins and the fallback
+        if let Err(e) = self.check_source(action) {
+            action.set_error(e);
+            return DispatchOutcome::Rejected;
+        }
+        // before the builtins and the fallback, which would answer anyone otherwise
+        let auth = match self.authenticate(action, ctx) {
+            Ok(auth) => auth,
+            Err(e) => {
+                action.set_error(e);
+                return DispatchOutcome::Rejected;
+            }
+        };
+        let ctx = &ctx.authenticated(auth);
+        // after the signature and the token validator, so only holders of a token can
+        // spend its budget
+        if let Some(limiter) = self
+            .rate_limiter
+            .as_ref()
+            .filter(|_| !self.skips_rate_limit(action))
+        {
+            if let Err(e) = limiter.admit(action, self.clock.now()) {
+                action.set_error(e);
+                return DispatchOutcome::RateLimited;
+            }
+        }
+        // after authentication, so revoked tokens get no cached replies; dry runs are
+        // neither replayed nor kept, a real run with the same id has to happen
+        let dedupe = self.dedupe.as_ref().filter(|_| !ctx.is_dry_run());
+        if let Some(cache) = dedupe {
+            if cache.replay(action, self.clock.now()) {
+                return DispatchOutcome::Cached;
+            }
+        }
+        let _running = match &self.inflight {
+            Some(inflight) => match inflight.enter(action.token.as_deref(), action.id) {
+                Ok(guard) => Some(guard),
+                Err(e) => {
+                    let outcome = if e.is_retryable() {
+                        DispatchOutcome::Shed
+                    } else {
+                        DispatchOutcome::Rejected
+                    };
+                    action.set_error(e);
+                    return outcome;
+                }
+            },
+            None => None,
+        };
+        let _permits = match self.concurrency.enter(&action.name) {
+            Ok(permits) => permits,
+            Err(e) => {
+                action.set_error(e);
+                return DispatchOutcome::Shed;
+            }
+        };
+        if self.describe.as_deref() == Some(action.name.as_str()) && !self.has_action(&action.name)
+        {
+            action.set_result(self.describe());
+            return DispatchOutcome::Handled;
+        }
+        let mut trace = self.tracer();
+        let outcome = self.run_with_resource(action, &mut trace, ctx, mutating);
+        self.record_trace(trace, action);
+        if let Some(cache) = dedupe {
+            cache.store(action, self.clock.now());
+        }
+        outcome
+    }
+
+    /// runs `action` against a generated resource when there is a generator, the manager's
+    /// own one otherwise
+    fn run_with_resource(
+        &self,
+        action: &mut Action,
+        trace: &mut Tracer,
+        ctx: &ActionCtx<'_>,
+        mutating: Option<&mut Option<R>>,
+    ) -> DispatchOutcome {
+        let mutable = mutating.is_some();
+        match (&self.gen_resource, &self.resource) {
+            (Some(gen_resource), _) => {
+                let r = match trace.span("resource", gen_resource) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        action.set_error(resource_error(e));
+                        return DispatchOutcome::Shed;
+                    }
+                };
+                let mut r = match self.probe_resource(action, &r, Some(gen_resource.as_ref())) {
+                    Ok(Some(fresh)) => fresh,
+                    Ok(None) => r,
+                    Err(e) => {
+                        action.set_error(e);
+                        return DispatchOutcome::Shed;
+                    }
+                };
+                self.run_action(Target::new(&mut r, mutable), action, trace, ctx)
+            }
+            //println!("executing action {:?}", action.name);
+            (None, _) if self.pool.is_some() => {
+                let pool = self.pool.as_ref().expect("checked above");
+                let mut r = match trace.span("resource", || pool.take()) {
+                    Ok(r) => r,
+                    Err(e) if e.code == "PoolExhausted" => {
+                        action.set_error(e);
+                        return DispatchOutcome::Shed;
+                    }
+                    Err(e) => {
+                        action.set_error(resource_error(e));
+                        return DispatchOutcome::Shed;
+                    }
+                };
+                match self.probe_resource(action, &r, Some(pool.make())) {
+                    Ok(fresh) => {
+                        // the broken one is dropped, the fresh one goes back in its place
+                        if let Some(fresh) = fresh {
+                            *r = fresh;
+                        }
+                        self.run_action(Target::new(&mut r, mutable), action, trace, ctx)
+                    }
+                    Err(e) => {
+                        action.set_error(e);
+                        DispatchOutcome::Shed
+                    }
+                }
+            }
+            (None, _) if mutable => {
+                let own = mutating.expect("mutable");
+                let r = match (own, &self.lazy) {
+                    (Some(r), _) => r,
+                    (own, Some(lazy)) => match trace.span("resource", || (lazy.make)()) {
+                        Ok(r) => own.insert(r),
+                        Err(e) => {
+                            action.set_error(resource_error(e));
+                            return DispatchOutcome::Shed;
+                        }
+                    },
+                    (_, None) => return DispatchOutcome::NotFound,
+                };
+                match self.probe_resource(action, r, None) {
+                    Ok(_) => self.run_action(Target::Mut(r), action, trace, ctx),
+                    Err(e) => {
+                        action.set_error(e);
+                        DispatchOutcome::Shed
+                    }
+                }
+            }
+            (None, stored) => {
+                let r = match (stored, &self.lazy) {
+                    (Some(r), _) => r,
+                    (None, Some(lazy)) => match trace.span("resource", || lazy.get()) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            action.set_error(resource_error(e));
+                            return DispatchOutcome::Shed;
+                        }
+                    },
+                    (None, None) => return DispatchOutcome::NotFound,
+                };
+                match self.probe_resource(action, r, None) {
+                    Ok(_) => self.run_action(Target::Shared(r), action, trace, ctx),
+                    Err(e) => {
+                        action.set_error(e);
+                        DispatchOutcome::Shed
+                    }
+                }
+            }
+        }
+    }
+
+    /// runs the resource probe when one is due, `__health` always gets through
+    fn probe_resource(
+        &self,
+        action: &Action,
+        resource: &R,
+        regenerate: Option<&ResourceGen<R>>,
+    ) -> Result<Option<R>, ActionError> {
+        match &self.probe {
+            Some(probe) => match probe.check(resource, Instant::now(), regenerate) {
+                Err(_) if action.name == HEALTH_ACTION => Ok(None),
+                res => res,
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn run_action(
+        &self,
+        target: Target<'_, R>,
+        action: &mut Action,
+        trace: &mut Tracer,
+        ctx: &ActionCtx<'_>,
+    ) -> DispatchOutcome {
+        if !self.record_timings {
+            return self.run_action_untimed(target, action, trace, ctx);
+        }
+        action.received_at = Some(self.clock.unix_ms());
+        let started = Instant::now();
+        let outcome = self.run_action_untimed(target, action, trace, ctx);
+        action.duration_ms = Some(started.elapsed().as_millis() as u64);
+        outcome
+    }
+
+    fn run_action_untimed(
+        &self,
+        mut target: Target<'_, R>,
+        action: &mut Action,
+        trace: &mut Tracer,
+        ctx: &ActionCtx<'_>,
+    ) -> DispatchOutcome {
+        event!(
+            debug,
+            manager = self.name.as_str(), action = action.name.as_str(), id = action.id;
+            "dispatch {} ({})", action.name, action.id
+        );
+        let outcome = match self
+            .before_hooks
+            .iter()
+            .try_for_each(|h| h(target.get(), action))
+        {
+            Ok(()) => self.run_handler(&mut target, action, trace, ctx),
+            Err(e) => {
+                action.set_error(e);
+                DispatchOutcome::Rejected
+            }
+        };
+        // what the fallback answers still counts as a miss, unknown names get no counters
+        let counted = match outcome {
+            DispatchOutcome::Handled | DispatchOutcome::HandlerError
+                if !self.has_action(&action.name) =>
+            {
+                DispatchOutcome::NotFound
+            }
+            o => o,
+        };
+        self.stats.record(&action.name, counted);
+        if outcome.is_success() {
+            event!(
+                debug,
+                manager = self.name.as_str(), action = action.name.as_str(), id = action.id,
+                outcome = outcome.as_str();
+                "dispatched {} ({})", action.name, action.id
+            );
+        } else {
+            event!(
+                debug,
+                manager = self.name.as_str(), action = action.name.as_str(), id = action.id,
+                outcome = outcome.as_str();
+                "dispatch of {} ({}) failed: {:?}", action.name, action.id, action.errors
+            );
+        }
+        if matches!(
+            outcome,
+            DispatchOutcome::HandlerError | DispatchOutcome::NotFound
+        ) {
+            self.report_error(action);
+        }
+        if !self.after_hooks.is_empty() {
+            trace.span("after", || {
+                for hook in &self.after_hooks {
+                    hook(target.get(), action);
+                }
+            });
+        }
+        outcome
+    }
+
+    fn run_handler(
+        &self,
+        target: &mut Target<'_, R>,
+        action: &mut Action,
+        trace: &mut Tracer,
+        ctx: &ActionCtx<'_>,
+    ) -> DispatchOutcome {
+        let mutating =
+            matches!(target, Target::Mut(_)) && self.mut_actions.contains_key(&action.name);
+        if !mutating && !self.actions.contains_key(&action.name) {
+            return self.run_unregistered(target.get(), action);
+        }
+        let auth = match self.prepare(target.get(), action, trace, ctx) {
+            Ok(auth) => auth,
+            Err(outcome) => return outcome,
+        };
+        match target {
+            Target::Mut(r) if mutating => self.run_mut_handler(r, action, trace),
+            target => self.run_registered(target.get(), action, auth, trace, ctx),
+        }
+    }
+
+    /// the checks every handler goes through before it runs, `Err` with the outcome when
+    /// one failed and the action got an error; otherwise who sent it
+    fn prepare(
+        &self,
+        resource: &R,
+        action: &mut Action,
+        trace: &mut Tracer,
+        ctx: &ActionCtx<'_>,
+    ) -> Result<Option<AuthContext>, DispatchOutcome> {
+        let auth = match self.authenticate(action, ctx) {
+            Ok(auth) => auth,
+            Err(e) => {
+                action.set_error(e);
+                return Err(DispatchOutcome::Rejected);
+            }
+        };
+        if let Err(e) = self.check_roles(action, auth.as_ref()) {
+            action.set_error(e);
+            return Err(DispatchOutcome::Rejected);
+        }
+        if let Some(renames) = self.key_maps.payload.get(&action.name) {
+            rename_payload(&mut action.payload, renames);
+        }
+        if let Some(s) = &self.schemas {
+            s.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .observe(&action.name, &action.payload);
+        }
+        if self.sizes.is_some() {
+            if let Ok(size) = json_len(&action.payload) {
+                self.track_size(|s| s.payload(&action.name, size));
+            }
+        }
+        self.warn_deprecated(action);
+        if !ctx.skips_validation() && !trace.span("before", || self.validate(action)) {
+            return Err(DispatchOutcome::Rejected);
+        }
+        if let Err(e) = self.check_guards(resource, action) {
+            action.set_error(e);
+            return Err(DispatchOutcome::Rejected);
+        }
+        if ctx.is_dry_run() && !self.dry_handlers.contains_key(&action.name) {
+            action.set_error(ActionError::new(
+                "DryRunUnsupported",
+                &format!("{} can not be dry run", action.name),
+            ));
+            return Err(DispatchOutcome::Rejected);
+        }
+        Ok(auth)
+    }
+
+    /// runs a handler registered with `on_mut`
+    fn run_mut_handler(
+        &self,
+        resource: &mut R,
+        action: &mut Action,
+        trace: &mut Tracer,
+    ) -> DispatchOutcome {
+        let f = self
+            .mut_actions
+            .get(&action.name)
+            .expect("checked by run_handler");
+        let mut f = f.lock().unwrap_or_else(|e| e.into_inner());
+        let res = trace.span("handler", || {
+            if !self.catch_panics {
+                return Ok(f(resource, action));
+            }
+            std::panic::catch_unwind(AssertUnwindSafe(|| f(resource, action)))
+                .map_err(|p| panic_message(&*p))
+        });
+        let res = match res {
+            Ok(res) => res.and_then(|v| self.finish_result(action, v)),
+            Err(message) => Err(self.panic_error(action, &message)),
+        };
+        match res {
+            Ok(v) => {
+                action.set_result(v);
+                DispatchOutcome::Handled
+            }
+            Err(e) => {
+                action.set_error(e);
+                DispatchOutcome::HandlerError
+            }
+        }
+    }
+
+    /// runs a handler registered with `on` or `on_ctx`, or its dry variant
+    fn run_registered(
+        &self,
+        resource: &R,
+        action: &mut Action,
+        auth: Option<AuthContext>,
+        trace: &mut Tracer,
+        ctx: &ActionCtx<'_>,
+    ) -> DispatchOutcome {
+        let func = match self.dry_handlers.get(&action.name) {
+            Some(dry) if ctx.is_dry_run() => dry,
+            _ => self
+                .actions
+                .get(&action.name)
+                .expect("checked by run_handler"),
+        };
+        let cache_key = if !ctx.is_dry_run() && self.cache.caches(&action.name) {
+            Some(cache_key(action))
+        } else {
+            None
+        };
+        let mut stale = None;
+        if let Some(key) = &cache_key {
+            match self.cache.lookup(key, Instant::now()) {
+                Lookup::Miss => {}
+                Lookup::Fresh(v) => {
+                    action.set_result(v);
+                    return DispatchOutcome::Cached;
+                }
+                Lookup::Stale(v) => {
+                    action.set_result(v);
+                    action.stale = true;
+                    return DispatchOutcome::Cached;
+                }
+                Lookup::Refresh(v) => stale = Some(v),
+            }
+        }
+        let scope = Scope {
+            manager: self,
+            resource,
+        };
+        let limit = self.budgets.get(&action.name).copied();
+        let budget = Budget::new(limit);
+        let logs = self
+            .reply_logs
+            .map(|max| LogBuffer::new(max, MAX_REPLY_LOG_BYTES));
+        let ctx = ctx
+            .with_dispatcher(&scope, &budget)
+            .with_logger(ActionLogger::new(Some(&self.name), action, logs.as_ref()))
+            .with_auth(auth);
+        let res = trace.span("handler", || {
+            if !self.catch_panics {
+                return Ok(func(resource, action, &ctx));
+            }
+            std::panic::catch_unwind(AssertUnwindSafe(|| func(resource, action, &ctx)))
+                .map_err(|p| panic_message(&*p))
+        });
+        if limit.is_some() {
+            action.budget_used = Some(budget.used());
+        }
+        action.meta.extend(ctx.take_meta());
+        if let Some(logs) = &logs {
+            action.logs = logs.take();
+            for entry in &mut action.logs {
+                for key in &self.redact {
+                    if let Some(v) = entry.fields.get_mut(key) {
+                        *v = Value::String(REDACTED.to_owned());
+                    }
+                }
+            }
+        }
+        let res = match res {
+            Ok(res) => res,
+            Err(message) => {
+                if let (Some(key), Some(_)) = (&cache_key, &stale) {
+                    self.cache.refresh_failed(key);
+                }
+                let e = self.panic_error(action, &message);
+                action.set_error(e);
+                return DispatchOutcome::HandlerError;
+            }
+        };
+        match res {
+            Ok(v) => {
+                //println!("func returned some result {:?}",v);
+                let v = match trace.span("encode", || serde_json::value::to_value(&v)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        action.set_error(serialize_error(e));
+                        return DispatchOutcome::HandlerError;
+                    }
+                };
+                match self.finish_result(action, v) {
+                    Ok(v) => {
+                        if let Some(key) = cache_key {
+                            self.cache.store(key, v.clone(), Instant::now());
+                        }
+                        action.set_result(v)
+                    }
+                    Err(e) => {
+                        if let (Some(key), Some(_)) = (&cache_key, &stale) {
+                            self.cache.refresh_failed(key);
+                        }
+                        action.set_error(e);
+                        return DispatchOutcome::HandlerError;
+                    }
+                }
+                if ctx.is_dry_run() {
+                    DispatchOutcome::DryRun
+                } else {
+                    DispatchOutcome::Handled
+                }
+            }
+            Err(e) => {
+                if let (Some(key), Some(v)) = (&cache_key, stale) {
+                    event!(
+                        warn,
+                        "Manager [{}] refreshing {} failed, serving the stale result: {}",
+                        self.name,
+                        action.name,
+                        e
+                    );
+                    self.cache.refresh_failed(key);
+                    action.set_result(v);
+                    action.stale = true;
+                    return DispatchOutcome::Cached;
+                }
+                action.set_error(handler_error(e));
+                DispatchOutcome::HandlerError
+            }
+        }
+    }
+
+    /// renames the keys of a handler result and holds it to the result limit
+    fn finish_result(&self, action: &Action, mut v: Value) -> Result<Value, ActionError> {
+        if let Some(renames) = self.key_maps.result.get(&action.name) {
+            rename_result(&mut v, renames);
+        }
+        // measured once and shared by the size stats and the result limit
+        let size = if self.sizes.is_some() || self.result_limit.is_some() {
+            json_len(&v).ok()
+        } else {
+            None
+        };
+        if let Some(size) = size {
+            self.track_size(|s| s.result(&action.name, size));
+        }
+        self.limit_result(v, size)
+    }
+
+    /// answers actions without a handler to run here
+    fn run_unregistered(&self, resource: &R, action: &mut Action) -> DispatchOutcome {
+        if self.mut_actions.contains_key(&action.name) {
+            action.set_error(ActionError::new(
+                "MutHandler",
+                &format!(
+                    "{} changes the resource, dispatch it with do_action_mut",
+                    action.name
+                ),
+            ));
+            return DispatchOutcome::Rejected;
+        }
+        match &self.unknown {
+            Some(fallback) => match fallback(resource, action) {
+                Ok(v) => {
+                    action.set_result(v);
+                    DispatchOutcome::Handled
+                }
+                Err(e) => {
+                    action.set_error(e);
+                    DispatchOutcome::HandlerError
+                }
+            },
+            None => {
+                // reply with an error, cuz action was not found
+                action.set_error(ActionError::new(
+                    &format!("{:} - DoAction", self.name),
+                    "Action does NOT exist, make sure it is valid",
+                ));
+                DispatchOutcome::NotFound
+            }
+        }
+    }
+
+    /// runs `action` only when it is registered here, `None` when it is not and the action
+    /// is left alone; registered ones go through everything `do_action` checks
+    pub fn do_action_if_exists(&self, action: &mut Action) -> Option<DispatchOutcome> {
+        if self.has_action(&action.name) {
+            Some(self.do_action(action))
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: Send + Sync> Maintenance for Manager<R> {
+    fn sweep(&self, now: SystemTime) -> SweepStats {
+        self.sweep_at(now)
+    }
+}
+
+/// the manager and resource of a running dispatch, for sub-actions to reuse
+struct Scope<'a, R> {
+    manager: &'a Manager<R>,
+    resource: &'a R,
+}
+
+impl<R> SubDispatch for Scope<'_, R> {
+    fn sub_dispatch(
+        &self,
+        mut action: Action,
+        parent: &ActionCtx<'_>,
+        skip_validation: bool,
+    ) -> Result<ActionReply, ActionError> {
+        if parent.depth() >= self.manager.max_dispatch_depth {
+            return Err(ActionError::new(
+                "RecursionLimit",
+                &format!(
+                    "{} can not be dispatched, sub-actions are limited to a depth of {}",
+                    action.name, self.manager.max_dispatch_depth
+                ),
+            )
+            .with_details(json!({ "max_depth": self.manager.max_dispatch_depth })));
+        }
+        let ctx = parent.nested(&action, skip_validation);
+        action.source = ctx.source().map(|s| s.to_owned());
+        action.correlation_id = ctx.correlation_id().map(|s| s.to_owned());
+        if ctx.is_dry_run() {
+            action.dry_run = Some(true);
+        }
+        let mut trace = self.manager.tracer();
+        self.manager
+            .run_action(Target::Shared(self.resource), &mut action, &mut trace, &ctx);
+        self.manager.record_trace(trace, &action);
+        Ok(action.into_reply())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(name: &str, payload: Value) -> Action {
+        Action {
+            payload: serde_json::from_value(payload).unwrap(),
+            ..Action::new(name, 1)
+        }
+    }
+
+    fn manager(mode: EchoMode) -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("ok", |_, _| action_ok());
+        m.on("fail", |_, _| Err(ActionError::new("Fail", "failed").into()));
+        m.echo_payload(mode);
+        m
+    }
+
+    fn run(m: &Manager<()>, name: &str) -> ActionReply {
+        let mut a = action(name, json!({"user": "bob", "password": "hunter2"}));
+        m.do_action(&mut a);
+        m.reply(a)
+    }
+
+    #[test]
+    fn echo_never_is_default() {
+        let mut m = manager(EchoMode::Always);
+        m.echo_payload(EchoMode::default());
+        assert!(run(&m, "ok").payload.is_empty());
+        assert!(run(&m, "fail").payload.is_empty());
+        let v = serde_json::to_value(run(&m, "ok")).unwrap();
+        assert!(v.get("payload").is_none());
+    }
+
+    #[test]
+    fn echo_always() {
+        let m = manager(EchoMode::Always);
+        assert_eq!(run(&m, "ok").payload["user"], json!("bob"));
+        assert_eq!(run(&m, "fail").payload["user"], json!("bob"));
+    }
+
+    #[test]
+    fn echo_on_error() {
+        let m = manager(EchoMode::OnError);
+        assert!(run(&m, "ok").payload.is_empty());
+        assert_eq!(run(&m, "fail").payload["user"], json!("bob"));
+        assert_eq!(run(&m, "missing").payload["user"], json!("bob"));
+    }
+
+    #[test]
+    fn batch_applies_echo_per_action() {
+        let m = manager(EchoMode::OnError);
+        let replies = m.do_batch(vec![
+            action("ok", json!({"user": "a"})),
+            action("fail", json!({"user": "b"})),
+        ]);
+        assert!(replies[0].payload.is_empty());
+        assert_eq!(replies[1].payload["user"], json!("b"));
+    }
+
+    fn batch(n: u64) -> Vec<Action> {
+        (0..n)
+            .map(|id| Action {
+                id,
+                ..action("slow", json!({}))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_deadline_partial() {
+        let mut m = manager(EchoMode::Never);
+        m.on("slow", |_, _| {
+            std::thread::sleep(Duration::from_millis(30));
+            action_ok()
+        });
+        let opts = BatchOptions {
+            deadline: Some(Instant::now() + Duration::from_millis(70)),
+        };
+        let out = m.do_batch_with(batch(10), opts);
+        assert_eq!(out.replies.len(), 10);
+        assert!(out.completed >= 1 && out.completed < 10);
+        for (i, r) in out.replies.iter().enumerate() {
+            assert_eq!(r.id, i as u64);
+            if i < out.completed {
+                assert!(r.errors.is_empty());
+            } else {
+                assert_eq!(r.errors[0].code, "BatchDeadlineExceeded");
+                assert!(r.errors[0].is_retryable());
+            }
+        }
+    }
+
+    #[test]
+    fn batch_deadline_already_passed() {
+        let m = manager(EchoMode::Never);
+        let opts = BatchOptions {
+            deadline: Some(Instant::now()),
+        };
+        let out = m.do_batch_with(batch(3), opts);
+        assert_eq!(out.completed, 0);
+        assert!(out.replies.iter().all(|r| r.result.is_none()));
+        let out = m.do_batch_with(batch(3), BatchOptions::default());
+        assert_eq!(out.completed, 3);
+    }
+
+    #[test]
+    fn echo_is_redacted() {
+        let mut m = manager(EchoMode::Always);
+        m.redact_keys(&["password"]);
+        let reply = run(&m, "ok");
+        assert_eq!(reply.payload["password"], json!(REDACTED));
+        assert_eq!(reply.payload["user"], json!("bob"));
+    }
+
+    #[test]
+    fn multiple_attachments() {
+        let mut a = action("mail", json!({}));
+        a.attach("a.txt", "text/plain", b"hello");
+        a.attach("b.bin", "application/octet-stream", &[0, 1, 2, 255]);
+        assert!(a.base64.is_none());
+        assert_eq!(a.attachments[1].size, 4);
+        let bytes = Bytes::from(serde_json::to_vec(&a).unwrap());
+        let parsed = Action::from_bytes_with(bytes, &ParseOptions::default()).unwrap();
+        let decoded = parsed.attachments_decoded().unwrap();
+        assert_eq!(decoded[0], ("a.txt".to_owned(), b"hello".to_vec()));
+        assert_eq!(decoded[1], ("b.bin".to_owned(), vec![0, 1, 2, 255]));
+    }
+
+    #[test]
+    fn attachment_size_limits() {
+        let mut a = action("mail", json!({}));
+        a.attach("a", "text/plain", &[7; 10]);
+        a.attach("b", "text/plain", &[7; 11]);
+        let buf = Bytes::from(serde_json::to_vec(&a).unwrap());
+        let opts = ParseOptions {
+            max_attachment_bytes: Some(10),
+            ..Default::default()
+        };
+        let err = Action::from_bytes_with(buf.clone(), &opts).unwrap_err();
+        assert_eq!(err.code, "AttachmentTooLarge");
+        let opts = ParseOptions {
+            max_attachment_bytes: Some(11),
+            max_total_attachment_bytes: Some(20),
+        };
+        assert!(Action::from_bytes_with(buf.clone(), &opts).is_err());
+        let opts = ParseOptions {
+            max_attachment_bytes: Some(11),
+            max_total_attachment_bytes: Some(21),
+        };
+        assert!(Action::from_bytes_with(buf, &opts).is_ok());
+    }
+
+    #[test]
+    fn from_bytes_errors_instead_of_panicking() {
+        let err = Action::from_bytes(Bytes::from_static(b"")).unwrap_err();
+        assert_eq!(err.code, "JsonError");
+        let err = Action::from_bytes(Bytes::from_static(br#"{"name":"a","id":3,"#)).unwrap_err();
+        assert_eq!(err.code, "JsonError");
+
+        let mut bad = br#"{"name":""#.to_vec();
+        bad.extend([0xff, 0xfe]);
+        bad.extend(br#"","id":1,"payload":{}}"#);
+        let err = Action::from_bytes(Bytes::from(bad.clone())).unwrap_err();
+        assert_eq!(err.code, "Utf8Error");
+        assert_eq!(err.details.unwrap()["offset"], json!(9));
+        // a truncated multi-byte sequence
+        let err = Action::from_slice(&[b'{', b'"', 0xe2, 0x82]).unwrap_err();
+        assert_eq!(err.code, "Utf8Error");
+
+        let a = Action::from_slice(br#"{"name":"a","id":3,"payload":{}}"#).unwrap();
+        assert_eq!((a.name.as_str(), a.id), ("a", 3));
+    }
+
+    fn fut_manager() -> ManagerFut<Arc<Mutex<Vec<String>>>> {
+        let mut m = ManagerFut::new("async", Arc::new(Mutex::new(vec!["ada".to_owned()])));
+        m.on("users.count", |db, _| {
+            let db = db.clone();
+            async move {
+                tokio::task::yield_now().await;
+                let n = db.lock().unwrap().len();
+                Ok(json!(n))
+            }
+        });
+        m.on("users.get", |db, a| {
+            let db = db.clone();
+            let idx = a.payload.get("idx").and_then(Value::as_u64);
+            async move {
+                tokio::task::yield_now().await;
+                let db = db.lock().unwrap();
+                match idx.and_then(|i| db.get(i as usize)) {
+                    Some(name) => Ok(json!(name)),
+                    None => Err(ActionError::new("UserNotFound", "no such user")),
+                }
+            }
+        });
+        m
+    }
+
+    #[tokio::test]
+    async fn fut_success() {
+        let m = fut_manager();
+        let mut a = action("users.count", json!({}));
+        m.do_action(&mut a).await;
+        assert!(a.errors.is_none());
+        assert_eq!(a.result, Some(json!(1)));
+        let mut a = action("users.get", json!({"idx": 0}));
+        m.do_action(&mut a).await;
+        assert_eq!(a.result, Some(json!("ada")));
+    }
+
+    #[tokio::test]
+    async fn fut_errors() {
+        let m = fut_manager();
+        let mut a = action("users.get", json!({"idx": 5}));
+        m.do_action(&mut a).await;
+        assert_eq!(a.result, None);
+        assert_eq!(a.errors.as_ref().unwrap()[0].code, "UserNotFound");
+
+        let mut a = action("users.delete", json!({}));
+        m.do_action(&mut a).await;
+        let errors = a.errors.unwrap();
+        assert_eq!(errors[0].code, "async - DoAction");
+        assert_eq!(
+            errors[0].message,
+            "Action does NOT exist, make sure it is valid"
+        );
+    }
+
+    struct Counter {
+        hits: u64,
+    }
+
+    #[test]
+    fn mut_handlers_are_listed() {
+        let mut m = Manager::new("test", Counter { hits: 0 });
+        m.on_mut("hit", |c, _| {
+            c.hits += 1;
+            Ok(json!(c.hits))
+        });
+        m.on("hits", |c, _| value_ok(c.hits));
+        assert!(m.has_action("hit"));
+        assert_eq!(m.action_names().collect::<Vec<_>>(), vec!["hit", "hits"]);
+        assert_eq!(m.len(), 2);
+        assert!(!m.is_empty());
+
+        let mut a = action("__describe", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result.unwrap()["actions"], json!(["hit", "hits"]));
+        let mut a = action("hit", json!({}));
+        assert_eq!(
+            m.do_action_if_exists(&mut a),
+            Some(DispatchOutcome::Rejected)
+        );
+        assert_eq!(a.errors.unwrap()[0].code, "MutHandler");
+        assert!(m.handle_if_exists(action("hit", json!({}))).is_ok());
+    }
+
+    #[test]
+    fn mut_handlers_change_the_resource() {
+        let mut m = Manager::new("test", Counter { hits: 0 });
+        m.on_mut("hit", |c, a| {
+            c.hits += a.payload.get("by").and_then(Value::as_u64).unwrap_or(1);
+            Ok(json!(c.hits))
+        });
+        m.on("hits", |c, _| value_ok(c.hits));
+        for _ in 0..3 {
+            m.do_action_mut(&mut action("hit", json!({})));
+        }
+        let mut a = action("hit", json!({"by": 10}));
+        m.do_action_mut(&mut a);
+        assert_eq!(a.result, Some(json!(13)));
+
+        // read-only handlers go through do_action_mut as well
+        let mut a = action("hits", json!({}));
+        m.do_action_mut(&mut a);
+        assert_eq!(a.result, Some(json!(13)));
+
+        let mut a = action("hit", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "MutHandler");
+        let mut a = action("hits", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(13)));
+    }
+
+    #[test]
+    fn mut_handlers_go_through_the_pipeline() {
+        let mut m = Manager::new("test", Counter { hits: 0 });
+        m.on_mut("hit", |c, _| {
+            c.hits += 1;
+            Ok(json!(c.hits))
+        });
+        m.use_before(|_, a| match a.payload.get("blocked") {
+            Some(_) => Err(ActionError::new("Blocked", "blocked by a hook")),
+            None => Ok(()),
+        });
+        m.use_after(|c, a| {
+            a.set_payload_field("seen", c.hits).unwrap();
+        });
+        m.max_concurrent_for("hit", 1);
+
+        // no dry variant, nothing is changed
+        let mut a = action("hit", json!({}));
+        a.dry_run = Some(true);
+        m.do_action_mut(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "DryRunUnsupported");
+        let mut a = action("hit", json!({ "blocked": true }));
+        m.do_action_mut(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "Blocked");
+        assert_eq!(a.payload["seen"], json!(0));
+
+        let mut a = action("hit", json!({}));
+        m.do_action_mut(&mut a);
+        assert_eq!(a.result, Some(json!(1)));
+        assert_eq!(a.payload["seen"], json!(1));
+        assert_eq!(m.stats()["hit"].invocations, 1);
+
+        m.set_token_validator(|_| Err(crate::auth::unauthorized("a token is required")));
+        let mut a = action("hit", json!({}));
+        m.do_action_mut(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "Unauthorized");
+        assert_eq!(m.resource().unwrap().unwrap().hits, 1);
+    }
+
+    #[test]
+    fn mut_panics_keep_the_resource() {
+        let mut m = Manager::new("test", Counter { hits: 7 });
+        m.on_mut("boom", |_, _| panic!("boom"));
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            m.do_action_mut(&mut action("boom", json!({})));
+        }));
+        assert!(res.is_err());
+        assert_eq!(m.resource().unwrap().unwrap().hits, 7);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut a = action(
+            "ok",
+            json!({"n": 1, "s": "é", "nested": {"list": [1, null, true]}}),
+        );
+        a.id = 42;
+        a.token = Some("t".to_owned());
+        a.base64 = Some("AAE=".to_owned());
+        a.set_error(ActionError::new("Bad", "bad").with_details(json!({"field": "n"})));
+        let back = Action::from_bytes(a.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&back).unwrap(),
+            serde_json::to_value(&a).unwrap()
+        );
+        let bytes = a.to_bytes().unwrap();
+        assert_eq!(a.into_bytes().unwrap(), bytes);
+
+        let mut a = action("ok", json!({"n": 1}));
+        a.set_result(json!({"done": true}));
+        let reply = a.into_reply();
+        let back: ActionReply = serde_json::from_slice(&reply.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&back).unwrap(),
+            serde_json::to_value(&reply).unwrap()
+        );
+        assert_eq!(
+            reply.clone().into_bytes().unwrap(),
+            reply.to_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn unserializable_is_an_error() {
+        let mut bad = HashMap::new();
+        bad.insert(vec![1u8], 1);
+        assert_eq!(encode(&bad).unwrap_err().code, "SerializeError");
+    }
+
+    #[test]
+    fn attachments_wire_compat() {
+        let old = r#"{"name":"a","id":3,"token":null,"base64":"AAE=","payload":{},"result":null,"errors":null}"#;
+        let a = Action::from_bytes(Bytes::from(old)).unwrap();
+        assert!(a.attachments.is_empty());
+        let v = serde_json::to_value(&a).unwrap();
+        assert!(v.get("attachments").is_none());
+    }
+
+    #[test]
+    fn attachments_carried_into_reply_when_enabled() {
+        let mut m = manager(EchoMode::Never);
+        let mut a = action("ok", json!({}));
+        a.attach("a", "text/plain", b"x");
+        assert!(m.reply(a.clone()).attachments.is_empty());
+        m.reply_attachments(true);
+        assert_eq!(m.reply(a).attachments[0].name, "a");
+    }
+
+    fn big_result_manager(policy: ResultPolicy) -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("big", |_, _| {
+            value_ok(json!({"id": 1, "name": "x", "blob": "y".repeat(100)}))
+        });
+        m.on("small", |_, _| value_ok(json!({"id": 1})));
+        m.max_result_bytes(64, policy);
+        m
+    }
+
+    #[test]
+    fn result_limit_reject() {
+        let m = big_result_manager(ResultPolicy::Reject);
+        let mut a = action("big", json!({}));
+        m.do_action(&mut a);
+        assert!(a.result.is_none());
+        let e = &a.errors.unwrap()[0];
+        assert_eq!(e.code, "ResultTooLarge");
+        assert!(e.details.as_ref().unwrap()["size"].as_u64().unwrap() > 64);
+    }
+
+    #[test]
+    fn result_limit_truncate() {
+        let m = big_result_manager(ResultPolicy::Truncate {
+            keep_keys: vec!["id".to_owned(), "missing".to_owned()],
+        });
+        let mut a = action("big", json!({}));
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+        assert_eq!(a.result.unwrap(), json!({"id": 1, "_truncated": true}));
+    }
+
+    #[test]
+    fn result_under_limit_untouched() {
+        let m = big_result_manager(ResultPolicy::Reject);
+        let mut a = action("small", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result.unwrap(), json!({"id": 1}));
+
+        // exactly at the limit passes too
+        let mut m = Manager::new("test", ());
+        m.on("exact", |_, _| value_ok(json!({"k": "vvvvv"})));
+        m.max_result_bytes(r#"{"k":"vvvvv"}"#.len(), ResultPolicy::Reject);
+        let mut a = action("exact", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result.unwrap(), json!({"k": "vvvvv"}));
+    }
+
+    fn dry_run_manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on_with_dry_run(
+            "delete",
+            |_, _| value_ok("deleted"),
+            |_, a| value_ok(json!({"would_delete": a.payload["id"]})),
+        );
+        m.on("plain", |_, _| value_ok("done"));
+        m.validate_action("delete", |a| {
+            crate::validate::require_keys(a, &["id"]).map_err(|e| vec![e])
+        });
+        m
+    }
+
+    fn dry(name: &str, payload: Value) -> Action {
+        Action {
+            dry_run: Some(true),
+            ..action(name, payload)
+        }
+    }
+
+    #[test]
+    fn dry_run_uses_dry_handler() {
+        let m = dry_run_manager();
+        let mut a = dry("delete", json!({"id": 3}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!({"would_delete": 3})));
+        let reply = serde_json::to_value(m.reply(a)).unwrap();
+        assert_eq!(reply["dry_run"], json!(true));
+
+        // validation still applies
+        let mut a = dry("delete", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "ValidationError");
+    }
+
+    #[test]
+    fn dry_run_unsupported() {
+        let m = dry_run_manager();
+        let mut a = dry("plain", json!({}));
+        m.do_action(&mut a);
+        assert!(a.result.is_none());
+        assert_eq!(a.errors.unwrap()[0].code, "DryRunUnsupported");
+    }
+
+    #[test]
+    fn dry_handler_not_added_to_mut_handlers() {
+        let mut m = Manager::new("test", ());
+        m.on_mut("delete", |_, _| Ok(json!("deleted")));
+        m.on_with_dry_run("delete", |_, _| value_ok("shared"), |_, _| value_ok("would"));
+        let mut a = dry("delete", json!({}));
+        m.do_action_mut(&mut a);
+        assert!(a.result.is_none());
+        assert_eq!(a.errors.unwrap()[0].code, "DryRunUnsupported");
+    }
+
+    #[test]
+    fn normal_run_ignores_dry_handler() {
+        let m = dry_run_manager();
+        let mut a = action("delete", json!({"id": 3}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("deleted")));
+        let reply = serde_json::to_value(m.reply(a)).unwrap();
+        assert!(reply.get("dry_run").is_none());
+    }
+
+    #[test]
+    fn timings_on_replies() {
+        let mut m = Manager::new("test", ());
+        m.on("slow", |_, _| {
+            std::thread::sleep(Duration::from_millis(50));
+            value_ok("done")
+        });
+        let (reply, _) = m.handle_with_outcome(action("slow", json!({})));
+        let v = serde_json::to_value(&reply).unwrap();
+        assert!(v.get("received_at").is_none() && v.get("duration_ms").is_none());
+
+        m.record_timings(true);
+        let before = crate::clock::unix_ms();
+        let (reply, _) = m.handle_with_outcome(action("slow", json!({})));
+        let duration = reply.duration_ms.unwrap();
+        assert!((50..1000).contains(&duration), "took {}ms", duration);
+        let received = reply.received_at.unwrap();
+        assert!(received >= before && received <= before + 1000);
+        let v = serde_json::to_value(&reply).unwrap();
+        assert_eq!(v["duration_ms"], json!(duration));
+
+        // failures are timed as well
+        let (reply, _) = m.handle_with_outcome(action("missing", json!({})));
+        assert!(reply.duration_ms.is_some());
+    }
+
+    #[test]
+    fn meta_on_replies() {
+        let mut m = Manager::new("test", ());
+        m.on_ctx("list", |_, _, ctx| {
+            ctx.set_meta("next_cursor", "page-2")?;
+            ctx.set_meta("max_age", 30)?;
+            value_ok(json!([1, 2]))
+        });
+        m.on("get", |_, _| value_ok(json!(1)));
+        let (reply, _) = m.handle_with_outcome(action("list", json!({})));
+        assert_eq!(reply.result, Some(json!([1, 2])));
+
+        let json = serde_json::to_string(&reply).unwrap();
+        let reply: ActionReply = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            reply
+                .meta_value::<String>("next_cursor")
+                .unwrap()
+                .as_deref(),
+            Some("page-2")
+        );
+        assert_eq!(reply.meta_value::<u32>("max_age").unwrap(), Some(30));
+        assert_eq!(reply.meta_value::<u32>("missing").unwrap(), None);
+        assert_eq!(
+            reply.meta_value::<u32>("next_cursor").unwrap_err().code,
+            "PayloadError"
+        );
+
+        let (reply, _) = m.handle_with_outcome(action("get", json!({})));
+        let v = serde_json::to_value(&reply).unwrap();
+        assert!(v.get("meta").is_none());
+
+        let mut a = action("get", json!({}));
+        a.set_meta("hint", json!({ "stale": false })).unwrap();
+        assert_eq!(a.into_reply().meta["hint"], json!({ "stale": false }));
+    }
+
+    /// the resource says whether maintenance is on
+    fn guarded_manager() -> Manager<bool> {
+        let mut m = Manager::new("test", false);
+        m.on("delete-user", |_, a| value_ok(a.payload["id"].clone()));
+        m.on("export", |_, _| value_ok("exported"));
+        m.guard("delete-user", |_, a| match a.payload.get("admin") {
+            Some(Value::Bool(true)) => Ok(()),
+            _ => Err(ActionError::new("Forbidden", "admins only")),
+        })
+        .unwrap();
+        m.guard("delete-user", |_, a| match a.payload.get("id") {
+            Some(_) => Ok(()),
+            None => Err(ActionError::new("MissingId", "which user")),
+        })
+        .unwrap();
+        m.guard("export", |maintenance, _| match maintenance {
+            false => Ok(()),
+            true => Err(ActionError::new("Maintenance", "try again later")),
+        })
+        .unwrap();
+        m
+    }
+
+    #[test]
+    fn guards_pass_through() {
+        let m = guarded_manager();
+        let mut a = action("delete-user", json!({"admin": true, "id": 7}));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!(7)));
+        let mut a = action("export", json!({}));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn first_failing_guard_wins() {
+        let m = guarded_manager();
+        // both guards would fail, only the first one runs
+        let mut a = action("delete-user", json!({}));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+        assert!(a.result.is_none());
+        let errors = a.errors.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "Forbidden");
+
+        let mut a = action("delete-user", json!({"admin": true}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "MissingId");
+
+        let mut m = m;
+        *m.resource_mut().unwrap() = true;
+        let mut a = action("export", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "Maintenance");
+    }
+
+    #[test]
+    fn guarding_unknown_actions() {
+        let mut m = guarded_manager();
+        let err = m.guard("missing", |_, _| Ok(())).unwrap_err();
+        assert_eq!(err.code, "UnknownAction");
+        // guards go with the handler
+        m.off("export");
+        m.on("export", |_, _| value_ok("exported"));
+        *m.resource_mut().unwrap() = true;
+        let mut a = action("export", json!({}));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+    }
+
+    fn full_reply() -> ActionReply {
+        let mut a = action("upstream", json!({"q": 1}));
+        a.id = 42;
+        a.correlation_id = Some("req-9".to_owned());
+        a.attachments = vec![Attachment::new("a.txt", "text/plain", b"hi")];
+        a.dry_run = Some(true);
+        a.set_meta("next_cursor", "page-2").unwrap();
+        a.set_result(json!({"rows": 3}));
+        a.set_error(ActionError::new("Partial", "one shard down"));
+        a.add_warning(ActionError::new("Deprecated", "old"));
+        let mut m = Manager::new("test", ());
+        m.echo_payload(EchoMode::Always);
+        m.reply_attachments(true);
+        m.reply(a)
+    }
+
+    #[test]
+    fn reply_into_action_keeps_everything() {
+        let reply = full_reply();
+        let a = reply.clone().into_action("downstream");
+        assert_eq!(a.name, "downstream");
+        assert_eq!(a.id, 42);
+        assert_eq!(a.correlation_id.as_deref(), Some("req-9"));
+        assert_eq!(a.attachments, reply.attachments);
+        assert_eq!(a.dry_run, Some(true));
+        assert_eq!(a.meta, reply.meta);
+        assert_eq!(a.meta["next_cursor"], json!("page-2"));
+        assert!(a.result.is_none() && a.errors.is_none());
+        assert_eq!(a.payload[FORWARDED_RESULT_KEY], json!({"rows": 3}));
+        let errors: Vec<ActionError> =
+            serde_json::from_value(a.payload[FORWARDED_ERRORS_KEY].clone()).unwrap();
+        assert_eq!(errors[0].code, "Partial");
+        let warnings: Vec<ActionError> =
+            serde_json::from_value(a.payload[FORWARDED_WARNINGS_KEY].clone()).unwrap();
+        assert_eq!(warnings[0].code, "Deprecated");
+        assert_eq!(a.payload[FORWARDED_REQUEST_KEY], json!({"q": 1}));
+
+        let b = Action::from_reply(&reply, "downstream");
+        assert_eq!(
+            serde_json::to_value(&a).unwrap(),
+            serde_json::to_value(&b).unwrap()
+        );
+        let c = reply.into_action_with("downstream", "upstream_result");
+        assert_eq!(c.payload["upstream_result"], json!({"rows": 3}));
+    }
+
+    #[test]
+    fn forwarded_resets_outcome() {
+        let mut a = action("upstream", json!({"q": 1}));
+        a.token = Some("t".to_owned());
+        a.source = Some(crate::source::WS.to_owned());
+        a.correlation_id = Some("req-9".to_owned());
+        a.attachments = vec![Attachment::new("a.txt", "text/plain", b"hi")];
+        a.set_result(json!(1));
+        a.set_error(ActionError::new("X", ""));
+        a.add_warning(ActionError::new("W", ""));
+        let f = a.as_forwarded("downstream", 7);
+        assert_eq!((f.name.as_str(), f.id), ("downstream", 7));
+        assert!(f.result.is_none() && f.errors.is_none() && f.warnings.is_empty());
+        assert_eq!(f.token, a.token);
+        assert_eq!(f.source, a.source);
+        assert_eq!(f.correlation_id, a.correlation_id);
+        assert_eq!(f.attachments, a.attachments);
+        assert_eq!(f.payload, a.payload);
+
+        // forwarding and replying gets the same correlation back
+        let reply = f.into_reply();
+        assert_eq!(reply.correlation_id.as_deref(), Some("req-9"));
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn manager_is_send_sync() {
+        assert_send_sync::<Manager<()>>();
+        assert_send_sync::<Arc<Manager<Mutex<Vec<u64>>>>>();
+    }
+
+    #[test]
+    fn describe_without_the_resource() {
+        let made = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let n = made.clone();
+        let mut m = Manager::with("users", move || {
+            n.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        m.on("delete", |_, _| action_ok());
+        m.on("create", |_, _| action_ok());
+        let mut a = action(DESCRIBE_ACTION, json!({}));
+        m.do_action(&mut a);
+        assert_eq!(
+            a.result,
+            Some(json!({"manager": "users", "actions": ["create", "delete"]}))
+        );
+        assert_eq!(made.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        m.describe_action(Some("meta.describe"));
+        let mut a = action("meta.describe", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result.unwrap()["manager"], json!("users"));
+        m.describe_action(None);
+        let mut a = action("meta.describe", json!({}));
+        m.do_action(&mut a);
+        assert!(a.errors.is_some());
+    }
+
+    #[test]
+    fn describe_overridden_by_handler() {
+        let mut m = Manager::new("users", ());
+        m.on(DESCRIBE_ACTION, |_, _| value_ok("mine"));
+        let mut a = action(DESCRIBE_ACTION, json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("mine")));
+    }
+
+    #[test]
+    fn hooks_in_order_and_short_circuit() {
+        let mut m = Manager::new("test", Mutex::new(Vec::<String>::new()));
+        m.on("run", |seen, a| {
+            seen.lock()
+                .unwrap()
+                .push(format!("handler:{}", a.payload["n"]));
+            action_ok()
+        });
+        m.on("fail", |_, _| Err(ActionError::new("Nope", "").into()));
+        m.use_before(|seen, a| {
+            seen.lock().unwrap().push("before1".to_owned());
+            a.payload.insert("n".to_owned(), json!(1));
+            Ok(())
+        });
+        m.use_before(|seen, a| {
+            seen.lock().unwrap().push("before2".to_owned());
+            match a.token.as_deref() {
+                Some("bad") => Err(ActionError::new("Unauthorized", "bad token")),
+                _ => Ok(()),
+            }
+        });
+        m.use_after(|seen, a| {
+            let errors = a.errors.as_ref().map_or(0, |e| e.len());
+            seen.lock().unwrap().push(format!("after1:{}", errors));
+        });
+        m.use_after(|seen, _| seen.lock().unwrap().push("after2".to_owned()));
+        let take = |m: &Manager<Mutex<Vec<String>>>| {
+            std::mem::take(&mut *m.resource.as_ref().unwrap().lock().unwrap())
+        };
+
+        m.do_action(&mut action("run", json!({})));
+        assert_eq!(
+            take(&m),
+            vec!["before1", "before2", "handler:1", "after1:0", "after2"]
+        );
+
+        let mut a = action("run", json!({}));
+        a.token = Some("bad".to_owned());
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "Unauthorized");
+        assert_eq!(take(&m), vec!["before1", "before2", "after1:1", "after2"]);
+
+        // after hooks see failures and unknown actions
+        m.do_action(&mut action("fail", json!({})));
+        assert_eq!(take(&m), vec!["before1", "before2", "after1:1", "after2"]);
+        m.do_action(&mut action("missing", json!({})));
+        assert_eq!(take(&m), vec!["before1", "before2", "after1:1", "after2"]);
+    }
+
+    #[test]
+    fn on_error_sees_failures() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut m = Manager::new("test", ());
+        m.on("ok", |_, _| action_ok());
+        m.on("fail", |_, _| Err(ActionError::new("Nope", "no").into()));
+        m.on_error(|_, _, _| panic!("alerting is down"));
+        let s = seen.clone();
+        m.on_error(move |name, a, e| {
+            s.lock()
+                .unwrap()
+                .push((name.to_owned(), a.id, e.code.clone()));
+        });
+
+        m.do_action(&mut action("ok", json!({})));
+        let mut a = action("fail", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "Nope");
+        m.do_action(&mut action("missing", json!({})));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("fail".to_owned(), 1, "Nope".to_owned()),
+                ("missing".to_owned(), 1, "test - DoAction".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fallback() {
+        let mut m = Manager::with("test", || 5u32);
+        m.on("known", |_, _| value_ok("known"));
+        m.on_unknown(|r, a| match a.name.as_str() {
+            "legacy.get" => Ok(json!({ "forwarded": a.name, "resource": r })),
+            _ => Err(ActionError::new("UnknownAction", "no such action")),
+        });
+        let mut a = action("legacy.get", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(
+            a.result,
+            Some(json!({"forwarded": "legacy.get", "resource": 5}))
+        );
+        let mut a = action("other", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "UnknownAction");
+        assert_eq!(m.not_found_count(), 2);
+        assert!(!m.stats().contains_key("other"));
+
+        let mut a = action("legacy.get", json!({}));
+        m.do_action_if_exists(&mut a);
+        assert!(a.result.is_none() && a.errors.is_none());
+        let mut a = action("known", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("known")));
+    }
+
+    #[test]
+    fn off_and_clear() {
+        let mut m = Manager::new("test", ());
+        m.on("a", |_, _| value_ok(1));
+        m.on("b", |_, _| value_ok(2));
+        let mut a = action("a", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(1)));
+
+        assert!(m.off("a"));
+        assert!(!m.off("a"));
+        let (reply, outcome) = m.handle_with_outcome(action("a", json!({})));
+        assert_eq!(outcome, DispatchOutcome::NotFound);
+        assert_eq!(reply.errors[0].code, "test - DoAction");
+        // registering it again works like a first registration
+        m.on("a", |_, _| value_ok(3));
+        let mut a = action("a", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(3)));
+
+        m.clear();
+        let (_, outcome) = m.handle_with_outcome(action("b", json!({})));
+        assert_eq!(outcome, DispatchOutcome::NotFound);
+    }
+
+    #[tokio::test]
+    async fn fut_off() {
+        let mut m = ManagerFut::new("async", ());
+        m.on("a", |_, _| async { value_ok(1).map_err(handler_error) });
+        let mut a = action("a", json!({}));
+        m.do_action(&mut a).await;
+        assert_eq!(a.result, Some(json!(1)));
+        assert!(m.off("a"));
+        let mut a = action("a", json!({}));
+        m.do_action(&mut a).await;
+        assert_eq!(a.errors.unwrap()[0].code, "async - DoAction");
+        m.clear();
+    }
+
+    #[test]
+    fn introspection_accessors() {
+        let mut m = Manager::new("users", ());
+        assert!(m.is_empty());
+        m.on("update", |_, _| action_ok());
+        m.on("create", |_, _| action_ok());
+        m.on("delete", |_, _| action_ok());
+        assert_eq!(m.manager_name(), "users");
+        assert!(m.has_action("create") && !m.has_action("list"));
+        assert_eq!(
+            m.action_names().collect::<Vec<_>>(),
+            vec!["create", "delete", "update"]
+        );
+        assert_eq!((m.len(), m.is_empty()), (3, false));
+
+        let mut f = ManagerFut::new("async", ());
+        f.on("b", |_, _| async { Ok(json!(1)) });
+        f.on("a", |_, _| async { Ok(json!(1)) });
+        assert_eq!(f.manager_name(), "async");
+        assert!(f.has_action("a"));
+        assert_eq!(f.action_names().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(f.len(), 2);
+    }
+
+    #[test]
+    fn duplicates_reported_or_replaced() {
+        let mut m = Manager::new("test", ());
+        m.try_on("save", |_, _| value_ok("first")).unwrap();
+        let err = m.try_on("save", |_, _| value_ok("second")).unwrap_err();
+        assert_eq!(err.code, "DuplicateAction");
+        assert_eq!(err.details.unwrap()["action"], json!("save"));
+        let mut a = action("save", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("first")));
+
+        assert!(m.on_replace("save", |_, _| value_ok("replaced")));
+        let mut a = action("save", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("replaced")));
+        assert!(!m.on_replace("load", |_, _| value_ok("new")));
+        assert!(m.has_action("load"));
+    }
+
+    #[test]
+    fn builder() {
+        #[derive(Serialize)]
+        struct Filter {
+            user_id: u64,
+            active: bool,
+        }
+        let a = Action::new("user.list", 3)
+            .with_token("t")
+            .with_payload_value("limit", 10)
+            .with_payload_struct(Filter {
+                user_id: 7,
+                active: true,
+            })
+            .unwrap()
+            .with_base64("aGk=");
+        assert_eq!((a.name.as_str(), a.id), ("user.list", 3));
+        assert_eq!(a.token.as_deref(), Some("t"));
+        assert_eq!(a.base64.as_deref(), Some("aGk="));
+        assert_eq!(
+            serde_json::to_value(&a.payload).unwrap(),
+            json!({"limit": 10, "user_id": 7, "active": true})
+        );
+        assert!(a.errors.is_none() && a.result.is_none());
+
+        let err = Action::new("x", 1).with_payload_struct(5).unwrap_err();
+        assert_eq!(err.code, "PayloadError");
+
+        let mut m = Manager::new("test", ());
+        m.on("sum", |_, a| {
+            value_ok(a.payload["a"].as_u64().unwrap() + a.payload["b"].as_u64().unwrap())
+        });
+        let (reply, _) = m.handle_with_outcome(
+            Action::new("sum", 1)
+                .with_payload_value("a", 2)
+                .with_payload_value("b", 3),
+        );
+        assert_eq!(reply.result, Some(json!(5)));
+    }
+
+    #[test]
+    fn typed_payload_accessors() {
+        let a = action(
+            "x",
+            json!({"name": "ann", "age": 30, "ratio": 0.5, "admin": false, "neg": -1}),
+        );
+        assert_eq!(a.payload_str("name").unwrap(), "ann");
+        assert_eq!(a.payload_u64("age").unwrap(), 30);
+        assert_eq!(a.payload_f64("age").unwrap(), 30.0);
+        assert_eq!(a.payload_f64("ratio").unwrap(), 0.5);
+        assert!(!a.payload_bool("admin").unwrap());
+        assert_eq!(a.payload_value("age").unwrap(), &json!(30));
+
+        let missing = a.payload_str("email").unwrap_err();
+        assert_eq!(missing.code, "MissingField");
+        assert!(missing.message.contains("email"));
+        for (key, err) in [
+            ("ratio", a.payload_u64("ratio").unwrap_err()),
+            ("neg", a.payload_u64("neg").unwrap_err()),
+            ("age", a.payload_str("age").unwrap_err()),
+            ("name", a.payload_bool("name").unwrap_err()),
+        ] {
+            assert_eq!(err.code, "TypeMismatch");
+            assert!(err.message.contains(key));
+            assert_eq!(err.details.unwrap()["field"], json!(key));
+        }
+    }
+
+    #[test]
+    fn single_payload_fields() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Filter {
+            status: String,
+            since: u64,
+        }
+        let mut a = action("orders.list", json!({"page": 2}));
+        a.set_payload_field(
+            "filter",
+            Filter {
+                status: "open".to_owned(),
+                since: 10,
+            },
+        )
+        .unwrap();
+        a.set_payload_field("ids", vec![1, 2, 3]).unwrap();
+
+        let filter: Filter = a.from_payload_field("filter").unwrap();
+        assert_eq!(filter.status, "open");
+        assert_eq!(
+            a.from_payload_field::<Vec<u64>>("ids").unwrap(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(a.from_payload_field::<u32>("page").unwrap(), 2);
+
+        let err = a.from_payload_field::<Filter>("sort").unwrap_err();
+        assert_eq!(err.code, "MissingField");
+        let err = a.from_payload_field::<Filter>("ids").unwrap_err();
+        assert_eq!(err.code, "PayloadError");
+        assert!(err.message.contains("ids"));
+    }
+
+    #[test]
+    fn unserializable_results_are_errors() {
+        struct Broken;
+        impl Serialize for Broken {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("cannot serialize Broken"))
+            }
+        }
+        let mut m = Manager::new("test", ());
+        m.on("broken", |_, _| value_ok(Broken));
+        m.on("tuple_keys", |_, _| {
+            value_ok(std::iter::once(((1, 2), 3)).collect::<HashMap<(u8, u8), u8>>())
+        });
+        m.on("ok", |_, _| value_ok(1));
+        for name in ["broken", "tuple_keys"] {
+            let reply = m.handle_with_outcome(action(name, json!({}))).0;
+            assert!(reply.result.is_none());
+            assert_eq!(reply.errors[0].code, "SerializeError");
+        }
+        // still serving
+        assert_eq!(
+            m.handle_with_outcome(action("ok", json!({}))).0.result,
+            Some(json!(1))
+        );
+    }
+
+    #[test]
+    fn base64_field() {
+        let mut a = Action::new("upload", 1);
+        assert_eq!(a.decode_base64().unwrap(), None);
+        assert_eq!(a.base64_len_hint(), None);
+
+        a.set_base64_bytes(b"");
+        assert_eq!(a.base64.as_deref(), Some(""));
+        assert_eq!(a.decode_base64().unwrap(), Some(vec![]));
+        assert_eq!(a.base64_len_hint(), Some(0));
+
+        a.set_base64_bytes(&[0xfb, 0xff]);
+        assert_eq!(a.base64.as_deref(), Some("+/8="));
+        assert_eq!(a.base64_len_hint(), Some(2));
+        // padded, unpadded and url-safe all decode the same
+        for encoded in ["+/8=", "+/8", "-_8=", "-_8"] {
+            a.base64 = Some(encoded.to_owned());
+            assert_eq!(a.decode_base64().unwrap(), Some(vec![0xfb, 0xff]));
+            assert_eq!(a.base64_len_hint(), Some(2));
+        }
+        a.base64 = Some("aGVsbG8=".to_owned());
+        assert_eq!(a.decode_base64().unwrap().unwrap(), b"hello");
+        assert_eq!(a.base64_len_hint(), Some(5));
+
+        for bad in ["a*b=", "+_8=", "a", "aGk=aGk="] {
+            a.base64 = Some(bad.to_owned());
+            assert_eq!(
+                a.decode_base64().unwrap_err().code,
+                "Base64Error",
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn binary_field() {
+        // clients which only know base64
+        let old = br#"{"name":"up","id":1,"token":null,"base64":"aGk=","payload":{},"result":null,"errors":null}"#;
+        let mut a = Action::from_bytes(Bytes::from_static(old)).unwrap();
+        assert_eq!(a.binary, None);
+        assert_eq!(a.binary_or_base64().unwrap().unwrap(), b"hi");
+        let bytes = a.to_bytes().unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("binary"));
+
+        a.set_binary(vec![0, 1, 255]);
+        assert_eq!(a.binary_or_base64().unwrap().unwrap(), vec![0, 1, 255]);
+        let back = Action::from_bytes(a.to_bytes().unwrap()).unwrap();
+        assert_eq!(back.binary.as_deref(), Some(&[0u8, 1, 255][..]));
+        assert_eq!(a.take_binary(), Some(vec![0, 1, 255]));
+        assert_eq!(a.binary, None);
+    }
+
+    #[test]
+    fn outcomes_and_chaining() {
+        let mut users = Manager::new("users", ());
+        users.on("user.get", |_, _| value_ok("bob"));
+        users.on("user.fail", |_, _| Err(ActionError::new("Nope", "").into()));
+        let mut files = Manager::new("files", ());
+        files.on("file.get", |_, _| value_ok("a.txt"));
+
+        let mut a = action("user.get", json!({}));
+        assert_eq!(users.do_action(&mut a), DispatchOutcome::Handled);
+        let mut a = action("file.get", json!({}));
+        assert_eq!(users.do_action_if_exists(&mut a), None);
+        assert!(a.errors.is_none() && a.result.is_none());
+        assert_eq!(
+            files.do_action_if_exists(&mut a),
+            Some(DispatchOutcome::Handled)
+        );
+        let mut a = action("user.fail", json!({}));
+        assert_eq!(
+            users.do_action_if_exists(&mut a),
+            Some(DispatchOutcome::HandlerError)
+        );
+
+        // the first manager knowing the name answers, "not mine" is not "mine and failed"
+        let chain = |a: Action| {
+            users
+                .handle_if_exists(a)
+                .or_else(|a| files.handle_if_exists(*a))
+        };
+        let (reply, outcome) = chain(action("file.get", json!({}))).unwrap();
+        assert_eq!(
+            (reply.result, outcome),
+            (Some(json!("a.txt")), DispatchOutcome::Handled)
+        );
+        let (reply, outcome) = chain(action("user.fail", json!({}))).unwrap();
+        assert_eq!(outcome, DispatchOutcome::HandlerError);
+        assert_eq!(reply.errors[0].code, "Nope");
+        let back = chain(action("nobody", json!({}))).unwrap_err();
+        assert_eq!(back.name, "nobody");
+    }
+
+    #[test]
+    fn generated_resource_runs_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut m = Manager::new("users", 0usize);
+        let counted = runs.clone();
+        m.on("user.create", move |r, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            value_ok(*r)
+        });
+        m.for_each(|| 7);
+        let mut a = action("user.create", json!({}));
+        assert_eq!(
+            m.do_action_if_exists(&mut a),
+            Some(DispatchOutcome::Handled)
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(a.result, Some(json!(7)));
+        let mut a = action("user.create", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(a.result, Some(json!(7)));
+    }
+
+    #[test]
+    fn fallible_resource_generator() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let calls = Arc::new(AtomicUsize::new(0));
+        let made = calls.clone();
+        let mut m = Manager::try_with("db", move || match made.fetch_add(1, Ordering::SeqCst) {
+            0 => Err(ActionError::new("ConnectFailed", "database is down")),
+            n => Ok(n),
+        });
+        m.on("conn", |n, _| value_ok(*n));
+
+        let (reply, outcome) = m.handle_with_outcome(action("conn", json!({})));
+        assert_eq!(outcome, DispatchOutcome::Shed);
+        let err = &reply.errors[0];
+        assert_eq!(err.code, "ResourceError");
+        assert!(err.is_retryable());
+        assert_eq!(
+            err.details.as_ref().unwrap()["cause"]["code"],
+            json!("ConnectFailed")
+        );
+
+        let (reply, outcome) = m.handle_with_outcome(action("conn", json!({})));
+        assert_eq!(outcome, DispatchOutcome::Handled);
+        assert_eq!(reply.result, Some(json!(1)));
+    }
+
+    #[test]
+    fn lazy_resource_made_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let calls = Arc::new(AtomicUsize::new(0));
+        let made = calls.clone();
+        let mut m = Manager::lazy("pool", move || match made.fetch_add(1, Ordering::SeqCst) {
+            0 => Err(ActionError::new("ConfigMissing", "not loaded yet")),
+            n => Ok(vec![n; 3]),
+        });
+        m.on("size", |pool, _| value_ok(pool.len() + pool[0] * 10));
+        // nothing is made before the first dispatch
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let (reply, outcome) = m.handle_with_outcome(action("size", json!({})));
+        assert_eq!(outcome, DispatchOutcome::Shed);
+        assert_eq!(reply.errors[0].code, "ResourceError");
+
+        let m = Arc::new(m);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let m = m.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        let mut a = action("size", json!({}));
+                        m.do_action(&mut a);
+                        assert_eq!(a.result, Some(json!(13)));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn lazy_resource_for_mut_handlers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let calls = Arc::new(AtomicUsize::new(0));
+        let made = calls.clone();
+        let mut m = Manager::lazy("test", move || {
+            made.fetch_add(1, Ordering::SeqCst);
+            Ok(Counter { hits: 0 })
+        });
+        m.on_mut("hit", |c, _| {
+            c.hits += 1;
+            Ok(json!(c.hits))
+        });
+        m.on("hits", |c, _| value_ok(c.hits));
+        for n in 1..=3 {
+            let mut a = action("hit", json!({}));
+            m.do_action_mut(&mut a);
+            assert_eq!(a.result, Some(json!(n)));
+        }
+        let mut a = action("hits", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(3)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn init_reports_errors() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let min_rows = 3;
+        let mut m = Manager::new("db", vec![1, 2]);
+        let err = m
+            .init(|rows| {
+                if rows.len() < min_rows {
+                    return Err(ActionError::new("MigrationPending", "too few rows"));
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(err.code, "MigrationPending");
+        let mut seen = Vec::new();
+        m.init(|rows| {
+            seen.extend_from_slice(rows);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, vec![1, 2]);
+
+        let mut failing = Manager::try_with("db", || {
+            Err::<u8, _>(ActionError::new("Down", "database is down"))
+        });
+        let err = failing.init(|_| Ok(())).unwrap_err();
+        assert_eq!(err.code, "ResourceError");
+
+        let made = Arc::new(AtomicUsize::new(0));
+        let counter = made.clone();
+        let mut m = Manager::with("db", move || counter.fetch_add(1, Ordering::SeqCst));
+        m.on("n", |n, _| value_ok(*n));
+        m.init(|_| Ok(())).unwrap();
+        assert!(m
+            .init_retaining(|_| Err(ActionError::new("Nope", "")))
+            .is_err());
+        m.init_retaining(|n| {
+            assert_eq!(*n, 2);
+            Ok(())
+        })
+        .unwrap();
+        for _ in 0..3 {
+            let mut a = action("n", json!({}));
+            m.do_action(&mut a);
+            assert_eq!(a.result, Some(json!(2)));
+        }
+        assert_eq!(made.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn init_mut_fills_the_resource() {
+        #[derive(Default)]
+        struct Schema {
+            tables: HashMap<String, Vec<String>>,
+        }
+        let mut m = Manager::new("db", Schema::default());
+        m.on("columns", |s, a| {
+            let table = a.payload_str("table")?;
+            value_ok(&s.tables[table])
+        });
+        m.init_mut(|s| {
+            s.tables
+                .insert("users".to_owned(), vec!["id".to_owned(), "name".to_owned()]);
+            Ok(())
+        })
+        .unwrap();
+        let mut a = action("columns", json!({"table": "users"}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(["id", "name"])));
+
+        // a generated resource is filled in and kept
+        let mut m = Manager::with("db", Schema::default);
+        m.on("count", |s, _| value_ok(s.tables.len()));
+        m.init_mut(|s| {
+            s.tables.insert("users".to_owned(), Vec::new());
+            Ok(())
+        })
+        .unwrap();
+        let mut a = action("count", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(1)));
+
+        let mut pooled = Manager::pooled("db", 1, || Ok(Schema::default()));
+        let err = pooled.init_mut(|_| Ok(())).unwrap_err();
+        assert_eq!(err.code, "InitNotApplicable");
     }
 }
@@ -0,0 +1,222 @@
+use std::collections::HashSet;
+
+use crate::action::Manager;
+use crate::error::ActionError;
+
+/// who sent an action, as told by the token validator of the manager; handlers registered
+/// with `Manager::on_ctx` read it from `ActionCtx::auth`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthContext {
+    pub user_id: String,
+    pub roles: Vec<String>,
+}
+
+impl AuthContext {
+    pub fn new(user_id: &str, roles: &[&str]) -> Self {
+        AuthContext {
+            user_id: user_id.to_owned(),
+            roles: roles.iter().map(|r| (*r).to_owned()).collect(),
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// see `Manager::set_token_validator`
+pub type TokenValidator = dyn Fn(&Option<String>) -> Result<AuthContext, ActionError> + Send + Sync;
+
+/// the token validator of a manager and the actions it lets through without one
+#[derive(Default)]
+pub(crate) struct Authenticator {
+    validator: Option<Box<TokenValidator>>,
+    anonymous: HashSet<String>,
+}
+
+impl Authenticator {
+    /// `None` for public actions and managers without a validator
+    pub(crate) fn check(
+        &self,
+        name: &str,
+        token: &Option<String>,
+    ) -> Result<Option<AuthContext>, ActionError> {
+        match &self.validator {
+            Some(validate) if !self.anonymous.contains(name) => validate(token).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// the error for an action without a token, for validators to return
+pub fn unauthorized(message: &str) -> ActionError {
+    ActionError::new("Unauthorized", message)
+}
+
+impl<R> Manager<R> {
+    /// checks the token of every action before its handler runs, `__describe` and the
+    /// `on_unknown` fallback included; an error is stored on the action instead of
+    /// running it. Sub-actions dispatched through the context keep
+    /// the `AuthContext` of the action dispatching them
+    pub fn set_token_validator<F>(&mut self, f: F)
+    where
+        F: Fn(&Option<String>) -> Result<AuthContext, ActionError> + Send + Sync + 'static,
+    {
+        self.authenticator_mut().validator = Some(Box::new(f));
+    }
+
+    /// lets the action `name` run without going through the token validator, like a login
+    pub fn allow_anonymous(&mut self, name: &str) {
+        self.authenticator_mut().anonymous.insert(name.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action};
+    use crate::outcome::DispatchOutcome;
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.set_token_validator(|token| match token.as_deref() {
+            None => Err(unauthorized("a token is required")),
+            Some("admin-token") => Ok(AuthContext::new("1", &["admin"])),
+            Some("billing-token") => Ok(AuthContext::new("2", &["billing"])),
+            Some(_) => Err(unauthorized("the token is not valid")),
+        });
+        m.allow_anonymous("login");
+        m.on("login", |_r, _a| value_ok(json!("welcome")));
+        m.on_ctx("whoami", |_r, _a, ctx| {
+            let auth = ctx.auth().expect("validated");
+            value_ok(json!({ "user": auth.user_id, "admin": auth.has_role("admin") }))
+        });
+        m.on_ctx("via", |_r, _a, ctx| {
+            // the sub-action carries no token, it runs as the caller
+            let reply = ctx.dispatch(Action::new("whoami", 2))?;
+            Ok(reply.result.unwrap_or_default())
+        });
+        m
+    }
+
+    fn with_token(name: &str, token: Option<&str>) -> Action {
+        let mut a = Action::new(name, 1);
+        a.token = token.map(|t| t.to_owned());
+        a
+    }
+
+    #[test]
+    fn missing_token() {
+        let mut a = with_token("whoami", None);
+        assert_eq!(manager().do_action(&mut a), DispatchOutcome::Rejected);
+        assert_eq!(a.errors.unwrap()[0].code, "Unauthorized");
+        assert!(a.result.is_none());
+    }
+
+    #[test]
+    fn bad_token() {
+        let mut a = with_token("whoami", Some("guessed"));
+        assert_eq!(manager().do_action(&mut a), DispatchOutcome::Rejected);
+        assert_eq!(a.errors.unwrap()[0].message, "the token is not valid");
+    }
+
+    #[test]
+    fn valid_token() {
+        let m = manager();
+        let mut a = with_token("whoami", Some("admin-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!({ "user": "1", "admin": true })));
+
+        let mut a = with_token("via", Some("admin-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!({ "user": "1", "admin": true })));
+    }
+
+    #[test]
+    fn public_actions() {
+        let mut a = with_token("login", None);
+        assert_eq!(manager().do_action(&mut a), DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!("welcome")));
+    }
+
+    #[test]
+    fn builtins_and_fallback() {
+        let mut m = manager();
+        m.on_unknown(|_r, a| Ok(json!({ "forwarded": a.name })));
+        for name in &["__describe", "admin.wipe"] {
+            let mut a = with_token(name, None);
+            assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+            assert!(a.result.is_none());
+            assert_eq!(a.errors.unwrap()[0].code, "Unauthorized");
+        }
+        let mut a = with_token("admin.wipe", Some("admin-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!({ "forwarded": "admin.wipe" })));
+        m.allow_anonymous("__describe");
+        let mut a = with_token("__describe", None);
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn mut_handlers() {
+        let mut m = manager();
+        m.on_mut("bump", |_r, _a| Ok(json!(true)));
+        let mut a = with_token("bump", None);
+        m.do_action_mut(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "Unauthorized");
+        let mut a = with_token("bump", Some("admin-token"));
+        m.do_action_mut(&mut a);
+        assert_eq!(a.result, Some(json!(true)));
+    }
+
+    fn with_roles() -> Manager<()> {
+        let mut m = manager();
+        m.on_with_roles("refund", &["admin", "billing"], |_r, _a| {
+            value_ok(json!("refunded"))
+        });
+        m.on_with_roles("purge", &["admin"], |_r, _a| value_ok(json!("purged")));
+        m.on_with_roles("login", &["admin"], |_r, _a| value_ok(json!("ignored")));
+        m
+    }
+
+    #[test]
+    fn allowed_roles() {
+        let m = with_roles();
+        let mut a = with_token("refund", Some("billing-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        let mut a = with_token("purge", Some("admin-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        // no requirements, any valid token
+        let mut a = with_token("whoami", Some("billing-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn denied_roles() {
+        let m = with_roles();
+        let mut a = with_token("purge", Some("billing-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+        let err = &a.errors.unwrap()[0];
+        assert_eq!(err.code, "Forbidden");
+        assert_eq!(err.message, "purge requires the role admin");
+        assert_eq!(err.details.as_ref().unwrap()["required"], json!(["admin"]));
+
+        // without a validator nobody holds a role
+        let mut m = Manager::new("test", ());
+        m.on_with_roles("purge", &["admin"], |_r, _a| value_ok(json!("purged")));
+        let mut a = with_token("purge", Some("admin-token"));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+    }
+
+    #[test]
+    fn roles_are_described() {
+        let m = with_roles();
+        // login was taken already, its handler and lack of roles stay
+        assert_eq!(m.roles_of("login"), None);
+        assert_eq!(
+            m.describe()["roles"],
+            json!({ "refund": ["admin", "billing"], "purge": ["admin"] })
+        );
+        assert!(manager().describe().get("roles").is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use std::cell::Cell;
+
+use crate::error::ActionError;
+
+/// units of work a handler may spend on one action, handed out by
+/// `ActionCtx::budget`; handlers that never consume are unaffected
+pub struct Budget {
+    limit: Option<u64>,
+    used: Cell<u64>,
+}
+
+impl Budget {
+    pub(crate) fn new(limit: Option<u64>) -> Self {
+        Budget {
+            limit,
+            used: Cell::new(0),
+        }
+    }
+
+    /// counts `units` as spent, failing with `BudgetExceeded` once that goes over the limit
+    /// set with `Manager::budget`
+    pub fn consume(&self, units: u64) -> Result<(), ActionError> {
+        let used = self.used.get().saturating_add(units);
+        self.used.set(used);
+        match self.limit {
+            Some(limit) if used > limit => Err(ActionError::new(
+                "BudgetExceeded",
+                &format!("the action used {} of its {} units", used, limit),
+            )
+            .with_details(json!({ "limit": limit, "used": used }))),
+            _ => Ok(()),
+        }
+    }
+
+    /// units left, `u64::MAX` for actions without a budget
+    pub fn remaining(&self) -> u64 {
+        match self.limit {
+            Some(limit) => limit.saturating_sub(self.used.get()),
+            None => u64::MAX,
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.get()
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.limit.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager};
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        // examines rows until the budget runs out
+        m.on_ctx("scan", |_, _, ctx| {
+            let mut rows = 0;
+            loop {
+                ctx.budget().consume(10)?;
+                rows += 1;
+                if rows == 100 {
+                    return value_ok(rows);
+                }
+            }
+        });
+        m.on_ctx("cheap", |_, _, ctx| {
+            ctx.budget().consume(5)?;
+            value_ok(ctx.budget().remaining())
+        });
+        m.budget("scan", 250);
+        m.budget("cheap", 100);
+        m
+    }
+
+    #[test]
+    fn consume_until_exceeded() {
+        let m = manager();
+        let mut a = action("scan");
+        m.do_action(&mut a);
+        let reply = m.reply(a);
+        assert_eq!(reply.errors[0].code, "BudgetExceeded");
+        assert_eq!(
+            reply.errors[0].details.as_ref().unwrap()["limit"],
+            json!(250)
+        );
+        assert_eq!(reply.budget_used, Some(260));
+
+        let mut a = action("cheap");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(95)));
+        let reply = serde_json::to_value(m.reply(a)).unwrap();
+        assert_eq!(reply["budget_used"], json!(5));
+    }
+
+    #[test]
+    fn unbudgeted_is_unaffected() {
+        let b = Budget::new(None);
+        b.consume(u64::MAX).unwrap();
+        assert_eq!(b.remaining(), u64::MAX);
+
+        let mut m = Manager::new("test", ());
+        m.on_ctx("free", |_, _, ctx| {
+            ctx.budget().consume(1_000_000)?;
+            value_ok(true)
+        });
+        let mut a = action("free");
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+        let reply = serde_json::to_value(m.reply(a)).unwrap();
+        assert!(reply.get("budget_used").is_none());
+    }
+}
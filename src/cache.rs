@@ -0,0 +1,290 @@
+//! results of idempotent actions kept for a while, see `Manager::cache`
+
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+use crate::idempotency::Idempotency;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// entries are dropped once their ttl passed, the next caller runs the handler
+    Fresh,
+    /// for `stale_ttl` past the ttl an entry is still served, marked stale, while it is
+    /// refreshed. The sync manager has nowhere to refresh in the background, so the call
+    /// after the first stale hit runs the handler; refreshes of one entry never overlap
+    /// and a failed one keeps serving the stale result
+    StaleWhileRevalidate { stale_ttl: Duration },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachePolicy {
+    ttl: Duration,
+    mode: CacheMode,
+}
+
+/// token, action name and payload, with the payload in a stable order
+type Key = (Option<String>, String, String);
+
+struct Entry {
+    result: Value,
+    stored: Instant,
+    /// a stale hit was served, the next lookup refreshes
+    pending: bool,
+    /// a lookup is refreshing the entry right now
+    refreshing: bool,
+}
+
+/// what a lookup found
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Lookup {
+    Miss,
+    Fresh(Value),
+    /// serve it, marked stale
+    Stale(Value),
+    /// run the handler to refresh, the stale result is the fallback when it fails
+    Refresh(Value),
+}
+
+#[derive(Default)]
+pub(crate) struct ResponseCache {
+    policies: HashMap<String, CachePolicy>,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+pub(crate) fn cache_key(action: &Action) -> Key {
+    let payload: BTreeMap<_, _> = action.payload.iter().collect();
+    (
+        action.token.clone(),
+        action.name.clone(),
+        serde_json::to_string(&payload).unwrap_or_default(),
+    )
+}
+
+impl ResponseCache {
+    pub(crate) fn caches(&self, name: &str) -> bool {
+        self.policies.contains_key(name)
+    }
+
+    pub(crate) fn lookup(&self, key: &Key, now: Instant) -> Lookup {
+        let policy = match self.policies.get(&key.1) {
+            Some(p) => *p,
+            None => return Lookup::Miss,
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = match entries.get_mut(key) {
+            Some(e) => e,
+            None => return Lookup::Miss,
+        };
+        let age = now.saturating_duration_since(entry.stored);
+        if age < policy.ttl {
+            return Lookup::Fresh(entry.result.clone());
+        }
+        match policy.mode {
+            CacheMode::StaleWhileRevalidate { stale_ttl } if age < policy.ttl + stale_ttl => {
+                if entry.refreshing {
+                    Lookup::Stale(entry.result.clone())
+                } else if entry.pending {
+                    entry.refreshing = true;
+                    Lookup::Refresh(entry.result.clone())
+                } else {
+                    entry.pending = true;
+                    Lookup::Stale(entry.result.clone())
+                }
+            }
+            _ => {
+                entries.remove(key);
+                Lookup::Miss
+            }
+        }
+    }
+
+    pub(crate) fn store(&self, key: Key, result: Value, now: Instant) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                key,
+                Entry {
+                    result,
+                    stored: now,
+                    pending: false,
+                    refreshing: false,
+                },
+            );
+    }
+
+    /// the refresh failed, the entry stays as it was and the next lookup tries again
+    pub(crate) fn refresh_failed(&self, key: &Key) {
+        if let Some(e) = self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(key)
+        {
+            e.refreshing = false;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+impl<R> Manager<R> {
+    /// keeps successful results of `name` for `ttl`, per token and payload, and answers
+    /// repeats from them with `DispatchOutcome::Cached`. Only actions classified as safe
+    /// to run again can be cached, others fail with `NotCacheable`
+    pub fn cache(&mut self, name: &str, ttl: Duration, mode: CacheMode) -> Result<(), ActionError> {
+        let idempotency = self.idempotency(name);
+        if idempotency == Idempotency::NonIdempotent {
+            return Err(ActionError::new(
+                "NotCacheable",
+                &format!("{} is not classified as idempotent", name),
+            )
+            .with_details(json!({ "action": name, "idempotency": idempotency })));
+        }
+        self.response_cache_mut()
+            .policies
+            .insert(name.to_owned(), CachePolicy { ttl, mode });
+        Ok(())
+    }
+
+    /// how many results are cached
+    pub fn cached_count(&self) -> usize {
+        self.response_cache().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+    use crate::outcome::DispatchOutcome;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const TTL: Duration = Duration::from_millis(100);
+    const STALE: Duration = Duration::from_secs(60);
+
+    fn action(name: &str) -> Action {
+        let mut a = Action::new(name, 0);
+        a.payload.insert("q".to_owned(), json!("x"));
+        a
+    }
+
+    /// counts its runs, failing while `failing` is set
+    fn manager(runs: Arc<AtomicU64>, failing: Arc<AtomicBool>) -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("report", move |_, _| {
+            let n = runs.fetch_add(1, Ordering::SeqCst) + 1;
+            if failing.load(Ordering::SeqCst) {
+                return Err(ActionError::new("Unavailable", "backend down").into());
+            }
+            value_ok(n)
+        });
+        m.on("charge", |_, _| value_ok(true));
+        m.classify("report", Idempotency::Idempotent);
+        m.cache(
+            "report",
+            TTL,
+            CacheMode::StaleWhileRevalidate { stale_ttl: STALE },
+        )
+        .unwrap();
+        m
+    }
+
+    fn run(m: &Manager<()>) -> (Value, bool, DispatchOutcome) {
+        let (reply, outcome) = m.handle_with_outcome(action("report"));
+        (reply.result.unwrap(), reply.stale, outcome)
+    }
+
+    #[test]
+    fn non_idempotent_rejected_at_configuration() {
+        let mut m = manager(Arc::default(), Arc::default());
+        let err = m.cache("charge", TTL, CacheMode::Fresh).unwrap_err();
+        assert_eq!(err.code, "NotCacheable");
+        m.classify("charge", Idempotency::IdempotentWithKey);
+        assert!(m.cache("charge", TTL, CacheMode::Fresh).is_ok());
+    }
+
+    #[test]
+    fn serves_stale_then_refreshes_on_next_call() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let m = manager(runs.clone(), Arc::default());
+        assert_eq!(run(&m), (json!(1), false, DispatchOutcome::Handled));
+        assert_eq!(run(&m), (json!(1), false, DispatchOutcome::Cached));
+        thread::sleep(TTL * 2);
+
+        // the first caller past the ttl does not wait for the handler
+        assert_eq!(run(&m), (json!(1), true, DispatchOutcome::Cached));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // the next one refreshes
+        assert_eq!(run(&m), (json!(2), false, DispatchOutcome::Handled));
+        assert_eq!(run(&m), (json!(2), false, DispatchOutcome::Cached));
+        assert_eq!(m.cached_count(), 1);
+
+        let mut other = action("report");
+        other.payload.insert("q".to_owned(), json!("y"));
+        let (reply, _) = m.handle_with_outcome(other);
+        assert_eq!(reply.result, Some(json!(3)));
+    }
+
+    #[test]
+    fn failed_refresh_keeps_the_stale_entry() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let failing = Arc::new(AtomicBool::new(false));
+        let m = manager(runs.clone(), failing.clone());
+        run(&m);
+        thread::sleep(TTL * 2);
+        failing.store(true, Ordering::SeqCst);
+
+        assert_eq!(run(&m), (json!(1), true, DispatchOutcome::Cached));
+        // the refresh fails, the caller still gets the stale result
+        assert_eq!(run(&m), (json!(1), true, DispatchOutcome::Cached));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        // and the one after tries again
+        failing.store(false, Ordering::SeqCst);
+        assert_eq!(run(&m), (json!(3), false, DispatchOutcome::Handled));
+    }
+
+    #[test]
+    fn refreshes_are_single_flight() {
+        let mut cache = ResponseCache::default();
+        cache.policies.insert(
+            "report".to_owned(),
+            CachePolicy {
+                ttl: Duration::ZERO,
+                mode: CacheMode::StaleWhileRevalidate { stale_ttl: STALE },
+            },
+        );
+        let key = cache_key(&action("report"));
+        let now = Instant::now();
+        cache.store(key.clone(), json!(1), now);
+        assert_eq!(cache.lookup(&key, now), Lookup::Stale(json!(1)));
+
+        let cache = Arc::new(cache);
+        let refreshes: usize = (0..16)
+            .map(|_| {
+                let (cache, key) = (cache.clone(), key.clone());
+                thread::spawn(move || matches!(cache.lookup(&key, now), Lookup::Refresh(_)))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap() as usize)
+            .sum();
+        assert_eq!(refreshes, 1);
+
+        cache.refresh_failed(&key);
+        assert_eq!(cache.lookup(&key, now), Lookup::Refresh(json!(1)));
+        cache.store(key.clone(), json!(2), now + Duration::from_secs(1));
+        assert_eq!(
+            cache.lookup(&key, now + Duration::from_secs(1)),
+            Lookup::Stale(json!(2))
+        );
+    }
+}
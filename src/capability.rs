@@ -0,0 +1,269 @@
+//! scoped, expiring grants carried in the token field of an action, for handing out
+//! access to a few actions without an account (`crypto` feature)
+//!
+//! A capability token is `cap1.<claims>.<tag>`, both parts base64url: the claims are the
+//! JSON of a `Capability` and the tag is their HMAC-SHA256 under the server key.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+use crate::validate::glob_match;
+
+/// prefix telling capability tokens apart from ordinary ones, carries the format version
+pub const CAPABILITY_PREFIX: &str = "cap1.";
+
+/// payload key holding the tenant an action is for, checked against `Capability::tenant`
+pub const TENANT_KEY: &str = "tenant";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Capability {
+    /// action names (globs, see `validate::glob_match`) the holder may call
+    pub actions: Vec<String>,
+    /// when set, the action payload must name this tenant under `TENANT_KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// unix time in seconds
+    pub expires_at: i64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extra: HashMap<String, Value>,
+}
+
+impl Capability {
+    pub fn allows(&self, action: &str) -> bool {
+        self.actions.iter().any(|p| glob_match(p, action))
+    }
+
+    /// whether the action may run under this capability, failing with
+    /// `CapabilityDenied` or `TenantMismatch`
+    pub fn check(&self, action: &Action) -> Result<(), ActionError> {
+        if !self.allows(&action.name) {
+            return Err(ActionError::new(
+                "CapabilityDenied",
+                &format!("the capability does not grant {}", action.name),
+            ));
+        }
+        if let Some(tenant) = &self.tenant {
+            if action.payload.get(TENANT_KEY).and_then(Value::as_str) != Some(tenant) {
+                return Err(ActionError::new(
+                    "TenantMismatch",
+                    &format!("the capability is limited to tenant {}", tenant),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mac(key: &[u8], claims: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(claims.as_bytes());
+    mac
+}
+
+fn malformed(message: &str) -> ActionError {
+    ActionError::new("CapabilityMalformed", message)
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// signs `cap` into a token
+pub fn issue(cap: &Capability, key: &[u8]) -> String {
+    let claims = URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(cap).expect("a capability always serializes to json"));
+    let tag = URL_SAFE_NO_PAD.encode(mac(key, &claims).finalize().into_bytes());
+    format!("{}{}.{}", CAPABILITY_PREFIX, claims, tag)
+}
+
+/// the capability in `token` as of now, see `verify_at`
+pub fn verify(token: &str, key: &[u8]) -> Result<Capability, ActionError> {
+    verify_at(token, key, now_secs())
+}
+
+/// checks the signature and expiry of `token` at unix time `now`: `CapabilityMalformed`
+/// when it is not a capability token, `CapabilityInvalid` when it was not signed with
+/// `key` or was changed, `CapabilityExpired` once `expires_at` passed
+pub fn verify_at(token: &str, key: &[u8], now: i64) -> Result<Capability, ActionError> {
+    let rest = token
+        .strip_prefix(CAPABILITY_PREFIX)
+        .ok_or_else(|| malformed("not a capability token"))?;
+    let (claims, tag) = rest
+        .split_once('.')
+        .ok_or_else(|| malformed("the capability token has no signature"))?;
+    let tag = URL_SAFE_NO_PAD
+        .decode(tag)
+        .map_err(|_| malformed("the capability signature is not base64url"))?;
+    mac(key, claims).verify_slice(&tag).map_err(|_| {
+        ActionError::new(
+            "CapabilityInvalid",
+            "the capability token was not issued by this server",
+        )
+    })?;
+    let claims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .map_err(|_| malformed("the capability claims are not base64url"))?;
+    let cap: Capability = serde_json::from_slice(&claims)
+        .map_err(|e| malformed(&format!("the capability claims are invalid: {}", e)))?;
+    if now >= cap.expires_at {
+        return Err(
+            ActionError::new("CapabilityExpired", "the capability expired")
+                .with_details(json!({ "expired_at": cap.expires_at })),
+        );
+    }
+    Ok(cap)
+}
+
+/// checks actions whose token is a capability, other tokens pass untouched; `true` when
+/// a capability let the action through
+pub(crate) fn check_action(action: &Action, key: &[u8]) -> Result<bool, ActionError> {
+    match action.token.as_deref() {
+        Some(t) if t.starts_with(CAPABILITY_PREFIX) => {
+            verify(t, key)?.check(action)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+impl<R> Manager<R> {
+    /// accepts capability tokens signed with `key`: an action carrying one only runs when
+    /// the capability is valid, grants the action name and matches its tenant. Such
+    /// actions skip the token validator, they run without an `AuthContext`
+    pub fn accept_capabilities(&mut self, key: &[u8]) {
+        *self.capability_key_mut() = Some(key.to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+
+    const KEY: &[u8] = b"capability test key";
+
+    fn cap(expires_at: i64) -> Capability {
+        Capability {
+            actions: vec!["report.*".to_owned()],
+            tenant: Some("42".to_owned()),
+            expires_at,
+            extra: HashMap::new(),
+        }
+    }
+
+    fn action(name: &str, token: &str, tenant: &str) -> Action {
+        let mut a = Action::new(name, 0);
+        a.token = Some(token.to_owned());
+        a.payload.insert(TENANT_KEY.to_owned(), json!(tenant));
+        a
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("report.get", |_, _| value_ok("report"));
+        m.on("user.delete", |_, _| value_ok("deleted"));
+        m.accept_capabilities(KEY);
+        m
+    }
+
+    fn code(m: &Manager<()>, mut a: Action) -> Option<String> {
+        m.do_action(&mut a);
+        a.errors.map(|e| e[0].code.clone())
+    }
+
+    #[test]
+    fn round_trip_and_expiry() {
+        let token = issue(&cap(1_000), KEY);
+        assert!(token.starts_with(CAPABILITY_PREFIX));
+        assert_eq!(verify_at(&token, KEY, 999).unwrap(), cap(1_000));
+        let err = verify_at(&token, KEY, 1_000).unwrap_err();
+        assert_eq!(err.code, "CapabilityExpired");
+    }
+
+    #[test]
+    fn tampered_and_malformed() {
+        let token = issue(&cap(i64::MAX), KEY);
+        assert_eq!(
+            verify(&token, b"other key").unwrap_err().code,
+            "CapabilityInvalid"
+        );
+        // widen the grant without re-signing
+        let mut wide = cap(i64::MAX);
+        wide.actions = vec!["*".to_owned()];
+        let forged = issue(&wide, b"attacker key");
+        let (_, forged_claims) = forged.split_at(CAPABILITY_PREFIX.len());
+        let tag = token.rsplit('.').next().unwrap();
+        let claims = forged_claims.split('.').next().unwrap();
+        let tampered = format!("{}{}.{}", CAPABILITY_PREFIX, claims, tag);
+        assert_eq!(
+            verify(&tampered, KEY).unwrap_err().code,
+            "CapabilityInvalid"
+        );
+
+        for bad in ["cap1.", "cap1.abc", "cap1.abc.!!", "session-token"] {
+            assert_eq!(verify(bad, KEY).unwrap_err().code, "CapabilityMalformed");
+        }
+    }
+
+    #[test]
+    fn enforced_on_dispatch() {
+        let m = manager();
+        let token = issue(&cap(i64::MAX), KEY);
+        assert_eq!(code(&m, action("report.get", &token, "42")), None);
+        assert_eq!(
+            code(&m, action("user.delete", &token, "42")).as_deref(),
+            Some("CapabilityDenied")
+        );
+        assert_eq!(
+            code(&m, action("report.get", &token, "7")).as_deref(),
+            Some("TenantMismatch")
+        );
+        let expired = issue(&cap(1), KEY);
+        assert_eq!(
+            code(&m, action("report.get", &expired, "42")).as_deref(),
+            Some("CapabilityExpired")
+        );
+        // ordinary tokens are left to the rest of the manager
+        assert_eq!(code(&m, action("user.delete", "session", "7")), None);
+    }
+
+    #[test]
+    fn skips_the_token_validator() {
+        let mut m = manager();
+        m.set_token_validator(|token| match token.as_deref() {
+            Some("session") => Ok(crate::auth::AuthContext::new("1", &[])),
+            _ => Err(crate::auth::unauthorized("the token is not valid")),
+        });
+        let token = issue(&cap(i64::MAX), KEY);
+        assert_eq!(code(&m, action("report.get", &token, "42")), None);
+        assert_eq!(
+            code(&m, action("user.delete", &token, "42")).as_deref(),
+            Some("CapabilityDenied")
+        );
+        assert_eq!(code(&m, action("user.delete", "session", "7")), None);
+        assert_eq!(
+            code(&m, action("user.delete", "guessed", "7")).as_deref(),
+            Some("Unauthorized")
+        );
+    }
+
+    #[test]
+    fn glob_grants() {
+        let mut c = cap(0);
+        c.actions = vec!["report.get".to_owned(), "export.?sv".to_owned()];
+        assert!(c.allows("report.get"));
+        assert!(!c.allows("report.list"));
+        assert!(c.allows("export.csv"));
+        assert!(c.allows("export.tsv"));
+        assert!(!c.allows("export.json"));
+    }
+}
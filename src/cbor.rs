@@ -0,0 +1,137 @@
+//! CBOR encoding of actions and replies (`cbor` feature)
+//!
+//! CBOR maps may have keys of any type while JSON objects only have strings, so decoding
+//! rejects messages with a non-string key anywhere instead of letting it turn into
+//! something else.
+
+use ciborium::Value;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::action::{Action, ActionReply};
+use crate::error::ActionError;
+
+fn cbor_error(e: impl std::fmt::Display) -> ActionError {
+    ActionError::new("CborError", &e.to_string())
+}
+
+fn encode<T: Serialize>(v: &T) -> Result<Vec<u8>, ActionError> {
+    let mut buf = Vec::new();
+    ciborium::into_writer(v, &mut buf).map_err(cbor_error)?;
+    Ok(buf)
+}
+
+/// the path of the first map below `v` with a key which is not a string
+fn non_string_key(v: &Value, path: &str) -> Option<String> {
+    match v {
+        Value::Map(entries) => entries.iter().find_map(|(k, v)| match k {
+            Value::Text(k) => non_string_key(v, &format!("{}/{}", path, k)),
+            _ => Some(if path.is_empty() { "/" } else { path }.to_owned()),
+        }),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(i, v)| non_string_key(v, &format!("{}/{}", path, i))),
+        Value::Tag(_, v) => non_string_key(v, path),
+        _ => None,
+    }
+}
+
+fn decode<T: DeserializeOwned>(buf: &[u8]) -> Result<T, ActionError> {
+    let value: Value = ciborium::from_reader(buf).map_err(cbor_error)?;
+    if let Some(path) = non_string_key(&value, "") {
+        return Err(ActionError::new(
+            "CborError",
+            &format!("map at {} has a key which is not a string", path),
+        )
+        .with_details(json!({ "path": path })));
+    }
+    value.deserialized().map_err(cbor_error)
+}
+
+impl Action {
+    /// parses a CBOR encoded action, failing with `CborError`
+    pub fn from_cbor(buf: &[u8]) -> Result<Self, ActionError> {
+        decode(buf)
+    }
+
+    pub fn to_cbor(&self) -> Result<Vec<u8>, ActionError> {
+        encode(self)
+    }
+}
+
+impl ActionReply {
+    pub fn from_cbor(buf: &[u8]) -> Result<Self, ActionError> {
+        decode(buf)
+    }
+
+    pub fn to_cbor(&self) -> Result<Vec<u8>, ActionError> {
+        encode(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> serde_json::Value {
+        json!({
+            "device": {"id": u64::MAX, "temp": -12.5, "ok": true},
+            "readings": [[1, 2, 3], {"at": null}],
+            "name": "sensör",
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut a = Action::new("device.report", 3);
+        a.payload = serde_json::from_value(payload()).unwrap();
+        a.set_binary(vec![9, 8, 7]);
+        let back = Action::from_cbor(&a.to_cbor().unwrap()).unwrap();
+        assert_eq!(back.name, "device.report");
+        assert_eq!(json!(back.payload), payload());
+        assert_eq!(back.binary, Some(vec![9, 8, 7]));
+
+        // the same action through JSON ends up the same
+        let via_json = Action::from_bytes(a.to_bytes().unwrap()).unwrap();
+        assert_eq!(json!(via_json.payload), json!(back.payload));
+
+        let mut a = Action::new("device.report", 3);
+        a.set_result(payload());
+        let reply = ActionReply::from_cbor(&a.into_reply().to_cbor().unwrap()).unwrap();
+        assert_eq!(reply.result, Some(payload()));
+    }
+
+    #[test]
+    fn non_string_keys_rejected() {
+        let inner = Value::Map(vec![(Value::Integer(1.into()), Value::Text("x".into()))]);
+        let message = Value::Map(vec![
+            (Value::Text("name".into()), Value::Text("a".into())),
+            (Value::Text("id".into()), Value::Integer(1.into())),
+            (
+                Value::Text("payload".into()),
+                Value::Map(vec![(
+                    Value::Text("list".into()),
+                    Value::Array(vec![Value::Null, inner]),
+                )]),
+            ),
+        ]);
+        let mut buf = Vec::new();
+        ciborium::into_writer(&message, &mut buf).unwrap();
+        let err = Action::from_cbor(&buf).unwrap_err();
+        assert_eq!(err.code, "CborError");
+        assert_eq!(err.details.unwrap()["path"], json!("/payload/list/1"));
+    }
+
+    #[test]
+    fn corrupted_input() {
+        let good = Action::new("a", 1).to_cbor().unwrap();
+        for buf in [&good[..good.len() - 1], &[0xff][..], &[][..]] {
+            assert_eq!(Action::from_cbor(buf).unwrap_err().code, "CborError");
+        }
+        // valid CBOR, not an action
+        let mut buf = Vec::new();
+        ciborium::into_writer(&[1, 2], &mut buf).unwrap();
+        assert_eq!(Action::from_cbor(&buf).unwrap_err().code, "CborError");
+    }
+}
@@ -0,0 +1,141 @@
+//! run loops dispatching the actions of a channel and sending the replies on another
+//!
+//! A loop ends once every sender of actions is dropped and the channel is drained, or
+//! once the receiver of replies is dropped. Dropping the senders is how a loop is
+//! stopped gracefully: actions already queued are still dispatched and answered.
+
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::action::{Action, ActionReply, Manager};
+
+impl<R> Manager<R> {
+    /// dispatches each action from `rx` and sends its reply, made by `reply`, on `tx`;
+    /// returns how many replies were sent
+    pub fn run(&self, rx: Receiver<Action>, tx: Sender<ActionReply>) -> usize {
+        let mut sent = 0;
+        for mut action in rx {
+            self.do_action(&mut action);
+            if tx.send(self.reply(action)).is_err() {
+                event!(
+                    debug,
+                    manager = self.manager_name();
+                    "Manager [{}] reply channel closed, stopping", self.manager_name()
+                );
+                break;
+            }
+            sent += 1;
+        }
+        sent
+    }
+}
+
+#[cfg(any(test, feature = "duplex"))]
+impl<R> crate::action::ManagerFut<R> {
+    /// `Manager::run` on tokio channels, each action is awaited before the next one is
+    /// taken; replies are made with `Action::into_reply`. Needs the `duplex` feature,
+    /// which brings in tokio
+    pub async fn run(
+        &self,
+        mut rx: tokio::sync::mpsc::Receiver<Action>,
+        tx: tokio::sync::mpsc::Sender<ActionReply>,
+    ) -> usize {
+        let mut sent = 0;
+        while let Some(mut action) = rx.recv().await {
+            self.do_action(&mut action).await;
+            if tx.send(action.into_reply()).await.is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, ManagerFut};
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("double", |_r, a| {
+            let n: u64 = a.from_payload_field("n")?;
+            value_ok(json!(n * 2))
+        });
+        m
+    }
+
+    fn double(id: u64) -> Action {
+        let mut a = Action::new("double", id);
+        a.set_payload_field("n", id).unwrap();
+        a
+    }
+
+    #[test]
+    fn replies_to_every_action() {
+        let (actions, rx) = mpsc::channel();
+        let (tx, replies) = mpsc::channel();
+        let worker = thread::spawn(move || manager().run(rx, tx));
+        for id in 1..=20 {
+            actions.send(double(id)).unwrap();
+        }
+        actions.send(Action::new("missing", 21)).unwrap();
+        // dropping the sender stops the loop once the queue is drained
+        drop(actions);
+        assert_eq!(worker.join().unwrap(), 21);
+
+        let replies: Vec<ActionReply> = replies.iter().collect();
+        assert_eq!(replies.len(), 21);
+        for (reply, id) in replies.iter().zip(1..) {
+            assert_eq!(reply.id, id);
+        }
+        assert_eq!(replies[4].result, Some(json!(10)));
+        assert!(!replies[20].errors.is_empty());
+    }
+
+    #[test]
+    fn stops_when_replies_are_not_read() {
+        let (actions, rx) = mpsc::channel();
+        let (tx, replies) = mpsc::channel();
+        drop(replies);
+        actions.send(double(1)).unwrap();
+        actions.send(double(2)).unwrap();
+        // the sender is still alive, the closed reply channel ends the loop
+        assert_eq!(manager().run(rx, tx), 0);
+        drop(actions);
+    }
+
+    #[test]
+    fn async_run() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut m = ManagerFut::new("async", ());
+            m.on("echo", |_r, a| {
+                let id = a.id;
+                async move { Ok(json!(id)) }
+            });
+            let (actions, rx) = tokio::sync::mpsc::channel(4);
+            let (tx, mut replies) = tokio::sync::mpsc::channel::<ActionReply>(4);
+            let feed = async move {
+                for id in 1..=10 {
+                    actions.send(Action::new("echo", id)).await.unwrap();
+                }
+            };
+            let collect = async move {
+                let mut ids = Vec::new();
+                while let Some(reply) = replies.recv().await {
+                    assert_eq!(reply.result, Some(json!(reply.id)));
+                    ids.push(reply.id);
+                }
+                ids
+            };
+            let (sent, (), ids) = tokio::join!(m.run(rx, tx), feed, collect);
+            assert_eq!(sent, 10);
+            assert_eq!(ids, (1..=10).collect::<Vec<u64>>());
+        });
+    }
+}
@@ -0,0 +1,128 @@
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(test, feature = "test-util"))]
+use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// where a manager takes the time from, replaceable so tests need not sleep, see
+/// `Manager::set_clock`
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// unix time in milliseconds
+    fn unix_ms(&self) -> u64 {
+        unix_ms()
+    }
+}
+
+/// the time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// the current unix time in milliseconds, 0 before the epoch
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// a clock which only moves on `advance`, for tests; both `now` and `unix_ms` move
+#[cfg(any(test, feature = "test-util"))]
+pub struct FakeClock {
+    start: Instant,
+    unix_start: u64,
+    offset: Mutex<Duration>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl FakeClock {
+    /// stopped at the current time
+    pub fn new() -> Arc<Self> {
+        FakeClock::at(unix_ms())
+    }
+
+    /// stopped at the unix time `unix_ms`, in milliseconds
+    pub fn at(unix_ms: u64) -> Arc<Self> {
+        Arc::new(FakeClock {
+            start: Instant::now(),
+            unix_start: unix_ms,
+            offset: Mutex::new(Duration::ZERO),
+        })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        self.unix_start + self.offset.lock().unwrap().as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager};
+    use crate::outcome::DispatchOutcome;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn manager() -> (Manager<Arc<AtomicUsize>>, Arc<FakeClock>, Arc<AtomicUsize>) {
+        let clock = FakeClock::at(1_000_000);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut m = Manager::new("test", runs.clone());
+        m.on("typing", |runs, _| {
+            runs.fetch_add(1, Ordering::SeqCst);
+            value_ok(json!(true))
+        });
+        m.set_clock(clock.clone());
+        (m, clock, runs)
+    }
+
+    #[test]
+    fn expired_actions_are_skipped() {
+        let (m, clock, runs) = manager();
+        let mut a = Action::new("typing", 1);
+        a.expires_at = Some(1_000_500);
+        assert_eq!(m.do_action(&mut a.clone()), DispatchOutcome::Handled);
+
+        clock.advance(Duration::from_millis(700));
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Shed);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let err = &a.errors.unwrap()[0];
+        assert_eq!(err.code, "Expired");
+        assert_eq!(err.message, "typing expired 200ms ago");
+
+        // no expiry, no check
+        let mut a = Action::new("typing", 2);
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn ttl_and_serialization() {
+        let a = Action::new("typing", 1).with_ttl(Duration::from_secs(5));
+        let at = a.expires_at.unwrap();
+        assert!(at >= unix_ms() + 4_000);
+        assert!(!a.is_expired_at(at - 1));
+        assert!(a.is_expired_at(at));
+        let v = serde_json::to_value(&a).unwrap();
+        assert_eq!(v["expires_at"], json!(at));
+
+        // left out when unset, and older senders leave it out
+        let v = serde_json::to_value(Action::new("typing", 1)).unwrap();
+        assert!(v.get("expires_at").is_none());
+        let a: Action = serde_json::from_value(v).unwrap();
+        assert_eq!(a.expires_at, None);
+    }
+}
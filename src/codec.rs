@@ -0,0 +1,216 @@
+//! a tokio-util codec for `Framed` transports (`tokio-codec` feature)
+//!
+//! Frames are newline delimited JSON, servers decode actions and encode replies, clients
+//! encode actions. A frame longer than the maximum fails with `FrameTooLarge` and the
+//! rest of it is skipped up to the next newline.
+
+use tokio_util::bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::action::{Action, ActionReply};
+use crate::error::ActionError;
+
+#[derive(Debug, Clone, Default)]
+pub struct ActionCodec {
+    max_frame_length: Option<usize>,
+    /// how far the buffer was searched for a newline already
+    next_index: usize,
+    /// the frame being read went over the maximum and is being thrown away
+    discarding: bool,
+}
+
+impl ActionCodec {
+    /// a codec without a limit on the frame length
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// frames longer than `max` bytes, the newline not counted, fail with `FrameTooLarge`
+    pub fn with_max_frame_length(mut self, max: usize) -> Self {
+        self.max_frame_length = Some(max);
+        self
+    }
+
+    pub fn max_frame_length(&self) -> Option<usize> {
+        self.max_frame_length
+    }
+
+    fn too_large(&self) -> ActionError {
+        let max = self.max_frame_length.unwrap_or_default();
+        ActionError::new(
+            "FrameTooLarge",
+            &format!("frame is longer than {} bytes", max),
+        )
+        .with_details(json!({ "max_frame_length": max }))
+    }
+}
+
+fn parse(frame: &[u8]) -> Result<Action, ActionError> {
+    let frame = frame.strip_suffix(b"\r").unwrap_or(frame);
+    Action::from_slice(frame)
+}
+
+impl Decoder for ActionCodec {
+    type Item = Action;
+    type Error = ActionError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Action>, ActionError> {
+        loop {
+            let newline = buf[self.next_index..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|i| self.next_index + i);
+            match (newline, self.discarding) {
+                (Some(at), true) => {
+                    let _ = buf.split_to(at + 1);
+                    self.next_index = 0;
+                    self.discarding = false;
+                }
+                (None, true) => {
+                    buf.clear();
+                    self.next_index = 0;
+                    return Ok(None);
+                }
+                (Some(at), false) => {
+                    self.next_index = 0;
+                    if self.max_frame_length.is_some_and(|max| at > max) {
+                        let _ = buf.split_to(at + 1);
+                        return Err(self.too_large());
+                    }
+                    let frame = buf.split_to(at + 1);
+                    let frame = &frame[..at];
+                    if frame.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    return parse(frame).map(Some);
+                }
+                (None, false) => {
+                    if self.max_frame_length.is_some_and(|max| buf.len() > max) {
+                        buf.clear();
+                        self.next_index = 0;
+                        self.discarding = true;
+                        return Err(self.too_large());
+                    }
+                    self.next_index = buf.len();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// a last frame without a newline is still decoded
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Action>, ActionError> {
+        if let Some(action) = self.decode(buf)? {
+            return Ok(Some(action));
+        }
+        self.next_index = 0;
+        if self.discarding || buf.iter().all(u8::is_ascii_whitespace) {
+            buf.clear();
+            self.discarding = false;
+            return Ok(None);
+        }
+        let frame = buf.split();
+        parse(&frame).map(Some)
+    }
+}
+
+fn write_line(bytes: &[u8], buf: &mut BytesMut) {
+    buf.reserve(bytes.len() + 1);
+    buf.put_slice(bytes);
+    buf.put_u8(b'\n');
+}
+
+impl Encoder<ActionReply> for ActionCodec {
+    type Error = ActionError;
+
+    fn encode(&mut self, reply: ActionReply, buf: &mut BytesMut) -> Result<(), ActionError> {
+        write_line(&reply.into_bytes()?, buf);
+        Ok(())
+    }
+}
+
+impl Encoder<Action> for ActionCodec {
+    type Error = ActionError;
+
+    fn encode(&mut self, action: Action, buf: &mut BytesMut) -> Result<(), ActionError> {
+        write_line(&action.into_bytes()?, buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(n: u64) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        let mut a = Action::new("count", n);
+        a.payload.insert("text".to_owned(), json!("a\nb"));
+        ActionCodec::new().encode(a, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn reassembles_split_frames() {
+        let mut stream: Vec<u8> = (1..=3).flat_map(encoded).collect();
+        stream.extend_from_slice(b"\r\n");
+        let mut codec = ActionCodec::new();
+        for chunk_size in [1, 2, 7, 64, stream.len()] {
+            let mut buf = BytesMut::new();
+            let mut ids = Vec::new();
+            for chunk in stream.chunks(chunk_size) {
+                buf.extend_from_slice(chunk);
+                while let Some(a) = codec.decode(&mut buf).unwrap() {
+                    ids.push(a.id);
+                }
+            }
+            assert_eq!(ids, vec![1, 2, 3], "chunks of {}", chunk_size);
+            assert_eq!(codec.decode_eof(&mut buf).unwrap().map(|a| a.id), None);
+        }
+
+        // the last frame without its newline
+        let mut buf = BytesMut::from(&encoded(4)[..encoded(4).len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap().id, 4);
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let small = encoded(1);
+        let mut codec = ActionCodec::new().with_max_frame_length(small.len());
+        let mut big = Action::new("count", 2);
+        big.payload
+            .insert("text".to_owned(), json!("x".repeat(small.len() * 4)));
+        let big = big.to_bytes().unwrap();
+
+        // arrives in pieces, the first one already over the limit
+        let mut buf = BytesMut::new();
+        let (head, tail) = big.split_at(small.len() + 10);
+        buf.extend_from_slice(head);
+        assert_eq!(codec.decode(&mut buf).unwrap_err().code, "FrameTooLarge");
+        assert!(buf.is_empty());
+        buf.extend_from_slice(tail);
+        buf.extend_from_slice(b"\n");
+        buf.extend_from_slice(&small);
+        // the rest of the big frame is skipped, the next one decodes
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().id, 1);
+
+        // arrives whole
+        let mut buf = BytesMut::from(&big[..]);
+        buf.extend_from_slice(b"\n");
+        buf.extend_from_slice(&small);
+        assert_eq!(codec.decode(&mut buf).unwrap_err().code, "FrameTooLarge");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().id, 1);
+    }
+
+    #[test]
+    fn encodes_replies() {
+        let mut a = Action::new("count", 5);
+        a.set_result(json!(5));
+        let mut buf = BytesMut::new();
+        ActionCodec::new().encode(a.into_reply(), &mut buf).unwrap();
+        assert_eq!(buf.last(), Some(&b'\n'));
+        let reply: ActionReply = serde_json::from_slice(&buf[..buf.len() - 1]).unwrap();
+        assert_eq!((reply.id, reply.result), (5, Some(json!(5))));
+    }
+}
@@ -0,0 +1,205 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::validate::glob_match;
+
+/// a stored payload the current type no longer accepts, or a field old clients need that
+/// the current type no longer produces
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompatIssue {
+    /// index of the sample, `None` for forward issues
+    pub sample: Option<usize>,
+    /// where it went wrong, dotted, `.` being the top level
+    pub path: String,
+    pub message: String,
+}
+
+/// tries every historical payload against the current `T`
+pub fn check_backward<T: DeserializeOwned>(old_samples: &[Value]) -> Vec<CompatIssue> {
+    old_samples
+        .iter()
+        .enumerate()
+        .filter_map(|(i, sample)| {
+            serde_path_to_error::deserialize::<_, T>(sample)
+                .err()
+                .map(|e| CompatIssue {
+                    sample: Some(i),
+                    path: e.path().to_string(),
+                    message: e.into_inner().to_string(),
+                })
+        })
+        .collect()
+}
+
+/// checks that `T::default()` still serializes every field in `required_by_clients`,
+/// nested fields written with dots
+pub fn check_forward<T: Serialize + Default>(required_by_clients: &[&str]) -> Vec<CompatIssue> {
+    let current = match serde_json::to_value(T::default()) {
+        Ok(v) => v,
+        Err(e) => {
+            return vec![CompatIssue {
+                sample: None,
+                path: ".".to_owned(),
+                message: e.to_string(),
+            }]
+        }
+    };
+    required_by_clients
+        .iter()
+        .filter(|path| {
+            path.split('.')
+                .try_fold(&current, |v, key| v.get(key))
+                .is_none()
+        })
+        .map(|path| CompatIssue {
+            sample: None,
+            path: (*path).to_owned(),
+            message: format!("{} is no longer serialized", path),
+        })
+        .collect()
+}
+
+/// reads the samples in the files matching `pattern`, a directory followed by a file name
+/// glob such as `tests/fixtures/user_create/*.json`; a file holds one payload or an array
+/// of them, files are read in name order
+pub fn load_samples<P: AsRef<Path>>(pattern: P) -> io::Result<Vec<Value>> {
+    let pattern = pattern.as_ref();
+    let dir = match pattern.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let glob = pattern.file_name().and_then(|f| f.to_str()).unwrap_or("*");
+    let mut files: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|f| f.to_str())
+                .is_some_and(|f| glob_match(glob, f))
+        })
+        .collect();
+    files.sort();
+    let mut samples = Vec::new();
+    for file in files {
+        let v: Value = serde_json::from_slice(&fs::read(&file)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match v {
+            Value::Array(items) => samples.extend(items),
+            v => samples.push(v),
+        }
+    }
+    Ok(samples)
+}
+
+/// panics listing every historical sample matching the glob that `T` can no longer read
+///
+/// ```ignore
+/// #[test]
+/// fn user_create_is_compatible() {
+///     assert_payload_compat!(UserCreate, "tests/fixtures/user_create/*.json");
+/// }
+/// ```
+#[macro_export]
+macro_rules! assert_payload_compat {
+    ($t:ty, $pattern:expr) => {{
+        let samples = $crate::compat::load_samples($pattern)
+            .unwrap_or_else(|e| panic!("could not load samples from {}: {}", $pattern, e));
+        let issues = $crate::compat::check_backward::<$t>(&samples);
+        if !issues.is_empty() {
+            panic!(
+                "{} can not read {} of the {} samples in {}: {:?}",
+                stringify!($t),
+                issues.len(),
+                samples.len(),
+                $pattern,
+                issues
+            );
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Address {
+        city: String,
+    }
+
+    /// the current shape, old clients send `nickname` which is no longer read and no
+    /// `age` which was added with a default
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct UserCreate {
+        name: String,
+        #[serde(default)]
+        age: u32,
+        address: Address,
+    }
+
+    #[derive(Serialize, Default)]
+    struct UserReply {
+        id: u64,
+        profile: Profile,
+    }
+
+    #[derive(Serialize, Default)]
+    struct Profile {
+        display_name: String,
+    }
+
+    #[test]
+    fn backward() {
+        let samples = vec![
+            json!({"name": "bob", "nickname": "b", "address": {"city": "Oslo"}}),
+            json!({"address": {"city": "Oslo"}}),
+            json!({"name": "bob", "address": {"city": 7}}),
+        ];
+        let issues = check_backward::<UserCreate>(&samples);
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].sample, Some(1));
+        assert!(issues[0].message.contains("missing field `name`"));
+        assert_eq!(issues[1].sample, Some(2));
+        assert_eq!(issues[1].path, "address.city");
+    }
+
+    #[test]
+    fn forward() {
+        assert!(check_forward::<UserReply>(&["id", "profile.display_name"]).is_empty());
+        let issues = check_forward::<UserReply>(&["id", "email", "profile.avatar"]);
+        let paths: Vec<&str> = issues.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, vec!["email", "profile.avatar"]);
+    }
+
+    #[test]
+    fn macro_over_fixture_files() {
+        let dir = std::env::temp_dir().join(format!("json_action_compat_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.json"),
+            r#"{"name": "bob", "address": {"city": "Oslo"}}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.json"),
+            r#"[{"name": "amy", "age": 3, "address": {"city": "Rome"}}]"#,
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "not json").unwrap();
+        let pattern = dir.join("*.json");
+        let pattern = pattern.to_str().unwrap();
+        assert_eq!(load_samples(pattern).unwrap().len(), 2);
+        assert_payload_compat!(UserCreate, pattern);
+
+        fs::write(dir.join("c.json"), r#"{"name": "old"}"#).unwrap();
+        let broken = std::panic::catch_unwind(|| assert_payload_compat!(UserCreate, pattern));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(broken.is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! zstd dictionary compression for small, repetitive actions (`zstd-dict` feature)
+//!
+//! A compressed frame is a one byte format version, the 4 byte big endian id of the
+//! dictionary and the zstd data, so a peer holding another dictionary gets a
+//! `DictMismatch` error instead of garbage. Peers agree on a dictionary through the
+//! `dict` field of the handshake, see `Manager::enable_handshake_with_dicts`.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use std::io::Read;
+use std::path::Path;
+
+use crate::action::Action;
+use crate::error::ActionError;
+
+const FRAME_VERSION: u8 = 1;
+const HEADER_LEN: usize = 5;
+const LEVEL: i32 = 3;
+
+fn compression_error(message: &str) -> ActionError {
+    ActionError::new("CompressionError", message)
+}
+
+/// a trained zstd dictionary along with the id frames compressed with it carry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionDict {
+    id: u32,
+    bytes: Vec<u8>,
+}
+
+/// fnv-1a, stable across builds and platforms
+fn dict_id(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |h: u32, b| {
+        (h ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+impl CompressionDict {
+    /// trains a dictionary of at most `max_size` bytes on `samples`, which should look
+    /// like the encoded actions it will compress; fails when there are too few samples
+    pub fn train(samples: &[Bytes], max_size: usize) -> Result<CompressionDict, ActionError> {
+        let samples: Vec<&[u8]> = samples.iter().map(|s| &s[..]).collect();
+        let bytes = zstd::dict::from_samples(&samples, max_size)
+            .map_err(|e| compression_error(&format!("could not train a dictionary: {}", e)))?;
+        Ok(CompressionDict::from_bytes(bytes))
+    }
+
+    /// a dictionary trained elsewhere, e.g. by the zstd command line tool
+    pub fn from_bytes(bytes: Vec<u8>) -> CompressionDict {
+        CompressionDict {
+            id: dict_id(&bytes),
+            bytes,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// writes the dictionary as a plain zstd dictionary file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ActionError> {
+        Ok(std::fs::write(path, &self.bytes)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<CompressionDict, ActionError> {
+        Ok(CompressionDict::from_bytes(std::fs::read(path)?))
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Bytes, ActionError> {
+        let compressed = zstd::bulk::Compressor::with_dictionary(LEVEL, &self.bytes)
+            .and_then(|mut c| c.compress(data))
+            .map_err(|e| compression_error(&e.to_string()))?;
+        let mut frame = vec![0; HEADER_LEN];
+        frame[0] = FRAME_VERSION;
+        BigEndian::write_u32(&mut frame[1..HEADER_LEN], self.id);
+        frame.extend(compressed);
+        Ok(Bytes::from(frame))
+    }
+
+    /// fails with `DictMismatch` for frames compressed with another dictionary
+    pub fn decompress(&self, frame: &[u8]) -> Result<Vec<u8>, ActionError> {
+        if frame.len() < HEADER_LEN || frame[0] != FRAME_VERSION {
+            return Err(compression_error("not a dictionary compressed frame"));
+        }
+        let id = BigEndian::read_u32(&frame[1..HEADER_LEN]);
+        if id != self.id {
+            return Err(ActionError::new(
+                "DictMismatch",
+                &format!(
+                    "frame was compressed with dictionary {}, this side has {}",
+                    id, self.id
+                ),
+            )
+            .with_details(json!({ "frame": id, "local": self.id })));
+        }
+        let mut out = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(&frame[HEADER_LEN..], &self.bytes)
+            .and_then(|mut d| d.read_to_end(&mut out))
+            .map_err(|e| compression_error(&e.to_string()))?;
+        Ok(out)
+    }
+}
+
+impl Action {
+    /// the JSON encoding of the action compressed with `dict`
+    pub fn to_bytes_dict(&self, dict: &CompressionDict) -> Result<Bytes, ActionError> {
+        dict.compress(&self.to_bytes()?)
+    }
+
+    pub fn from_bytes_dict(buf: Bytes, dict: &CompressionDict) -> Result<Action, ActionError> {
+        Ok(serde_json::from_slice(&dict.decompress(&buf)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const STATUSES: &[&str] = &["pending", "shipped", "delivered", "cancelled"];
+
+    fn order(n: u64) -> Action {
+        let mut a = Action::new("orders.update_status", n);
+        a.token = Some(format!("session-{:08}", n * 7919 % 100_000));
+        a.payload = serde_json::from_value(json!({
+            "order_id": format!("ord_{:06}", n * 31 % 1_000_000),
+            "status": STATUSES[n as usize % STATUSES.len()],
+            "customer": {"id": n % 977, "tier": "standard", "region": "eu-west"},
+            "notify": {"email": true, "sms": false, "push": n.is_multiple_of(2)},
+        }))
+        .unwrap();
+        a
+    }
+
+    fn samples() -> Vec<Bytes> {
+        (0..2000)
+            .map(|n| Bytes::from(serde_json::to_vec(&order(n)).unwrap()))
+            .collect()
+    }
+
+    fn gzip(data: &[u8]) -> usize {
+        let mut e = GzEncoder::new(Vec::new(), Compression::best());
+        e.write_all(data).unwrap();
+        e.finish().unwrap().len()
+    }
+
+    #[test]
+    fn beats_gzip_on_small_actions() {
+        let dict = CompressionDict::train(&samples(), 4096).unwrap();
+        let a = order(123_457);
+        let plain = serde_json::to_vec(&a).unwrap();
+        assert!(plain.len() > 250 && plain.len() < 400, "{}", plain.len());
+
+        let frame = a.to_bytes_dict(&dict).unwrap();
+        assert!(
+            frame.len() < gzip(&plain),
+            "dict {} gzip {}",
+            frame.len(),
+            gzip(&plain)
+        );
+        let back = Action::from_bytes_dict(frame, &dict).unwrap();
+        assert_eq!(
+            serde_json::to_value(&back).unwrap(),
+            serde_json::to_value(&a).unwrap()
+        );
+    }
+
+    #[test]
+    fn mismatch_is_detected() {
+        let dict = CompressionDict::train(&samples(), 4096).unwrap();
+        let other = CompressionDict::train(&samples()[..1000], 2048).unwrap();
+        assert_ne!(dict.id(), other.id());
+        let frame = order(1).to_bytes_dict(&dict).unwrap();
+        let err = Action::from_bytes_dict(frame, &other).unwrap_err();
+        assert_eq!(err.code, "DictMismatch");
+        let err = Action::from_bytes_dict(Bytes::from_static(b"{}"), &dict).unwrap_err();
+        assert_eq!(err.code, "CompressionError");
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dict = CompressionDict::train(&samples(), 4096).unwrap();
+        let path = std::env::temp_dir().join(format!("json_action_dict_{}", std::process::id()));
+        dict.save(&path).unwrap();
+        let loaded = CompressionDict::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, dict);
+        let frame = order(5).to_bytes_dict(&dict).unwrap();
+        assert!(Action::from_bytes_dict(frame, &loaded).is_ok());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::action::Manager;
+use crate::error::ActionError;
+
+/// what dispatches past a concurrency limit do, see `Manager::max_concurrent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectPolicy {
+    /// wait for a running dispatch to finish
+    #[default]
+    Block,
+    /// fail right away with a retryable `Overloaded`
+    Reject,
+}
+
+/// a counting semaphore over the dispatches running at once
+struct Slots {
+    max: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// gives its slot back once dropped, also when the dispatch unwinds
+pub(crate) struct Permit<'a> {
+    slots: &'a Slots,
+}
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Slots {
+            max,
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn acquire(&self, policy: RejectPolicy, limited: &str) -> Result<Permit<'_>, ActionError> {
+        let mut running = self.lock();
+        while *running >= self.max {
+            if policy == RejectPolicy::Reject {
+                return Err(ActionError::new(
+                    "Overloaded",
+                    &format!("{} already runs {} actions at once", limited, self.max),
+                )
+                .with_details(json!({ "limit": self.max, "limited": limited }))
+                .retryable());
+            }
+            running = self.freed.wait(running).unwrap_or_else(|e| e.into_inner());
+        }
+        *running += 1;
+        Ok(Permit { slots: self })
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.slots.lock() -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+/// the concurrency limits of a manager
+#[derive(Default)]
+pub(crate) struct Concurrency {
+    all: Option<Slots>,
+    per_action: HashMap<String, Slots>,
+    policy: RejectPolicy,
+}
+
+impl Concurrency {
+    /// slots under the limits of the action `name`, the one of the action taken first
+    /// so that a dispatch waiting on it does not hold a slot of the whole manager
+    pub(crate) fn enter(&self, name: &str) -> Result<Vec<Permit<'_>>, ActionError> {
+        let mut permits = Vec::new();
+        if let Some(slots) = self.per_action.get(name) {
+            permits.push(slots.acquire(self.policy, name)?);
+        }
+        if let Some(slots) = &self.all {
+            permits.push(slots.acquire(self.policy, "the manager")?);
+        }
+        Ok(permits)
+    }
+}
+
+impl<R> Manager<R> {
+    /// at most `n` dispatches run at once, the ones past it block or are rejected as set
+    /// by `reject_policy`
+    pub fn max_concurrent(&mut self, n: usize) {
+        self.concurrency_mut().all = Some(Slots::new(n));
+    }
+
+    /// at most `n` dispatches of the action `name` run at once, on top of
+    /// `max_concurrent`
+    pub fn max_concurrent_for(&mut self, name: &str, n: usize) {
+        self.concurrency_mut()
+            .per_action
+            .insert(name.to_owned(), Slots::new(n));
+    }
+
+    /// `RejectPolicy::Block` unless set
+    pub fn reject_policy(&mut self, policy: RejectPolicy) {
+        self.concurrency_mut().policy = policy;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action};
+    use crate::outcome::DispatchOutcome;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    /// a manager whose `wait` actions run until a value comes on the returned sender
+    fn gated() -> (
+        Manager<Mutex<mpsc::Receiver<()>>>,
+        mpsc::Sender<()>,
+        mpsc::Receiver<()>,
+    ) {
+        let (release, gate) = mpsc::channel();
+        let (started_tx, started) = mpsc::channel();
+        let started_tx = Mutex::new(started_tx);
+        let mut m = Manager::new("test", Mutex::new(gate));
+        m.on("wait", move |gate, _a| {
+            let _ = started_tx.lock().unwrap().send(());
+            gate.lock().unwrap().recv().unwrap();
+            value_ok(json!(true))
+        });
+        m.on("other", |_r, _a| value_ok(json!(true)));
+        (m, release, started)
+    }
+
+    fn spawn_wait(
+        m: &Arc<Manager<Mutex<mpsc::Receiver<()>>>>,
+        id: u64,
+    ) -> thread::JoinHandle<(Action, DispatchOutcome)> {
+        let m = Arc::clone(m);
+        thread::spawn(move || {
+            let mut a = Action::new("wait", id);
+            let outcome = m.do_action(&mut a);
+            (a, outcome)
+        })
+    }
+
+    #[test]
+    fn rejects_past_the_limit() {
+        let (mut m, release, started) = gated();
+        m.max_concurrent(2);
+        m.reject_policy(RejectPolicy::Reject);
+        let m = Arc::new(m);
+        let first = spawn_wait(&m, 1);
+        let second = spawn_wait(&m, 2);
+        started.recv().unwrap();
+        started.recv().unwrap();
+
+        let mut a = Action::new("other", 3);
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Shed);
+        let err = &a.errors.unwrap()[0];
+        assert_eq!(err.code, "Overloaded");
+        assert!(err.is_retryable());
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        assert_eq!(first.join().unwrap().1, DispatchOutcome::Handled);
+        assert_eq!(second.join().unwrap().1, DispatchOutcome::Handled);
+        // the slots are free again
+        let mut a = Action::new("other", 4);
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn blocks_until_a_slot_frees() {
+        let (mut m, release, started) = gated();
+        m.max_concurrent_for("wait", 1);
+        let m = Arc::new(m);
+        let first = spawn_wait(&m, 1);
+        started.recv().unwrap();
+        let second = spawn_wait(&m, 2);
+        // the second one waits for the slot of the first
+        assert!(started.recv_timeout(Duration::from_millis(50)).is_err());
+        // other actions are not limited
+        let mut a = Action::new("other", 3);
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+
+        release.send(()).unwrap();
+        assert_eq!(first.join().unwrap().1, DispatchOutcome::Handled);
+        started.recv().unwrap();
+        release.send(()).unwrap();
+        let (a, outcome) = second.join().unwrap();
+        assert_eq!(outcome, DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!(true)));
+    }
+}
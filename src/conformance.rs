@@ -0,0 +1,369 @@
+//! scripted scenarios checking that a transport speaks the wire protocol the way this
+//! crate does (`test-util` feature)
+//!
+//! The scenarios live in `conformance/scenarios.json` so implementations in other
+//! languages can run them too, the file also describes the server they expect.
+
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::action::{value_ok, Action, Manager, ParseOptions};
+use crate::error::ActionError;
+use crate::protocol::{parse_frame, protocol_error, ProtocolFeatures, ProtocolStage};
+
+/// the scenario file, see `spec`
+pub const SCENARIOS_JSON: &str = include_str!("../conformance/scenarios.json");
+
+/// how long `run_conformance` waits for a reply
+pub const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// what `run_conformance` drives, one connection to the server under test
+pub trait FrameTransport {
+    fn send(&mut self, frame: &[u8]) -> Result<(), ActionError>;
+    /// the next frame, `None` when nothing arrived within `timeout`
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, ActionError>;
+    /// starts over on a fresh connection, called before every scenario
+    fn reconnect(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Frame {
+    /// sent as serialized JSON
+    Json(Value),
+    /// sent as is
+    Raw(String),
+    /// `action` with a `"pad"` payload entry making the frame at least `bytes` long
+    Padded { action: Value, bytes: usize },
+}
+
+impl Frame {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Frame::Json(v) => v.to_string().into_bytes(),
+            Frame::Raw(s) => s.clone().into_bytes(),
+            Frame::Padded { action, bytes } => {
+                let mut action = action.clone();
+                action["payload"]["pad"] = Value::String("x".repeat(*bytes));
+                action.to_string().into_bytes()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub name: String,
+    pub description: String,
+    pub frames: Vec<Frame>,
+    /// one value per reply frame, matched as described in the scenario file
+    pub expect: Vec<Value>,
+    /// whether replies must arrive in the order of `expect`
+    #[serde(default)]
+    pub ordered: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConformanceSpec {
+    pub version: u32,
+    /// the frame limit the server under test enforces
+    pub max_frame_bytes: usize,
+    pub scenarios: Vec<Scenario>,
+}
+
+/// the scenarios shipped with the crate
+pub fn spec() -> ConformanceSpec {
+    serde_json::from_str(SCENARIOS_JSON).expect("the shipped scenario file is valid")
+}
+
+/// the server the scenarios expect, see the scenario file
+pub fn conformance_manager() -> Manager<()> {
+    let mut m = Manager::new("conformance", ());
+    m.on("echo", |_, a| value_ok(&a.payload));
+    m.on("fail", |_, _| {
+        Err(ActionError::new("ConformanceFailure", "requested failure").into())
+    });
+    m.enable_handshake(ProtocolFeatures::BATCH);
+    m
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioResult {
+    pub name: String,
+    pub passed: bool,
+    /// what did not match, one line each
+    pub diffs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConformanceReport {
+    pub results: Vec<ScenarioResult>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed).count()
+    }
+
+    pub fn failed(&self) -> Vec<&ScenarioResult> {
+        self.results.iter().filter(|r| !r.passed).collect()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.results.iter().all(|r| r.passed)
+    }
+}
+
+/// `run_conformance_with` the shipped scenarios and `DEFAULT_RECV_TIMEOUT`
+pub fn run_conformance(transport: &mut dyn FrameTransport) -> ConformanceReport {
+    run_conformance_with(transport, &spec().scenarios, DEFAULT_RECV_TIMEOUT)
+}
+
+/// runs every scenario on a fresh connection; replies are collected until the expected
+/// number arrived and then once more for `timeout`, so extra replies are caught
+pub fn run_conformance_with(
+    transport: &mut dyn FrameTransport,
+    scenarios: &[Scenario],
+    timeout: Duration,
+) -> ConformanceReport {
+    let results = scenarios
+        .iter()
+        .map(|s| {
+            let diffs = run_scenario(transport, s, timeout)
+                .unwrap_or_else(|e| vec![format!("transport failed: {}", e.message)]);
+            ScenarioResult {
+                name: s.name.clone(),
+                passed: diffs.is_empty(),
+                diffs,
+            }
+        })
+        .collect();
+    ConformanceReport { results }
+}
+
+fn run_scenario(
+    transport: &mut dyn FrameTransport,
+    scenario: &Scenario,
+    timeout: Duration,
+) -> Result<Vec<String>, ActionError> {
+    transport.reconnect()?;
+    for frame in &scenario.frames {
+        transport.send(&frame.to_bytes())?;
+    }
+    let mut replies = Vec::new();
+    while let Some(frame) = transport.recv(timeout)? {
+        replies.push(
+            serde_json::from_slice::<Value>(&frame)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&frame).into_owned())),
+        );
+        if replies.len() > scenario.expect.len() {
+            break;
+        }
+    }
+    Ok(compare(scenario, replies))
+}
+
+fn compare(scenario: &Scenario, mut replies: Vec<Value>) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for (i, expected) in scenario.expect.iter().enumerate() {
+        let found = if scenario.ordered {
+            (!replies.is_empty() && mismatches(expected, &replies[0], "$").is_empty()).then_some(0)
+        } else {
+            replies
+                .iter()
+                .position(|r| mismatches(expected, r, "$").is_empty())
+        };
+        match found {
+            Some(n) => {
+                replies.remove(n);
+            }
+            None => {
+                // closest guess: the reply with the same id, else the next one
+                let closest = if scenario.ordered {
+                    replies.first()
+                } else {
+                    replies
+                        .iter()
+                        .find(|r| r.get("id").is_some() && r.get("id") == expected.get("id"))
+                        .or_else(|| replies.first())
+                };
+                match closest {
+                    Some(r) => diffs.extend(
+                        mismatches(expected, r, "$")
+                            .into_iter()
+                            .map(|d| format!("reply {}: {}", i, d)),
+                    ),
+                    None => diffs.push(format!("reply {}: missing, expected {}", i, expected)),
+                }
+                if scenario.ordered && !replies.is_empty() {
+                    replies.remove(0);
+                }
+            }
+        }
+    }
+    if diffs.is_empty() {
+        diffs.extend(replies.iter().map(|r| format!("unexpected reply {}", r)));
+    }
+    diffs
+}
+
+/// where `actual` does not match `expected`, see the scenario file for the rules
+pub fn mismatches(expected: &Value, actual: &Value, path: &str) -> Vec<String> {
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => e
+            .iter()
+            .flat_map(|(k, v)| {
+                let path = format!("{}.{}", path, k);
+                match a.get(k) {
+                    Some(got) => mismatches(v, got, &path),
+                    None => vec![format!("{}: expected {}, missing", path, v)],
+                }
+            })
+            .collect(),
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => e
+            .iter()
+            .zip(a)
+            .enumerate()
+            .flat_map(|(i, (e, a))| mismatches(e, a, &format!("{}[{}]", path, i)))
+            .collect(),
+        (Value::Array(e), Value::Array(a)) => vec![format!(
+            "{}: expected {} elements, got {}",
+            path,
+            e.len(),
+            a.len()
+        )],
+        (e, a) if e == a => Vec::new(),
+        (e, a) => vec![format!("{}: expected {}, got {}", path, e, a)],
+    }
+}
+
+/// the crate's own frame handling over an in-memory queue, the reference the scenarios
+/// were written against
+pub struct LocalTransport<'a, R> {
+    manager: &'a Manager<R>,
+    max_frame_bytes: usize,
+    opts: ParseOptions,
+    out: VecDeque<Vec<u8>>,
+}
+
+impl<'a, R> LocalTransport<'a, R> {
+    pub fn new(manager: &'a Manager<R>, max_frame_bytes: usize) -> Self {
+        LocalTransport {
+            manager,
+            max_frame_bytes,
+            opts: ParseOptions::default(),
+            out: VecDeque::new(),
+        }
+    }
+
+    fn batch(&self, frame: &[u8]) -> Vec<u8> {
+        match serde_json::from_slice::<Vec<Action>>(frame) {
+            Ok(actions) => to_vec(&self.manager.do_batch(actions)),
+            Err(e) => to_vec(&protocol_error(ProtocolStage::Parse, e.into())),
+        }
+    }
+}
+
+fn to_vec<T: serde::Serialize>(v: &T) -> Vec<u8> {
+    serde_json::to_vec(v).expect("replies always serialize")
+}
+
+impl<R> FrameTransport for LocalTransport<'_, R> {
+    fn send(&mut self, frame: &[u8]) -> Result<(), ActionError> {
+        if frame.len() <= self.max_frame_bytes && frame.first() == Some(&b'[') {
+            let reply = self.batch(frame);
+            self.out.push_back(reply);
+            return Ok(());
+        }
+        let buf = bytes::Bytes::from(frame.to_vec());
+        match parse_frame(buf, Some(self.max_frame_bytes), &self.opts) {
+            Ok(mut action) if action.id == 0 => {
+                self.manager.do_action(&mut action);
+            }
+            Ok(action) => {
+                let (reply, _) = self.manager.handle_with_outcome(action);
+                self.out.push_back(to_vec(&reply));
+            }
+            Err(reply) => self.out.push_back(to_vec(&reply)),
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>, ActionError> {
+        Ok(self.out.pop_front())
+    }
+
+    fn reconnect(&mut self) -> Result<(), ActionError> {
+        self.out.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_transport_passes_everything() {
+        let m = conformance_manager();
+        let spec = spec();
+        let mut t = LocalTransport::new(&m, spec.max_frame_bytes);
+        let report = run_conformance(&mut t);
+        assert_eq!(report.results.len(), spec.scenarios.len());
+        assert!(report.all_passed(), "{:#?}", report.failed());
+    }
+
+    /// answers every frame with a fixed reply, to see failures reported
+    struct Broken(VecDeque<Vec<u8>>);
+
+    impl FrameTransport for Broken {
+        fn send(&mut self, _: &[u8]) -> Result<(), ActionError> {
+            self.0
+                .push_back(br#"{"id":1,"name":"echo","result":null,"errors":[]}"#.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self, _: Duration) -> Result<Option<Vec<u8>>, ActionError> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    #[test]
+    fn failures_come_with_diffs() {
+        let report = run_conformance(&mut Broken(VecDeque::new()));
+        assert_eq!(report.passed(), 0);
+        let id_echo = &report.results[0];
+        assert_eq!(id_echo.name, "id_echo");
+        assert!(id_echo
+            .diffs
+            .contains(&"reply 0: $.id: expected 17, got 1".to_owned()));
+        assert!(id_echo
+            .diffs
+            .contains(&"reply 0: $.result: expected {\"x\":1}, got null".to_owned()));
+        let notification = report
+            .results
+            .iter()
+            .find(|r| r.name == "notification")
+            .unwrap();
+        assert!(!notification.passed);
+    }
+
+    #[test]
+    fn partial_matching() {
+        let expected = json!({"id": 1, "errors": [{"code": "X"}]});
+        let reply = json!({"id": 1, "name": "a", "errors": [{"code": "X", "message": "m"}]});
+        assert!(mismatches(&expected, &reply, "$").is_empty());
+        let reply = json!({"id": 1, "errors": []});
+        assert_eq!(
+            mismatches(&expected, &reply, "$"),
+            vec!["$.errors: expected 1 elements, got 0"]
+        );
+        assert_eq!(
+            mismatches(&json!({"a": 1}), &json!({}), "$"),
+            vec!["$.a: expected 1, missing"]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// key under which the id travels on actions and replies
+pub const CORRELATION_KEY: &str = "correlation_id";
+
+/// header HTTP gateways are expected to carry the id in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// supplied ids longer than this are replaced rather than trusted
+pub const MAX_CORRELATION_LEN: usize = 128;
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// ties an action, its reply and its trace to the external request that caused it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct CorrelationId(String);
+
+impl CorrelationId {
+    pub fn new(id: &str) -> Self {
+        CorrelationId(id.to_owned())
+    }
+
+    /// a fresh id, unique within the process and unlikely to collide across processes
+    pub fn generate() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        CorrelationId(format!("{:016x}-{:x}", nanos, seq))
+    }
+
+    /// the id supplied by the caller, e.g. the `x-request-id` header, or a generated one
+    /// when it is missing, empty, too long or contains anything but printable ascii
+    pub fn from_header(value: Option<&str>) -> Self {
+        match value.map(str::trim) {
+            Some(v)
+                if !v.is_empty()
+                    && v.len() <= MAX_CORRELATION_LEN
+                    && v.bytes().all(|b| b.is_ascii_graphic()) =>
+            {
+                CorrelationId::new(v)
+            }
+            _ => CorrelationId::generate(),
+        }
+    }
+
+    /// id for one message of a long lived connection, e.g. a websocket, so messages can
+    /// be told apart while still grouping under their connection
+    pub fn for_message(&self, seq: u64) -> Self {
+        CorrelationId(format!("{}.{}", self.0, seq))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<CorrelationId> for String {
+    fn from(id: CorrelationId) -> String {
+        id.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager};
+    use serde_json::Value;
+
+    fn action(id: Option<&str>) -> Action {
+        let mut a = Action::new("whoami", 0);
+        a.correlation_id = id.map(|s| s.to_owned());
+        a
+    }
+
+    #[test]
+    fn header_or_generated() {
+        assert_eq!(CorrelationId::from_header(Some("req-1")).as_str(), "req-1");
+        let a = CorrelationId::from_header(None);
+        let b = CorrelationId::from_header(Some("  "));
+        assert_ne!(a, b);
+        assert!(!a.as_str().is_empty());
+        let long = "x".repeat(MAX_CORRELATION_LEN + 1);
+        assert_ne!(CorrelationId::from_header(Some(&long)).as_str(), long);
+        assert_ne!(CorrelationId::from_header(Some("a\nb")).as_str(), "a\nb");
+        assert_eq!(CorrelationId::new("conn").for_message(3).as_str(), "conn.3");
+    }
+
+    #[test]
+    fn round_trips_through_dispatch() {
+        let mut m = Manager::new("test", ());
+        m.on_ctx("whoami", |_, _, ctx| value_ok(ctx.correlation_id()));
+        m.trace_dispatches(true);
+
+        let mut a = action(Some("req-1"));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("req-1")));
+        let reply = serde_json::to_value(m.reply(a)).unwrap();
+        assert_eq!(reply[CORRELATION_KEY], json!("req-1"));
+        assert_eq!(
+            m.recent_traces(1)[0].correlation_id.as_deref(),
+            Some("req-1")
+        );
+
+        let mut a = action(None);
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(Value::Null));
+        let reply = serde_json::to_value(m.reply(a)).unwrap();
+        assert!(reply.get(CORRELATION_KEY).is_none());
+    }
+}
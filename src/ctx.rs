@@ -0,0 +1,467 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::action::{Action, ActionReply};
+use crate::auth::AuthContext;
+use crate::budget::Budget;
+use crate::error::ActionError;
+use crate::logger::ActionLogger;
+
+/// how deep `ActionCtx::dispatch` may nest unless `Manager::max_dispatch_depth` says otherwise
+pub const DEFAULT_MAX_DISPATCH_DEPTH: usize = 4;
+
+/// routes a sub-action back into the manager running the current one, with the
+/// resource that manager already has at hand
+pub(crate) trait SubDispatch {
+    fn sub_dispatch(
+        &self,
+        action: Action,
+        parent: &ActionCtx<'_>,
+        skip_validation: bool,
+    ) -> Result<ActionReply, ActionError>;
+}
+
+/// per dispatch context handed to handlers registered with `Manager::on_ctx`
+pub struct ActionCtx<'a> {
+    batch: Option<&'a BatchCache>,
+    noop: BatchCache,
+    source: Option<String>,
+    correlation: Option<String>,
+    depth: usize,
+    dry_run: bool,
+    skip_validation: bool,
+    dispatcher: Option<&'a dyn SubDispatch>,
+    budget: Option<&'a Budget>,
+    unlimited: Budget,
+    logger: Option<ActionLogger<'a>>,
+    auth: Option<AuthContext>,
+    /// the token of the action was checked already, see `authenticated`
+    authenticated: bool,
+    meta: Mutex<HashMap<String, Value>>,
+}
+
+impl<'a> ActionCtx<'a> {
+    pub(crate) fn new(batch: Option<&'a BatchCache>) -> Self {
+        ActionCtx {
+            batch,
+            noop: BatchCache::noop(),
+            source: None,
+            correlation: None,
+            depth: 0,
+            dry_run: false,
+            skip_validation: false,
+            dispatcher: None,
+            budget: None,
+            unlimited: Budget::new(None),
+            logger: None,
+            auth: None,
+            authenticated: false,
+            meta: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// context carrying what the receiving side tagged the action with
+    pub(crate) fn for_action(batch: Option<&'a BatchCache>, action: &Action) -> Self {
+        let mut ctx = ActionCtx::new(batch);
+        ctx.source = action.source.clone();
+        ctx.correlation = action.correlation_id.clone();
+        ctx.dry_run = action.dry_run.unwrap_or(false);
+        ctx
+    }
+
+    /// the same context able to dispatch sub-actions through `dispatcher` and spending
+    /// `budget`
+    pub(crate) fn with_dispatcher<'b>(
+        &'b self,
+        dispatcher: &'b dyn SubDispatch,
+        budget: &'b Budget,
+    ) -> ActionCtx<'b> {
+        ActionCtx {
+            batch: self.batch,
+            noop: BatchCache::noop(),
+            source: self.source.clone(),
+            correlation: self.correlation.clone(),
+            depth: self.depth,
+            dry_run: self.dry_run,
+            skip_validation: self.skip_validation,
+            dispatcher: Some(dispatcher),
+            budget: Some(budget),
+            unlimited: Budget::new(None),
+            logger: None,
+            auth: self.auth.clone(),
+            authenticated: self.authenticated,
+            meta: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the same context on behalf of `auth`
+    pub(crate) fn with_auth(mut self, auth: Option<AuthContext>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// the same context once the token of the action was checked, on behalf of `auth`
+    pub(crate) fn authenticated(&self, auth: Option<AuthContext>) -> ActionCtx<'a> {
+        ActionCtx {
+            batch: self.batch,
+            noop: BatchCache::noop(),
+            source: self.source.clone(),
+            correlation: self.correlation.clone(),
+            depth: self.depth,
+            dry_run: self.dry_run,
+            skip_validation: self.skip_validation,
+            dispatcher: self.dispatcher,
+            budget: self.budget,
+            unlimited: Budget::new(None),
+            logger: self.logger.clone(),
+            auth,
+            authenticated: true,
+            meta: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// the same context logging through `logger`
+    pub(crate) fn with_logger(mut self, logger: ActionLogger<'a>) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// context of a sub-action dispatched from this one
+    pub(crate) fn nested(&self, action: &Action, skip_validation: bool) -> ActionCtx<'a> {
+        ActionCtx {
+            batch: self.batch,
+            noop: BatchCache::noop(),
+            source: self.source.clone(),
+            correlation: action
+                .correlation_id
+                .clone()
+                .or_else(|| self.correlation.clone()),
+            depth: self.depth + 1,
+            dry_run: self.dry_run || action.dry_run.unwrap_or(false),
+            skip_validation,
+            dispatcher: None,
+            budget: None,
+            unlimited: Budget::new(None),
+            logger: None,
+            auth: self.auth.clone(),
+            // its own token is checked unless it runs on behalf of this one
+            authenticated: false,
+            meta: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// runs another action of the same manager, with the same resource, source and batch
+    /// cache, and returns its reply; the action inherits the correlation id when it has
+    /// none, and nesting deeper than `Manager::max_dispatch_depth` fails with `RecursionLimit`
+    pub fn dispatch(&self, action: Action) -> Result<ActionReply, ActionError> {
+        self.sub_dispatch(action, false)
+    }
+
+    /// like `dispatch` but without running the validators registered for the action
+    pub fn dispatch_unvalidated(&self, action: Action) -> Result<ActionReply, ActionError> {
+        self.sub_dispatch(action, true)
+    }
+
+    fn sub_dispatch(
+        &self,
+        action: Action,
+        skip_validation: bool,
+    ) -> Result<ActionReply, ActionError> {
+        match self.dispatcher {
+            Some(d) => d.sub_dispatch(action, self, skip_validation),
+            None => Err(ActionError::new(
+                "DispatchUnavailable",
+                "this context can not dispatch sub-actions",
+            )),
+        }
+    }
+
+    /// how many `dispatch` calls deep the current action is, 0 for one sent by a client
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// whether the action only asks what would happen, sub-actions of a dry run are dry
+    /// runs as well
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub(crate) fn skips_validation(&self) -> bool {
+        self.skip_validation
+    }
+
+    /// who sent the action, `None` for public actions and managers without
+    /// `Manager::set_token_validator`
+    pub fn auth(&self) -> Option<&AuthContext> {
+        self.auth.as_ref()
+    }
+
+    /// where the action came from, as tagged by the receiving side
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// id of the external request the action belongs to, see the `correlation` module
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation.as_deref()
+    }
+
+    /// the work this action may still do, unlimited unless `Manager::budget` set one;
+    /// sub-actions spend their own budgets
+    pub fn budget(&self) -> &Budget {
+        self.budget.unwrap_or(&self.unlimited)
+    }
+
+    /// a logger tagging what the handler logs with the manager, action and correlation id,
+    /// see `Manager::attach_logs_to_reply` for getting the entries back in the reply
+    pub fn log(&self) -> ActionLogger<'a> {
+        self.logger
+            .clone()
+            .unwrap_or_else(|| ActionLogger::detached(self.correlation.clone()))
+    }
+
+    /// sets `key` of the meta the reply carries, like `Action::set_meta`; failing with
+    /// `SerializeError`
+    pub fn set_meta<V: Serialize>(&self, key: &str, value: V) -> Result<(), ActionError> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| ActionError::new("SerializeError", &format!("meta {}: {}", key, e)))?;
+        self.meta
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    /// what the handler put through `set_meta`
+    pub(crate) fn take_meta(&self) -> HashMap<String, Value> {
+        std::mem::take(&mut *self.meta.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// values shared between the actions of one `do_batch` call; outside of a batch this
+    /// is a cache that never stores anything, so loaders always run
+    pub fn batch_cache(&self) -> &BatchCache {
+        self.batch.unwrap_or(&self.noop)
+    }
+
+    /// whether the action is being dispatched as part of `do_batch`
+    pub fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+}
+
+/// memoizes values for the duration of a single batch, values of different key and value
+/// types live side by side
+pub struct BatchCache {
+    enabled: bool,
+    maps: Mutex<HashMap<(TypeId, TypeId), Box<dyn Any + Send>>>,
+}
+
+impl Default for BatchCache {
+    fn default() -> Self {
+        BatchCache::new()
+    }
+}
+
+impl BatchCache {
+    pub fn new() -> Self {
+        BatchCache {
+            enabled: true,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn noop() -> Self {
+        BatchCache {
+            enabled: false,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the cached value for `key`, computing it with `f` on a miss; errors are returned
+    /// as is and not cached
+    pub fn get_or_insert_with<K, V, F>(&self, key: K, f: F) -> Result<V, ActionError>
+    where
+        K: Hash + Eq + Send + 'static,
+        V: Clone + Send + 'static,
+        F: FnOnce() -> Result<V, ActionError>,
+    {
+        if !self.enabled {
+            return f();
+        }
+        if let Some(v) = self.with_map(|m: &mut HashMap<K, V>| m.get(&key).cloned()) {
+            return Ok(v);
+        }
+        // the lock is not held while computing so loaders can use the cache themselves
+        let v = f()?;
+        self.with_map(|m: &mut HashMap<K, V>| m.insert(key, v.clone()));
+        Ok(v)
+    }
+
+    fn with_map<K, V, T, F>(&self, f: F) -> T
+    where
+        K: Hash + Eq + Send + 'static,
+        V: Send + 'static,
+        F: FnOnce(&mut HashMap<K, V>) -> T,
+    {
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        let map = maps
+            .entry((TypeId::of::<K>(), TypeId::of::<V>()))
+            .or_insert_with(|| Box::new(HashMap::<K, V>::new()));
+        f(map
+            .downcast_mut::<HashMap<K, V>>()
+            .expect("batch cache entry keyed by its own types"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn action(name: &str, id: u64) -> Action {
+        Action::new(name, id)
+    }
+
+    fn manager(loads: Arc<AtomicUsize>) -> Manager<Arc<AtomicUsize>> {
+        let mut m = Manager::new("users", loads);
+        m.on_ctx("user", |loads, _, ctx| {
+            let user = ctx.batch_cache().get_or_insert_with(42u64, || {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Ok("bob".to_owned())
+            })?;
+            // same key, different value type, is a separate entry
+            let age = ctx.batch_cache().get_or_insert_with(42u64, || Ok(30u32))?;
+            value_ok(json!({"user": user, "age": age}))
+        });
+        m
+    }
+
+    #[test]
+    fn batch_computes_once() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let m = manager(loads.clone());
+        let replies = m.do_batch(vec![
+            action("user", 1),
+            action("user", 2),
+            action("user", 3),
+        ]);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        let ids: Vec<u64> = replies.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        for r in &replies {
+            assert_eq!(r.result, Some(json!({"user": "bob", "age": 30})));
+        }
+        // the cache does not outlive the batch
+        m.do_batch(vec![action("user", 4)]);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn single_dispatch_always_computes() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let m = manager(loads.clone());
+        for id in 1..=3 {
+            let mut a = action("user", id);
+            m.do_action(&mut a);
+            assert!(a.errors.is_none());
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+
+    fn sub_action(name: &str, n: u64) -> Action {
+        let mut a = action(name, 0);
+        a.payload.insert("n".to_owned(), json!(n));
+        a
+    }
+
+    #[test]
+    fn two_level_composition() {
+        let mut m = Manager::new("test", ());
+        m.on_ctx("double", |_, a, _| {
+            value_ok(a.payload["n"].as_u64().unwrap() * 2)
+        });
+        m.on_ctx("quad", |_, a, ctx| {
+            let n = a.payload["n"].as_u64().unwrap();
+            let once = ctx.dispatch(sub_action("double", n))?;
+            let n = once.result.unwrap().as_u64().unwrap();
+            value_ok(ctx.dispatch(sub_action("double", n))?.result)
+        });
+        m.on_ctx("octo", |_, a, ctx| {
+            let reply = ctx.dispatch(sub_action("quad", a.payload["n"].as_u64().unwrap()))?;
+            value_ok(json!({"n": reply.result, "correlation": ctx.correlation_id()}))
+        });
+        let mut a = sub_action("octo", 3);
+        a.correlation_id = Some("req-1".to_owned());
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!({"n": 12, "correlation": "req-1"})));
+    }
+
+    #[test]
+    fn recursion_limit() {
+        let mut m = Manager::new("test", ());
+        m.on_ctx("loop", |_, _, ctx| {
+            let reply = ctx.dispatch(action("loop", 0))?;
+            if let Some(e) = reply.errors.into_iter().next() {
+                return Err(Box::new(e));
+            }
+            value_ok(ctx.depth())
+        });
+        m.max_dispatch_depth(2);
+        let mut a = action("loop", 1);
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "RecursionLimit");
+    }
+
+    #[test]
+    fn sub_dispatch_reuses_resource() {
+        let made = Arc::new(AtomicUsize::new(0));
+        let counter = made.clone();
+        let mut m = Manager::with("test", move || counter.fetch_add(1, Ordering::SeqCst));
+        m.on_ctx("inner", |r, _, _| value_ok(*r));
+        m.on_ctx("outer", |r, _, ctx| {
+            let inner = ctx.dispatch(action("inner", 0))?.result;
+            value_ok(json!([r, inner]))
+        });
+        let mut a = action("outer", 1);
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!([0, 0])));
+        assert_eq!(made.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unvalidated_dispatch() {
+        let mut m = Manager::new("test", ());
+        m.on_ctx("inner", |_, _, _| value_ok("ran"));
+        m.validate_action("inner", |_| Err(vec![ActionError::new("Nope", "")]));
+        m.on_ctx("outer", |_, _, ctx| {
+            let checked = ctx.dispatch(action("inner", 0))?;
+            let unchecked = ctx.dispatch_unvalidated(action("inner", 0))?;
+            value_ok(json!([checked.errors.len(), unchecked.result]))
+        });
+        let mut a = action("outer", 1);
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!([1, "ran"])));
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let cache = BatchCache::new();
+        let r: Result<u8, ActionError> =
+            cache.get_or_insert_with("k", || Err(ActionError::new("Load", "down")));
+        assert!(r.is_err());
+        assert_eq!(cache.get_or_insert_with("k", || Ok(1u8)).unwrap(), 1);
+        assert_eq!(cache.get_or_insert_with("k", || Ok(2u8)).unwrap(), 1);
+    }
+}
@@ -0,0 +1,285 @@
+//! actions whose handler failed, kept so they can be exported, fixed up and replayed,
+//! see `Manager::dead_letter_to`
+
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+use crate::outcome::DispatchOutcome;
+
+/// failed replays after which an entry is parked and no longer replayed
+pub const MAX_REPLAY_ATTEMPTS: u32 = 5;
+
+/// one failed action, exported as one line of NDJSON
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    /// the action as it arrived, token encoded by the manager's `TokenCodec`
+    pub action: Action,
+    pub errors: Vec<ActionError>,
+    /// milliseconds since the unix epoch
+    pub first_seen: u64,
+    /// how many replays failed
+    pub attempts: u32,
+    /// set once `attempts` reached the cap, parked entries are skipped by replays
+    #[serde(default)]
+    pub parked: bool,
+}
+
+impl DeadLetter {
+    pub fn new(action: Action, errors: Vec<ActionError>) -> Self {
+        let first_seen = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        DeadLetter {
+            action,
+            errors,
+            first_seen,
+            attempts: 0,
+            parked: false,
+        }
+    }
+}
+
+pub(crate) fn io_error(e: std::io::Error) -> ActionError {
+    ActionError::new("DeadLetterError", &e.to_string())
+}
+
+pub trait DeadLetterSink: Send + Sync {
+    fn push(&self, entry: DeadLetter);
+    /// removes and returns every entry
+    fn drain(&self) -> Vec<DeadLetter>;
+    /// every entry, left in place
+    fn entries(&self) -> Vec<DeadLetter>;
+
+    /// writes every entry as a line of JSON, returns how many were written
+    fn export(&self, w: &mut dyn Write) -> Result<u64, ActionError> {
+        let mut n = 0;
+        for entry in self.entries() {
+            serde_json::to_writer(&mut *w, &entry)?;
+            w.write_all(b"\n").map_err(io_error)?;
+            n += 1;
+        }
+        w.flush().map_err(io_error)?;
+        Ok(n)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryDeadLetters {
+    entries: Mutex<Vec<DeadLetter>>,
+}
+
+impl MemoryDeadLetters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds the entries of an `export`, blank lines are skipped; returns how many were read
+    pub fn import<Rd: BufRead>(&self, r: Rd) -> Result<u64, ActionError> {
+        let mut read = Vec::new();
+        for line in r.lines() {
+            let line = line.map_err(io_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            read.push(serde_json::from_str::<DeadLetter>(&line)?);
+        }
+        let n = read.len() as u64;
+        self.lock().extend(read);
+        Ok(n)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<DeadLetter>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DeadLetterSink for MemoryDeadLetters {
+    fn push(&self, entry: DeadLetter) {
+        self.lock().push(entry);
+    }
+
+    fn drain(&self) -> Vec<DeadLetter> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn entries(&self) -> Vec<DeadLetter> {
+        self.lock().clone()
+    }
+}
+
+/// what `replay_dead_letters` did
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub replayed: u64,
+    /// succeeded and removed from the sink
+    pub succeeded: u64,
+    /// failed again and put back
+    pub failed: u64,
+    /// failed for the last allowed time and parked
+    pub parked: u64,
+    /// not matching the filter or already parked
+    pub skipped: u64,
+}
+
+/// `replay_dead_letters_with` allowing `MAX_REPLAY_ATTEMPTS`
+pub fn replay_dead_letters<R>(
+    manager: &Manager<R>,
+    sink: &dyn DeadLetterSink,
+    filter: Option<&dyn Fn(&Action) -> bool>,
+) -> ReplayReport {
+    replay_dead_letters_with(manager, sink, filter, MAX_REPLAY_ATTEMPTS)
+}
+
+/// dispatches the entries matching `filter` again; successful ones are dropped, failing
+/// ones go back with their new errors and one more attempt, parked at `max_attempts`.
+/// Tokens are restored through the manager's `TokenCodec` when it can
+pub fn replay_dead_letters_with<R>(
+    manager: &Manager<R>,
+    sink: &dyn DeadLetterSink,
+    filter: Option<&dyn Fn(&Action) -> bool>,
+    max_attempts: u32,
+) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    for mut entry in sink.drain() {
+        if entry.parked || filter.is_some_and(|f| !f(&entry.action)) {
+            report.skipped += 1;
+            sink.push(entry);
+            continue;
+        }
+        report.replayed += 1;
+        let mut action = manager
+            .restore_token(&entry.action)
+            .unwrap_or_else(|_| entry.action.clone());
+        match manager.redispatch(&mut action) {
+            DispatchOutcome::Handled => report.succeeded += 1,
+            _ => {
+                entry.attempts += 1;
+                entry.errors = action.errors.unwrap_or_default();
+                if entry.attempts >= max_attempts {
+                    entry.parked = true;
+                    report.parked += 1;
+                } else {
+                    report.failed += 1;
+                }
+                sink.push(entry);
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn action(n: u64) -> Action {
+        let mut a = Action::new("charge", n);
+        a.token = Some(format!("t{}", n));
+        a.payload.insert("n".to_owned(), json!(n));
+        a
+    }
+
+    /// odd amounts fail until `fixed` is set
+    fn manager(fixed: Arc<AtomicBool>, sink: Arc<MemoryDeadLetters>) -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("charge", move |_, a| {
+            let n = a.payload["n"].as_u64().unwrap();
+            if n % 2 == 1 && !fixed.load(Ordering::SeqCst) {
+                return Err(ActionError::new("Declined", "card declined").into());
+            }
+            value_ok(n)
+        });
+        m.dead_letter_to(sink);
+        m
+    }
+
+    #[test]
+    fn failed_actions_are_kept() {
+        let sink = Arc::new(MemoryDeadLetters::new());
+        let m = manager(Arc::new(AtomicBool::new(false)), sink.clone());
+        for n in 1..=4 {
+            m.do_action(&mut action(n));
+        }
+        let entries = sink.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action.id, 1);
+        assert!(entries[0].action.errors.is_none());
+        assert_eq!(entries[0].errors[0].code, "Declined");
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let sink = MemoryDeadLetters::new();
+        let mut a = action(1);
+        a.attach("receipt.txt", "text/plain", b"hi");
+        sink.push(DeadLetter::new(
+            a,
+            vec![ActionError::new("Declined", "card declined").retryable()],
+        ));
+        let mut parked = DeadLetter::new(action(2), vec![]);
+        parked.attempts = 5;
+        parked.parked = true;
+        sink.push(parked);
+
+        let mut out = Vec::new();
+        assert_eq!(sink.export(&mut out).unwrap(), 2);
+        let copy = MemoryDeadLetters::new();
+        assert_eq!(copy.import(Cursor::new(&out)).unwrap(), 2);
+        let mut again = Vec::new();
+        copy.export(&mut again).unwrap();
+        assert_eq!(out, again);
+
+        assert!(copy.import(Cursor::new(b"{not json}\n")).is_err());
+    }
+
+    #[test]
+    fn replay_where_half_succeed() {
+        let sink = Arc::new(MemoryDeadLetters::new());
+        let fixed = Arc::new(AtomicBool::new(false));
+        let m = manager(fixed.clone(), sink.clone());
+        for n in 1..=4 {
+            sink.push(DeadLetter::new(action(n), vec![]));
+        }
+
+        let report = replay_dead_letters_with(&m, &*sink, None, 2);
+        assert_eq!(
+            report,
+            ReplayReport {
+                replayed: 4,
+                succeeded: 2,
+                failed: 2,
+                ..ReplayReport::default()
+            }
+        );
+        // replays do not add new entries for their own failures
+        assert_eq!(sink.len(), 2);
+        assert!(sink.entries().iter().all(|e| e.attempts == 1));
+        assert_eq!(sink.entries()[0].errors[0].code, "Declined");
+
+        let only_1 = |a: &Action| a.id == 1;
+        let report = replay_dead_letters_with(&m, &*sink, Some(&only_1), 2);
+        assert_eq!((report.parked, report.skipped), (1, 1));
+
+        // parked entries stay even once the cause is fixed
+        fixed.store(true, Ordering::SeqCst);
+        let report = replay_dead_letters(&m, &*sink, None);
+        assert_eq!((report.succeeded, report.skipped), (1, 1));
+        assert_eq!(sink.len(), 1);
+        assert!(sink.entries()[0].parked);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+
+type Key = (Option<String>, String, u64);
+
+struct Entry {
+    at: Instant,
+    used: u64,
+    result: Option<Value>,
+    errors: Option<Vec<ActionError>>,
+    meta: HashMap<String, Value>,
+}
+
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// keys by when they were last used, oldest first
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+/// what earlier dispatches came to by (token, name, id), see `Manager::idempotent`
+pub(crate) struct DedupeCache {
+    window: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl DedupeCache {
+    fn new(window: Duration, capacity: usize) -> Self {
+        DedupeCache {
+            window,
+            capacity,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    fn key(action: &Action) -> Option<Key> {
+        // 0 is the id of `Action::server_err`
+        match action.id {
+            0 => None,
+            id => Some((action.token.clone(), action.name.clone(), id)),
+        }
+    }
+
+    /// copies what an earlier dispatch of the same (token, name, id) came to onto the action
+    pub(crate) fn replay(&self, action: &mut Action, now: Instant) -> bool {
+        let key = match Self::key(action) {
+            Some(key) => key,
+            None => return false,
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let e = &mut *entries;
+        let entry = match e.map.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if now.saturating_duration_since(entry.at) >= self.window {
+            let used = entry.used;
+            e.map.remove(&key);
+            e.order.remove(&used);
+            return false;
+        }
+        e.tick += 1;
+        e.order.remove(&entry.used);
+        entry.used = e.tick;
+        e.order.insert(e.tick, key);
+        action.result = entry.result.clone();
+        action.errors = entry.errors.clone();
+        action.meta = entry.meta.clone();
+        true
+    }
+
+    /// keeps what the dispatch came to, unless it failed with an error the client is
+    /// told to retry
+    pub(crate) fn store(&self, action: &Action, now: Instant) {
+        let key = match Self::key(action) {
+            Some(key) => key,
+            None => return,
+        };
+        let retryable = action
+            .errors
+            .as_ref()
+            .is_some_and(|errors| errors.iter().any(|e| e.is_retryable()));
+        if retryable || self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let e = &mut *entries;
+        e.tick += 1;
+        let entry = Entry {
+            at: now,
+            used: e.tick,
+            result: action.result.clone(),
+            errors: action.errors.clone(),
+            meta: action.meta.clone(),
+        };
+        if let Some(old) = e.map.insert(key.clone(), entry) {
+            e.order.remove(&old.used);
+        }
+        e.order.insert(e.tick, key);
+        while e.map.len() > self.capacity {
+            match e.order.pop_first() {
+                Some((_, oldest)) => {
+                    e.map.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map
+            .len()
+    }
+}
+
+impl<R> Manager<R> {
+    /// repeats of a (token, name, id) within `window` get the result, errors and meta of
+    /// the first dispatch copied onto them instead of running again, with the `Cached`
+    /// outcome; at most `capacity` are kept, the least recently used go first. Actions with
+    /// id 0 are never deduplicated, nor are dry runs or dispatches failing with a
+    /// retryable error. Repeats are authenticated before they get a replay.
+    /// Repeats arriving while the first one still runs are not caught, see
+    /// `reject_inflight_duplicates`. The window is measured with the clock of the manager
+    pub fn idempotent(&mut self, window: Duration, capacity: usize) {
+        *self.dedupe_mut() = Some(DedupeCache::new(window, capacity));
+    }
+
+    /// how many outcomes `idempotent` currently keeps
+    pub fn idempotent_len(&self) -> usize {
+        self.dedupe().map_or(0, DedupeCache::len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+    use crate::auth::{unauthorized, AuthContext};
+    use crate::clock::FakeClock;
+    use crate::outcome::DispatchOutcome;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn manager(window: Duration, capacity: usize) -> (Manager<Arc<AtomicUsize>>, Arc<AtomicUsize>) {
+        let charges = Arc::new(AtomicUsize::new(0));
+        let mut m = Manager::new("test", charges.clone());
+        m.on("refund", |charges, _| {
+            charges.fetch_sub(1, Ordering::SeqCst);
+            value_ok(json!("refunded"))
+        });
+        m.on("charge-card", |charges, a| {
+            let n = charges.fetch_add(1, Ordering::SeqCst) + 1;
+            value_ok(json!({ "charge": n, "amount": a.payload["amount"] }))
+        });
+        m.on("flaky", |_, _| {
+            Err(ActionError::new("GatewayTimeout", "try again")
+                .retryable()
+                .into())
+        });
+        m.idempotent(window, capacity);
+        (m, charges)
+    }
+
+    fn charge(token: Option<&str>, id: u64) -> Action {
+        let mut a = Action::new("charge-card", id);
+        a.token = token.map(|t| t.to_owned());
+        a.payload.insert("amount".to_owned(), json!(25));
+        a
+    }
+
+    #[test]
+    fn repeats_run_once() {
+        let (m, charges) = manager(Duration::from_secs(60), 100);
+        let mut first = charge(Some("t"), 7);
+        assert_eq!(m.do_action(&mut first), DispatchOutcome::Handled);
+        let mut again = charge(Some("t"), 7);
+        assert_eq!(m.do_action(&mut again), DispatchOutcome::Cached);
+        assert_eq!(again.result, first.result);
+        assert_eq!(charges.load(Ordering::SeqCst), 1);
+
+        // another token or id is another action
+        m.do_action(&mut charge(Some("u"), 7));
+        m.do_action(&mut charge(Some("t"), 8));
+        m.do_action(&mut charge(None, 7));
+        assert_eq!(charges.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn revoked_tokens_get_no_replays() {
+        let (mut m, charges) = manager(Duration::from_secs(60), 100);
+        let revoked = Arc::new(AtomicBool::new(false));
+        let r = revoked.clone();
+        m.set_token_validator(move |_| match r.load(Ordering::SeqCst) {
+            true => Err(unauthorized("revoked")),
+            false => Ok(AuthContext::default()),
+        });
+        m.do_action(&mut charge(Some("t"), 7));
+        revoked.store(true, Ordering::SeqCst);
+        let mut again = charge(Some("t"), 7);
+        assert_eq!(m.do_action(&mut again), DispatchOutcome::Rejected);
+        assert!(again.result.is_none());
+        assert_eq!(charges.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dry_runs_are_not_kept() {
+        let (m, charges) = manager(Duration::from_secs(60), 100);
+        let mut dry = charge(Some("t"), 7);
+        dry.dry_run = Some(true);
+        m.do_action(&mut dry);
+        assert_eq!(m.idempotent_len(), 0);
+        let mut real = charge(Some("t"), 7);
+        assert_eq!(m.do_action(&mut real), DispatchOutcome::Handled);
+        assert_eq!(charges.load(Ordering::SeqCst), 1);
+
+        // nor is a real run replayed to a dry run
+        let mut dry = charge(Some("t"), 7);
+        dry.dry_run = Some(true);
+        assert_ne!(m.do_action(&mut dry), DispatchOutcome::Cached);
+    }
+
+    #[test]
+    fn another_name_is_another_action() {
+        let (m, charges) = manager(Duration::from_secs(60), 100);
+        m.do_action(&mut charge(Some("t"), 7));
+        let mut refund = charge(Some("t"), 7);
+        refund.name = "refund".to_owned();
+        assert_eq!(m.do_action(&mut refund), DispatchOutcome::Handled);
+        assert_eq!(refund.result, Some(json!("refunded")));
+        assert_eq!(charges.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn exemptions() {
+        let (m, charges) = manager(Duration::from_secs(60), 100);
+        m.do_action(&mut charge(Some("t"), 0));
+        m.do_action(&mut charge(Some("t"), 0));
+        assert_eq!(charges.load(Ordering::SeqCst), 2);
+
+        let mut a = Action::new("flaky", 3);
+        m.do_action(&mut a);
+        assert_eq!(m.idempotent_len(), 0);
+        let mut a = Action::new("missing", 4);
+        m.do_action(&mut a);
+        let mut again = Action::new("missing", 4);
+        assert_eq!(m.do_action(&mut again), DispatchOutcome::Cached);
+        assert_eq!(again.errors.unwrap()[0].code, a.errors.unwrap()[0].code);
+    }
+
+    #[test]
+    fn window_and_capacity() {
+        let (mut m, charges) = manager(Duration::from_secs(60), 2);
+        let clock = FakeClock::new();
+        m.set_clock(clock.clone());
+        m.do_action(&mut charge(Some("t"), 1));
+        m.do_action(&mut charge(Some("t"), 2));
+        // 1 was used last, 2 goes when 3 comes in
+        m.do_action(&mut charge(Some("t"), 1));
+        m.do_action(&mut charge(Some("t"), 3));
+        assert_eq!(charges.load(Ordering::SeqCst), 3);
+        assert_eq!(m.idempotent_len(), 2);
+        m.do_action(&mut charge(Some("t"), 1));
+        assert_eq!(charges.load(Ordering::SeqCst), 3);
+        m.do_action(&mut charge(Some("t"), 2));
+        assert_eq!(charges.load(Ordering::SeqCst), 4);
+
+        clock.advance(Duration::from_secs(59));
+        m.do_action(&mut charge(Some("t"), 1));
+        assert_eq!(charges.load(Ordering::SeqCst), 4);
+        clock.advance(Duration::from_secs(1));
+        m.do_action(&mut charge(Some("t"), 1));
+        assert_eq!(charges.load(Ordering::SeqCst), 5);
+    }
+}
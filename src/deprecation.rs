@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::error::ActionError;
+use crate::maintenance::{SweepStats, SWEEP_BUDGET};
+
+/// how often the same token is told about the same deprecated action, on every dispatch
+/// unless `Manager::deprecation_warn_interval` sets a throttle
+pub const DEFAULT_WARN_INTERVAL: Duration = Duration::ZERO;
+
+/// how many (token, action) pairs the warning throttle remembers
+pub const WARN_CACHE_CAPACITY: usize = 10_000;
+
+/// registered through `Manager::deprecate`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    pub note: String,
+    /// date after which the action stops working or changes behavior
+    pub sunset: Option<String>,
+}
+
+impl Deprecation {
+    /// the warning attached to replies of the deprecated action
+    pub fn warning(&self, action: &str) -> ActionError {
+        let message = match &self.sunset {
+            Some(sunset) => format!(
+                "{} is deprecated (sunset {}): {}",
+                action, sunset, self.note
+            ),
+            None => format!("{} is deprecated: {}", action, self.note),
+        };
+        ActionError::new("Deprecated", &message).with_details(json!({
+            "action": action,
+            "note": self.note,
+            "sunset": self.sunset,
+        }))
+    }
+}
+
+/// remembers when a token was last warned about an action so chatty clients are not
+/// told on every single reply
+pub(crate) struct WarnThrottle {
+    interval: Duration,
+    capacity: usize,
+    last: HashMap<(String, String), Instant>,
+    /// where the next sweep continues
+    cursor: usize,
+}
+
+impl WarnThrottle {
+    pub(crate) fn new(interval: Duration, capacity: usize) -> Self {
+        WarnThrottle {
+            interval,
+            capacity,
+            last: HashMap::new(),
+            cursor: 0,
+        }
+    }
+
+    /// whether `token` should be warned about `action` now, tokenless actions always are
+    /// and so is everyone without an interval
+    pub(crate) fn should_warn(&mut self, token: Option<&str>, action: &str, now: Instant) -> bool {
+        let token = match token {
+            Some(t) if !self.interval.is_zero() => t,
+            _ => return true,
+        };
+        let key = (token.to_owned(), action.to_owned());
+        if let Some(last) = self.last.get(&key) {
+            if now.duration_since(*last) < self.interval {
+                return false;
+            }
+        }
+        if self.last.len() >= self.capacity && !self.last.contains_key(&key) {
+            self.evict(now);
+        }
+        self.last.insert(key, now);
+        true
+    }
+
+    /// drops expired entries among the next `SWEEP_BUDGET`, continuing where the last
+    /// sweep stopped
+    pub(crate) fn sweep(&mut self, now: Instant) -> SweepStats {
+        if self.cursor >= self.last.len() {
+            self.cursor = 0;
+        }
+        let interval = self.interval;
+        let mut scanned = 0;
+        let expired: Vec<(String, String)> = self
+            .last
+            .iter()
+            .skip(self.cursor)
+            .take(SWEEP_BUDGET)
+            .inspect(|_| scanned += 1)
+            .filter(|(_, last)| now.saturating_duration_since(**last) >= interval)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in &expired {
+            self.last.remove(k);
+        }
+        self.cursor += scanned - expired.len();
+        SweepStats {
+            scanned,
+            evicted: expired.len(),
+            bytes_freed: 0,
+        }
+    }
+
+    /// drops expired entries, and the oldest one if that did not make room
+    fn evict(&mut self, now: Instant) {
+        let interval = self.interval;
+        self.last
+            .retain(|_, last| now.duration_since(*last) < interval);
+        if self.last.len() >= self.capacity {
+            let oldest = self
+                .last
+                .iter()
+                .min_by_key(|(_, last)| **last)
+                .map(|(k, _)| k.clone());
+            if let Some(k) = oldest {
+                self.last.remove(&k);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, Manager};
+    use crate::clock::FakeClock;
+
+    fn action(name: &str, token: Option<&str>) -> Action {
+        let mut a = Action::new(name, 0);
+        a.token = token.map(|t| t.to_owned());
+        a
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("old", |_, _| action_ok());
+        m.on("new", |_, _| action_ok());
+        m.deprecate("old", "use new instead", Some("2024-09-01"));
+        m
+    }
+
+    #[test]
+    fn warning_on_reply() {
+        let m = manager();
+        let mut a = action("old", Some("t1"));
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+        assert!(a.result.is_some());
+        let reply = m.reply(a);
+        assert_eq!(reply.warnings.len(), 1);
+        assert_eq!(reply.warnings[0].code, "Deprecated");
+        let details = reply.warnings[0].details.as_ref().unwrap();
+        assert_eq!(details["sunset"], json!("2024-09-01"));
+        assert_eq!(details["note"], json!("use new instead"));
+
+        let mut a = action("new", Some("t1"));
+        m.do_action(&mut a);
+        let v = serde_json::to_value(m.reply(a)).unwrap();
+        assert!(v.get("warnings").is_none());
+    }
+
+    #[test]
+    fn throttled_per_token() {
+        let mut m = manager();
+        let clock = FakeClock::new();
+        m.set_clock(clock.clone());
+        m.deprecation_warn_interval(Duration::from_secs(60));
+        let warned = |token| {
+            let mut a = action("old", token);
+            m.do_action(&mut a);
+            !a.warnings.is_empty()
+        };
+        assert!(warned(Some("t1")));
+        assert!(!warned(Some("t1")));
+        assert!(warned(Some("t2")));
+        assert!(warned(None));
+        assert!(warned(None));
+        clock.advance(Duration::from_secs(59));
+        assert!(!warned(Some("t1")));
+        clock.advance(Duration::from_secs(1));
+        assert!(warned(Some("t1")));
+    }
+
+    #[test]
+    fn every_dispatch_by_default() {
+        let m = manager();
+        for _ in 0..3 {
+            let mut a = action("old", Some("t1"));
+            m.do_action(&mut a);
+            let v = serde_json::to_value(a.into_reply()).unwrap();
+            assert_eq!(v["errors"], json!([]));
+            assert_eq!(v["warnings"][0]["code"], json!("Deprecated"));
+        }
+    }
+
+    #[test]
+    fn throttle_expires() {
+        let mut t = WarnThrottle::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        assert!(t.should_warn(Some("a"), "x", now));
+        assert!(!t.should_warn(Some("a"), "x", now + Duration::from_secs(9)));
+        assert!(t.should_warn(Some("a"), "x", now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn throttle_is_bounded() {
+        let mut t = WarnThrottle::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        assert!(t.should_warn(Some("a"), "x", now));
+        assert!(t.should_warn(Some("b"), "x", now + Duration::from_secs(1)));
+        assert!(t.should_warn(Some("c"), "x", now + Duration::from_secs(2)));
+        assert_eq!(t.last.len(), 2);
+        // "a" was the oldest and got evicted, so it is warned again
+        assert!(t.should_warn(Some("a"), "x", now + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn introspection() {
+        let m = manager();
+        let info = m.list_actions_detailed();
+        let names: Vec<&str> = info.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["new", "old"]);
+        assert!(info[0].deprecated.is_none());
+        assert_eq!(
+            info[1].deprecated.as_ref().unwrap().sunset.as_deref(),
+            Some("2024-09-01")
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! streams in both directions on the async manager (`duplex` feature)
+//!
+//! The first action opens the stream and starts the handler, follow-up actions of the
+//! client carry the same id and a `seq` and go to the handler instead of being
+//! dispatched. Either side ends the stream with `more: false` on its last frame, a
+//! disconnect ends every stream of the connection. Both directions hold at most
+//! `DUPLEX_CAPACITY` frames, senders wait for room.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::action::{Action, ActionReply, ManagerFut};
+use crate::error::ActionError;
+
+/// frames buffered in each direction of a stream
+pub const DUPLEX_CAPACITY: usize = 16;
+
+/// the running handler of a stream, for the transport to spawn
+pub type DuplexTask = Pin<Box<dyn Future<Output = ()>>>;
+
+pub(crate) type DuplexHandler<R> = dyn Fn(Arc<R>, Action, DuplexStream) -> DuplexTask;
+
+fn closed(id: u64) -> ActionError {
+    ActionError::new("StreamClosed", &format!("stream {} is closed", id))
+}
+
+/// the handler side of a stream
+pub struct DuplexStream {
+    id: u64,
+    inbound: mpsc::Receiver<Action>,
+    outbound: mpsc::Sender<ActionReply>,
+    inbound_done: bool,
+    outbound_done: bool,
+}
+
+impl DuplexStream {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// the next follow-up from the client, `None` once it sent `more: false` or went away
+    pub async fn next_inbound(&mut self) -> Option<Action> {
+        if self.inbound_done {
+            return None;
+        }
+        let action = self.inbound.recv().await;
+        match &action {
+            Some(a) if a.more == Some(false) => self.inbound_done = true,
+            None => self.inbound_done = true,
+            _ => (),
+        }
+        action
+    }
+
+    /// sends a reply under the stream's id, `more: false` ends the stream; fails with
+    /// `StreamClosed` once it ended or the client is gone
+    pub async fn send(&mut self, mut reply: ActionReply) -> Result<(), ActionError> {
+        if self.outbound_done {
+            return Err(closed(self.id));
+        }
+        reply.id = self.id;
+        if reply.more == Some(false) {
+            self.outbound_done = true;
+        }
+        self.outbound.send(reply).await.map_err(|_| closed(self.id))
+    }
+}
+
+/// a stream just opened by `DuplexRouter::route`
+pub struct DuplexSession {
+    pub id: u64,
+    /// the handler, to be spawned on the connection's executor
+    pub task: DuplexTask,
+    /// what the handler sends, to be written to the connection
+    pub replies: mpsc::Receiver<ActionReply>,
+}
+
+/// where `DuplexRouter::route` sent an action
+pub enum Routed {
+    /// it opened a stream
+    Opened(DuplexSession),
+    /// it went to the handler of an open stream
+    Forwarded,
+    /// it is not part of a stream, dispatch it as usual
+    Dispatch(Box<Action>),
+}
+
+/// the open streams of one connection
+#[derive(Default)]
+pub struct DuplexRouter {
+    open: HashMap<u64, mpsc::Sender<Action>>,
+}
+
+impl DuplexRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// forwards follow-ups to their stream, waiting while its queue is full, opens a
+    /// stream for actions with a duplex handler and hands everything else back; follow-ups
+    /// for a stream whose handler finished fail with `StreamClosed`
+    pub async fn route<R: 'static>(
+        &mut self,
+        manager: &ManagerFut<R>,
+        action: Action,
+    ) -> Result<Routed, ActionError> {
+        if let Some(tx) = self.open.get(&action.id) {
+            let id = action.id;
+            let last = action.more == Some(false);
+            let sent = tx.send(action).await;
+            if last || sent.is_err() {
+                self.open.remove(&id);
+            }
+            return sent.map(|_| Routed::Forwarded).map_err(|_| closed(id));
+        }
+        let handler = match manager.duplex_handlers().get(&action.name) {
+            Some(h) => h,
+            None => return Ok(Routed::Dispatch(Box::new(action))),
+        };
+        let (in_tx, in_rx) = mpsc::channel(DUPLEX_CAPACITY);
+        let (out_tx, out_rx) = mpsc::channel(DUPLEX_CAPACITY);
+        let id = action.id;
+        let stream = DuplexStream {
+            id,
+            inbound: in_rx,
+            outbound: out_tx,
+            inbound_done: action.more == Some(false),
+            outbound_done: false,
+        };
+        if action.more != Some(false) {
+            self.open.insert(id, in_tx);
+        }
+        Ok(Routed::Opened(DuplexSession {
+            id,
+            task: handler(manager.resource.clone(), action, stream),
+            replies: out_rx,
+        }))
+    }
+
+    /// the transport wrote `reply`, a final one closes its stream for further follow-ups
+    pub fn sent(&mut self, reply: &ActionReply) {
+        if reply.more == Some(false) {
+            self.open.remove(&reply.id);
+        }
+    }
+
+    pub fn is_open(&self, id: u64) -> bool {
+        self.open.contains_key(&id)
+    }
+
+    /// the connection went away, handlers see the end of their inbound stream
+    pub fn disconnect(&mut self) {
+        self.open.clear();
+    }
+}
+
+impl<R: 'static> ManagerFut<R> {
+    /// registers a handler for streams opened by `name`, it gets the opening action and
+    /// the stream to read follow-ups from and send replies to
+    pub fn on_duplex<T, F>(&mut self, name: &str, f: T)
+    where
+        T: Fn(Arc<R>, Action, DuplexStream) -> F + 'static,
+        F: Future<Output = ()> + 'static,
+    {
+        if self.duplex_handlers().contains_key(name) {
+            event!(
+                warn,
+                manager = self.manager_name(), action = name;
+                "Manager [{}] registered existing action: {}, ignoring", self.manager_name(), name
+            );
+        } else {
+            event!(
+                info,
+                manager = self.manager_name(), action = name;
+                "Manager [{}] register duplex: {}", self.manager_name(), name
+            );
+            self.duplex_handlers_mut().insert(
+                name.to_owned(),
+                Box::new(move |r, a, s| Box::pin(f(r, a, s))),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::task::LocalSet;
+
+    fn action(name: &str, id: u64, payload: Value) -> Action {
+        let mut a = Action::new(name, id);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    fn line_reply(id: u64, lines: Vec<&str>, more: bool) -> ActionReply {
+        let mut a = action("logs.tail", id, json!({}));
+        a.set_result(json!(lines));
+        let mut reply = a.into_reply();
+        reply.more = Some(more);
+        reply
+    }
+
+    /// tails a fixed log, the client changes the filter as it goes
+    fn manager() -> ManagerFut<Vec<&'static str>> {
+        let mut m = ManagerFut::new(
+            "async",
+            vec!["GET /", "POST /login", "GET /admin", "POST /logout"],
+        );
+        m.on_duplex("logs.tail", |log, open, mut stream| async move {
+            let filter = |a: &Action| a.payload["filter"].as_str().unwrap_or("").to_owned();
+            let matching = |f: &str| log.iter().copied().filter(|l| l.contains(f)).collect();
+            let mut f = filter(&open);
+            stream
+                .send(line_reply(0, matching(&f), true))
+                .await
+                .unwrap();
+            while let Some(next) = stream.next_inbound().await {
+                f = filter(&next);
+                let last = next.more == Some(false);
+                stream
+                    .send(line_reply(0, matching(&f), !last))
+                    .await
+                    .unwrap();
+                if last {
+                    let err = stream.send(line_reply(0, vec![], false)).await;
+                    assert_eq!(err.unwrap_err().code, "StreamClosed");
+                }
+            }
+        });
+        m
+    }
+
+    #[test]
+    fn three_frames_each_way() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        LocalSet::new().block_on(&rt, async {
+            let m = manager();
+            let mut router = DuplexRouter::new();
+
+            let open = action("logs.tail", 7, json!({"filter": "GET"}));
+            let mut session = match router.route(&m, open).await.unwrap() {
+                Routed::Opened(s) => s,
+                _ => panic!("expected a stream"),
+            };
+            let task = tokio::task::spawn_local(session.task);
+
+            let mut follow = action("logs.tail", 7, json!({"filter": "POST"}));
+            follow.seq = Some(1);
+            assert!(matches!(
+                router.route(&m, follow).await.unwrap(),
+                Routed::Forwarded
+            ));
+            let mut last = action("logs.tail", 7, json!({"filter": "admin"}));
+            last.seq = Some(2);
+            last.more = Some(false);
+            assert!(matches!(
+                router.route(&m, last).await.unwrap(),
+                Routed::Forwarded
+            ));
+            assert!(!router.is_open(7));
+
+            let mut got = Vec::new();
+            while let Some(reply) = session.replies.recv().await {
+                router.sent(&reply);
+                assert_eq!(reply.id, 7);
+                got.push((reply.result.unwrap(), reply.more));
+            }
+            assert_eq!(
+                got,
+                vec![
+                    (json!(["GET /", "GET /admin"]), Some(true)),
+                    (json!(["POST /login", "POST /logout"]), Some(true)),
+                    (json!(["GET /admin"]), Some(false)),
+                ]
+            );
+            task.await.unwrap();
+
+            // a closed stream takes no more follow-ups, other actions are not streams
+            let stray = action("other", 8, json!({}));
+            assert!(matches!(
+                router.route(&m, stray).await.unwrap(),
+                Routed::Dispatch(_)
+            ));
+        });
+    }
+
+    #[test]
+    fn disconnect_ends_the_inbound_side() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        LocalSet::new().block_on(&rt, async {
+            let m = manager();
+            let mut router = DuplexRouter::new();
+            let open = action("logs.tail", 1, json!({"filter": "/"}));
+            let mut session = match router.route(&m, open).await.unwrap() {
+                Routed::Opened(s) => s,
+                _ => panic!("expected a stream"),
+            };
+            let task = tokio::task::spawn_local(session.task);
+            router.disconnect();
+            assert!(!router.is_open(1));
+            let first = session.replies.recv().await.unwrap();
+            assert_eq!(first.result.unwrap().as_array().unwrap().len(), 4);
+            // the handler gives up once its inbound side ends
+            task.await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,232 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::action::{value_ok, ActionReply, Manager};
+use crate::error::ActionError;
+
+/// a result value with a documented shape, `{"kind": ..., "data": ...}`; any
+/// `Serialize` value is a result as it is
+pub trait IntoActionResult {
+    fn into_action_result(self) -> Result<Value, ActionError>;
+}
+
+impl<T: Serialize> IntoActionResult for T {
+    fn into_action_result(self) -> Result<Value, ActionError> {
+        Result::Ok(serde_json::to_value(self)?)
+    }
+}
+
+/// which envelope a result was wrapped in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeKind {
+    Ok,
+    Created,
+    NoContent,
+    Paged,
+}
+
+impl EnvelopeKind {
+    /// the status an HTTP transport should answer with
+    pub fn http_status(self) -> u16 {
+        match self {
+            EnvelopeKind::Created => 201,
+            EnvelopeKind::NoContent => 204,
+            EnvelopeKind::Ok | EnvelopeKind::Paged => 200,
+        }
+    }
+}
+
+/// `{"kind": "ok", "data": ...}`
+pub struct Ok<T> {
+    pub data: T,
+}
+
+/// `{"kind": "created", "data": ..., "location": ...}`
+pub struct Created<T> {
+    pub data: T,
+    /// where the new thing can be fetched, becomes the `Location` header over HTTP
+    pub location: Option<String>,
+}
+
+/// `{"kind": "no_content"}`
+pub struct NoContent;
+
+/// `{"kind": "paged", "data": [...], "next": ..., "total": ...}`
+pub struct Paged<T> {
+    pub data: Vec<T>,
+    /// cursor of the next page, `None` on the last one
+    pub next: Option<String>,
+    pub total: Option<u64>,
+}
+
+fn envelope(kind: EnvelopeKind, data: Option<Value>) -> Value {
+    let mut v = json!({ "kind": kind });
+    if let Some(data) = data {
+        v["data"] = data;
+    }
+    v
+}
+
+impl<T: Serialize> IntoActionResult for Ok<T> {
+    fn into_action_result(self) -> Result<Value, ActionError> {
+        let data = serde_json::to_value(self.data)?;
+        Result::Ok(envelope(EnvelopeKind::Ok, Some(data)))
+    }
+}
+
+impl<T: Serialize> IntoActionResult for Created<T> {
+    fn into_action_result(self) -> Result<Value, ActionError> {
+        let mut v = envelope(
+            EnvelopeKind::Created,
+            Some(serde_json::to_value(self.data)?),
+        );
+        v["location"] = json!(self.location);
+        Result::Ok(v)
+    }
+}
+
+impl IntoActionResult for NoContent {
+    fn into_action_result(self) -> Result<Value, ActionError> {
+        Result::Ok(envelope(EnvelopeKind::NoContent, None))
+    }
+}
+
+impl<T: Serialize> IntoActionResult for Paged<T> {
+    fn into_action_result(self) -> Result<Value, ActionError> {
+        let mut v = envelope(EnvelopeKind::Paged, Some(serde_json::to_value(self.data)?));
+        v["next"] = json!(self.next);
+        v["total"] = json!(self.total);
+        Result::Ok(v)
+    }
+}
+
+impl ActionReply {
+    /// the envelope the result came in, `None` for plain results
+    pub fn envelope_kind(&self) -> Option<EnvelopeKind> {
+        let kind = self.result.as_ref()?.get("kind")?;
+        serde_json::from_value(kind.clone()).ok()
+    }
+
+    /// `location` of a `Created` result
+    pub fn envelope_location(&self) -> Option<&str> {
+        match self.envelope_kind()? {
+            EnvelopeKind::Created => self.result.as_ref()?.get("location")?.as_str(),
+            _ => None,
+        }
+    }
+}
+
+impl<R> Manager<R> {
+    /// registers a handler taking the payload deserialized as `P` and returning a plain
+    /// value or one of the envelopes
+    pub fn on_typed<P, O, F>(&mut self, name: &str, f: F)
+    where
+        P: DeserializeOwned,
+        O: IntoActionResult,
+        F: Fn(&R, P) -> Result<O, ActionError> + Send + Sync + 'static,
+    {
+        self.on(name, move |r, action| {
+            let payload: P = action.from_payload()?;
+            value_ok(f(r, payload)?.into_action_result()?)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+
+    #[derive(Deserialize)]
+    struct Create {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct List {
+        page: u64,
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("users", ());
+        m.on_typed("user.get", |_, c: Create| Result::Ok(Ok { data: c.name }));
+        m.on_typed("user.create", |_, c: Create| {
+            Result::Ok(Created {
+                data: json!({"id": 7, "name": c.name}),
+                location: Some("/users/7".to_owned()),
+            })
+        });
+        m.on_typed("user.delete", |_, _: Value| Result::Ok(NoContent));
+        m.on_typed("user.list", |_, l: List| {
+            Result::Ok(Paged {
+                data: vec![l.page * 10, l.page * 10 + 1],
+                next: Some(format!("{}", l.page + 1)),
+                total: None,
+            })
+        });
+        m.on_typed("user.plain", |_, c: Create| {
+            Result::Ok(json!({"name": c.name}))
+        });
+        m
+    }
+
+    fn run(m: &Manager<()>, name: &str, payload: Value) -> ActionReply {
+        let mut a = Action::new(name, 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        m.do_action(&mut a);
+        m.reply(a)
+    }
+
+    #[test]
+    fn ok() {
+        let r = run(&manager(), "user.get", json!({"name": "bob"}));
+        assert_eq!(r.result, Some(json!({"kind": "ok", "data": "bob"})));
+        assert_eq!(r.envelope_kind(), Some(EnvelopeKind::Ok));
+        assert_eq!(EnvelopeKind::Ok.http_status(), 200);
+    }
+
+    #[test]
+    fn created() {
+        let r = run(&manager(), "user.create", json!({"name": "bob"}));
+        assert_eq!(
+            r.result,
+            Some(
+                json!({"kind": "created", "data": {"id": 7, "name": "bob"}, "location": "/users/7"})
+            )
+        );
+        let kind = r.envelope_kind().unwrap();
+        assert_eq!(kind, EnvelopeKind::Created);
+        assert_eq!(kind.http_status(), 201);
+        assert_eq!(r.envelope_location(), Some("/users/7"));
+    }
+
+    #[test]
+    fn no_content() {
+        let r = run(&manager(), "user.delete", json!({}));
+        assert_eq!(r.result, Some(json!({"kind": "no_content"})));
+        assert_eq!(r.envelope_kind().unwrap().http_status(), 204);
+        assert_eq!(r.envelope_location(), None);
+    }
+
+    #[test]
+    fn paged() {
+        let r = run(&manager(), "user.list", json!({"page": 2}));
+        assert_eq!(
+            r.result,
+            Some(json!({"kind": "paged", "data": [20, 21], "next": "3", "total": null}))
+        );
+        assert_eq!(r.envelope_kind(), Some(EnvelopeKind::Paged));
+    }
+
+    #[test]
+    fn plain_values_unchanged() {
+        let m = manager();
+        let r = run(&m, "user.plain", json!({"name": "bob"}));
+        assert_eq!(r.result, Some(json!({"name": "bob"})));
+        assert_eq!(r.envelope_kind(), None);
+        let r = run(&m, "user.create", json!({}));
+        assert_eq!(r.errors[0].code, "PayloadError");
+    }
+}
@@ -1,4 +1,6 @@
+use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
+use serde_json::Value;
 use std::error;
 use std::fmt;
 
@@ -6,6 +8,9 @@ use std::fmt;
 pub struct ActionError {
     pub code: String,
     pub message: String,
+    /// machine readable context, e.g. which field failed which constraint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
 }
 
 impl ActionError {
@@ -13,8 +18,46 @@ impl ActionError {
         ActionError {
             code: code.to_owned(),
             message: message.to_owned(),
+            details: None,
         }
     }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// marks the error as safe for the client to retry, as `"retryable": true` in details
+    pub fn retryable(mut self) -> Self {
+        match &mut self.details {
+            Some(Value::Object(map)) => {
+                map.insert("retryable".to_owned(), Value::Bool(true));
+            }
+            _ => self.details = Some(json!({ "retryable": true })),
+        }
+        self
+    }
+
+    /// the details deserialized as `T`, `None` when there are none or they do not fit
+    pub fn details_as<T: DeserializeOwned>(&self) -> Option<T> {
+        self.details
+            .as_ref()
+            .and_then(|d| serde_json::from_value(d.clone()).ok())
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.details
+            .as_ref()
+            .and_then(|d| d.get("retryable"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+}
+
+/// a typed error a client recognizes by its code, see `ActionReply::error_as`
+pub trait FromActionError: Sized {
+    /// `None` when the error is not one of `Self`
+    fn from_action_error(e: &ActionError) -> Option<Self>;
 }
 
 impl fmt::Display for ActionError {
@@ -39,6 +82,17 @@ impl From<JsonError> for ActionError {
     }
 }
 
+impl From<std::str::Utf8Error> for ActionError {
+    fn from(error: std::str::Utf8Error) -> Self {
+        let offset = error.valid_up_to();
+        ActionError::new(
+            "Utf8Error",
+            &format!("invalid UTF-8 at byte {}: {}", offset, error),
+        )
+        .with_details(json!({ "offset": offset }))
+    }
+}
+
 impl From<(String, String)> for ActionError {
     fn from((a, b): (String, String)) -> ActionError {
         ActionError::new(&a, &b)
@@ -63,3 +117,68 @@ impl From<Box<dyn std::error::Error>> for ActionError {
         ActionError::new("Boxed::Error", &error.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{Action, Manager};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct NotFound {
+        user_id: u64,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum UserError {
+        NotFound(NotFound),
+        Banned,
+    }
+
+    impl From<UserError> for ActionError {
+        fn from(e: UserError) -> Self {
+            match e {
+                UserError::NotFound(d) => ActionError::new("UserNotFound", "no such user")
+                    .with_details(serde_json::to_value(d).unwrap()),
+                UserError::Banned => ActionError::new("UserBanned", "the user is banned"),
+            }
+        }
+    }
+
+    impl FromActionError for UserError {
+        fn from_action_error(e: &ActionError) -> Option<Self> {
+            match e.code.as_str() {
+                "UserNotFound" => e.details_as().map(UserError::NotFound),
+                "UserBanned" => Some(UserError::Banned),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_through_a_reply() {
+        let mut m = Manager::new("test", ());
+        m.on("user", |_, a| {
+            let err = match a.payload.get("banned") {
+                Some(_) => UserError::Banned,
+                None => UserError::NotFound(NotFound { user_id: 9 }),
+            };
+            Err(ActionError::from(err).into())
+        });
+        let mut a = Action::new("user", 0);
+        let mut banned = a.clone();
+        banned.payload.insert("banned".to_owned(), json!(true));
+
+        m.do_action(&mut a);
+        let reply = m.reply(a);
+        assert_eq!(
+            reply.error_as::<UserError>(),
+            Some(UserError::NotFound(NotFound { user_id: 9 }))
+        );
+        m.do_action(&mut banned);
+        assert_eq!(m.reply(banned).error_as(), Some(UserError::Banned));
+
+        let mut other = Action::server_err(ActionError::new("Other", ""));
+        other.name = "nope".to_owned();
+        assert_eq!(other.into_reply().error_as::<UserError>(), None);
+    }
+}
@@ -0,0 +1,235 @@
+use serde_json::Value;
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+
+/// a payload documenting how an action is used, optionally with the result it gives
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub action: String,
+    pub payload: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<Value>,
+}
+
+/// one place where a result differs from what was expected, `path` is dotted with array
+/// indexes as numbers and `.` for the whole value
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ValueDiff {
+    pub path: String,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+/// an example which no longer works as documented
+#[derive(Serialize, Debug, Clone)]
+pub struct ExampleFailure {
+    pub example: Example,
+    /// errors the action replied with
+    pub errors: Vec<ActionError>,
+    /// how the result differs from the expected one
+    pub diff: Vec<ValueDiff>,
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// the differences between two values, keys listed in `ignore` (by name at any depth,
+/// or by full dotted path) are not compared
+pub fn diff_values(expected: &Value, actual: &Value, ignore: &[String]) -> Vec<ValueDiff> {
+    let mut out = Vec::new();
+    diff_at("", expected, actual, ignore, &mut out);
+    out
+}
+
+fn diff_at(
+    path: &str,
+    expected: &Value,
+    actual: &Value,
+    ignore: &[String],
+    out: &mut Vec<ValueDiff>,
+) {
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => {
+            let mut keys: Vec<&String> = e.keys().chain(a.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let at = join(path, key);
+                if ignore.iter().any(|i| *i == at || i == key) {
+                    continue;
+                }
+                match (e.get(key), a.get(key)) {
+                    (Some(e), Some(a)) => diff_at(&at, e, a, ignore, out),
+                    (e, a) => out.push(ValueDiff {
+                        path: at,
+                        expected: e.cloned(),
+                        actual: a.cloned(),
+                    }),
+                }
+            }
+        }
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => {
+            for (i, (e, a)) in e.iter().zip(a).enumerate() {
+                diff_at(&join(path, &i.to_string()), e, a, ignore, out);
+            }
+        }
+        (e, a) if e != a => out.push(ValueDiff {
+            path: if path.is_empty() {
+                ".".to_owned()
+            } else {
+                path.to_owned()
+            },
+            expected: Some(e.clone()),
+            actual: Some(a.clone()),
+        }),
+        _ => {}
+    }
+}
+
+impl<R> Manager<R> {
+    /// documents `name` with an example payload, `verify_examples` checks it still works
+    pub fn example(&mut self, name: &str, payload: Value) {
+        self.examples_mut().push(Example {
+            action: name.to_owned(),
+            payload,
+            expected: None,
+        });
+    }
+
+    /// like `example` but `verify_examples` also compares the result with `expected`
+    pub fn example_expect(&mut self, name: &str, payload: Value, expected: Value) {
+        self.examples_mut().push(Example {
+            action: name.to_owned(),
+            payload,
+            expected: Some(expected),
+        });
+    }
+
+    /// result keys `verify_examples` does not compare, e.g. generated ids or timestamps
+    pub fn example_ignore(&mut self, keys: &[&str]) {
+        self.example_ignores_mut()
+            .extend(keys.iter().map(|k| (*k).to_owned()));
+    }
+
+    /// dispatches every example with `resource` and returns those that fail or give a
+    /// different result than expected
+    pub fn verify_examples(&self, resource: &R) -> Vec<ExampleFailure> {
+        self.examples()
+            .iter()
+            .filter_map(|example| {
+                let mut action = Action::new(example.action.clone(), 0);
+                action.payload = match serde_json::from_value(example.payload.clone()) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        return Some(ExampleFailure {
+                            example: example.clone(),
+                            errors: vec![ActionError::new(
+                                "PayloadError",
+                                &format!("the example payload is not an object: {}", e),
+                            )],
+                            diff: Vec::new(),
+                        })
+                    }
+                };
+                self.do_action_with(resource, &mut action);
+                let errors = action.errors.unwrap_or_default();
+                let diff = match (&example.expected, &action.result) {
+                    (Some(expected), Some(actual)) if errors.is_empty() => {
+                        diff_values(expected, actual, self.example_ignores())
+                    }
+                    _ => Vec::new(),
+                };
+                if errors.is_empty() && diff.is_empty() {
+                    None
+                } else {
+                    Some(ExampleFailure {
+                        example: example.clone(),
+                        errors,
+                        diff,
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+
+    struct Db {
+        next_id: u64,
+    }
+
+    fn manager() -> Manager<Db> {
+        let mut m = Manager::new("users", Db { next_id: 1 });
+        m.on("user.create", |db, a| {
+            value_ok(json!({"id": db.next_id, "name": a.payload["name"], "tags": ["new"]}))
+        });
+        m.on("user.delete", |_, _| {
+            Err(Box::new(ActionError::new("Forbidden", "no")))
+        });
+        m.example_ignore(&["id"]);
+        m
+    }
+
+    #[test]
+    fn matching_expectation() {
+        let mut m = manager();
+        m.example_expect(
+            "user.create",
+            json!({"name": "bob"}),
+            json!({"id": 999, "name": "bob", "tags": ["new"]}),
+        );
+        m.example("user.create", json!({"name": "amy"}));
+        assert!(m.verify_examples(&Db { next_id: 42 }).is_empty());
+        let info = m.list_actions_detailed();
+        assert_eq!(info[0].examples.len(), 2);
+    }
+
+    #[test]
+    fn mismatched_expectation() {
+        let mut m = manager();
+        m.example_expect(
+            "user.create",
+            json!({"name": "bob"}),
+            json!({"name": "bobby", "tags": ["old"], "email": null}),
+        );
+        let failures = m.verify_examples(&Db { next_id: 1 });
+        assert_eq!(failures.len(), 1);
+        let paths: Vec<&str> = failures[0].diff.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["email", "name", "tags.0"]);
+        assert_eq!(failures[0].diff[0].actual, None);
+        assert_eq!(failures[0].diff[1].actual, Some(json!("bob")));
+    }
+
+    #[test]
+    fn handler_error() {
+        let mut m = manager();
+        m.example("user.delete", json!({"id": 1}));
+        m.example("user.missing", json!({}));
+        let failures = m.verify_examples(&Db { next_id: 1 });
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].errors[0].code, "Forbidden");
+        assert_eq!(failures[1].errors[0].code, "users - DoAction");
+    }
+
+    #[test]
+    fn diff_whole_values() {
+        let d = diff_values(&json!([1, 2]), &json!([1]), &[]);
+        assert_eq!(d[0].path, ".");
+        assert!(diff_values(
+            &json!({"a": {"id": 1}}),
+            &json!({"a": {"id": 2}}),
+            &["a.id".to_owned()]
+        )
+        .is_empty());
+    }
+}
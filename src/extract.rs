@@ -0,0 +1,240 @@
+//! pieces of an action a handler can ask for in its signature, see `Manager::on_extract`
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::marker::PhantomData;
+
+use crate::action::{value_ok, Action, Manager};
+use crate::ctx::ActionCtx;
+use crate::envelope::IntoActionResult;
+use crate::error::ActionError;
+
+/// something built out of the dispatched action before the handler runs
+pub trait Extract<R>: Sized {
+    fn extract(action: &Action, ctx: &ActionCtx<'_>, resource: &R) -> Result<Self, ActionError>;
+}
+
+/// the whole payload deserialized as `T`
+pub struct Payload<T>(pub T);
+
+impl<R, T: DeserializeOwned> Extract<R> for Payload<T> {
+    fn extract(action: &Action, _: &ActionCtx<'_>, _: &R) -> Result<Self, ActionError> {
+        action.from_payload().map(Payload)
+    }
+}
+
+/// names the payload key a `Field` reads, declared with `field_name!`
+pub trait FieldName {
+    const NAME: &'static str;
+}
+
+/// declares a `FieldName` type, `field_name!(UserId, "user_id")`
+#[macro_export]
+macro_rules! field_name {
+    ($name:ident, $key:expr) => {
+        pub struct $name;
+        impl $crate::extract::FieldName for $name {
+            const NAME: &'static str = $key;
+        }
+    };
+}
+
+/// the payload key `N::NAME` deserialized as `T`; a missing key only passes when `T`
+/// accepts null, like an `Option`
+pub struct Field<N, T>(pub T, PhantomData<N>);
+
+impl<N, T> Field<N, T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<R, N: FieldName, T: DeserializeOwned> Extract<R> for Field<N, T> {
+    fn extract(action: &Action, _: &ActionCtx<'_>, _: &R) -> Result<Self, ActionError> {
+        let value = action.payload.get(N::NAME);
+        match serde_json::from_value(value.cloned().unwrap_or(Value::Null)) {
+            Ok(v) => Ok(Field(v, PhantomData)),
+            Err(_) if value.is_none() => Err(ActionError::new(
+                "ValidationError",
+                &format!("missing required field: {}", N::NAME),
+            )
+            .with_details(json!({"field": N::NAME, "constraint": "required"}))),
+            Err(e) => Err(ActionError::new(
+                "ValidationError",
+                &format!("{} is invalid: {}", N::NAME, e),
+            )
+            .with_details(json!({"field": N::NAME, "constraint": "type"}))),
+        }
+    }
+}
+
+/// the action token, failing with `TokenMissing` when there is none
+pub struct Token(pub String);
+
+impl<R> Extract<R> for Token {
+    fn extract(action: &Action, _: &ActionCtx<'_>, _: &R) -> Result<Self, ActionError> {
+        match &action.token {
+            Some(t) => Ok(Token(t.clone())),
+            None => Err(ActionError::new(
+                "TokenMissing",
+                &format!("{} needs a token", action.name),
+            )),
+        }
+    }
+}
+
+/// where the action came from, see `ActionCtx::source`
+pub struct Source(pub Option<String>);
+
+impl<R> Extract<R> for Source {
+    fn extract(_: &Action, ctx: &ActionCtx<'_>, _: &R) -> Result<Self, ActionError> {
+        Ok(Source(ctx.source().map(|s| s.to_owned())))
+    }
+}
+
+/// the correlation id of the action, see `ActionCtx::correlation_id`
+pub struct Correlation(pub Option<String>);
+
+impl<R> Extract<R> for Correlation {
+    fn extract(_: &Action, ctx: &ActionCtx<'_>, _: &R) -> Result<Self, ActionError> {
+        Ok(Correlation(ctx.correlation_id().map(|s| s.to_owned())))
+    }
+}
+
+/// a copy of the action as it reached the handler
+pub struct RawAction(pub Action);
+
+impl<R> Extract<R> for RawAction {
+    fn extract(action: &Action, _: &ActionCtx<'_>, _: &R) -> Result<Self, ActionError> {
+        Ok(RawAction(action.clone()))
+    }
+}
+
+macro_rules! tuple_extract {
+    ($($t:ident),+) => {
+        impl<R, $($t: Extract<R>),+> Extract<R> for ($($t,)+) {
+            fn extract(
+                action: &Action,
+                ctx: &ActionCtx<'_>,
+                resource: &R,
+            ) -> Result<Self, ActionError> {
+                Ok(($($t::extract(action, ctx, resource)?,)+))
+            }
+        }
+    };
+}
+
+tuple_extract!(A);
+tuple_extract!(A, B);
+tuple_extract!(A, B, C);
+tuple_extract!(A, B, C, D);
+tuple_extract!(A, B, C, D, E);
+
+impl<R> Manager<R> {
+    /// registers a handler taking what `E` extracts from the action, the first failing
+    /// extractor fails the action with its error
+    pub fn on_extract<E, O, F>(&mut self, name: &str, f: F)
+    where
+        E: Extract<R>,
+        O: IntoActionResult,
+        F: Fn(&R, E) -> Result<O, ActionError> + Send + Sync + 'static,
+    {
+        self.on_ctx(name, move |r, action, ctx| {
+            let extracted = E::extract(action, ctx, r)?;
+            value_ok(f(r, extracted)?.into_action_result()?)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    field_name!(UserId, "user_id");
+    field_name!(Note, "note");
+
+    #[derive(Deserialize)]
+    struct Rename {
+        user_id: u64,
+        name: String,
+    }
+
+    fn action(name: &str, payload: Value) -> Action {
+        let mut a = Action::new(name, 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    fn manager() -> Manager<u64> {
+        let mut m = Manager::new("test", 7);
+        m.on_extract("payload", |_, Payload(p): Payload<Rename>| {
+            Ok(format!("{}:{}", p.user_id, p.name))
+        });
+        m.on_extract("field", |_, Field(id, _): Field<UserId, u64>| Ok(id));
+        m.on_extract("token", |_, Token(t)| Ok(t));
+        m.on_extract("raw", |_, RawAction(a)| Ok(a.name));
+        m.on_extract("source", |_, Source(s)| Ok(s));
+        m.on_extract(
+            "combined",
+            |r: &u64,
+             (Field(id, _), Field(note, _), Token(t), Correlation(c)): (
+                Field<UserId, u64>,
+                Field<Note, Option<String>>,
+                Token,
+                Correlation,
+            )| { Ok(json!([*r + id, note, t, c])) },
+        );
+        m
+    }
+
+    fn run(m: &Manager<u64>, mut a: Action) -> Action {
+        m.do_action(&mut a);
+        a
+    }
+
+    #[test]
+    fn each_extractor() {
+        let m = manager();
+        let payload = json!({"user_id": 3, "name": "ann"});
+        let a = run(&m, action("payload", payload.clone()));
+        assert_eq!(a.result, Some(json!("3:ann")));
+        let a = run(&m, action("field", payload.clone()));
+        assert_eq!(a.result, Some(json!(3)));
+        let a = run(&m, action("raw", json!({})));
+        assert_eq!(a.result, Some(json!("raw")));
+
+        let mut a = action("token", json!({}));
+        a.token = Some("t1".to_owned());
+        assert_eq!(run(&m, a).result, Some(json!("t1")));
+        let a = run(&m, action("token", json!({})));
+        assert_eq!(a.errors.unwrap()[0].code, "TokenMissing");
+
+        let mut a = action("source", json!({}));
+        m.do_action_from(crate::source::WS, &mut a);
+        assert_eq!(a.result, Some(json!("ws")));
+    }
+
+    #[test]
+    fn tuple() {
+        let m = manager();
+        let mut a = action("combined", json!({"user_id": 3}));
+        a.token = Some("t1".to_owned());
+        a.correlation_id = Some("c1".to_owned());
+        assert_eq!(run(&m, a).result, Some(json!([10, null, "t1", "c1"])));
+    }
+
+    #[test]
+    fn failing_field() {
+        let m = manager();
+        let a = run(&m, action("field", json!({})));
+        let e = &a.errors.unwrap()[0];
+        assert_eq!(e.code, "ValidationError");
+        assert_eq!(e.message, "missing required field: user_id");
+        assert_eq!(e.details.as_ref().unwrap()["field"], json!("user_id"));
+
+        let a = run(&m, action("field", json!({"user_id": "three"})));
+        let e = &a.errors.unwrap()[0];
+        assert!(e.message.starts_with("user_id is invalid: "));
+        assert_eq!(e.details.as_ref().unwrap()["constraint"], json!("type"));
+    }
+}
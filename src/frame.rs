@@ -0,0 +1,204 @@
+//! length prefixed framing over plain `Read` and `Write`: each frame is a 4 byte big
+//! endian length and that many bytes of JSON, so values may hold newlines and the body
+//! could as well be another encoding
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{ErrorKind, Read, Write};
+
+use crate::action::{Action, ActionReply};
+use crate::error::ActionError;
+
+/// largest frame `read_frame` accepts
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 << 20;
+
+const PREFIX_LEN: usize = 4;
+
+/// reads and writes length prefixed frames, see the module docs
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    max_frame_size: u32,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// frames longer than `max` bytes fail with `FrameTooLarge` before they are read
+    pub fn with_max_frame_size(mut self, max: u32) -> Self {
+        self.max_frame_size = max;
+        self
+    }
+
+    /// the body of the next frame, `None` when the reader ends before a new frame
+    /// starts. Fails with `TruncatedFrame` when it ends inside one, `EmptyFrame` for a
+    /// zero length and `FrameTooLarge` past the maximum
+    pub fn read_body<R: Read>(&self, mut r: R) -> Result<Option<Vec<u8>>, ActionError> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        let mut read = 0;
+        while read < PREFIX_LEN {
+            match r.read(&mut prefix[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(ActionError::new(
+                        "TruncatedFrame",
+                        "stream ended inside a frame length",
+                    ))
+                }
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let len = BigEndian::read_u32(&prefix);
+        if len == 0 {
+            return Err(ActionError::new("EmptyFrame", "frame has a length of zero"));
+        }
+        if len > self.max_frame_size {
+            return Err(ActionError::new(
+                "FrameTooLarge",
+                &format!(
+                    "frame of {} bytes is longer than {}",
+                    len, self.max_frame_size
+                ),
+            )
+            .with_details(json!({ "length": len, "max_frame_size": self.max_frame_size })));
+        }
+        let mut body = vec![0u8; len as usize];
+        r.read_exact(&mut body).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => ActionError::new(
+                "TruncatedFrame",
+                &format!("stream ended inside a frame of {} bytes", len),
+            ),
+            _ => e.into(),
+        })?;
+        Ok(Some(body))
+    }
+
+    /// the next action, `None` at the end of the stream
+    pub fn read_action<R: Read>(&self, r: R) -> Result<Option<Action>, ActionError> {
+        match self.read_body(r)? {
+            Some(body) => Action::from_slice(&body).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// writes `body` as one frame
+    pub fn write_body<W: Write>(&self, mut w: W, body: &[u8]) -> Result<(), ActionError> {
+        if body.len() > self.max_frame_size as usize {
+            return Err(ActionError::new(
+                "FrameTooLarge",
+                &format!(
+                    "frame of {} bytes is longer than {}",
+                    body.len(),
+                    self.max_frame_size
+                ),
+            ));
+        }
+        let mut prefix = [0u8; PREFIX_LEN];
+        BigEndian::write_u32(&mut prefix, body.len() as u32);
+        w.write_all(&prefix)?;
+        w.write_all(body)?;
+        Ok(())
+    }
+
+    pub fn write_reply<W: Write>(&self, w: W, reply: &ActionReply) -> Result<(), ActionError> {
+        self.write_body(w, &reply.to_bytes()?)
+    }
+
+    pub fn write_action<W: Write>(&self, w: W, action: &Action) -> Result<(), ActionError> {
+        self.write_body(w, &action.to_bytes()?)
+    }
+}
+
+/// writes `reply` as one frame
+pub fn write_frame<W: Write>(w: W, reply: &ActionReply) -> Result<(), ActionError> {
+    FrameCodec::new().write_reply(w, reply)
+}
+
+/// reads one action frame of at most `DEFAULT_MAX_FRAME_SIZE` bytes; a stream ending
+/// before the frame starts fails with `EndOfStream`
+pub fn read_frame<R: Read>(r: R) -> Result<Action, ActionError> {
+    FrameCodec::new()
+        .read_action(r)?
+        .ok_or_else(|| ActionError::new("EndOfStream", "stream ended before a frame"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// hands out at most 3 bytes per read
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn frames() -> Vec<u8> {
+        let codec = FrameCodec::new();
+        let mut buf = Vec::new();
+        for id in 1..=3 {
+            let mut a = Action::new("note", id);
+            a.payload
+                .insert("text".to_owned(), json!("line one\nline two"));
+            codec.write_action(&mut buf, &a).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn back_to_back() {
+        let buf = frames();
+        for mut r in [
+            Box::new(Cursor::new(buf.clone())) as Box<dyn Read>,
+            Box::new(Trickle(&buf)),
+        ] {
+            let ids: Vec<u64> = (0..3).map(|_| read_frame(&mut r).unwrap().id).collect();
+            assert_eq!(ids, vec![1, 2, 3]);
+            assert_eq!(read_frame(&mut r).unwrap_err().code, "EndOfStream");
+        }
+
+        let mut a = Action::new("note", 9);
+        a.set_result(json!("done"));
+        let mut out = Vec::new();
+        write_frame(&mut out, &a.into_reply()).unwrap();
+        assert_eq!(BigEndian::read_u32(&out) as usize, out.len() - 4);
+    }
+
+    #[test]
+    fn truncated_empty_and_oversized() {
+        let buf = frames();
+        let mut r = Cursor::new(&buf[..buf.len() - 5]);
+        read_frame(&mut r).unwrap();
+        read_frame(&mut r).unwrap();
+        assert_eq!(read_frame(&mut r).unwrap_err().code, "TruncatedFrame");
+        let mut r = Cursor::new(&buf[..2]);
+        assert_eq!(read_frame(&mut r).unwrap_err().code, "TruncatedFrame");
+
+        let mut r = Cursor::new(vec![0, 0, 0, 0]);
+        assert_eq!(read_frame(&mut r).unwrap_err().code, "EmptyFrame");
+
+        let codec = FrameCodec::new().with_max_frame_size(16);
+        let err = codec.read_action(Cursor::new(&buf)).unwrap_err();
+        assert_eq!(err.code, "FrameTooLarge");
+        let err = codec
+            .write_action(Vec::new(), &Action::new("note", 1))
+            .unwrap_err();
+        assert_eq!(err.code, "FrameTooLarge");
+    }
+}
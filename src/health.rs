@@ -0,0 +1,320 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::action::{value_ok, Manager, ResourceGen};
+use crate::error::ActionError;
+
+/// name of the action registered by `Manager::enable_health`
+pub const HEALTH_ACTION: &str = "__health";
+
+/// the state of the resource as last seen by the probe, see `Manager::resource_probe`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResourceHealth {
+    pub healthy: bool,
+    /// what the last failing probe returned, cleared once a probe passes again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ActionError>,
+    pub probes: u64,
+    pub failures: u64,
+}
+
+#[derive(Default)]
+struct ProbeState {
+    last: Option<Instant>,
+    failure: Option<ActionError>,
+    probes: u64,
+    failures: u64,
+}
+
+impl ProbeState {
+    fn health(&self) -> ResourceHealth {
+        ResourceHealth {
+            healthy: self.failure.is_none(),
+            error: self.failure.clone(),
+            probes: self.probes,
+            failures: self.failures,
+        }
+    }
+}
+
+fn unhealthy(cause: &ActionError) -> ActionError {
+    ActionError::new(
+        "ResourceUnhealthy",
+        &format!("the resource is unhealthy: {}", cause.message),
+    )
+    .with_details(json!({ "cause": cause.code }))
+    .retryable()
+}
+
+type ProbeFn<R> = dyn Fn(&R) -> Result<(), ActionError> + Send + Sync;
+
+pub(crate) struct ResourceProbe<R> {
+    check: Box<ProbeFn<R>>,
+    every: Duration,
+    state: Arc<Mutex<ProbeState>>,
+}
+
+impl<R> ResourceProbe<R> {
+    /// probes `resource` when the interval has passed since the last probe; a failing
+    /// resource is replaced through `regenerate` when there is one and probed once more.
+    /// Returns the replacement, or the error to fail the dispatch with while unhealthy
+    pub(crate) fn check(
+        &self,
+        resource: &R,
+        now: Instant,
+        regenerate: Option<&ResourceGen<R>>,
+    ) -> Result<Option<R>, ActionError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let due = match state.last {
+            Some(last) => now.saturating_duration_since(last) >= self.every,
+            None => true,
+        };
+        if !due {
+            return match &state.failure {
+                Some(e) => Err(unhealthy(e)),
+                None => Ok(None),
+            };
+        }
+        state.last = Some(now);
+        state.probes += 1;
+        let mut replacement = None;
+        let mut res = (self.check)(resource);
+        if let (Err(_), Some(regenerate)) = (&res, regenerate) {
+            match regenerate() {
+                Ok(fresh) => {
+                    res = (self.check)(&fresh);
+                    replacement = Some(fresh);
+                }
+                Err(e) => res = Err(e),
+            }
+        }
+        match res {
+            Ok(()) => {
+                state.failure = None;
+                Ok(replacement)
+            }
+            Err(e) => {
+                state.failures += 1;
+                let err = unhealthy(&e);
+                state.failure = Some(e);
+                Err(err)
+            }
+        }
+    }
+
+    fn health(&self) -> ResourceHealth {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .health()
+    }
+}
+
+impl<R> Manager<R> {
+    /// probes the resource with `f` at most once every `check_every`, piggybacked on
+    /// dispatch. While the probe fails every action gets a retryable `ResourceUnhealthy`
+    /// error instead of running, until a later probe passes; managers generating their
+    /// resource first replace a failing one and probe it again
+    pub fn resource_probe<F>(&mut self, f: F, check_every: Duration)
+    where
+        F: Fn(&R) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        *self.resource_probe_mut() = Some(ResourceProbe {
+            check: Box::new(f),
+            every: check_every,
+            state: Arc::new(Mutex::new(ProbeState::default())),
+        });
+    }
+
+    /// runs the probe as a dispatch at `now` would, healthy when there is no probe. A
+    /// lazy resource is made first, a pooled one is taken when one is free and replaced
+    /// in the pool when broken
+    pub fn check_resource_at(&self, now: Instant) -> ResourceHealth {
+        let probe = match self.probe() {
+            Some(p) => p,
+            None => return self.resource_health(),
+        };
+        match (self.gen_resource(), self.pool()) {
+            (Some(gen_resource), _) => {
+                if let Ok(r) = gen_resource() {
+                    let _ = probe.check(&r, now, Some(gen_resource));
+                }
+            }
+            (None, Some(pool)) => {
+                if let Some(Ok(mut r)) = pool.try_take() {
+                    if let Ok(Some(fresh)) = probe.check(&r, now, Some(pool.make())) {
+                        *r = fresh;
+                    }
+                }
+            }
+            (None, None) => {
+                if let Some(Ok(r)) = self.resource() {
+                    let _ = probe.check(r, now, None);
+                }
+            }
+        }
+        probe.health()
+    }
+
+    /// the state as of the last probe
+    pub fn resource_health(&self) -> ResourceHealth {
+        match self.probe() {
+            Some(p) => p.health(),
+            None => ResourceHealth {
+                healthy: true,
+                ..ResourceHealth::default()
+            },
+        }
+    }
+
+    /// registers the `__health` action answering with the `ResourceHealth`; it keeps
+    /// working while the resource is unhealthy. Call it after `resource_probe`
+    pub fn enable_health(&mut self) {
+        let state = self.probe().map(|p| p.state.clone());
+        self.on(HEALTH_ACTION, move |_, _| match &state {
+            Some(s) => value_ok(s.lock().unwrap_or_else(|e| e.into_inner()).health()),
+            None => value_ok(ResourceHealth {
+                healthy: true,
+                ..ResourceHealth::default()
+            }),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{Action, Manager};
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    fn probe(r: &Arc<AtomicBool>) -> Result<(), ActionError> {
+        if r.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(ActionError::new(
+                "ConnectionLost",
+                "connection reset by peer",
+            ))
+        }
+    }
+
+    #[test]
+    fn degrades_and_recovers() {
+        let up = Arc::new(AtomicBool::new(false));
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut m = Manager::new("test", up.clone());
+        m.resource_probe(probe, Duration::from_secs(10));
+        let c = calls.clone();
+        m.on("ok", move |_, _| {
+            c.fetch_add(1, Ordering::SeqCst);
+            value_ok(true)
+        });
+
+        let t0 = Instant::now();
+        assert!(!m.check_resource_at(t0).healthy);
+        let mut a = action("ok");
+        m.do_action(&mut a);
+        let e = &a.errors.unwrap()[0];
+        assert_eq!(e.code, "ResourceUnhealthy");
+        assert_eq!(e.details.as_ref().unwrap()["retryable"], json!(true));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // the health action still answers while degraded
+        m.enable_health();
+        let mut a = action(HEALTH_ACTION);
+        m.do_action(&mut a);
+        assert_eq!(a.result.unwrap()["healthy"], json!(false));
+
+        // not probed again before the interval is up
+        up.store(true, Ordering::SeqCst);
+        assert!(!m.check_resource_at(t0 + Duration::from_secs(5)).healthy);
+        let health = m.check_resource_at(t0 + Duration::from_secs(10));
+        assert!(health.healthy && health.error.is_none());
+        assert_eq!((health.probes, health.failures), (2, 1));
+
+        let mut a = action("ok");
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn generated_resource_is_replaced() {
+        let made = Arc::new(AtomicU32::new(0));
+        let n = made.clone();
+        let mut m = Manager::with("test", move || n.fetch_add(1, Ordering::SeqCst) + 1);
+        // the first resource made is broken
+        m.resource_probe(
+            |r: &u32| {
+                if *r == 1 {
+                    Err(ActionError::new("Stale", "stale"))
+                } else {
+                    Ok(())
+                }
+            },
+            Duration::from_secs(10),
+        );
+        m.on("which", |r, _| value_ok(*r));
+        let mut a = action("which");
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+        assert_eq!(a.result, Some(json!(2)));
+        assert!(m.resource_health().healthy);
+    }
+
+    fn stale_first(r: &u32) -> Result<(), ActionError> {
+        if *r == 1 {
+            Err(ActionError::new("Stale", "stale"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn counting_pool(made: &Arc<AtomicU32>) -> Manager<u32> {
+        let n = made.clone();
+        let mut m = Manager::pooled("db", 1, move || Ok(n.fetch_add(1, Ordering::SeqCst) + 1));
+        m.resource_probe(stale_first, Duration::from_secs(10));
+        m.on("which", |r, _| value_ok(*r));
+        m
+    }
+
+    #[test]
+    fn pooled_resource_is_replaced() {
+        let made = Arc::new(AtomicU32::new(0));
+        let m = counting_pool(&made);
+        for _ in 0..2 {
+            let mut a = action("which");
+            m.do_action(&mut a);
+            assert!(a.errors.is_none());
+            assert_eq!(a.result, Some(json!(2)));
+        }
+        assert_eq!(made.load(Ordering::SeqCst), 2);
+        let status = m.pool_status().unwrap();
+        assert_eq!((status.live, status.idle), (1, 1));
+    }
+
+    #[test]
+    fn checks_pooled_and_lazy_resources() {
+        let made = Arc::new(AtomicU32::new(0));
+        let m = counting_pool(&made);
+        let health = m.check_resource_at(Instant::now());
+        assert!(health.healthy);
+        assert_eq!(health.probes, 1);
+        // the broken one was swapped out of the pool
+        let mut a = action("which");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(2)));
+
+        let up = Arc::new(AtomicBool::new(false));
+        let shared = up.clone();
+        let mut m = Manager::lazy("test", move || Ok(shared.clone()));
+        m.resource_probe(probe, Duration::from_secs(10));
+        let health = m.check_resource_at(Instant::now());
+        assert!(!health.healthy);
+        assert_eq!(health.error.unwrap().code, "ConnectionLost");
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use crate::action::ActionReply;
+use crate::maintenance::{SweepStats, SWEEP_BUDGET};
+
+/// byte budget of `Manager::keep_recent_replies` unless set otherwise
+pub const DEFAULT_REPLY_LOG_BYTES: usize = 4 * 1024 * 1024;
+
+struct Entry {
+    at: SystemTime,
+    size: usize,
+    reply: ActionReply,
+}
+
+/// the most recent replies, bounded by count and by their total serialized size
+pub(crate) struct ReplyLog {
+    capacity: usize,
+    max_bytes: usize,
+    bytes: usize,
+    max_age: Option<Duration>,
+    entries: VecDeque<Entry>,
+}
+
+impl ReplyLog {
+    pub(crate) fn new(capacity: usize, max_bytes: usize) -> Self {
+        ReplyLog {
+            capacity,
+            max_bytes,
+            bytes: 0,
+            max_age: None,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    pub(crate) fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age);
+    }
+
+    /// drops replies older than the max age, oldest first
+    pub(crate) fn sweep(&mut self, now: SystemTime) -> SweepStats {
+        let mut stats = SweepStats::default();
+        let cutoff = match self.max_age.and_then(|age| now.checked_sub(age)) {
+            Some(cutoff) => cutoff,
+            None => return stats,
+        };
+        while stats.scanned < SWEEP_BUDGET {
+            match self.entries.front() {
+                Some(e) if e.at < cutoff => {
+                    stats.scanned += 1;
+                    stats.evicted += 1;
+                    stats.bytes_freed += e.size;
+                    self.bytes -= e.size;
+                    self.entries.pop_front();
+                }
+                Some(_) => {
+                    stats.scanned += 1;
+                    break;
+                }
+                None => break,
+            }
+        }
+        stats
+    }
+
+    pub(crate) fn push(&mut self, at: SystemTime, reply: ActionReply) {
+        let size = serde_json::to_vec(&reply).map(|v| v.len()).unwrap_or(0);
+        if size > self.max_bytes || self.capacity == 0 {
+            return;
+        }
+        self.bytes += size;
+        self.entries.push_back(Entry { at, size, reply });
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity || self.bytes > self.max_bytes {
+            match self.entries.pop_front() {
+                Some(e) => self.bytes -= e.size,
+                None => break,
+            }
+        }
+    }
+
+    /// newest reply with that id
+    pub(crate) fn find(&self, id: u64) -> Option<ActionReply> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| e.reply.id == id)
+            .map(|e| e.reply.clone())
+    }
+
+    /// replies recorded at or after `since`, oldest first, optionally only for one action
+    pub(crate) fn recent(&self, name: Option<&str>, since: Option<SystemTime>) -> Vec<ActionReply> {
+        self.entries
+            .iter()
+            .filter(|e| name.is_none_or(|n| e.reply.name == n))
+            .filter(|e| since.is_none_or(|s| e.at >= s))
+            .map(|e| e.reply.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager};
+    use std::time::Duration;
+
+    fn action(name: &str, id: u64) -> Action {
+        Action::new(name, id)
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("login", |_, _| {
+            value_ok(json!({"user": "bob", "secret": "s3cr3t"}))
+        });
+        m.on("ping", |_, _| value_ok("pong"));
+        m.keep_recent_replies(10);
+        m
+    }
+
+    fn run(m: &Manager<()>, name: &str, id: u64) {
+        let mut a = action(name, id);
+        m.do_action(&mut a);
+        m.reply(a);
+    }
+
+    #[test]
+    fn lookup_hit_and_miss() {
+        let m = manager();
+        run(&m, "ping", 1);
+        run(&m, "login", 18443);
+        run(&m, "ping", 2);
+        assert_eq!(m.find_reply(18443).unwrap().name, "login");
+        assert!(m.find_reply(5).is_none());
+        let pings: Vec<u64> = m
+            .recent_replies(Some("ping"))
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(pings, vec![1, 2]);
+        assert_eq!(m.recent_replies(None).len(), 3);
+    }
+
+    #[test]
+    fn stored_replies_are_redacted() {
+        let mut m = manager();
+        m.redact_keys(&["secret"]);
+        let mut a = action("login", 1);
+        m.do_action(&mut a);
+        let live = m.reply(a);
+        assert_eq!(live.result.unwrap()["secret"], json!("s3cr3t"));
+        let stored = m.find_reply(1).unwrap().result.unwrap();
+        assert_eq!(stored["secret"], json!(crate::action::REDACTED));
+        assert_eq!(stored["user"], json!("bob"));
+    }
+
+    #[test]
+    fn evicts_oldest_by_count_and_bytes() {
+        let mut log = ReplyLog::new(3, 10_000);
+        let at = SystemTime::now();
+        for id in 0..5 {
+            log.push(at, action("ping", id).into_reply());
+        }
+        let ids: Vec<u64> = log.recent(None, None).iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+
+        let one = serde_json::to_vec(&action("ping", 0).into_reply())
+            .unwrap()
+            .len();
+        let mut log = ReplyLog::new(100, one * 2 + one / 2);
+        for id in 0..5 {
+            log.push(at, action("ping", id).into_reply());
+        }
+        let ids: Vec<u64> = log.recent(None, None).iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![3, 4]);
+        assert!(log.bytes <= one * 2 + one / 2);
+        log.set_max_bytes(one);
+        assert_eq!(log.recent(None, None).len(), 1);
+    }
+
+    #[test]
+    fn since_filter() {
+        let mut log = ReplyLog::new(10, 10_000);
+        let t0 = SystemTime::now();
+        log.push(t0, action("ping", 1).into_reply());
+        log.push(t0 + Duration::from_secs(5), action("ping", 2).into_reply());
+        let ids: Vec<u64> = log
+            .recent(None, Some(t0 + Duration::from_secs(1)))
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(ids, vec![2]);
+    }
+}
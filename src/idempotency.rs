@@ -0,0 +1,177 @@
+//! which actions are safe to run again, see `Manager::classify`
+
+use crate::action::{value_ok, ActionReply, Manager};
+
+/// name of the built-in introspection action, see `Manager::enable_introspection`
+pub const ACTIONS_ACTION: &str = "__actions";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Idempotency {
+    /// running it twice has the same effect as running it once
+    Idempotent,
+    /// safe to run again as long as the client resends the same idempotency key
+    IdempotentWithKey,
+    /// may have an effect every time it runs, what unclassified actions are
+    #[default]
+    NonIdempotent,
+}
+
+impl Idempotency {
+    pub fn is_retry_safe(self) -> bool {
+        self != Idempotency::NonIdempotent
+    }
+}
+
+/// an action as listed by `__actions`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActionInfo {
+    pub name: String,
+    #[serde(default)]
+    pub idempotency: Idempotency,
+}
+
+/// when a failed action may be sent again automatically: only when an error is
+/// retryable and the action is not `NonIdempotent`, unless `retry_non_idempotent` is set
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retry_non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    pub fn should_retry(&self, idempotency: Idempotency, reply: &ActionReply) -> bool {
+        reply.errors.iter().any(|e| e.is_retryable())
+            && (idempotency.is_retry_safe() || self.retry_non_idempotent)
+    }
+
+    /// `should_retry` going by the classifications a client fetched from `__actions`,
+    /// actions missing from `actions` count as `NonIdempotent`
+    pub fn should_retry_listed(&self, actions: &[ActionInfo], reply: &ActionReply) -> bool {
+        let idempotency = actions
+            .iter()
+            .find(|a| a.name == reply.name)
+            .map(|a| a.idempotency)
+            .unwrap_or_default();
+        self.should_retry(idempotency, reply)
+    }
+}
+
+impl<R> Manager<R> {
+    /// records whether `name` is safe to run again, actions are `NonIdempotent` until
+    /// classified
+    pub fn classify(&mut self, name: &str, idempotency: Idempotency) {
+        self.idempotency_mut().insert(name.to_owned(), idempotency);
+    }
+
+    pub fn idempotency(&self, name: &str) -> Idempotency {
+        self.idempotency_of(name)
+    }
+
+    /// the registered actions sorted by name
+    pub fn describe_actions(&self) -> Vec<ActionInfo> {
+        let mut actions: Vec<ActionInfo> = self
+            .action_names()
+            .map(|name| ActionInfo {
+                name: name.to_owned(),
+                idempotency: self.idempotency_of(name),
+            })
+            .collect();
+        actions.sort_by(|a, b| a.name.cmp(&b.name));
+        actions
+    }
+
+    /// registers the `__actions` action answering with `describe_actions`. Call it after
+    /// registering and classifying the actions, later ones are not listed
+    pub fn enable_introspection(&mut self) {
+        self.classify(ACTIONS_ACTION, Idempotency::Idempotent);
+        let mut actions = self.describe_actions();
+        actions.push(ActionInfo {
+            name: ACTIONS_ACTION.to_owned(),
+            idempotency: Idempotency::Idempotent,
+        });
+        actions.sort_by(|a, b| a.name.cmp(&b.name));
+        self.on(ACTIONS_ACTION, move |_, _| value_ok(&actions));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action};
+    use crate::error::ActionError;
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("user.get", |_, _| action_ok());
+        m.on("order.create", |_, _| action_ok());
+        m.on("payment.charge", |_, _| {
+            Err(ActionError::new("Timeout", "gateway timed out")
+                .retryable()
+                .into())
+        });
+        m.on("report.get", |_, _| {
+            Err(ActionError::new("Timeout", "timed out").retryable().into())
+        });
+        m.classify("user.get", Idempotency::Idempotent);
+        m.classify("report.get", Idempotency::Idempotent);
+        m.classify("order.create", Idempotency::IdempotentWithKey);
+        m
+    }
+
+    #[test]
+    fn retry_refused_for_non_idempotent() {
+        let m = manager();
+        assert_eq!(m.idempotency("payment.charge"), Idempotency::NonIdempotent);
+        let (charge, _) = m.handle_with_outcome(action("payment.charge"));
+        assert!(charge.errors[0].is_retryable());
+        let (report, _) = m.handle_with_outcome(action("report.get"));
+
+        let policy = RetryPolicy::default();
+        assert!(!policy.should_retry(m.idempotency("payment.charge"), &charge));
+        assert!(policy.should_retry(m.idempotency("report.get"), &report));
+        let policy = RetryPolicy {
+            retry_non_idempotent: true,
+        };
+        assert!(policy.should_retry(m.idempotency("payment.charge"), &charge));
+
+        // errors not flagged retryable are never retried
+        let mut a = action("user.get");
+        a.set_error(ActionError::new("NotFound", "no such user"));
+        assert!(!policy.should_retry(Idempotency::Idempotent, &a.into_reply()));
+    }
+
+    #[test]
+    fn introspection_round_trip() {
+        let mut m = manager();
+        m.enable_introspection();
+        let (reply, _) = m.handle_with_outcome(action(ACTIONS_ACTION));
+        let bytes = reply.to_bytes().unwrap();
+        let reply: ActionReply = serde_json::from_slice(&bytes).unwrap();
+        let listed: Vec<ActionInfo> = serde_json::from_value(reply.result.unwrap()).unwrap();
+        assert_eq!(listed, m.describe_actions());
+        let names: Vec<&str> = listed.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                ACTIONS_ACTION,
+                "order.create",
+                "payment.charge",
+                "report.get",
+                "user.get"
+            ]
+        );
+        assert_eq!(listed[1].idempotency, Idempotency::IdempotentWithKey);
+
+        // a client going by the list it fetched
+        let (charge, _) = m.handle_with_outcome(action("payment.charge"));
+        let (report, _) = m.handle_with_outcome(action("report.get"));
+        let policy = RetryPolicy::default();
+        assert!(!policy.should_retry_listed(&listed, &charge));
+        assert!(policy.should_retry_listed(&listed, &report));
+        assert!(!policy.should_retry_listed(&[], &report));
+    }
+}
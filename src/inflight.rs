@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::error::ActionError;
+
+/// how many actions can be tracked as executing at once
+pub const DEFAULT_INFLIGHT_CAPACITY: usize = 10_000;
+
+type Key = (Option<String>, u64);
+
+/// the (token, id) pairs of actions currently executing, see
+/// `Manager::reject_inflight_duplicates`
+pub(crate) struct InFlight {
+    capacity: usize,
+    running: Mutex<HashSet<Key>>,
+}
+
+/// removes its pair once dropped, also when the dispatch unwinds
+pub(crate) struct InFlightGuard<'a> {
+    set: &'a InFlight,
+    key: Option<Key>,
+}
+
+impl InFlight {
+    pub(crate) fn new(capacity: usize) -> Self {
+        InFlight {
+            capacity,
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// marks the pair as executing, failing with `DuplicateInFlight` while it already is
+    /// and with a retryable `InFlightLimit` once `capacity` pairs are
+    pub(crate) fn enter(
+        &self,
+        token: Option<&str>,
+        id: u64,
+    ) -> Result<InFlightGuard<'_>, ActionError> {
+        let key = (token.map(|t| t.to_owned()), id);
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        if running.contains(&key) {
+            return Err(ActionError::new(
+                "DuplicateInFlight",
+                &format!("an action with id {} is still executing", id),
+            )
+            .with_details(json!({ "id": id })));
+        }
+        if running.len() >= self.capacity {
+            return Err(ActionError::new(
+                "InFlightLimit",
+                &format!("{} actions are already executing", running.len()),
+            )
+            .retryable());
+        }
+        running.insert(key.clone());
+        Ok(InFlightGuard {
+            set: self,
+            key: Some(key),
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.running.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.set
+                .running
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, Manager, ManagerFut};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    fn action(name: &str, token: Option<&str>, id: u64) -> Action {
+        let mut a = Action::new(name, id);
+        a.token = token.map(|t| t.to_owned());
+        a
+    }
+
+    #[test]
+    fn second_arrival_is_rejected() {
+        let set = InFlight::new(DEFAULT_INFLIGHT_CAPACITY);
+        let first = set.enter(Some("t1"), 1).unwrap();
+        let err = set.enter(Some("t1"), 1).err().unwrap();
+        assert_eq!(err.code, "DuplicateInFlight");
+        // other tokens and ids are independent
+        let _other = set.enter(Some("t2"), 1).unwrap();
+        let _next = set.enter(Some("t1"), 2).unwrap();
+        drop(first);
+        assert!(set.enter(Some("t1"), 1).is_ok());
+    }
+
+    #[test]
+    fn bounded() {
+        let set = InFlight::new(2);
+        let _a = set.enter(None, 1).unwrap();
+        let _b = set.enter(None, 2).unwrap();
+        let err = set.enter(None, 3).err().unwrap();
+        assert_eq!(err.code, "InFlightLimit");
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn released_after_dispatch_and_panic() {
+        let mut m = Manager::new("test", ());
+        m.reject_inflight_duplicates(true);
+        m.on("ok", |_, _| action_ok());
+        m.on("boom", |_, _| panic!("boom"));
+
+        let mut a = action("ok", Some("t1"), 1);
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+        let mut a = action("ok", Some("t1"), 1);
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+
+        let mut a = action("boom", Some("t1"), 2);
+        assert!(catch_unwind(AssertUnwindSafe(|| m.do_action(&mut a))).is_err());
+        assert_eq!(m.inflight_count(), 0);
+        let mut a = action("ok", Some("t1"), 2);
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+    }
+
+    #[tokio::test]
+    async fn async_duplicates_run_once() {
+        let mut m = ManagerFut::new("async", Arc::new((Notify::new(), AtomicUsize::new(0))));
+        m.reject_inflight_duplicates(true);
+        m.on("wait", |r, _| {
+            let r = r.clone();
+            async move {
+                r.1.fetch_add(1, Ordering::SeqCst);
+                r.0.notified().await;
+                Ok(json!("done"))
+            }
+        });
+
+        let mut first = action("wait", Some("t1"), 1);
+        let mut second = action("wait", Some("t1"), 1);
+        let release = async {
+            tokio::task::yield_now().await;
+            m.resource.0.notify_one();
+        };
+        tokio::join!(m.do_action(&mut first), m.do_action(&mut second), release);
+        assert_eq!(m.resource.1.load(Ordering::SeqCst), 1);
+        assert_eq!(first.result, Some(json!("done")));
+        assert_eq!(second.errors.unwrap()[0].code, "DuplicateInFlight");
+        assert_eq!(m.inflight_count(), 0);
+
+        // the id can be reused once the first one finished
+        let mut again = action("wait", Some("t1"), 1);
+        m.resource.0.notify_one();
+        m.do_action(&mut again).await;
+        assert_eq!(again.result, Some(json!("done")));
+    }
+}
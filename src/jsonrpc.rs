@@ -0,0 +1,336 @@
+//! JSON-RPC 2.0 requests as actions and replies as JSON-RPC responses
+//!
+//! `method` is the action name, `params` the payload and `id` the action id. Only
+//! non-negative integer ids are taken, string ids are turned away since the reply could
+//! not carry them back. Notifications, requests without an id, come with a flag telling
+//! the transport to send no response; their action gets id 0. Error codes map onto the JSON-RPC ones (see `jsonrpc_code`),
+//! the original code goes along in `error.data`.
+
+use serde_json::{Map, Value};
+
+use crate::action::{Action, ActionReply};
+use crate::error::ActionError;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// everything not covered by the codes above, the start of the server error range
+pub const SERVER_ERROR: i64 = -32000;
+
+/// how requests this crate has no direct counterpart for are taken
+#[derive(Debug, Clone)]
+pub struct JsonRpcOptions {
+    /// whether notifications (requests without an id) are taken
+    pub notifications: bool,
+    /// the payload key positional params go under, `None` rejects them
+    pub positional_params_key: Option<String>,
+}
+
+impl Default for JsonRpcOptions {
+    fn default() -> Self {
+        JsonRpcOptions {
+            notifications: true,
+            positional_params_key: Some("params".to_owned()),
+        }
+    }
+}
+
+/// the action of a request and whether it is a notification, which gets no response
+pub type JsonRpcRequest = (Action, bool);
+
+fn invalid_request(message: &str) -> ActionError {
+    ActionError::new("InvalidRequest", message)
+}
+
+/// the JSON-RPC code an error is reported with
+pub fn jsonrpc_code(e: &ActionError) -> i64 {
+    match e.code.as_str() {
+        "ParseError" | "JsonError" | "Utf8Error" => PARSE_ERROR,
+        "InvalidRequest" => INVALID_REQUEST,
+        "NoRoute" => METHOD_NOT_FOUND,
+        code if code.ends_with(" - DoAction") => METHOD_NOT_FOUND,
+        "InvalidParams" | "ValidationError" | "PayloadError" | "MissingField" | "TypeMismatch" => {
+            INVALID_PARAMS
+        }
+        "HandlerPanic" | "SerializeError" => INTERNAL_ERROR,
+        _ => SERVER_ERROR,
+    }
+}
+
+fn error_object(e: &ActionError) -> Value {
+    let mut data = json!({ "code": e.code });
+    if let Some(details) = &e.details {
+        data["details"] = details.clone();
+    }
+    json!({ "code": jsonrpc_code(e), "message": e.message, "data": data })
+}
+
+/// the response for a request which could not be read far enough to know its id
+pub fn jsonrpc_error(e: &ActionError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error_object(e), "id": null })
+}
+
+impl Action {
+    /// `from_jsonrpc_with` the default options: notifications are taken and positional
+    /// params go under `"params"`
+    pub fn from_jsonrpc(request: Value) -> Result<JsonRpcRequest, ActionError> {
+        Action::from_jsonrpc_with(request, &JsonRpcOptions::default())
+    }
+
+    /// the action for one request and whether it is a notification, which gets no
+    /// response; fails with `InvalidRequest` or, for params the options reject,
+    /// `InvalidParams`
+    pub fn from_jsonrpc_with(
+        request: Value,
+        opts: &JsonRpcOptions,
+    ) -> Result<JsonRpcRequest, ActionError> {
+        let mut request = match request {
+            Value::Object(o) => o,
+            _ => return Err(invalid_request("a request must be an object")),
+        };
+        if request.get("jsonrpc") != Some(&json!("2.0")) {
+            return Err(invalid_request("jsonrpc must be \"2.0\""));
+        }
+        let name = match request.remove("method") {
+            Some(Value::String(m)) => m,
+            _ => return Err(invalid_request("method must be a string")),
+        };
+        let id = match request.remove("id") {
+            None if opts.notifications => None,
+            None => return Err(invalid_request("notifications are not accepted")),
+            Some(id) => Some(
+                id.as_u64()
+                    .ok_or_else(|| invalid_request("id must be a non-negative integer"))?,
+            ),
+        };
+        let payload: Map<String, Value> = match request.remove("params") {
+            None => Map::new(),
+            Some(Value::Object(params)) => params,
+            Some(params @ Value::Array(_)) => match &opts.positional_params_key {
+                Some(key) => {
+                    let mut wrapped = Map::new();
+                    wrapped.insert(key.clone(), params);
+                    wrapped
+                }
+                None => {
+                    return Err(ActionError::new(
+                        "InvalidParams",
+                        "params must be an object, positional params are not accepted",
+                    ))
+                }
+            },
+            Some(_) => return Err(invalid_request("params must be an object or an array")),
+        };
+        let mut action = Action::new(&name, id.unwrap_or(0));
+        action.payload = payload.into_iter().collect();
+        Ok((action, id.is_none()))
+    }
+
+    /// the actions of a batch, or of a single request given as one; entries which are
+    /// not valid requests come back as their error, an empty batch fails as a whole
+    pub fn from_jsonrpc_batch(
+        batch: Value,
+        opts: &JsonRpcOptions,
+    ) -> Result<Vec<Result<JsonRpcRequest, ActionError>>, ActionError> {
+        match batch {
+            Value::Array(requests) if requests.is_empty() => {
+                Err(invalid_request("a batch must not be empty"))
+            }
+            Value::Array(requests) => Ok(requests
+                .into_iter()
+                .map(|r| Action::from_jsonrpc_with(r, opts))
+                .collect()),
+            single => Ok(vec![Action::from_jsonrpc_with(single, opts)]),
+        }
+    }
+}
+
+impl ActionReply {
+    /// the response, `error` from the first error when there are any
+    pub fn to_jsonrpc(&self) -> Value {
+        match self.errors.first() {
+            Some(e) => json!({ "jsonrpc": "2.0", "error": error_object(e), "id": self.id }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "result": self.result.clone().unwrap_or(Value::Null),
+                "id": self.id,
+            }),
+        }
+    }
+
+    /// the responses of a batch, as the array JSON-RPC expects
+    pub fn batch_to_jsonrpc(replies: &[ActionReply]) -> Value {
+        Value::Array(replies.iter().map(ActionReply::to_jsonrpc).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Manager};
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("rpc", ());
+        m.on("subtract", |_, a| {
+            let (a, b) = match a.payload.get("params") {
+                Some(p) => (p[0].as_i64(), p[1].as_i64()),
+                None => (
+                    a.payload["minuend"].as_i64(),
+                    a.payload["subtrahend"].as_i64(),
+                ),
+            };
+            value_ok(a.unwrap_or(0) - b.unwrap_or(0))
+        });
+        m.on("sum", |_, a| {
+            value_ok(
+                a.payload["params"]
+                    .as_array()
+                    .map_or(0, |p| p.iter().filter_map(Value::as_i64).sum::<i64>()),
+            )
+        });
+        m.on("get_data", |_, _| value_ok(json!(["hello", 5])));
+        m.on("notify_hello", |_, _| value_ok(Value::Null));
+        m
+    }
+
+    fn call(m: &Manager<()>, request: Value) -> Value {
+        let (a, _) = Action::from_jsonrpc(request).unwrap();
+        m.handle_with_outcome(a).0.to_jsonrpc()
+    }
+
+    // the examples of the JSON-RPC 2.0 specification, section 7
+    #[test]
+    fn spec_examples() {
+        let m = manager();
+        assert_eq!(
+            call(
+                &m,
+                json!({"jsonrpc": "2.0", "method": "subtract", "params": [42, 23], "id": 1})
+            ),
+            json!({"jsonrpc": "2.0", "result": 19, "id": 1})
+        );
+        assert_eq!(
+            call(
+                &m,
+                json!({"jsonrpc": "2.0", "method": "subtract", "params": [23, 42], "id": 2})
+            ),
+            json!({"jsonrpc": "2.0", "result": -19, "id": 2})
+        );
+        assert_eq!(
+            call(
+                &m,
+                json!({"jsonrpc": "2.0", "method": "subtract",
+                       "params": {"subtrahend": 23, "minuend": 42}, "id": 3})
+            ),
+            json!({"jsonrpc": "2.0", "result": 19, "id": 3})
+        );
+
+        let (n, notification) = Action::from_jsonrpc(
+            json!({"jsonrpc": "2.0", "method": "update", "params": [1, 2, 3, 4, 5]}),
+        )
+        .unwrap();
+        assert!(notification);
+        assert_eq!(n.name, "update");
+        // an explicit id 0 is a request like any other
+        let (_, notification) =
+            Action::from_jsonrpc(json!({"jsonrpc": "2.0", "method": "update", "id": 0})).unwrap();
+        assert!(!notification);
+        let opts = JsonRpcOptions {
+            notifications: false,
+            positional_params_key: None,
+        };
+        let err = Action::from_jsonrpc_with(json!({"jsonrpc": "2.0", "method": "foobar"}), &opts)
+            .unwrap_err();
+        assert_eq!(jsonrpc_code(&err), INVALID_REQUEST);
+        let err = Action::from_jsonrpc_with(
+            json!({"jsonrpc": "2.0", "method": "sum", "params": [1], "id": 1}),
+            &opts,
+        )
+        .unwrap_err();
+        assert_eq!(jsonrpc_code(&err), INVALID_PARAMS);
+
+        // non-existent method; the spec uses the string id "1", which is rejected here
+        let response = call(&m, json!({"jsonrpc": "2.0", "method": "foobar", "id": 1}));
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert_eq!(response["error"]["data"]["code"], json!("rpc - DoAction"));
+        assert_eq!(response["id"], json!(1));
+
+        // invalid JSON and invalid request objects
+        let err: ActionError = serde_json::from_str::<Value>(
+            r#"{"jsonrpc": "2.0", "method": "foobar, "params": "bar", "baz]"#,
+        )
+        .unwrap_err()
+        .into();
+        assert_eq!(jsonrpc_error(&err)["error"]["code"], json!(PARSE_ERROR));
+        let err = Action::from_jsonrpc(json!({"jsonrpc": "2.0", "method": 1, "params": "bar"}))
+            .unwrap_err();
+        assert_eq!(
+            jsonrpc_error(&err),
+            json!({"jsonrpc": "2.0", "id": null, "error": {
+                "code": INVALID_REQUEST, "message": "method must be a string",
+                "data": {"code": "InvalidRequest"}}})
+        );
+        let err =
+            Action::from_jsonrpc(json!({"jsonrpc": "2.0", "method": "a", "id": "1"})).unwrap_err();
+        assert_eq!(err.code, "InvalidRequest");
+    }
+
+    #[test]
+    fn batches() {
+        let m = manager();
+        let opts = JsonRpcOptions::default();
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "sum", "params": [1, 2, 4], "id": 1},
+            {"jsonrpc": "2.0", "method": "notify_hello", "params": [7]},
+            {"jsonrpc": "2.0", "method": "get_data", "id": 0},
+            {"jsonrpc": "2.0", "method": "subtract", "params": [42, 23], "id": 2},
+            {"foo": "boo"},
+            {"jsonrpc": "2.0", "method": "foo.get", "params": {"name": "myself"}, "id": 5},
+            {"jsonrpc": "2.0", "method": "get_data", "id": 9}
+        ]);
+        let responses: Vec<Value> = Action::from_jsonrpc_batch(batch, &opts)
+            .unwrap()
+            .into_iter()
+            .filter_map(|r| match r {
+                Ok((_, true)) => None,
+                Ok((a, false)) => Some(m.handle_with_outcome(a).0.to_jsonrpc()),
+                Err(e) => Some(jsonrpc_error(&e)),
+            })
+            .collect();
+        assert_eq!(responses.len(), 6);
+        assert_eq!(
+            responses[0],
+            json!({"jsonrpc": "2.0", "result": 7, "id": 1})
+        );
+        assert_eq!(
+            responses[1],
+            json!({"jsonrpc": "2.0", "result": ["hello", 5], "id": 0})
+        );
+        assert_eq!(
+            responses[2],
+            json!({"jsonrpc": "2.0", "result": 19, "id": 2})
+        );
+        assert_eq!(responses[3]["error"]["code"], json!(INVALID_REQUEST));
+        assert_eq!(responses[3]["id"], Value::Null);
+        assert_eq!(responses[4]["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert_eq!(
+            responses[5],
+            json!({"jsonrpc": "2.0", "result": ["hello", 5], "id": 9})
+        );
+
+        let err = Action::from_jsonrpc_batch(json!([]), &opts).unwrap_err();
+        assert_eq!(jsonrpc_code(&err), INVALID_REQUEST);
+        let each = Action::from_jsonrpc_batch(json!([1, 2]), &opts).unwrap();
+        assert!(each.iter().all(|r| r.is_err()));
+
+        let mut a = Action::new("x", 4);
+        a.set_result(json!(true));
+        let replies = vec![a.into_reply()];
+        assert_eq!(
+            ActionReply::batch_to_jsonrpc(&replies),
+            json!([{"jsonrpc": "2.0", "result": true, "id": 4}])
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::action::Manager;
+
+/// a key renamed on the way in or out, nested keys written with dots
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyRename {
+    pub from: String,
+    pub to: String,
+}
+
+/// payload and result renames by action
+#[derive(Default)]
+pub(crate) struct KeyMaps {
+    pub(crate) payload: HashMap<String, Vec<KeyRename>>,
+    pub(crate) result: HashMap<String, Vec<KeyRename>>,
+}
+
+fn renames(map: &[(&str, &str)]) -> Vec<KeyRename> {
+    map.iter()
+        .map(|(from, to)| KeyRename {
+            from: (*from).to_owned(),
+            to: (*to).to_owned(),
+        })
+        .collect()
+}
+
+fn get<'a>(v: &'a Value, path: &[&str]) -> Option<&'a Value> {
+    path.iter().try_fold(v, |v, k| v.get(k))
+}
+
+fn take(v: &mut Value, path: &[&str]) -> Option<Value> {
+    match path {
+        [key] => v.as_object_mut()?.remove(*key),
+        [key, rest @ ..] => take(v.get_mut(*key)?, rest),
+        [] => None,
+    }
+}
+
+/// puts `value` at `path` creating missing objects on the way, gives it back when
+/// something on the way is not an object
+fn put(v: &mut Value, path: &[&str], value: Value) -> Result<(), Value> {
+    let map = match v.as_object_mut() {
+        Some(map) => map,
+        None => return Err(value),
+    };
+    match path {
+        [key] => {
+            map.insert((*key).to_owned(), value);
+            Ok(())
+        }
+        [key, rest @ ..] => put(
+            map.entry((*key).to_owned())
+                .or_insert_with(|| Value::Object(Map::new())),
+            rest,
+            value,
+        ),
+        [] => Err(value),
+    }
+}
+
+/// moves `from` to `to` unless `to` is already there
+fn rename(v: &mut Value, from: &str, to: &str) {
+    let from: Vec<&str> = from.split('.').collect();
+    let to: Vec<&str> = to.split('.').collect();
+    if get(v, &to).is_some() {
+        return;
+    }
+    if let Some(moved) = take(v, &from) {
+        if let Err(moved) = put(v, &to, moved) {
+            let _ = put(v, &from, moved);
+        }
+    }
+}
+
+pub(crate) fn rename_result(result: &mut Value, renames: &[KeyRename]) {
+    for r in renames {
+        rename(result, &r.from, &r.to);
+    }
+}
+
+pub(crate) fn rename_payload(payload: &mut HashMap<String, Value>, renames: &[KeyRename]) {
+    for r in renames {
+        let (root, rest) = r.from.split_once('.').unwrap_or((&r.from, ""));
+        let (to_root, to_rest) = r.to.split_once('.').unwrap_or((&r.to, ""));
+        if root == to_root && !rest.is_empty() && !to_rest.is_empty() {
+            // stays under the same top level key
+            if let Some(v) = payload.get_mut(root) {
+                rename(v, rest, to_rest);
+            }
+            continue;
+        }
+        // the top level is a HashMap, move the affected entries through a Value
+        let mut tmp = Value::Object(Map::new());
+        for key in [root, to_root] {
+            if let Some(v) = payload.remove(key) {
+                tmp[key] = v;
+            }
+        }
+        rename(&mut tmp, &r.from, &r.to);
+        if let Value::Object(map) = tmp {
+            payload.extend(map);
+        }
+    }
+}
+
+impl<R> Manager<R> {
+    /// accepts old payload keys of `name` under their new names: each `(old, new)` is
+    /// moved before validation unless `new` is already present, keys nested in objects
+    /// are written with dots, e.g. `("user.user_id", "user.userId")`
+    pub fn payload_key_map(&mut self, name: &str, map: &[(&str, &str)]) {
+        self.key_maps_mut()
+            .payload
+            .insert(name.to_owned(), renames(map));
+    }
+
+    /// renames keys of the results of `name` the same way, e.g. to keep sending old names
+    /// to old clients
+    pub fn result_key_map(&mut self, name: &str, map: &[(&str, &str)]) {
+        self.key_maps_mut()
+            .result
+            .insert(name.to_owned(), renames(map));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action};
+
+    fn action(payload: Value) -> Action {
+        let mut a = Action::new("user.get", 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("users", ());
+        m.on("user.get", |_, a| value_ok(&a.payload));
+        m.payload_key_map(
+            "user.get",
+            &[("user_id", "userId"), ("filter.min_age", "filter.minAge")],
+        );
+        m
+    }
+
+    fn run(m: &Manager<()>, payload: Value) -> Value {
+        let mut a = action(payload);
+        m.do_action(&mut a);
+        a.result.unwrap()
+    }
+
+    #[test]
+    fn renames_old_keys() {
+        let m = manager();
+        assert_eq!(run(&m, json!({"user_id": 4})), json!({"userId": 4}));
+        assert_eq!(
+            run(&m, json!({"filter": {"min_age": 18, "x": 1}})),
+            json!({"filter": {"minAge": 18, "x": 1}})
+        );
+        assert_eq!(run(&m, json!({"other": 1})), json!({"other": 1}));
+    }
+
+    #[test]
+    fn never_clobbers() {
+        let m = manager();
+        assert_eq!(
+            run(&m, json!({"user_id": 4, "userId": 5})),
+            json!({"user_id": 4, "userId": 5})
+        );
+        assert_eq!(
+            run(&m, json!({"filter": {"min_age": 1, "minAge": 2}})),
+            json!({"filter": {"min_age": 1, "minAge": 2}})
+        );
+    }
+
+    #[test]
+    fn across_levels() {
+        let mut m = manager();
+        m.payload_key_map("user.get", &[("uid", "user.id")]);
+        assert_eq!(run(&m, json!({"uid": 1})), json!({"user": {"id": 1}}));
+        // `user` is not an object, nothing moves
+        assert_eq!(
+            run(&m, json!({"uid": 1, "user": 2})),
+            json!({"uid": 1, "user": 2})
+        );
+    }
+
+    #[test]
+    fn results() {
+        let mut m = manager();
+        m.result_key_map(
+            "user.get",
+            &[("userId", "user_id"), ("filter.minAge", "filter.min_age")],
+        );
+        assert_eq!(
+            run(&m, json!({"user_id": 4, "filter": {"min_age": 3}})),
+            json!({"user_id": 4, "filter": {"min_age": 3}})
+        );
+    }
+
+    #[test]
+    fn introspection() {
+        let m = manager();
+        let info = &m.list_actions_detailed()[0];
+        assert_eq!(
+            info.payload_keys[0],
+            KeyRename {
+                from: "user_id".to_owned(),
+                to: "userId".to_owned()
+            }
+        );
+        assert!(info.result_keys.is_empty());
+    }
+}
@@ -1,12 +1,143 @@
+//! Cargo features, each builds on the ones before it:
+//!
+//! - `core`: `Action`, `ActionReply`, the sync `Manager` and everything which only needs
+//!   serde, serde_bytes, bytes and base64
+//! - `log`: registration, dispatch and warning events through the `log` crate, and
+//!   handler logs from `ActionCtx::log`; without it those events are dropped and handler
+//!   logs only reach replies
+//! - `crypto`: signed and encrypted helpers, the `two_phase`, `capability` and `signature`
+//!   modules and the `Masked` and `Encrypted` token codecs (hmac, sha2, aes-gcm-siv)
+//! - `compat`: the `compat` module for payload compatibility tests (serde_path_to_error)
+//! - `zstd-dict`: the `compression` module, zstd dictionary compression of actions; it
+//!   builds the zstd C library and is not part of `default`
+//! - `duplex`: the `duplex` module, streams in both directions on `ManagerFut`, and
+//!   `ManagerFut::run` on tokio channels (tokio); `ManagerFut` itself is in `core`, only
+//!   these need the feature, which is not part of `default`
+//! - `msgpack`: the `msgpack` module, MessagePack encoding of actions and replies
+//!   (rmp-serde)
+//! - `cbor`: the `cbor` module, CBOR encoding of actions and replies (ciborium)
+//! - `tokio-codec`: the `codec` module, a newline delimited tokio-util codec for actions
+//!   and replies
+//! - `test-util`: the `conformance` module, scenarios for checking other transport
+//!   implementations against this crate, and `clock::FakeClock`
+//!
+//! `default` enables `core`, `log`, `crypto` and `compat`; minimal users build with
+//! `--no-default-features --features core`, `cargo run -p xtask` checks every combination.
+
+#[cfg(not(feature = "core"))]
+compile_error!("json_action needs at least the `core` feature");
+
+#[cfg(feature = "crypto")]
+extern crate aes_gcm_siv;
+extern crate base64;
 extern crate byteorder;
 extern crate bytes;
+#[cfg(feature = "crypto")]
+extern crate hmac;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_bytes;
+#[cfg(feature = "compat")]
+extern crate serde_path_to_error;
+#[cfg(feature = "crypto")]
+extern crate sha2;
+#[cfg(feature = "zstd-dict")]
+extern crate zstd;
 #[macro_use]
 extern crate serde_json;
+
+/// a `log` macro, `event!(warn, key = value; "...")`, which without the `log` feature
+/// only type checks its arguments
+macro_rules! event {
+    ($level:ident, $($key:ident = $value:expr),+; $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::$level!($($key = $value),+; $($arg)+);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = ($(&$value),+);
+            let _ = format_args!($($arg)+);
+        }
+    }};
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::$level!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
 pub mod action;
+pub mod auth;
+pub mod budget;
+pub mod cache;
+#[cfg(feature = "crypto")]
+pub mod capability;
+#[cfg(any(test, feature = "cbor"))]
+pub mod cbor;
+pub mod channel;
+pub mod clock;
+#[cfg(any(test, feature = "tokio-codec"))]
+pub mod codec;
+#[cfg(feature = "compat")]
+pub mod compat;
+#[cfg(feature = "zstd-dict")]
+pub mod compression;
+pub mod concurrency;
+#[cfg(any(test, feature = "test-util"))]
+pub mod conformance;
+pub mod correlation;
+pub mod ctx;
+pub mod dead_letter;
+pub mod dedupe;
+pub mod deprecation;
+#[cfg(any(test, feature = "duplex"))]
+pub mod duplex;
+pub mod envelope;
 pub mod error;
+pub mod examples;
+pub mod extract;
+pub mod frame;
+pub mod health;
+pub mod history;
+pub mod idempotency;
+pub mod inflight;
+pub mod jsonrpc;
+pub mod keymap;
+pub mod logger;
+pub mod maintenance;
+pub mod migrate;
+#[cfg(any(test, feature = "msgpack"))]
+pub mod msgpack;
+pub mod outbox;
+pub mod outcome;
+pub mod panics;
+pub mod parallel;
+pub mod patch;
+pub mod pool;
+pub mod protocol;
+pub mod rate_limit;
+pub mod resources;
+pub mod router;
+pub mod routes;
+pub mod schema;
+#[cfg(feature = "crypto")]
+pub mod signature;
+pub mod sizes;
+pub mod source;
+pub mod stamp;
+pub mod statics;
+pub mod stats;
+pub mod stream;
+pub mod timeout;
+pub mod token;
+pub mod trace;
+#[cfg(feature = "crypto")]
+pub mod two_phase;
+pub mod validate;
+pub mod verbosity;
 
 #[cfg(test)]
 mod tests {
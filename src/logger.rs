@@ -0,0 +1,352 @@
+//! logging from handlers with the action being processed attached, see `ActionCtx::log`;
+//! without the `log` feature entries only go to replies, see `Manager::attach_logs_to_reply`
+
+#[cfg(feature = "log")]
+use log::kv::{Key, Source, Value as KvValue, VisitSource};
+#[cfg(feature = "log")]
+use log::{Level, Record};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+
+use crate::action::Action;
+use crate::sizes::json_len;
+
+/// target of the log records handlers emit through `ActionLogger`
+pub const LOG_TARGET: &str = "json_action::handler";
+
+/// bytes of log entries one reply carries at most, see `Manager::attach_logs_to_reply`
+pub const MAX_REPLY_LOG_BYTES: usize = 16 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+#[cfg(feature = "log")]
+impl LogLevel {
+    fn level(self) -> Level {
+        match self {
+            LogLevel::Info => Level::Info,
+            LogLevel::Warn => Level::Warn,
+            LogLevel::Error => Level::Error,
+        }
+    }
+}
+
+/// one line logged by a handler, as attached to the reply
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub level: LogLevel,
+    pub message: String,
+    /// the fields added with `ActionLogger::kv`
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+/// entries kept for the reply of one dispatch, bounded by count and bytes; entries past
+/// either bound are logged but not kept
+pub(crate) struct LogBuffer {
+    max_entries: usize,
+    max_bytes: usize,
+    kept: RefCell<(Vec<LogEntry>, usize)>,
+}
+
+impl LogBuffer {
+    pub(crate) fn new(max_entries: usize, max_bytes: usize) -> Self {
+        LogBuffer {
+            max_entries,
+            max_bytes,
+            kept: RefCell::new((Vec::new(), 0)),
+        }
+    }
+
+    fn push(&self, entry: LogEntry) {
+        let mut kept = self.kept.borrow_mut();
+        let size = json_len(&entry).unwrap_or(usize::MAX);
+        if kept.0.len() < self.max_entries && kept.1.saturating_add(size) <= self.max_bytes {
+            kept.1 += size;
+            kept.0.push(entry);
+        }
+    }
+
+    pub(crate) fn take(&self) -> Vec<LogEntry> {
+        std::mem::take(&mut self.kept.borrow_mut().0)
+    }
+}
+
+/// emits log records carrying the manager, action name, id and correlation id as
+/// structured fields; fields added with `kv` go along with every record after that
+#[derive(Clone)]
+#[cfg_attr(not(feature = "log"), allow(dead_code))]
+pub struct ActionLogger<'a> {
+    manager: Option<String>,
+    action: String,
+    id: u64,
+    correlation: Option<String>,
+    fields: Map<String, Value>,
+    buffer: Option<&'a LogBuffer>,
+}
+
+impl ActionLogger<'static> {
+    /// a logger for handlers without an `ActionCtx`, its entries are never attached to
+    /// the reply
+    pub fn for_action(action: &Action) -> Self {
+        ActionLogger::new(None, action, None)
+    }
+}
+
+impl<'a> ActionLogger<'a> {
+    pub(crate) fn new(
+        manager: Option<&str>,
+        action: &Action,
+        buffer: Option<&'a LogBuffer>,
+    ) -> Self {
+        ActionLogger {
+            manager: manager.map(|m| m.to_owned()),
+            action: action.name.clone(),
+            id: action.id,
+            correlation: action.correlation_id.clone(),
+            fields: Map::new(),
+            buffer,
+        }
+    }
+
+    /// for contexts built outside of a dispatch, which know no action
+    pub(crate) fn detached(correlation: Option<String>) -> Self {
+        ActionLogger {
+            manager: None,
+            action: String::new(),
+            id: 0,
+            correlation,
+            fields: Map::new(),
+            buffer: None,
+        }
+    }
+
+    pub fn kv<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        self.fields.insert(key.to_owned(), value.into());
+        self
+    }
+
+    pub fn info(&self, message: &str) {
+        self.emit(LogLevel::Info, message);
+    }
+
+    pub fn warn(&self, message: &str) {
+        self.emit(LogLevel::Warn, message);
+    }
+
+    pub fn error(&self, message: &str) {
+        self.emit(LogLevel::Error, message);
+    }
+
+    fn emit(&self, level: LogLevel, message: &str) {
+        #[cfg(feature = "log")]
+        if level.level() <= log::max_level() {
+            log::logger().log(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(level.level())
+                    .target(LOG_TARGET)
+                    .key_values(self)
+                    .build(),
+            );
+        }
+        if let Some(buffer) = self.buffer {
+            buffer.push(LogEntry {
+                level,
+                message: message.to_owned(),
+                fields: self.fields.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+fn visit_value<'k>(
+    visitor: &mut dyn VisitSource<'k>,
+    key: &'k str,
+    value: &'k Value,
+) -> Result<(), log::kv::Error> {
+    match value {
+        Value::String(s) => visitor.visit_pair(Key::from_str(key), KvValue::from(s.as_str())),
+        Value::Bool(b) => visitor.visit_pair(Key::from_str(key), KvValue::from(*b)),
+        Value::Number(n) => visitor.visit_pair(Key::from_str(key), KvValue::from_display(n)),
+        other => visitor.visit_pair(Key::from_str(key), KvValue::from_display(other)),
+    }
+}
+
+#[cfg(feature = "log")]
+impl Source for ActionLogger<'_> {
+    fn visit<'k>(&'k self, visitor: &mut dyn VisitSource<'k>) -> Result<(), log::kv::Error> {
+        if let Some(manager) = &self.manager {
+            visitor.visit_pair(Key::from_str("manager"), KvValue::from(manager.as_str()))?;
+        }
+        visitor.visit_pair(Key::from_str("action"), KvValue::from(self.action.as_str()))?;
+        visitor.visit_pair(Key::from_str("id"), KvValue::from(self.id))?;
+        if let Some(correlation) = &self.correlation {
+            visitor.visit_pair(
+                Key::from_str("correlation_id"),
+                KvValue::from(correlation.as_str()),
+            )?;
+        }
+        for (key, value) in &self.fields {
+            visit_value(visitor, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "log"))]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Manager};
+    use log::{Log, Metadata};
+    use std::collections::BTreeMap;
+    use std::sync::{Mutex, Once};
+
+    struct Capture(Mutex<Vec<(String, BTreeMap<String, String>)>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            struct Fields(BTreeMap<String, String>);
+            impl<'k> VisitSource<'k> for Fields {
+                fn visit_pair(
+                    &mut self,
+                    key: Key<'k>,
+                    value: KvValue<'k>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.insert(key.to_string(), value.to_string());
+                    Ok(())
+                }
+            }
+            let mut fields = Fields(BTreeMap::new());
+            record.key_values().visit(&mut fields).unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.args().to_string(), fields.0));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+    static INSTALL: Once = Once::new();
+
+    fn captured(action: &str, id: u64) -> Vec<(String, BTreeMap<String, String>)> {
+        CAPTURE
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, f)| {
+                f.get("action").map(|a| a.as_str()) == Some(action)
+                    && f.get("id") == Some(&id.to_string())
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn action(name: &str, id: u64) -> Action {
+        let mut a = Action::new(name, id);
+        a.correlation_id = Some("req-1".to_owned());
+        a
+    }
+
+    fn manager() -> Manager<()> {
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        let mut m = Manager::new("billing", ());
+        m.on_ctx("log.ctx", |_, _, ctx| {
+            let log = ctx.log().kv("card", "4111").kv("rows", 3);
+            log.info("charging");
+            for n in 0..10 {
+                ctx.log().kv("n", n).warn(&"x".repeat(100));
+            }
+            value_ok(true)
+        });
+        m.on("log.plain", |_, a| {
+            ActionLogger::for_action(a).error("plain handler");
+            value_ok(true)
+        });
+        m.redact_keys(&["card"]);
+        m
+    }
+
+    #[test]
+    fn records_carry_the_action() {
+        let m = manager();
+        // the other test logs as well, ids tell the records apart
+        m.do_action(&mut action("log.ctx", 7));
+        let records = captured("log.ctx", 7);
+        assert_eq!(records.len(), 11);
+        let (message, fields) = &records[0];
+        assert_eq!(message, "charging");
+        assert_eq!(fields["manager"], "billing");
+        assert_eq!(fields["id"], "7");
+        assert_eq!(fields["correlation_id"], "req-1");
+        assert_eq!(fields["rows"], "3");
+
+        m.do_action(&mut action("log.plain", 7));
+        let records = captured("log.plain", 7);
+        assert_eq!(records[0].0, "plain handler");
+        assert!(!records[0].1.contains_key("manager"));
+        assert_eq!(records[0].1["correlation_id"], "req-1");
+    }
+
+    #[test]
+    fn attached_to_reply_capped_and_redacted() {
+        let mut m = manager();
+        let (reply, _) = m.handle_with_outcome(action("log.ctx", 1));
+        assert!(reply.logs.is_empty());
+
+        m.attach_logs_to_reply(5);
+        let (reply, _) = m.handle_with_outcome(action("log.ctx", 1));
+        assert_eq!(reply.logs.len(), 5);
+        assert_eq!(reply.logs[0].level, LogLevel::Info);
+        assert_eq!(reply.logs[0].fields["card"], json!("[redacted]"));
+        assert_eq!(reply.logs[0].fields["rows"], json!(3));
+        assert_eq!(reply.logs[4].fields["n"], json!(3));
+
+        // the byte cap applies however many entries are allowed
+        let buffer = LogBuffer::new(usize::MAX, 300);
+        let a = action("log.ctx", 1);
+        let log = ActionLogger::new(None, &a, Some(&buffer));
+        for _ in 0..10 {
+            log.warn(&"x".repeat(100));
+        }
+        assert_eq!(buffer.take().len(), 2);
+
+        let (reply, _) = m.handle_with_outcome(action("log.plain", 1));
+        assert!(reply.logs.is_empty());
+    }
+
+    #[test]
+    fn duplicate_registration_warns() {
+        let mut m = manager();
+        m.on("log.plain", |_, _| value_ok(false));
+        let warnings: Vec<_> = CAPTURE
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(message, f)| {
+                message.contains("registered existing action")
+                    && f.get("action").map(|a| a.as_str()) == Some("log.plain")
+            })
+            .cloned()
+            .collect();
+        assert!(!warnings.is_empty());
+        assert_eq!(warnings[0].1["manager"], "billing");
+    }
+}
@@ -0,0 +1,160 @@
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// how many entries a component looks at in one `sweep`, so a sweep never holds a lock
+/// for long; what is left is picked up by the next one
+pub const SWEEP_BUDGET: usize = 1024;
+
+/// what a sweep did
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SweepStats {
+    pub scanned: usize,
+    pub evicted: usize,
+    /// only counts what components know the size of
+    pub bytes_freed: usize,
+}
+
+impl AddAssign for SweepStats {
+    fn add_assign(&mut self, other: SweepStats) {
+        self.scanned += other.scanned;
+        self.evicted += other.evicted;
+        self.bytes_freed += other.bytes_freed;
+    }
+}
+
+/// something holding state that expires, swept periodically by `Manager::sweep`
+pub trait Maintenance: Send + Sync {
+    /// drops what expired as of `now`, looking at no more than `SWEEP_BUDGET` entries
+    fn sweep(&self, now: SystemTime) -> SweepStats;
+}
+
+/// the `Instant` matching `now`, for components that keep monotonic timestamps
+pub(crate) fn instant_at(now: SystemTime) -> Instant {
+    let wall = SystemTime::now();
+    let mono = Instant::now();
+    match now.duration_since(wall) {
+        Ok(ahead) => mono + ahead,
+        Err(behind) => mono.checked_sub(behind.duration()).unwrap_or(mono),
+    }
+}
+
+/// stops the sweeper thread when dropped or told to
+pub struct SweeperHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SweeperHandle {
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            t.thread().unpark();
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for SweeperHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// sweeps `target` every `interval` on its own thread
+pub fn spawn_sweeper<M>(target: Arc<M>, interval: Duration) -> SweeperHandle
+where
+    M: Maintenance + Send + Sync + ?Sized + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = thread::spawn(move || {
+        while !stopped.load(Ordering::SeqCst) {
+            thread::park_timeout(interval);
+            if !stopped.load(Ordering::SeqCst) {
+                target.sweep(SystemTime::now());
+            }
+        }
+    });
+    SweeperHandle {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, Manager};
+    use std::sync::atomic::AtomicUsize;
+
+    fn action(name: &str, token: &str) -> Action {
+        let mut a = Action::new(name, 0);
+        a.token = Some(token.to_owned());
+        a
+    }
+
+    #[test]
+    fn manager_aggregates_components() {
+        let mut m = Manager::new("test", ());
+        m.on("old", |_, _| action_ok());
+        m.deprecate("old", "use new", None);
+        m.deprecation_warn_interval(Duration::from_secs(60));
+        m.keep_recent_replies(100);
+        m.recent_replies_max_age(Duration::from_secs(600));
+        for token in ["a", "b", "c"] {
+            let mut a = action("old", token);
+            m.do_action(&mut a);
+            m.reply(a);
+        }
+
+        let now = SystemTime::now();
+        assert_eq!(m.sweep_at(now).evicted, 0);
+
+        // the warnings expired, the replies did not yet
+        let stats = m.sweep_at(now + Duration::from_secs(120));
+        assert_eq!(stats.evicted, 3);
+        assert_eq!(stats.bytes_freed, 0);
+        assert_eq!(m.recent_replies(None).len(), 3);
+
+        let stats = m.sweep_at(now + Duration::from_secs(1200));
+        assert_eq!(stats.evicted, 3);
+        assert!(stats.bytes_freed > 0);
+        assert!(m.recent_replies(None).is_empty());
+    }
+
+    struct Counter(AtomicUsize);
+
+    impl Maintenance for Counter {
+        fn sweep(&self, _: SystemTime) -> SweepStats {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            SweepStats {
+                scanned: 1,
+                ..SweepStats::default()
+            }
+        }
+    }
+
+    #[test]
+    fn external_components_and_sweeper() {
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let mut m = Manager::new("test", ());
+        m.maintain(counter.clone());
+        assert_eq!(m.sweep().scanned, 1);
+
+        let sweeper = spawn_sweeper(counter.clone(), Duration::from_millis(1));
+        while counter.0.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        sweeper.stop();
+        let swept = counter.0.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(counter.0.load(Ordering::SeqCst), swept);
+    }
+}
@@ -0,0 +1,261 @@
+//! upgrades of whole wire messages from older protocol generations, applied to the raw
+//! JSON before it is parsed as an `Action`, see `Action::from_bytes_migrating`
+
+use bytes::Bytes;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use crate::action::Action;
+use crate::dead_letter::{io_error, DeadLetter, DeadLetterSink, MemoryDeadLetters};
+use crate::error::ActionError;
+
+/// top-level key holding the wire version of a message
+pub const VERSION_KEY: &str = "version";
+
+/// one step of the chain, gets the message at one version and returns it at the next
+pub type WireMigration = dyn Fn(Value) -> Result<Value, ActionError> + Send + Sync;
+
+/// the registered steps, keyed by the version they upgrade from
+#[derive(Default)]
+pub struct WireMigrations {
+    steps: BTreeMap<u64, Box<WireMigration>>,
+    unversioned: u64,
+}
+
+impl WireMigrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers the step taking messages at `from_version` to `from_version + 1`,
+    /// replacing an earlier one; steps need not set `version`, the chain does
+    pub fn register_wire_migration<F>(&mut self, from_version: u64, f: F)
+    where
+        F: Fn(Value) -> Result<Value, ActionError> + Send + Sync + 'static,
+    {
+        self.steps.insert(from_version, Box::new(f));
+    }
+
+    /// the version messages without a `version` key are taken to be, 0 unless set
+    pub fn unversioned_as(&mut self, version: u64) {
+        self.unversioned = version;
+    }
+
+    /// the version the chain ends at, one past its newest step
+    pub fn current_version(&self) -> u64 {
+        self.steps.keys().next_back().map_or(0, |v| v + 1)
+    }
+
+    /// the version of `message`, failing with `InvalidWireVersion` when `version` is
+    /// not a number
+    pub fn version_of(&self, message: &Value) -> Result<u64, ActionError> {
+        match message.get(VERSION_KEY) {
+            None => Ok(self.unversioned),
+            Some(v) => v.as_u64().ok_or_else(|| {
+                ActionError::new("InvalidWireVersion", "version must be a whole number")
+                    .with_details(json!({ "version": v }))
+            }),
+        }
+    }
+
+    /// runs every step from the message's version up to `current_version`. A failing
+    /// step fails with `WireMigrationFailed` naming it and carrying its error as `cause`,
+    /// a missing one with `WireMigrationMissing` and a version past the chain with
+    /// `UnknownWireVersion`
+    pub fn migrate(&self, mut message: Value) -> Result<Value, ActionError> {
+        let current = self.current_version();
+        let mut version = self.version_of(&message)?;
+        if version > current {
+            return Err(ActionError::new(
+                "UnknownWireVersion",
+                &format!("version {} is newer than {}", version, current),
+            )
+            .with_details(json!({ "version": version, "current": current })));
+        }
+        while version < current {
+            let step = self.steps.get(&version).ok_or_else(|| {
+                ActionError::new(
+                    "WireMigrationMissing",
+                    &format!("no migration from version {}", version),
+                )
+                .with_details(json!({ "from_version": version }))
+            })?;
+            message = step(message).map_err(|cause| {
+                ActionError::new(
+                    "WireMigrationFailed",
+                    &format!(
+                        "migration from version {} to {} failed",
+                        version,
+                        version + 1
+                    ),
+                )
+                .with_details(json!({
+                    "from_version": version,
+                    "to_version": version + 1,
+                    "cause": cause,
+                }))
+            })?;
+            version += 1;
+            if let Value::Object(map) = &mut message {
+                map.insert(VERSION_KEY.to_owned(), json!(version));
+            }
+        }
+        Ok(message)
+    }
+}
+
+impl Action {
+    /// `from_bytes` for messages of older wire versions, migrated through `migrations`
+    /// first. Errors of the chain keep the message as it arrived in their details under
+    /// `original`, so it can go to a dead letter sink unchanged
+    pub fn from_bytes_migrating(
+        buf: Bytes,
+        migrations: &WireMigrations,
+    ) -> Result<Self, ActionError> {
+        let message: Value =
+            serde_json::from_slice(&buf).map_err(|e| match std::str::from_utf8(&buf) {
+                Err(utf8) => utf8.into(),
+                Ok(_) => ActionError::from(e),
+            })?;
+        let migrated = migrations.migrate(message).map_err(|mut e| {
+            let original = Value::String(String::from_utf8_lossy(&buf).into_owned());
+            match &mut e.details {
+                Some(Value::Object(map)) => {
+                    map.insert("original".to_owned(), original);
+                }
+                _ => e.details = Some(json!({ "original": original })),
+            }
+            e
+        })?;
+        Ok(serde_json::from_value(migrated)?)
+    }
+}
+
+impl MemoryDeadLetters {
+    /// `import` of an export written by an older version, each entry's action is migrated
+    /// through `migrations`
+    pub fn import_migrating<Rd: BufRead>(
+        &self,
+        r: Rd,
+        migrations: &WireMigrations,
+    ) -> Result<u64, ActionError> {
+        let mut read = Vec::new();
+        for line in r.lines() {
+            let line = line.map_err(io_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut entry: Value = serde_json::from_str(&line)?;
+            if let Some(action) = entry.get_mut("action") {
+                let message = std::mem::take(action);
+                let bytes = Bytes::from(serde_json::to_vec(&message)?);
+                *action = serde_json::to_value(Action::from_bytes_migrating(bytes, migrations)?)?;
+            }
+            read.push(serde_json::from_value::<DeadLetter>(entry)?);
+        }
+        let n = read.len() as u64;
+        for entry in read {
+            self.push(entry);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// two generations back: `action` instead of `name` and `data` instead of `payload`,
+    /// no version
+    const OLDEST: &str = r#"{"action": "user.get", "id": 1, "data": {"user_id": 7}}
+{"action": "user.list", "id": 2, "data": {}}
+"#;
+
+    fn migrations() -> WireMigrations {
+        let mut m = WireMigrations::new();
+        m.register_wire_migration(0, |mut v| {
+            let name = v
+                .as_object_mut()
+                .and_then(|o| o.remove("action"))
+                .ok_or_else(|| ActionError::new("MissingAction", "no action key"))?;
+            v["name"] = name;
+            Ok(v)
+        });
+        m.register_wire_migration(1, |mut v| {
+            let data = v
+                .as_object_mut()
+                .and_then(|o| o.remove("data"))
+                .unwrap_or_else(|| json!({}));
+            v["payload"] = data;
+            Ok(v)
+        });
+        m
+    }
+
+    #[test]
+    fn oldest_format_through_two_steps() {
+        let m = migrations();
+        assert_eq!(m.current_version(), 2);
+        let actions: Vec<Action> = OLDEST
+            .lines()
+            .map(|l| Action::from_bytes_migrating(Bytes::from(l.to_owned()), &m).unwrap())
+            .collect();
+        assert_eq!(actions[0].name, "user.get");
+        assert_eq!(actions[0].payload["user_id"], json!(7));
+        assert_eq!((actions[1].name.as_str(), actions[1].id), ("user.list", 2));
+
+        // current messages go through untouched
+        let current = br#"{"version": 2, "name": "user.get", "id": 3, "payload": {}}"#;
+        let a = Action::from_bytes_migrating(Bytes::from_static(current), &m).unwrap();
+        assert_eq!(a.name, "user.get");
+        let newer = br#"{"version": 3, "name": "user.get", "id": 3, "payload": {}}"#;
+        let err = Action::from_bytes_migrating(Bytes::from_static(newer), &m).unwrap_err();
+        assert_eq!(err.code, "UnknownWireVersion");
+    }
+
+    #[test]
+    fn failed_step_is_named_and_keeps_the_original() {
+        let m = migrations();
+        let line = r#"{"id": 1, "data": {}}"#;
+        let err = Action::from_bytes_migrating(Bytes::from(line), &m).unwrap_err();
+        assert_eq!(err.code, "WireMigrationFailed");
+        let details = err.details.unwrap();
+        assert_eq!(
+            (
+                details["from_version"].clone(),
+                details["to_version"].clone()
+            ),
+            (json!(0), json!(1))
+        );
+        assert_eq!(details["cause"]["code"], json!("MissingAction"));
+        assert_eq!(details["original"], json!(line));
+
+        // a gap in the chain
+        let mut gap = WireMigrations::new();
+        gap.register_wire_migration(1, Ok);
+        let err = Action::from_bytes_migrating(Bytes::from(line), &gap).unwrap_err();
+        assert_eq!(err.code, "WireMigrationMissing");
+        assert_eq!(err.details.unwrap()["original"], json!(line));
+    }
+
+    #[test]
+    fn dead_letter_import_migrates() {
+        let m = migrations();
+        let old = format!(
+            "{}\n",
+            json!({
+                "action": {"action": "charge", "id": 4, "data": {"n": 4}},
+                "errors": [],
+                "first_seen": 0,
+                "attempts": 1,
+            })
+        );
+        let sink = MemoryDeadLetters::new();
+        assert_eq!(sink.import_migrating(old.as_bytes(), &m).unwrap(), 1);
+        let entry = &sink.entries()[0];
+        assert_eq!(entry.action.name, "charge");
+        assert_eq!(entry.action.payload["n"], json!(4));
+        assert_eq!(entry.attempts, 1);
+    }
+}
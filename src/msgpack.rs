@@ -0,0 +1,109 @@
+//! MessagePack encoding of actions and replies (`msgpack` feature)
+//!
+//! Structs are written as maps keyed by field name, like the JSON encoding, so fields
+//! left out by either side do not shift the others. Payload values keep their JSON
+//! types, the `binary` field goes as MessagePack bin.
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::action::{Action, ActionReply};
+use crate::error::ActionError;
+
+fn msgpack_error(e: impl std::fmt::Display) -> ActionError {
+    ActionError::new("MsgpackError", &e.to_string())
+}
+
+fn encode<T: Serialize>(v: &T) -> Result<Bytes, ActionError> {
+    rmp_serde::to_vec_named(v)
+        .map(Bytes::from)
+        .map_err(msgpack_error)
+}
+
+fn decode<T: DeserializeOwned>(buf: &[u8]) -> Result<T, ActionError> {
+    rmp_serde::from_slice(buf).map_err(msgpack_error)
+}
+
+impl Action {
+    /// parses a MessagePack encoded action, failing with `MsgpackError`
+    pub fn from_msgpack(buf: Bytes) -> Result<Self, ActionError> {
+        decode(&buf)
+    }
+
+    pub fn to_msgpack(&self) -> Result<Bytes, ActionError> {
+        encode(self)
+    }
+}
+
+impl ActionReply {
+    pub fn from_msgpack(buf: Bytes) -> Result<Self, ActionError> {
+        decode(&buf)
+    }
+
+    pub fn to_msgpack(&self) -> Result<Bytes, ActionError> {
+        encode(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn nested() -> Value {
+        json!({
+            "user": {
+                "id": u64::MAX,
+                "balance": i64::MIN,
+                "ratio": 0.25,
+                "tags": ["a", {"deep": [[1, 2], [null, true]]}],
+                "profile": {"name": "ünï", "address": {"lines": ["1", "2"], "zip": null}},
+            },
+            "empty": {},
+            "none": [],
+        })
+    }
+
+    #[test]
+    fn action_round_trip() {
+        let mut a = Action::new("user.update", 42).with_token("t");
+        a.payload = serde_json::from_value(nested()).unwrap();
+        a.set_binary(vec![0, 1, 2, 255]);
+        let bytes = a.to_msgpack().unwrap();
+        assert!(bytes.len() < a.to_bytes().unwrap().len());
+
+        let back = Action::from_msgpack(bytes).unwrap();
+        assert_eq!((back.name.as_str(), back.id), ("user.update", 42));
+        assert_eq!(back.token.as_deref(), Some("t"));
+        assert_eq!(json!(back.payload), nested());
+        assert_eq!(back.payload["user"]["id"].as_u64(), Some(u64::MAX));
+        assert_eq!(back.payload["user"]["balance"].as_i64(), Some(i64::MIN));
+        assert_eq!(back.binary, Some(vec![0, 1, 2, 255]));
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let mut a = Action::new("user.get", 7);
+        a.set_result(nested());
+        a.set_error(ActionError::new("Partial", "some fields missing"));
+        let reply = a.into_reply();
+        let back = ActionReply::from_msgpack(reply.to_msgpack().unwrap()).unwrap();
+        assert_eq!(back.id, 7);
+        assert_eq!(back.result, Some(nested()));
+        assert_eq!(back.errors[0].code, "Partial");
+    }
+
+    #[test]
+    fn bad_input() {
+        for buf in [&b""[..], &[0xc1][..], &[0x81, 0xa4, b'n', b'a'][..]] {
+            let err = Action::from_msgpack(Bytes::from(buf.to_vec())).unwrap_err();
+            assert_eq!(err.code, "MsgpackError");
+        }
+        let json = Action::new("a", 1).to_bytes().unwrap();
+        assert_eq!(
+            ActionReply::from_msgpack(json).unwrap_err().code,
+            "MsgpackError"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! bounded per-connection queue of encoded replies waiting to be written, so a slow
+//! consumer can't make the server buffer without limit
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+use crate::action::ActionReply;
+use crate::error::ActionError;
+
+/// websocket close code sent when a consumer is disconnected for falling behind
+/// (1008, policy violation)
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 1008;
+pub const SLOW_CONSUMER_CLOSE_REASON: &str = "slow consumer";
+
+/// what to do once a connection's queue is over its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// close the connection with `SLOW_CONSUMER_CLOSE_CODE`
+    Disconnect,
+    /// discard the oldest queued replies until the new one fits
+    DropOldest,
+    /// keep queueing but stop reading new actions until the queue drains
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub max_depth: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_depth: 1024,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// result of queueing a reply
+#[derive(Debug, PartialEq, Eq)]
+pub enum Enqueued {
+    Queued,
+    /// queued after dropping these older replies, e.g. for a dead letter sink
+    Dropped(Vec<Bytes>),
+    /// the connection must be closed with this code and reason, nothing was queued
+    Disconnect {
+        code: u16,
+        reason: &'static str,
+    },
+}
+
+pub struct SendQueue {
+    limits: QueueLimits,
+    policy: SlowConsumerPolicy,
+    queue: VecDeque<Bytes>,
+    bytes: usize,
+    dropped: u64,
+    closed: bool,
+}
+
+impl SendQueue {
+    pub fn new(limits: QueueLimits, policy: SlowConsumerPolicy) -> Self {
+        SendQueue {
+            limits,
+            policy,
+            queue: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+            closed: false,
+        }
+    }
+
+    fn over(&self, extra: usize) -> bool {
+        self.queue.len() + 1 > self.limits.max_depth || self.bytes + extra > self.limits.max_bytes
+    }
+
+    pub fn push_reply(&mut self, reply: &ActionReply) -> Result<Enqueued, ActionError> {
+        Ok(self.push(Bytes::from(serde_json::to_vec(reply)?)))
+    }
+
+    pub fn push(&mut self, frame: Bytes) -> Enqueued {
+        if self.closed {
+            return Enqueued::Disconnect {
+                code: SLOW_CONSUMER_CLOSE_CODE,
+                reason: SLOW_CONSUMER_CLOSE_REASON,
+            };
+        }
+        let mut out = Enqueued::Queued;
+        if self.over(frame.len()) {
+            match self.policy {
+                SlowConsumerPolicy::Disconnect => {
+                    self.closed = true;
+                    self.queue.clear();
+                    self.bytes = 0;
+                    return Enqueued::Disconnect {
+                        code: SLOW_CONSUMER_CLOSE_CODE,
+                        reason: SLOW_CONSUMER_CLOSE_REASON,
+                    };
+                }
+                SlowConsumerPolicy::DropOldest => {
+                    let mut dropped = Vec::new();
+                    while !self.queue.is_empty() && self.over(frame.len()) {
+                        if let Some(old) = self.pop() {
+                            dropped.push(old);
+                        }
+                    }
+                    self.dropped += dropped.len() as u64;
+                    out = Enqueued::Dropped(dropped);
+                }
+                SlowConsumerPolicy::Block => (),
+            }
+        }
+        self.bytes += frame.len();
+        self.queue.push_back(frame);
+        out
+    }
+
+    /// next frame to write to the connection
+    pub fn pop(&mut self) -> Option<Bytes> {
+        let frame = self.queue.pop_front()?;
+        self.bytes -= frame.len();
+        Some(frame)
+    }
+
+    /// false while a `Block` queue is over its limits, the transport should stop reading
+    /// actions from the connection until this turns true again
+    pub fn accepting_reads(&self) -> bool {
+        match self.policy {
+            SlowConsumerPolicy::Block => {
+                self.queue.len() < self.limits.max_depth && self.bytes < self.limits.max_bytes
+            }
+            _ => !self.closed,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// how many replies `DropOldest` has discarded so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(n: u8) -> Bytes {
+        Bytes::from(vec![n; 10])
+    }
+
+    fn limits() -> QueueLimits {
+        QueueLimits {
+            max_depth: 3,
+            max_bytes: 1000,
+        }
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut q = SendQueue::new(limits(), SlowConsumerPolicy::Disconnect);
+        for n in 0..3 {
+            assert_eq!(q.push(frame(n)), Enqueued::Queued);
+        }
+        // the consumer never reads, the fourth reply closes the connection
+        assert_eq!(
+            q.push(frame(3)),
+            Enqueued::Disconnect {
+                code: SLOW_CONSUMER_CLOSE_CODE,
+                reason: SLOW_CONSUMER_CLOSE_REASON
+            }
+        );
+        assert!(q.is_empty());
+        assert!(!q.accepting_reads());
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut q = SendQueue::new(limits(), SlowConsumerPolicy::DropOldest);
+        for n in 0..3 {
+            q.push(frame(n));
+        }
+        assert_eq!(q.push(frame(3)), Enqueued::Dropped(vec![frame(0)]));
+        assert_eq!(q.push(frame(4)), Enqueued::Dropped(vec![frame(1)]));
+        assert_eq!(q.dropped(), 2);
+        let left: Vec<Bytes> = std::iter::from_fn(|| q.pop()).collect();
+        assert_eq!(left, vec![frame(2), frame(3), frame(4)]);
+    }
+
+    #[test]
+    fn drop_oldest_by_bytes() {
+        let mut q = SendQueue::new(
+            QueueLimits {
+                max_depth: 100,
+                max_bytes: 25,
+            },
+            SlowConsumerPolicy::DropOldest,
+        );
+        q.push(frame(0));
+        q.push(frame(1));
+        assert_eq!(q.push(frame(2)), Enqueued::Dropped(vec![frame(0)]));
+        assert_eq!(q.queued_bytes(), 20);
+    }
+
+    #[test]
+    fn block() {
+        let mut q = SendQueue::new(limits(), SlowConsumerPolicy::Block);
+        q.push(frame(0));
+        q.push(frame(1));
+        assert!(q.accepting_reads());
+        q.push(frame(2));
+        assert!(!q.accepting_reads());
+        // replies already produced are still kept
+        assert_eq!(q.push(frame(3)), Enqueued::Queued);
+        assert_eq!(q.len(), 4);
+        q.pop();
+        q.pop();
+        assert!(q.accepting_reads());
+    }
+
+    #[test]
+    fn push_reply() {
+        let mut q = SendQueue::new(limits(), SlowConsumerPolicy::Block);
+        let mut a = crate::action::Action::server_err(ActionError::new("", ""));
+        a.id = 9;
+        q.push_reply(&a.into_reply()).unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&q.pop().unwrap()).unwrap();
+        assert_eq!(v["id"], json!(9));
+    }
+}
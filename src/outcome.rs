@@ -0,0 +1,147 @@
+/// why a reply looks the way it does, so transports and metrics do not have to match on
+/// error codes; see `Manager::handle_with_outcome`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum DispatchOutcome {
+    /// the handler ran and succeeded
+    Handled,
+    /// no handler is registered under the name
+    NotFound,
+    /// turned away before the handler ran: source policy, validation, unsupported dry run
+    Rejected,
+    RateLimited,
+    /// not run because the manager could not take it on, like a passed batch deadline
+    Shed,
+    /// answered without running the handler
+    Cached,
+    /// the dry run handler ran and succeeded
+    DryRun,
+    /// the handler returned an error, panicked, timed out or its result could not be sent
+    HandlerError,
+}
+
+impl DispatchOutcome {
+    /// a stable name, usable as a metrics label
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DispatchOutcome::Handled => "handled",
+            DispatchOutcome::NotFound => "not_found",
+            DispatchOutcome::Rejected => "rejected",
+            DispatchOutcome::RateLimited => "rate_limited",
+            DispatchOutcome::Shed => "shed",
+            DispatchOutcome::Cached => "cached",
+            DispatchOutcome::DryRun => "dry_run",
+            DispatchOutcome::HandlerError => "handler_error",
+        }
+    }
+
+    /// the status an HTTP transport should answer with
+    pub fn http_status(self) -> u16 {
+        match self {
+            DispatchOutcome::Handled | DispatchOutcome::Cached | DispatchOutcome::DryRun => 200,
+            DispatchOutcome::NotFound => 404,
+            DispatchOutcome::Rejected => 400,
+            DispatchOutcome::RateLimited => 429,
+            DispatchOutcome::Shed => 503,
+            DispatchOutcome::HandlerError => 500,
+        }
+    }
+
+    pub fn is_success(self) -> bool {
+        matches!(
+            self,
+            DispatchOutcome::Handled | DispatchOutcome::Cached | DispatchOutcome::DryRun
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, BatchOptions, Manager};
+    use crate::error::ActionError;
+    use crate::source::PolicyOverrides;
+    use std::time::Instant;
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.catch_panics(true);
+        m.on("ok", |_, _| action_ok());
+        m.on(
+            "fail",
+            |_, _| Err(ActionError::new("Fail", "failed").into()),
+        );
+        m.on("boom", |_, _| panic!("boom"));
+        m.on_with_dry_run("pay", |_, _| action_ok(), |_, _| action_ok());
+        m.validate_action("ok", |a: &Action| {
+            if a.payload.contains_key("bad") {
+                Err(vec![ActionError::new("Invalid", "bad")])
+            } else {
+                Ok(())
+            }
+        });
+        m.source_policy(
+            "ws",
+            PolicyOverrides {
+                allowed_actions: Some(vec!["pay".to_owned()]),
+                ..PolicyOverrides::default()
+            },
+        );
+        m
+    }
+
+    fn outcome(m: &Manager<()>, a: Action) -> DispatchOutcome {
+        m.handle_with_outcome(a).1
+    }
+
+    #[test]
+    fn outcome_per_path() {
+        let m = manager();
+        assert_eq!(outcome(&m, action("ok")), DispatchOutcome::Handled);
+        assert_eq!(outcome(&m, action("nope")), DispatchOutcome::NotFound);
+        assert_eq!(outcome(&m, action("fail")), DispatchOutcome::HandlerError);
+        assert_eq!(outcome(&m, action("boom")), DispatchOutcome::HandlerError);
+
+        let mut a = action("ok");
+        a.payload.insert("bad".to_owned(), json!(true));
+        assert_eq!(outcome(&m, a), DispatchOutcome::Rejected);
+
+        let mut a = action("ok");
+        a.source = Some("ws".to_owned());
+        let (reply, o) = m.handle_with_outcome(a);
+        assert_eq!(o, DispatchOutcome::Rejected);
+        assert_eq!(reply.errors[0].code, "SourceNotAllowed");
+
+        let mut a = action("pay");
+        a.dry_run = Some(true);
+        assert_eq!(outcome(&m, a), DispatchOutcome::DryRun);
+        let mut a = action("ok");
+        a.dry_run = Some(true);
+        assert_eq!(outcome(&m, a), DispatchOutcome::Rejected);
+    }
+
+    #[test]
+    fn batch_deadline_sheds() {
+        let m = manager();
+        let opts = BatchOptions {
+            deadline: Some(Instant::now()),
+        };
+        let reply = m.do_batch_with(vec![action("ok")], opts);
+        assert_eq!(reply.outcomes, vec![DispatchOutcome::Shed]);
+        assert_eq!(DispatchOutcome::Shed.http_status(), 503);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(DispatchOutcome::HandlerError.as_str(), "handler_error");
+        assert_eq!(
+            serde_json::to_value(DispatchOutcome::NotFound).unwrap(),
+            json!("not_found")
+        );
+    }
+}
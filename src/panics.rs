@@ -0,0 +1,167 @@
+use std::any::Any;
+
+/// how many characters of a panic message the default scrubber keeps
+pub const MAX_PANIC_MESSAGE_LEN: usize = 200;
+
+/// what secret-looking runs are replaced with
+pub const SCRUBBED: &str = "[scrubbed]";
+
+/// runs of hex digits at least this long are taken for secrets (keys, hashes, ids)
+const MIN_HEX_RUN: usize = 16;
+
+/// runs of base64 characters at least this long and mixing letters with digits are taken
+/// for secrets (tokens, encoded keys)
+const MIN_BASE64_RUN: usize = 24;
+
+/// turns a panic message into what the client may see, see `Manager::panic_scrubber`
+pub type PanicScrubber = dyn Fn(&str) -> String + Send + Sync;
+
+/// the message of a panic raised with a `&str` or a `String`
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "the handler panicked".to_owned()
+    }
+}
+
+fn is_base64(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '-' | '_')
+}
+
+fn looks_secret(run: &str) -> bool {
+    let len = run.chars().count();
+    (len >= MIN_HEX_RUN && run.chars().all(|c| c.is_ascii_hexdigit()))
+        || (len >= MIN_BASE64_RUN
+            && run.chars().any(|c| c.is_ascii_digit())
+            && run.chars().any(|c| c.is_ascii_alphabetic()))
+}
+
+/// the default scrubber: long hex and base64 looking runs are replaced by `SCRUBBED` and
+/// the rest is cut to `MAX_PANIC_MESSAGE_LEN` characters
+pub fn scrub_panic_message(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut run = String::new();
+    let flush = |run: &mut String, out: &mut String| {
+        if looks_secret(run) {
+            out.push_str(SCRUBBED);
+        } else {
+            out.push_str(run);
+        }
+        run.clear();
+    };
+    for c in message.chars() {
+        if is_base64(c) {
+            run.push(c);
+        } else {
+            flush(&mut run, &mut out);
+            out.push(c);
+        }
+    }
+    flush(&mut run, &mut out);
+    if out.chars().count() > MAX_PANIC_MESSAGE_LEN {
+        out = out.chars().take(MAX_PANIC_MESSAGE_LEN).collect();
+        out.push('…');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, Manager};
+
+    const SECRET: &str = "9f86d081884c7d659a2feaa0c55ad015";
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.catch_panics(true);
+        m.on("boom", |_, _| {
+            panic!(
+                "query failed: SELECT * FROM users WHERE api_key = '{}'",
+                SECRET
+            )
+        });
+        m.on("ok", |_, _| action_ok());
+        m
+    }
+
+    #[test]
+    fn scrubs_secret_looking_runs() {
+        assert_eq!(
+            scrub_panic_message(&format!("key {} rejected", SECRET)),
+            "key [scrubbed] rejected"
+        );
+        let token = "c2stbGl2ZS1hYmMxMjMtZG8tbm90LWxlYWs=";
+        assert_eq!(
+            scrub_panic_message(&format!("bad token {}", token)),
+            "bad token [scrubbed]"
+        );
+        // ordinary words and numbers stay
+        assert_eq!(
+            scrub_panic_message("index out of bounds: len 3 index 12"),
+            "index out of bounds: len 3 index 12"
+        );
+        let long = scrub_panic_message(&"word ".repeat(100));
+        assert_eq!(long.chars().count(), MAX_PANIC_MESSAGE_LEN + 1);
+    }
+
+    #[test]
+    fn reply_is_scrubbed_incident_is_not() {
+        let m = manager();
+        let mut a = action("boom");
+        m.do_action(&mut a);
+        let reply = m.reply(a);
+        let e = &reply.errors[0];
+        assert_eq!(e.code, "HandlerPanic");
+        assert!(!e.message.contains(SECRET));
+        assert!(e.message.contains("[scrubbed]"));
+
+        let id = e.details.as_ref().unwrap()["incident"].as_str().unwrap();
+        let incident = m.find_incident(id).unwrap();
+        assert_eq!(incident.action, "boom");
+        assert!(incident.errors[0].message.contains(SECRET));
+
+        // the manager keeps serving
+        let mut a = action("ok");
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+    }
+
+    #[test]
+    fn mut_handlers_too() {
+        let mut m = manager();
+        m.on_mut("count", |_, a| {
+            if a.payload.contains_key("boom") {
+                panic!("{}", String::from("counter overflow"));
+            }
+            Ok(json!(true))
+        });
+        let mut a = action("count");
+        a.payload.insert("boom".to_owned(), json!(true));
+        m.do_action_mut(&mut a);
+        let e = &a.errors.unwrap()[0];
+        assert_eq!(
+            (e.code.as_str(), e.message.as_str()),
+            ("HandlerPanic", "counter overflow")
+        );
+        let mut a = action("count");
+        m.do_action_mut(&mut a);
+        assert!(a.errors.is_none());
+    }
+
+    #[test]
+    fn custom_scrubber() {
+        let mut m = manager();
+        m.panic_scrubber(|_| "something went wrong".to_owned());
+        let mut a = action("boom");
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].message, "something went wrong");
+    }
+}
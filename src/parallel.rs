@@ -0,0 +1,118 @@
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::action::{Action, ActionReply, Manager};
+
+/// the order of the replies of `Manager::do_actions_parallel_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplyOrder {
+    /// the order the actions were given in
+    #[default]
+    Input,
+    /// the order the dispatches finished in
+    Completion,
+}
+
+impl<R: Send + Sync> Manager<R> {
+    /// dispatches the actions on up to `workers` threads and returns their replies in
+    /// the order of `actions`, see `do_actions_parallel_with`
+    pub fn do_actions_parallel(&self, actions: Vec<Action>, workers: usize) -> Vec<ActionReply> {
+        self.do_actions_parallel_with(actions, workers, ReplyOrder::Input)
+    }
+
+    /// dispatches the actions on up to `workers` threads, each taking the next action
+    /// once its last one is done, and returns once all are. Every action goes through
+    /// `do_action`: a stored resource is shared by the workers, a generator makes one
+    /// for each action
+    pub fn do_actions_parallel_with(
+        &self,
+        actions: Vec<Action>,
+        workers: usize,
+        order: ReplyOrder,
+    ) -> Vec<ActionReply> {
+        let total = actions.len();
+        let workers = workers.clamp(1, total.max(1));
+        let queue = Mutex::new(actions.into_iter().enumerate());
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let queue = &queue;
+                s.spawn(move || loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let (i, mut action) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    self.do_action(&mut action);
+                    if tx.send((i, self.reply(action))).is_err() {
+                        break;
+                    }
+                });
+            }
+        });
+        drop(tx);
+        let mut replies: Vec<(usize, ActionReply)> = rx.iter().collect();
+        if order == ReplyOrder::Input {
+            replies.sort_by_key(|(i, _)| *i);
+        }
+        replies.into_iter().map(|(_, reply)| reply).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+    use std::time::{Duration, Instant};
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("sleep", |_r, a| {
+            let ms: u64 = a.from_payload_field("ms")?;
+            thread::sleep(Duration::from_millis(ms));
+            value_ok(json!(a.id))
+        });
+        m
+    }
+
+    fn sleep(id: u64, ms: u64) -> Action {
+        let mut a = Action::new("sleep", id);
+        a.set_payload_field("ms", ms).unwrap();
+        a
+    }
+
+    #[test]
+    fn faster_than_serial() {
+        let m = manager();
+        let actions: Vec<Action> = (1..=8).map(|id| sleep(id, 50)).collect();
+        let started = Instant::now();
+        let replies = m.do_actions_parallel(actions, 4);
+        // 400ms one after the other, about 100ms on four workers
+        assert!(started.elapsed() < Duration::from_millis(300));
+        let ids: Vec<u64> = replies.iter().map(|r| r.id).collect();
+        assert_eq!(ids, (1..=8).collect::<Vec<u64>>());
+        assert!(replies.iter().all(|r| r.result == Some(json!(r.id))));
+    }
+
+    #[test]
+    fn completion_order() {
+        let m = manager();
+        let actions = vec![sleep(1, 150), sleep(2, 80), sleep(3, 10)];
+        let replies = m.do_actions_parallel_with(actions, 3, ReplyOrder::Completion);
+        let ids: Vec<u64> = replies.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn errors_and_edge_sizes() {
+        let m = manager();
+        assert!(m.do_actions_parallel(Vec::new(), 4).is_empty());
+        let actions = vec![Action::new("missing", 1), sleep(2, 0)];
+        // zero workers still runs on one
+        let replies = m.do_actions_parallel(actions, 0);
+        assert_eq!(replies.len(), 2);
+        assert!(!replies[0].errors.is_empty());
+        assert_eq!(replies[1].result, Some(json!(2)));
+    }
+}
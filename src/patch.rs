@@ -0,0 +1,250 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::action::Action;
+use crate::error::ActionError;
+
+/// a JSON Merge Patch (RFC 7396): `null` removes a key, objects merge recursively and
+/// anything else, arrays included, replaces the target wholesale
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct MergePatch(pub Value);
+
+impl MergePatch {
+    pub fn apply(&self, target: &mut Value) {
+        merge(target, &self.0);
+    }
+
+    /// the patch turning `old` into `new`
+    pub fn diff(old: &Value, new: &Value) -> MergePatch {
+        MergePatch(diff(old, new))
+    }
+
+    /// whether applying the patch to an object changes nothing
+    pub fn is_empty(&self) -> bool {
+        matches!(&self.0, Value::Object(map) if map.is_empty())
+    }
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        v => {
+            *target = v.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(map) = target {
+        for (k, v) in patch {
+            if v.is_null() {
+                map.remove(k);
+            } else {
+                merge(map.entry(k.clone()).or_insert(Value::Null), v);
+            }
+        }
+    }
+}
+
+fn diff(old: &Value, new: &Value) -> Value {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut patch = Map::new();
+            for k in old.keys() {
+                if !new.contains_key(k) {
+                    patch.insert(k.clone(), Value::Null);
+                }
+            }
+            for (k, v) in new {
+                match old.get(k) {
+                    Some(o) if o == v => {}
+                    // an object can only be written as a patch of an object, a null inside
+                    // it would mean "remove"
+                    Some(o @ Value::Object(_)) if v.is_object() => {
+                        patch.insert(k.clone(), diff(o, v));
+                    }
+                    _ => {
+                        patch.insert(k.clone(), diff(&Value::Object(Map::new()), v));
+                    }
+                }
+            }
+            Value::Object(patch)
+        }
+        // nulls can not be set through a merge patch, they are left out
+        (_, Value::Object(new)) => Value::Object(
+            new.iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), diff(&Value::Object(Map::new()), v)))
+                .collect(),
+        ),
+        (_, v) => v.clone(),
+    }
+}
+
+/// applies `patch` to `current` through its json form
+pub fn apply_patch_typed<T>(current: T, patch: &MergePatch) -> Result<T, ActionError>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let mut v = serde_json::to_value(current)?;
+    patch.apply(&mut v);
+    serde_json::from_value(v).map_err(|e| {
+        ActionError::new(
+            "PatchError",
+            &format!("the patched value is invalid: {}", e),
+        )
+    })
+}
+
+impl Action {
+    /// the payload as a merge patch, for update actions sending only what changed
+    pub fn payload_as_merge_patch(&self) -> MergePatch {
+        MergePatch(Value::Object(
+            self.payload
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patched(target: Value, patch: Value) -> Value {
+        let mut t = target;
+        MergePatch(patch).apply(&mut t);
+        t
+    }
+
+    #[test]
+    fn rfc_examples() {
+        // the test cases of RFC 7396 appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (target, patch, expected) in cases {
+            assert_eq!(
+                patched(target.clone(), patch.clone()),
+                expected,
+                "{} + {}",
+                target,
+                patch
+            );
+        }
+    }
+
+    #[test]
+    fn null_vs_absent() {
+        let user = json!({"name": "bob", "email": "b@x", "age": 3});
+        // absent keys are left alone, null ones removed
+        assert_eq!(
+            patched(user.clone(), json!({"email": null})),
+            json!({"name": "bob", "age": 3})
+        );
+        assert_eq!(patched(user.clone(), json!({})), user);
+        // removing a key which is not there is fine
+        assert_eq!(patched(user.clone(), json!({"nope": null})), user);
+    }
+
+    #[test]
+    fn nested_removal() {
+        let v = json!({"a": {"b": {"c": 1, "d": 2}, "e": 3}});
+        assert_eq!(
+            patched(v.clone(), json!({"a": {"b": {"c": null}}})),
+            json!({"a": {"b": {"d": 2}, "e": 3}})
+        );
+        assert_eq!(
+            patched(v, json!({"a": {"b": null}})),
+            json!({"a": {"e": 3}})
+        );
+    }
+
+    #[test]
+    fn diff_round_trips() {
+        let old = json!({"a": 1, "b": {"c": 2, "d": 3}, "e": [1], "f": "x"});
+        let new = json!({"a": 1, "b": {"c": 5}, "e": [1, 2], "g": {"h": true}});
+        let patch = MergePatch::diff(&old, &new);
+        assert_eq!(
+            patch.0,
+            json!({"b": {"c": 5, "d": null}, "e": [1, 2], "f": null, "g": {"h": true}})
+        );
+        assert_eq!(patched(old.clone(), patch.0), new);
+        assert!(MergePatch::diff(&old, &old).is_empty());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        email: Option<String>,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn typed() {
+        let user = User {
+            name: "bob".to_owned(),
+            email: Some("b@x".to_owned()),
+            tags: vec!["a".to_owned()],
+        };
+        let mut a = Action::server_err(ActionError::new("", ""));
+        a.payload = serde_json::from_value(json!({"email": null, "tags": ["b", "c"]})).unwrap();
+        let updated = apply_patch_typed(user, &a.payload_as_merge_patch()).unwrap();
+        assert_eq!(
+            updated,
+            User {
+                name: "bob".to_owned(),
+                email: None,
+                tags: vec!["b".to_owned(), "c".to_owned()],
+            }
+        );
+        let e = apply_patch_typed(updated, &MergePatch(json!({"name": null}))).unwrap_err();
+        assert_eq!(e.code, "PatchError");
+    }
+}
@@ -0,0 +1,400 @@
+//! a fixed number of resources shared by the dispatches of a manager, see
+//! `Manager::pooled`
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::action::{Manager, ResourceGen};
+use crate::error::ActionError;
+
+/// decides whether a resource goes back to the pool after use, see `Manager::pool_health`
+pub type PoolHealth<R> = dyn Fn(&R) -> bool + Send + Sync;
+
+/// how many resources a pool holds and how many of them are in use
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub size: usize,
+    /// made and not dropped, in use or idle
+    pub live: usize,
+    pub idle: usize,
+}
+
+struct PoolState<R> {
+    idle: Vec<R>,
+    live: usize,
+}
+
+pub(crate) struct ResourcePool<R> {
+    make: Box<ResourceGen<R>>,
+    size: usize,
+    state: Mutex<PoolState<R>>,
+    returned: Condvar,
+    wait: Option<Duration>,
+    healthy: Option<Box<PoolHealth<R>>>,
+}
+
+/// a resource taken out of the pool, it goes back when dropped
+pub(crate) struct Pooled<'a, R> {
+    pool: &'a ResourcePool<R>,
+    resource: Option<R>,
+}
+
+impl<R> Deref for Pooled<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        self.resource
+            .as_ref()
+            .expect("a pooled resource is only taken on drop")
+    }
+}
+
+impl<R> DerefMut for Pooled<'_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        self.resource
+            .as_mut()
+            .expect("a pooled resource is only taken on drop")
+    }
+}
+
+impl<R> Drop for Pooled<'_, R> {
+    fn drop(&mut self) {
+        if let Some(r) = self.resource.take() {
+            self.pool.give_back(r);
+        }
+    }
+}
+
+fn exhausted(size: usize, waited: Duration) -> ActionError {
+    ActionError::new(
+        "PoolExhausted",
+        &format!(
+            "all {} pooled resources stayed in use for {}ms",
+            size,
+            waited.as_millis()
+        ),
+    )
+    .with_details(json!({ "size": size }))
+    .retryable()
+}
+
+impl<R> ResourcePool<R> {
+    fn new(size: usize, make: Box<ResourceGen<R>>) -> Self {
+        let idle: Vec<R> = (0..size).filter_map(|_| make().ok()).collect();
+        ResourcePool {
+            make,
+            size,
+            state: Mutex::new(PoolState {
+                live: idle.len(),
+                idle,
+            }),
+            returned: Condvar::new(),
+            wait: None,
+            healthy: None,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState<R>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// an idle resource, a new one while the pool is not full, or the next one given
+    /// back; fails with `PoolExhausted` once the wait is over and with the generator's
+    /// error when making one fails
+    pub(crate) fn take(&self) -> Result<Pooled<'_, R>, ActionError> {
+        let started = Instant::now();
+        let mut state = self.lock();
+        loop {
+            if let Some(r) = state.idle.pop() {
+                return Ok(self.pooled(r));
+            }
+            if state.live < self.size {
+                state.live += 1;
+                drop(state);
+                return self.make_counted();
+            }
+            state = match self.wait {
+                None => self.returned.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(wait) => {
+                    let left = match wait.checked_sub(started.elapsed()) {
+                        Some(left) if !left.is_zero() => left,
+                        _ => return Err(exhausted(self.size, wait)),
+                    };
+                    self.returned
+                        .wait_timeout(state, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+    }
+
+    /// `take` without waiting, `None` while every resource is in use
+    pub(crate) fn try_take(&self) -> Option<Result<Pooled<'_, R>, ActionError>> {
+        let mut state = self.lock();
+        if let Some(r) = state.idle.pop() {
+            return Some(Ok(self.pooled(r)));
+        }
+        if state.live < self.size {
+            state.live += 1;
+            drop(state);
+            return Some(self.make_counted());
+        }
+        None
+    }
+
+    /// the generator, to replace a resource the probe found broken
+    pub(crate) fn make(&self) -> &ResourceGen<R> {
+        self.make.as_ref()
+    }
+
+    fn pooled(&self, r: R) -> Pooled<'_, R> {
+        Pooled {
+            pool: self,
+            resource: Some(r),
+        }
+    }
+
+    /// makes a resource already counted as live, uncounting it when that fails
+    fn make_counted(&self) -> Result<Pooled<'_, R>, ActionError> {
+        match (self.make)() {
+            Ok(r) => Ok(self.pooled(r)),
+            Err(e) => {
+                self.lock().live -= 1;
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+
+    fn give_back(&self, r: R) {
+        let keep = self.healthy.as_ref().is_none_or(|healthy| healthy(&r));
+        let mut state = self.lock();
+        if keep {
+            state.idle.push(r);
+        } else {
+            state.live -= 1;
+            drop(state);
+            drop(r);
+        }
+        self.returned.notify_one();
+    }
+
+    fn status(&self) -> PoolStatus {
+        let state = self.lock();
+        PoolStatus {
+            size: self.size,
+            live: state.live,
+            idle: state.idle.len(),
+        }
+    }
+}
+
+impl<R> Manager<R> {
+    /// up to `size` resources made by `f` up front and shared by the dispatches, each
+    /// one holding a resource for as long as its handler runs. Dispatches wait for one
+    /// to be free, see `pool_timeout`; resources `f` failed to make are made when needed,
+    /// and an action whose resource could not be made gets a retryable `ResourceError`.
+    /// Handlers registered with `on_mut` change the resource they were handed, which then
+    /// goes back to the pool. Panics when `size` is 0, nothing could ever run
+    pub fn pooled<T>(name: &str, size: usize, f: T) -> Self
+    where
+        T: Fn() -> Result<R, ActionError> + Send + Sync + 'static,
+    {
+        assert!(size > 0, "a pool of {} needs at least one resource", name);
+        let mut m = Manager::try_with(name, f);
+        let make = m.take_gen_resource().expect("try_with sets a generator");
+        *m.pool_mut() = Some(ResourcePool::new(size, make));
+        m
+    }
+
+    /// dispatches waiting longer than `wait` for a pooled resource fail with a retryable
+    /// `PoolExhausted` instead of waiting on
+    pub fn pool_timeout(&mut self, wait: Duration) {
+        if let Some(pool) = self.pool_mut() {
+            pool.wait = Some(wait);
+        }
+    }
+
+    /// resources for which `f` says false after use are dropped instead of going back,
+    /// a new one is made in their place when needed
+    pub fn pool_health<F>(&mut self, f: F)
+    where
+        F: Fn(&R) -> bool + Send + Sync + 'static,
+    {
+        if let Some(pool) = self.pool_mut() {
+            pool.healthy = Some(Box::new(f));
+        }
+    }
+
+    /// `None` unless the manager was made with `pooled`
+    pub fn pool_status(&self) -> Option<PoolStatus> {
+        self.pool().map(ResourcePool::status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action};
+    use crate::outcome::DispatchOutcome;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    struct Conn {
+        id: usize,
+        broken: AtomicBool,
+    }
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 1)
+    }
+
+    fn counting_pool(size: usize) -> (Manager<Conn>, Arc<AtomicUsize>) {
+        let made = Arc::new(AtomicUsize::new(0));
+        let counter = made.clone();
+        let m = Manager::pooled("db", size, move || {
+            Ok(Conn {
+                id: counter.fetch_add(1, Ordering::SeqCst),
+                broken: AtomicBool::new(false),
+            })
+        });
+        (m, made)
+    }
+
+    #[test]
+    fn many_threads_share_a_small_pool() {
+        let (mut m, made) = counting_pool(2);
+        let active = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (a, b) = (active.clone(), most.clone());
+        m.on("query", move |conn, _| {
+            let now = a.fetch_add(1, Ordering::SeqCst) + 1;
+            b.fetch_max(now, Ordering::SeqCst);
+            thread::yield_now();
+            a.fetch_sub(1, Ordering::SeqCst);
+            value_ok(conn.id)
+        });
+        m.on("fail", |_, _| Err(ActionError::new("Nope", "").into()));
+        let m = Arc::new(m);
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        let mut a = action(if (t + i) % 5 == 0 { "fail" } else { "query" });
+                        m.do_action(&mut a);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        // failed handlers gave their resource back too
+        assert_eq!(made.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            m.pool_status(),
+            Some(PoolStatus {
+                size: 2,
+                live: 2,
+                idle: 2
+            })
+        );
+    }
+
+    #[test]
+    fn broken_resources_are_replaced() {
+        let (mut m, made) = counting_pool(1);
+        m.pool_health(|c| !c.broken.load(Ordering::SeqCst));
+        m.on("id", |c, _| value_ok(c.id));
+        m.on("break", |c, _| {
+            c.broken.store(true, Ordering::SeqCst);
+            Err(ActionError::new("Lost", "connection lost").into())
+        });
+        let mut a = action("id");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(0)));
+        m.do_action(&mut action("break"));
+        assert_eq!(m.pool_status().unwrap().live, 0);
+        let mut a = action("id");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(1)));
+        assert_eq!(made.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn exhausted_after_timeout() {
+        let (mut m, _) = counting_pool(1);
+        m.pool_timeout(Duration::from_millis(20));
+        let (held_tx, held_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let held_tx = Mutex::new(held_tx);
+        m.on("hold", move |_, _| {
+            held_tx.lock().unwrap().send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            value_ok(true)
+        });
+        m.on("id", |c, _| value_ok(c.id));
+        let m = Arc::new(m);
+        let holder = {
+            let m = m.clone();
+            thread::spawn(move || m.do_action(&mut action("hold")))
+        };
+        held_rx.recv().unwrap();
+        let (reply, outcome) = m.handle_with_outcome(action("id"));
+        assert_eq!(outcome, DispatchOutcome::Shed);
+        assert_eq!(reply.errors[0].code, "PoolExhausted");
+        assert!(reply.errors[0].is_retryable());
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        let (reply, _) = m.handle_with_outcome(action("id"));
+        assert_eq!(reply.result, Some(json!(0)));
+    }
+
+    #[test]
+    fn mut_handlers_change_a_pooled_resource() {
+        let mut m = Manager::pooled("db", 1, || Ok(Vec::new()));
+        m.on_mut("push", |v, _| {
+            v.push(1);
+            Ok(json!(v.len()))
+        });
+        m.on("len", |v, _| value_ok(v.len()));
+        for n in 1..=2 {
+            let mut a = action("push");
+            m.do_action_mut(&mut a);
+            assert_eq!(a.result, Some(json!(n)));
+        }
+        let mut a = action("len");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "needs at least one resource")]
+    fn empty_pools_are_refused() {
+        let _: Manager<()> = Manager::pooled("db", 0, || Ok(()));
+    }
+
+    #[test]
+    fn failed_generator_is_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut m = Manager::pooled("db", 1, move || {
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(ActionError::new("Down", "database is down")),
+                n => Ok(n),
+            }
+        });
+        m.on("n", |n, _| value_ok(*n));
+        // the pool could not make its resource up front, nor on the first dispatch
+        let (reply, _) = m.handle_with_outcome(action("n"));
+        assert_eq!(reply.errors[0].code, "ResourceError");
+        let (reply, _) = m.handle_with_outcome(action("n"));
+        assert_eq!(reply.result, Some(json!(2)));
+    }
+}
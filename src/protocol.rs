@@ -0,0 +1,414 @@
+use bytes::Bytes;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::ops::{BitAnd, BitOr};
+use std::sync::{Arc, Mutex};
+
+use crate::action::{Action, ActionReply, ParseOptions};
+use crate::error::ActionError;
+
+/// wire protocol version spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// name of the built-in handshake action, see `Manager::enable_handshake`
+pub const HANDSHAKE_ACTION: &str = "__hello";
+
+/// how many tokens the features agreed on by a handshake are kept for, later ones get
+/// the defaults
+pub const NEGOTIATED_CAPACITY: usize = 10_000;
+
+/// name of the reply sent for failures before an action could be dispatched, see
+/// `protocol_error`
+pub const PROTOCOL_ERROR_ACTION: &str = "__protocol_error";
+
+/// where a transport gave up on a frame before dispatching it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolStage {
+    /// the frame itself was unacceptable, e.g. oversized
+    Framing,
+    /// the frame is not a valid action
+    Parse,
+    /// the peer broke the handshake or asked for something not negotiated
+    Handshake,
+    Auth,
+    /// the server is not taking more work right now
+    Overload,
+}
+
+impl ProtocolStage {
+    /// the status an HTTP transport should answer with
+    pub fn http_status(self) -> u16 {
+        match self {
+            ProtocolStage::Framing => 413,
+            ProtocolStage::Parse | ProtocolStage::Handshake => 400,
+            ProtocolStage::Auth => 401,
+            ProtocolStage::Overload => 503,
+        }
+    }
+}
+
+/// the reply every transport sends for a frame it could not dispatch: id 0, named
+/// `__protocol_error`, the stage under `"stage"` in the payload and `err` attached
+pub fn protocol_error(stage: ProtocolStage, err: ActionError) -> ActionReply {
+    let mut payload = HashMap::new();
+    payload.insert("stage".to_owned(), json!(stage));
+    ActionReply {
+        id: 0,
+        name: PROTOCOL_ERROR_ACTION.to_owned(),
+        correlation_id: None,
+        payload,
+        result: None,
+        errors: vec![err],
+        warnings: Vec::new(),
+        attachments: Vec::new(),
+        dry_run: false,
+        budget_used: None,
+        server_ts_ms: None,
+        server_seq: None,
+        logs: Vec::new(),
+        stale: false,
+        seq: None,
+        more: None,
+        received_at: None,
+        duration_ms: None,
+        meta: HashMap::new(),
+    }
+}
+
+/// parses a frame for dispatch, or gives the protocol error to send back: frames over
+/// `max_frame_bytes` and attachments over the limits fail at `Framing`, anything not an
+/// action at `Parse`
+pub fn parse_frame(
+    buf: Bytes,
+    max_frame_bytes: Option<usize>,
+    opts: &ParseOptions,
+) -> Result<Action, Box<ActionReply>> {
+    if let Some(max) = max_frame_bytes {
+        if buf.len() > max {
+            return Err(Box::new(protocol_error(
+                ProtocolStage::Framing,
+                ActionError::new(
+                    "FrameTooLarge",
+                    &format!("frame is {} bytes, limit is {}", buf.len(), max),
+                ),
+            )));
+        }
+    }
+    let action =
+        Action::from_slice(&buf).map_err(|e| Box::new(protocol_error(ProtocolStage::Parse, e)))?;
+    opts.check(&action)
+        .map_err(|e| Box::new(protocol_error(ProtocolStage::Framing, e)))?;
+    Ok(action)
+}
+
+impl ActionReply {
+    /// the stage of a `protocol_error` reply, `None` for replies of dispatched actions
+    pub fn protocol_stage(&self) -> Option<ProtocolStage> {
+        if self.name != PROTOCOL_ERROR_ACTION {
+            return None;
+        }
+        self.payload
+            .get("stage")
+            .and_then(|s| serde_json::from_value(s.clone()).ok())
+    }
+}
+
+/// set of optional wire capabilities, serialized as a list of names so unknown
+/// features sent by newer peers are simply ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ProtocolFeatures(u32);
+
+const NAMES: &[(&str, ProtocolFeatures)] = &[
+    ("compact", ProtocolFeatures::COMPACT),
+    ("gzip", ProtocolFeatures::GZIP),
+    ("batch", ProtocolFeatures::BATCH),
+];
+
+impl ProtocolFeatures {
+    /// `Manager::reply` leaves out the echoed payload, the carried attachments and the logs
+    pub const COMPACT: ProtocolFeatures = ProtocolFeatures(1);
+    /// the transport may compress frames, see `Manager::negotiated`
+    pub const GZIP: ProtocolFeatures = ProtocolFeatures(1 << 1);
+    /// the transport may send batches, see `Manager::negotiated`
+    pub const BATCH: ProtocolFeatures = ProtocolFeatures(1 << 2);
+
+    /// nothing negotiated, this is what applies when no handshake happened
+    pub const fn empty() -> Self {
+        ProtocolFeatures(0)
+    }
+
+    pub const fn all() -> Self {
+        ProtocolFeatures(Self::COMPACT.0 | Self::GZIP.0 | Self::BATCH.0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: ProtocolFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: ProtocolFeatures) -> Self {
+        ProtocolFeatures(self.0 & other.0)
+    }
+
+    pub const fn union(self, other: ProtocolFeatures) -> Self {
+        ProtocolFeatures(self.0 | other.0)
+    }
+
+    pub fn insert(&mut self, other: ProtocolFeatures) {
+        self.0 |= other.0;
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        NAMES.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        NAMES
+            .iter()
+            .filter(|(_, f)| self.contains(*f))
+            .map(|(n, _)| *n)
+            .collect()
+    }
+}
+
+impl BitAnd for ProtocolFeatures {
+    type Output = ProtocolFeatures;
+    fn bitand(self, rhs: ProtocolFeatures) -> ProtocolFeatures {
+        self.intersection(rhs)
+    }
+}
+
+impl BitOr for ProtocolFeatures {
+    type Output = ProtocolFeatures;
+    fn bitor(self, rhs: ProtocolFeatures) -> ProtocolFeatures {
+        self.union(rhs)
+    }
+}
+
+impl Serialize for ProtocolFeatures {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.names().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolFeatures {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let names: Vec<String> = Vec::deserialize(deserializer)?;
+        Ok(names
+            .iter()
+            .filter_map(|n| ProtocolFeatures::from_name(n))
+            .fold(ProtocolFeatures::empty(), |acc, f| acc | f))
+    }
+}
+
+/// payload of the handshake action and of its reply
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub proto: u32,
+    #[serde(default)]
+    pub features: ProtocolFeatures,
+    /// id of the compression dictionary to use, see the `compression` module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dict: Option<u32>,
+}
+
+impl Hello {
+    /// the server's answer to a client hello: its own version and the features both
+    /// sides support, which is what the connection should use from then on
+    pub fn negotiate(&self, supported: ProtocolFeatures) -> Hello {
+        self.negotiate_with_dicts(supported, &[])
+    }
+
+    /// `negotiate`, also agreeing on the dictionary the client asked for when the server
+    /// has it among `dicts`
+    pub fn negotiate_with_dicts(&self, supported: ProtocolFeatures, dicts: &[u32]) -> Hello {
+        Hello {
+            proto: PROTOCOL_VERSION,
+            features: self.features & supported,
+            dict: self.dict.filter(|d| dicts.contains(d)),
+        }
+    }
+}
+
+/// the features agreed on by the handshakes so far, by token; shared with the handshake
+/// handler
+#[derive(Clone, Default)]
+pub(crate) struct Negotiated(Arc<Mutex<HashMap<Option<String>, ProtocolFeatures>>>);
+
+impl Negotiated {
+    pub(crate) fn store(&self, token: Option<&str>, features: ProtocolFeatures) {
+        let mut map = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let key = token.map(|t| t.to_owned());
+        if map.len() < NEGOTIATED_CAPACITY || map.contains_key(&key) {
+            map.insert(key, features);
+        }
+    }
+
+    pub(crate) fn get(&self, token: Option<&str>) -> ProtocolFeatures {
+        let key = token.map(|t| t.to_owned());
+        let map = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        map.get(&key).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, EchoMode, Manager};
+    use serde_json::Value;
+
+    #[test]
+    fn intersection() {
+        let client = ProtocolFeatures::COMPACT | ProtocolFeatures::GZIP;
+        let server = ProtocolFeatures::GZIP | ProtocolFeatures::BATCH;
+        assert_eq!(client & server, ProtocolFeatures::GZIP);
+        assert!(ProtocolFeatures::all().contains(client));
+        assert!(!server.contains(client));
+        assert!((ProtocolFeatures::COMPACT & ProtocolFeatures::BATCH).is_empty());
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let f = ProtocolFeatures::COMPACT | ProtocolFeatures::BATCH;
+        let v = serde_json::to_value(f).unwrap();
+        assert_eq!(v, json!(["compact", "batch"]));
+        assert_eq!(serde_json::from_value::<ProtocolFeatures>(v).unwrap(), f);
+        let unknown: ProtocolFeatures =
+            serde_json::from_value(json!(["gzip", "teleport"])).unwrap();
+        assert_eq!(unknown, ProtocolFeatures::GZIP);
+    }
+
+    #[test]
+    fn no_handshake_default() {
+        assert!(ProtocolFeatures::default().is_empty());
+        let hello: Hello = serde_json::from_value(json!({"proto": 1})).unwrap();
+        assert!(hello.features.is_empty());
+
+        let m = Manager::new("test", ());
+        let mut a = Action::new(HANDSHAKE_ACTION, 0);
+        m.do_action(&mut a);
+        assert!(a.has_errors());
+    }
+
+    #[test]
+    fn handshake_action() {
+        let mut m = Manager::new("test", ());
+        m.enable_handshake(ProtocolFeatures::GZIP | ProtocolFeatures::BATCH);
+        let mut a = Action::new(HANDSHAKE_ACTION, 0);
+        a.payload =
+            serde_json::from_value(json!({"proto": 1, "features": ["compact", "gzip", "batch"]}))
+                .unwrap();
+        m.do_action(&mut a);
+        let reply: Hello = a.from_result().unwrap();
+        assert_eq!(reply.proto, PROTOCOL_VERSION);
+        assert_eq!(
+            reply.features,
+            ProtocolFeatures::GZIP | ProtocolFeatures::BATCH
+        );
+    }
+
+    #[test]
+    fn negotiated_features_shape_replies() {
+        let mut m = Manager::new("test", ());
+        m.enable_handshake(ProtocolFeatures::COMPACT);
+        m.echo_payload(EchoMode::Always);
+        m.on("echo", |_, _| value_ok(true));
+        let send = |name: &str, payload: Value| {
+            let mut a = Action::new(name, 1);
+            a.token = Some("t1".to_owned());
+            a.payload = serde_json::from_value(payload).unwrap();
+            m.do_action(&mut a);
+            serde_json::to_value(m.reply(a)).unwrap()
+        };
+        assert_eq!(send("echo", json!({"q": 1}))["payload"], json!({"q": 1}));
+        assert!(m.negotiated(Some("t1")).is_empty());
+
+        send(
+            HANDSHAKE_ACTION,
+            json!({"proto": 1, "features": ["compact"]}),
+        );
+        assert_eq!(m.negotiated(Some("t1")), ProtocolFeatures::COMPACT);
+        assert!(m.negotiated(Some("t2")).is_empty());
+        let reply = send("echo", json!({"q": 1}));
+        assert!(reply.get("payload").is_none());
+        assert_eq!(reply["result"], json!(true));
+    }
+
+    #[test]
+    fn framing_errors() {
+        let frame = Bytes::from(vec![b' '; 64]);
+        let reply = parse_frame(frame, Some(32), &ParseOptions::default()).unwrap_err();
+        assert_eq!((reply.id, reply.name.as_str()), (0, PROTOCOL_ERROR_ACTION));
+        assert_eq!(reply.protocol_stage(), Some(ProtocolStage::Framing));
+        assert_eq!(reply.errors[0].code, "FrameTooLarge");
+        assert_eq!(
+            serde_json::to_value(&reply).unwrap()["payload"]["stage"],
+            json!("framing")
+        );
+
+        let mut a = Action::new("", 0);
+        a.attach("big.bin", "application/octet-stream", &[0; 100]);
+        let opts = ParseOptions {
+            max_attachment_bytes: Some(10),
+            ..ParseOptions::default()
+        };
+        let frame = Bytes::from(serde_json::to_vec(&a).unwrap());
+        let reply = parse_frame(frame, None, &opts).unwrap_err();
+        assert_eq!(reply.protocol_stage(), Some(ProtocolStage::Framing));
+        assert_eq!(reply.errors[0].code, "AttachmentTooLarge");
+        assert_eq!(ProtocolStage::Framing.http_status(), 413);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let reply = parse_frame(
+            Bytes::from_static(b"{\"name\": "),
+            None,
+            &ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(reply.protocol_stage(), Some(ProtocolStage::Parse));
+        assert_eq!(reply.errors[0].code, "JsonError");
+
+        let frame = Bytes::from_static(br#"{"name": "a", "id": 1, "token": null, "base64": null, "payload": {}, "result": null, "errors": null}"#);
+        assert_eq!(
+            parse_frame(frame, Some(1024), &ParseOptions::default())
+                .unwrap()
+                .name,
+            "a"
+        );
+
+        // replies of dispatched actions have no stage
+        let m = Manager::new("test", ());
+        let mut a = Action::new("nope", 0);
+        m.do_action(&mut a);
+        assert_eq!(m.reply(a).protocol_stage(), None);
+    }
+
+    #[test]
+    fn dictionary_negotiation() {
+        let mut m = Manager::new("test", ());
+        m.enable_handshake_with_dicts(ProtocolFeatures::all(), vec![7, 9]);
+        let hello = |dict: Option<u32>| {
+            let mut a = Action::new(HANDSHAKE_ACTION, 0);
+            a.payload.insert("proto".to_owned(), json!(1));
+            if let Some(d) = dict {
+                a.payload.insert("dict".to_owned(), json!(d));
+            }
+            m.do_action(&mut a);
+            a.from_result::<Hello>().unwrap().dict
+        };
+        assert_eq!(hello(Some(9)), Some(9));
+        assert_eq!(hello(Some(8)), None);
+        assert_eq!(hello(None), None);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+
+/// bucket tokenless actions share under `AnonymousPolicy::Shared`
+pub const ANONYMOUS_BUCKET: &str = "anonymous";
+
+/// how the per token limit treats actions without a token
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum AnonymousPolicy {
+    /// they all count against one bucket, `ANONYMOUS_BUCKET`
+    #[default]
+    Shared,
+    /// they are refused with `TokenRequired`
+    Reject,
+    /// they are not limited by token, `per_action` still applies
+    Unlimited,
+}
+
+/// limits for `Manager::rate_limit`, each counts the actions let through during the last
+/// `window`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    /// for each token, across its actions
+    pub per_token: Option<u32>,
+    /// for each action name, across tokens
+    pub per_action: Option<u32>,
+    pub window: Duration,
+    pub anonymous: AnonymousPolicy,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_token: None,
+            per_action: None,
+            window: Duration::from_secs(60),
+            anonymous: AnonymousPolicy::default(),
+        }
+    }
+}
+
+type Window = HashMap<String, VecDeque<Instant>>;
+
+struct Windows {
+    tokens: Window,
+    actions: Window,
+    /// buckets kept after the last sweep of empty ones
+    swept_at: usize,
+}
+
+/// sliding window counters of a manager
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    windows: Mutex<Windows>,
+}
+
+/// the seconds until the oldest of `times` leaves the window, at least 1
+fn retry_after(times: &VecDeque<Instant>, window: Duration, now: Instant) -> u64 {
+    let oldest = times.front().copied().unwrap_or(now);
+    let wait = (oldest + window).saturating_duration_since(now);
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    secs.max(1)
+}
+
+/// drops the times out of the window and fails once `limit` remain
+fn check(
+    window: &mut Window,
+    key: &str,
+    limit: Option<u32>,
+    rate: &RateLimit,
+    scope: &str,
+    now: Instant,
+) -> Result<(), ActionError> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let times = window.entry(key.to_owned()).or_default();
+    while times
+        .front()
+        .is_some_and(|t| now.saturating_duration_since(*t) >= rate.window)
+    {
+        times.pop_front();
+    }
+    if times.len() < limit as usize {
+        return Ok(());
+    }
+    let retry = retry_after(times, rate.window, now);
+    // tokens are credentials, they are not repeated back
+    let limited = match scope {
+        "token" => "the token".to_owned(),
+        _ => format!("{} {}", scope, key),
+    };
+    Err(ActionError::new(
+        "RateLimited",
+        &format!(
+            "{} is over {} actions per {}s, retry after {} seconds",
+            limited,
+            limit,
+            rate.window.as_secs(),
+            retry
+        ),
+    )
+    .with_details(json!({ "scope": scope, "limit": limit, "retry_after": retry }))
+    .retryable())
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            windows: Mutex::new(Windows {
+                tokens: HashMap::new(),
+                actions: HashMap::new(),
+                swept_at: 0,
+            }),
+        }
+    }
+
+    /// counts the action when both limits let it through
+    pub(crate) fn admit(&self, action: &Action, now: Instant) -> Result<(), ActionError> {
+        let rate = &self.limit;
+        let token = match (action.token.as_deref(), &rate.anonymous) {
+            (Some(token), _) => Some(token),
+            (None, AnonymousPolicy::Shared) => Some(ANONYMOUS_BUCKET),
+            (None, AnonymousPolicy::Unlimited) => None,
+            (None, AnonymousPolicy::Reject) if rate.per_token.is_some() => {
+                return Err(ActionError::new(
+                    "TokenRequired",
+                    &format!("{} needs a token", action.name),
+                ));
+            }
+            (None, AnonymousPolicy::Reject) => None,
+        };
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let w = &mut *windows;
+        if let Some(token) = token {
+            check(&mut w.tokens, token, rate.per_token, rate, "token", now)?;
+        }
+        check(
+            &mut w.actions,
+            &action.name,
+            rate.per_action,
+            rate,
+            "action",
+            now,
+        )?;
+        if let (Some(token), Some(_)) = (token, rate.per_token) {
+            w.tokens.entry(token.to_owned()).or_default().push_back(now);
+        }
+        if rate.per_action.is_some() {
+            w.actions
+                .entry(action.name.clone())
+                .or_default()
+                .push_back(now);
+        }
+        // forget idle tokens once the map doubled since the last sweep
+        if w.tokens.len() > 1024 && w.tokens.len() > w.swept_at * 2 {
+            let window = rate.window;
+            w.tokens.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|t| now.saturating_duration_since(*t) < window)
+            });
+            w.swept_at = w.tokens.len();
+        }
+        Ok(())
+    }
+}
+
+impl<R> Manager<R> {
+    /// refuses actions over `limit` with a retryable `RateLimited` error before the handler
+    /// runs, once the signature and the token were checked; only the actions let through
+    /// count. The windows are measured with the
+    /// clock of the manager, sources can be exempted with `PolicyOverrides::skip_rate_limit`
+    pub fn rate_limit(&mut self, limit: RateLimit) {
+        *self.rate_limiter_mut() = Some(RateLimiter::new(limit));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+    use crate::auth::{unauthorized, AuthContext};
+    use crate::clock::FakeClock;
+    use crate::outcome::DispatchOutcome;
+    use crate::source::{PolicyOverrides, SCHEDULER, WS};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn manager(limit: RateLimit) -> (Manager<()>, Arc<FakeClock>) {
+        let clock = FakeClock::new();
+        let mut m = Manager::new("test", ());
+        m.on("get", |_r, _a| value_ok(json!(true)));
+        m.on("export", |_r, _a| value_ok(json!(true)));
+        m.set_clock(clock.clone());
+        m.rate_limit(limit);
+        (m, clock)
+    }
+
+    fn send(m: &Manager<()>, name: &str, token: Option<&str>) -> (DispatchOutcome, Action) {
+        let mut a = Action::new(name, 1);
+        a.token = token.map(|t| t.to_owned());
+        (m.do_action(&mut a), a)
+    }
+
+    #[test]
+    fn per_token_window() {
+        let (m, clock) = manager(RateLimit {
+            per_token: Some(2),
+            window: Duration::from_secs(10),
+            ..RateLimit::default()
+        });
+        assert_eq!(send(&m, "get", Some("a")).0, DispatchOutcome::Handled);
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(send(&m, "export", Some("a")).0, DispatchOutcome::Handled);
+
+        let (outcome, a) = send(&m, "get", Some("a"));
+        assert_eq!(outcome, DispatchOutcome::RateLimited);
+        assert!(a.result.is_none());
+        let err = &a.errors.unwrap()[0];
+        assert_eq!(err.code, "RateLimited");
+        assert!(err.message.contains("retry after 6 seconds"));
+        assert_eq!(err.details.as_ref().unwrap()["retry_after"], json!(6));
+
+        // other tokens have their own windows
+        assert_eq!(send(&m, "get", Some("b")).0, DispatchOutcome::Handled);
+
+        // the first one leaves the window
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(send(&m, "get", Some("a")).0, DispatchOutcome::Handled);
+        assert_eq!(send(&m, "get", Some("a")).0, DispatchOutcome::RateLimited);
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(send(&m, "get", Some("a")).0, DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn per_action() {
+        let (m, clock) = manager(RateLimit {
+            per_action: Some(1),
+            window: Duration::from_secs(60),
+            ..RateLimit::default()
+        });
+        assert_eq!(send(&m, "export", Some("a")).0, DispatchOutcome::Handled);
+        let (outcome, a) = send(&m, "export", Some("b"));
+        assert_eq!(outcome, DispatchOutcome::RateLimited);
+        assert_eq!(
+            a.errors.unwrap()[0].details.as_ref().unwrap()["scope"],
+            json!("action")
+        );
+        assert_eq!(send(&m, "get", Some("b")).0, DispatchOutcome::Handled);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(send(&m, "export", Some("b")).0, DispatchOutcome::Handled);
+    }
+
+    #[test]
+    fn anonymous_policies() {
+        let limit = RateLimit {
+            per_token: Some(1),
+            ..RateLimit::default()
+        };
+        let (m, _) = manager(limit.clone());
+        assert_eq!(send(&m, "get", None).0, DispatchOutcome::Handled);
+        // one shared bucket
+        assert_eq!(send(&m, "export", None).0, DispatchOutcome::RateLimited);
+
+        let (m, _) = manager(RateLimit {
+            anonymous: AnonymousPolicy::Reject,
+            ..limit.clone()
+        });
+        let (outcome, a) = send(&m, "get", None);
+        assert_eq!(outcome, DispatchOutcome::RateLimited);
+        assert_eq!(a.errors.unwrap()[0].code, "TokenRequired");
+
+        let (m, _) = manager(RateLimit {
+            anonymous: AnonymousPolicy::Unlimited,
+            ..limit
+        });
+        for _ in 0..5 {
+            assert_eq!(send(&m, "get", None).0, DispatchOutcome::Handled);
+        }
+    }
+
+    #[test]
+    fn exempt_sources() {
+        let (mut m, _) = manager(RateLimit {
+            per_token: Some(1),
+            ..RateLimit::default()
+        });
+        m.source_policy(
+            SCHEDULER,
+            PolicyOverrides {
+                skip_rate_limit: true,
+                ..PolicyOverrides::default()
+            },
+        );
+        let from = |source: &str| {
+            let mut a = Action::new("get", 1);
+            a.token = Some("a".to_owned());
+            m.do_action_from(source, &mut a);
+            a.errors.is_none()
+        };
+        assert!(from(WS));
+        assert!(!from(WS));
+        for _ in 0..3 {
+            assert!(from(SCHEDULER));
+        }
+        assert!(!from(WS));
+    }
+
+    #[test]
+    fn rejected_tokens_spend_nothing() {
+        let (mut m, _) = manager(RateLimit {
+            per_token: Some(1),
+            ..RateLimit::default()
+        });
+        let valid = Arc::new(AtomicBool::new(false));
+        let v = valid.clone();
+        m.set_token_validator(move |_| match v.load(Ordering::SeqCst) {
+            true => Ok(AuthContext::default()),
+            false => Err(unauthorized("not yet")),
+        });
+        for _ in 0..3 {
+            let (outcome, a) = send(&m, "get", Some("a"));
+            assert_eq!(outcome, DispatchOutcome::Rejected);
+            assert_eq!(a.errors.unwrap()[0].code, "Unauthorized");
+        }
+        valid.store(true, Ordering::SeqCst);
+        assert_eq!(send(&m, "get", Some("a")).0, DispatchOutcome::Handled);
+        assert_eq!(send(&m, "get", Some("a")).0, DispatchOutcome::RateLimited);
+    }
+}
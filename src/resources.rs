@@ -0,0 +1,172 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::action::{value_ok, Manager};
+use crate::error::ActionError;
+
+/// resources looked up by type, the manager resource for handlers needing several
+/// independent dependencies (see `Manager::new_registry` and `Manager::on_inject`)
+#[derive(Default, Clone)]
+pub struct Resources {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Resources {
+            map: HashMap::new(),
+        }
+    }
+
+    /// adds `value`, replacing any earlier value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Result<Arc<T>, ActionError> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .cloned()
+            .and_then(|v| v.downcast::<T>().ok())
+            .ok_or_else(|| {
+                ActionError::new(
+                    "MissingResource",
+                    &format!("no resource of type {} registered", type_name::<T>()),
+                )
+            })
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+/// a tuple of resource types resolved from `Resources` at dispatch
+pub trait Inject {
+    type Output;
+    fn inject(resources: &Resources) -> Result<Self::Output, ActionError>;
+}
+
+macro_rules! impl_inject {
+    ($($t:ident),+) => {
+        impl<$($t: Send + Sync + 'static),+> Inject for ($($t,)+) {
+            type Output = ($(Arc<$t>,)+);
+            fn inject(resources: &Resources) -> Result<Self::Output, ActionError> {
+                Ok(($(resources.get::<$t>()?,)+))
+            }
+        }
+    };
+}
+
+impl_inject!(A);
+impl_inject!(A, B);
+impl_inject!(A, B, C);
+impl_inject!(A, B, C, D);
+
+impl Manager<Resources> {
+    /// a manager whose resource is an empty `Resources`, fill it with `provide`
+    pub fn new_registry(name: &str) -> Self {
+        Manager::new(name, Resources::new())
+    }
+
+    pub fn provide<T: Send + Sync + 'static>(&mut self, value: T) {
+        if let Some(r) = self.resource_mut() {
+            r.insert(value);
+        }
+    }
+
+    /// registers a handler receiving the resources listed in `D` and the payload
+    /// deserialized as `P`; a missing resource fails the action with `MissingResource`
+    ///
+    /// ```ignore
+    /// m.on_inject::<(Db, Config), _, _, _>("user.get", |(db, config), p: GetUser| {
+    ///     db.find(p.id, &config)
+    /// });
+    /// ```
+    pub fn on_inject<D, P, O, F>(&mut self, name: &str, f: F)
+    where
+        D: Inject,
+        P: DeserializeOwned,
+        O: Serialize,
+        F: Fn(D::Output, P) -> Result<O, ActionError> + Send + Sync + 'static,
+    {
+        self.on(name, move |resources, action| {
+            let deps = D::inject(resources)?;
+            let payload: P = action.from_payload()?;
+            value_ok(f(deps, payload)?)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+
+    struct Db {
+        users: Vec<&'static str>,
+    }
+
+    struct Config {
+        greeting: &'static str,
+    }
+
+    #[derive(Deserialize)]
+    struct Get {
+        index: usize,
+    }
+
+    fn action(name: &str, payload: serde_json::Value) -> Action {
+        let mut a = Action::new(name, 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    fn manager() -> Manager<Resources> {
+        let mut m = Manager::new_registry("users");
+        m.provide(Db {
+            users: vec!["ann", "bob"],
+        });
+        m.provide(Config { greeting: "hi" });
+        m.on_inject::<(Db, Config), _, _, _>("greet", |(db, config), p: Get| {
+            Ok(format!("{} {}", config.greeting, db.users[p.index]))
+        });
+        m.on_inject::<(Db, String), _, _, _>("broken", |(db, s), _: serde_json::Value| {
+            Ok(format!("{} {}", s, db.users.len()))
+        });
+        m
+    }
+
+    #[test]
+    fn two_resources() {
+        let m = manager();
+        let mut a = action("greet", json!({"index": 1}));
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("hi bob")));
+    }
+
+    #[test]
+    fn missing_resource() {
+        let m = manager();
+        let mut a = action("broken", json!({}));
+        m.do_action(&mut a);
+        let e = &a.errors.unwrap()[0];
+        assert_eq!(e.code, "MissingResource");
+        assert!(e.message.contains("String"));
+    }
+
+    #[test]
+    fn registry() {
+        let mut r = Resources::new();
+        assert!(r.get::<u32>().is_err());
+        r.insert(5u32);
+        r.insert(6u32);
+        assert_eq!(*r.get::<u32>().unwrap(), 6);
+        assert!(r.contains::<u32>());
+        let (n,) = <(u32,)>::inject(&r).unwrap();
+        assert_eq!(*n, 6);
+    }
+}
@@ -0,0 +1,224 @@
+//! several managers behind one entry point, picked by the prefix of the action name
+
+use std::collections::HashMap;
+
+use crate::action::{Action, ActionReply, Manager};
+use crate::error::ActionError;
+
+/// separator between the mount prefix and the inner action name unless set otherwise
+pub const DEFAULT_SEPARATOR: &str = ".";
+
+/// a manager with its resource type erased, so managers over different resources can
+/// share a collection or a `Router`; implemented for every `Manager<R>`
+pub trait Dispatch {
+    fn dispatch(&self, action: &mut Action);
+    fn name(&self) -> &str;
+    /// whether an action `name` is registered, to find the manager owning it before
+    /// dispatching
+    fn handles(&self, name: &str) -> bool;
+    fn reply(&self, action: Action) -> ActionReply;
+}
+
+impl<R> Dispatch for Manager<R> {
+    fn dispatch(&self, action: &mut Action) {
+        self.do_action(action);
+    }
+
+    fn name(&self) -> &str {
+        self.manager_name()
+    }
+
+    fn handles(&self, name: &str) -> bool {
+        self.has_action(name)
+    }
+
+    fn reply(&self, action: Action) -> ActionReply {
+        Manager::reply(self, action)
+    }
+}
+
+/// managers mounted under prefixes, `"user.create"` goes to the manager mounted as
+/// `"user"` as `"create"`
+pub struct Router {
+    separator: String,
+    mounts: HashMap<String, Box<dyn Dispatch>>,
+    root: Option<Box<dyn Dispatch>>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            separator: DEFAULT_SEPARATOR.to_owned(),
+            mounts: HashMap::new(),
+            root: None,
+        }
+    }
+
+    pub fn with_separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_owned();
+        self
+    }
+
+    /// mounts `manager` under `prefix`, fails with `DuplicateMount` when the prefix is
+    /// taken
+    pub fn mount<D: Dispatch + 'static>(
+        &mut self,
+        prefix: &str,
+        manager: D,
+    ) -> Result<(), ActionError> {
+        if self.mounts.contains_key(prefix) {
+            return Err(ActionError::new(
+                "DuplicateMount",
+                &format!("a manager is already mounted as {}", prefix),
+            ));
+        }
+        self.mounts.insert(prefix.to_owned(), Box::new(manager));
+        Ok(())
+    }
+
+    /// the manager getting names no mount matches, with the full name
+    pub fn mount_root<D: Dispatch + 'static>(&mut self, manager: D) {
+        self.root = Some(Box::new(manager));
+    }
+
+    /// the mount handling `name` and the name it sees; the longest mounted prefix wins,
+    /// so `"user.profile.get"` goes to `"user.profile"` as `"get"` when both it and
+    /// `"user"` are mounted
+    fn route<'a>(&self, name: &'a str) -> Option<(&dyn Dispatch, &'a str)> {
+        let sep = self.separator.as_str();
+        if !sep.is_empty() {
+            for (at, _) in name.rmatch_indices(sep) {
+                if let Some(m) = self.mounts.get(&name[..at]) {
+                    return Some((m.as_ref(), &name[at + sep.len()..]));
+                }
+            }
+        }
+        self.root.as_deref().map(|m| (m, name))
+    }
+
+    /// dispatches `action` to its manager under the inner name and puts the full name
+    /// back afterwards. Names no mount or root takes fail with `NoRoute`; names a
+    /// manager takes but does not know get that manager's error
+    pub fn dispatch(&self, action: &mut Action) {
+        let name = action.name.clone();
+        match self.route(&name) {
+            Some((manager, inner)) => {
+                action.name = inner.to_owned();
+                manager.dispatch(action);
+                action.name = name;
+            }
+            None => action.set_error(
+                ActionError::new("NoRoute", &format!("no manager is mounted for {}", name))
+                    .with_details(json!({ "action": name })),
+            ),
+        }
+    }
+
+    /// `dispatch` and the reply of the manager which handled it, built under the inner
+    /// name so the manager's per-action settings apply
+    pub fn handle(&self, mut action: Action) -> ActionReply {
+        let name = action.name.clone();
+        match self.route(&name) {
+            Some((manager, inner)) => {
+                action.name = inner.to_owned();
+                manager.dispatch(&mut action);
+                let mut reply = manager.reply(action);
+                reply.name = name;
+                reply
+            }
+            None => {
+                self.dispatch(&mut action);
+                action.into_reply()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    fn router() -> Router {
+        let mut users = Manager::new("users", ());
+        users.on("create", |_, a| value_ok(format!("users:{}", a.name)));
+        let mut profiles = Manager::new("profiles", ());
+        profiles.on("get", |_, _| value_ok("profiles:get"));
+        let mut billing = Manager::new("billing", 7u32);
+        billing.on("invoice.list", |r, _| value_ok(*r));
+        let mut root = Manager::new("root", ());
+        root.on("ping", |_, _| value_ok("pong"));
+
+        let mut r = Router::new();
+        r.mount("user", users).unwrap();
+        r.mount("user.profile", profiles).unwrap();
+        r.mount("billing", billing).unwrap();
+        r.mount_root(root);
+        r
+    }
+
+    #[test]
+    fn routes_by_prefix() {
+        let mut r = router();
+        let reply = r.handle(action("user.create"));
+        assert_eq!(reply.result, Some(json!("users:create")));
+        assert_eq!(reply.name, "user.create");
+        // the longest mounted prefix wins, the rest may contain dots
+        let reply = r.handle(action("user.profile.get"));
+        assert_eq!(reply.result, Some(json!("profiles:get")));
+        let reply = r.handle(action("billing.invoice.list"));
+        assert_eq!(reply.result, Some(json!(7)));
+        let reply = r.handle(action("ping"));
+        assert_eq!(reply.result, Some(json!("pong")));
+
+        let err = r.mount("user", Manager::new("again", ())).unwrap_err();
+        assert_eq!(err.code, "DuplicateMount");
+    }
+
+    #[test]
+    fn unknown_names() {
+        let mut r = router();
+        // the prefix exists, so the manager's own error
+        let reply = r.handle(action("user.delete"));
+        assert_eq!(reply.errors[0].code, "users - DoAction");
+        assert_eq!(reply.name, "user.delete");
+        let reply = r.handle(action("files.get"));
+        assert_eq!(reply.errors[0].code, "root - DoAction");
+
+        r.root = None;
+        let reply = r.handle(action("files.get"));
+        assert_eq!(reply.errors[0].code, "NoRoute");
+        let reply = r.handle(action("ping"));
+        assert_eq!(reply.errors[0].code, "NoRoute");
+    }
+
+    #[test]
+    fn managers_over_different_resources() {
+        let mut db = Manager::new("db", vec![1u64, 2, 3]);
+        db.on("rows.count", |rows, _| value_ok(rows.len()));
+        let mut files = Manager::new("files", "/srv".to_owned());
+        files.on("files.root", |root, _| value_ok(root));
+        let managers: Vec<Box<dyn Dispatch>> = vec![Box::new(db), Box::new(files)];
+
+        let owner = |name: &str| managers.iter().find(|m| m.handles(name));
+        assert_eq!(owner("rows.count").unwrap().name(), "db");
+        assert_eq!(owner("files.root").unwrap().name(), "files");
+        assert!(owner("files.delete").is_none());
+
+        for (name, expected) in [("rows.count", json!(3)), ("files.root", json!("/srv"))] {
+            let mut a = action(name);
+            owner(name).unwrap().dispatch(&mut a);
+            assert_eq!(a.result, Some(expected));
+        }
+    }
+}
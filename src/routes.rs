@@ -0,0 +1,176 @@
+use crate::action::{Action, CtxHandler};
+
+/// a table of action names and handlers, usually built with `routes!` and applied with
+/// `Manager::mount`
+pub struct Routes<R> {
+    pub(crate) entries: Vec<(String, Box<CtxHandler<R>>)>,
+}
+
+impl<R> Default for Routes<R> {
+    fn default() -> Self {
+        Routes::new()
+    }
+}
+
+impl<R> Routes<R> {
+    pub fn new() -> Self {
+        Routes {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add<T>(&mut self, name: &str, f: T)
+    where
+        T: Fn(&R, &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.entries
+            .push((name.to_owned(), Box::new(move |r, a, _| f(r, a))));
+    }
+
+    /// the names in the table, in the order they were listed
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|(n, _)| n.as_str()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// fails const evaluation, and so compilation, when a name appears twice; used by `routes!`
+pub const fn assert_unique(names: &[&str]) {
+    let mut i = 0;
+    while i < names.len() {
+        let mut j = i + 1;
+        while j < names.len() {
+            if str_eq(names[i], names[j]) {
+                panic!("routes! lists the same action name twice");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// builds a `Routes` table, listing the same name twice is a compile error
+///
+/// ```
+/// use json_action::action::{action_ok, Action};
+/// use json_action::routes::Routes;
+///
+/// fn create(_: &(), _: &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+///     action_ok()
+/// }
+///
+/// let routes: Routes<()> = json_action::routes! {
+///     "user.create" => create,
+///     "user.get" => |_: &(), _: &Action| action_ok(),
+/// };
+/// assert_eq!(routes.names(), vec!["user.create", "user.get"]);
+/// ```
+///
+/// ```compile_fail
+/// use json_action::action::{action_ok, Action};
+///
+/// let routes: json_action::routes::Routes<()> = json_action::routes! {
+///     "user.create" => |_: &(), _: &Action| action_ok(),
+///     "user.create" => |_: &(), _: &Action| action_ok(),
+/// };
+/// ```
+#[macro_export]
+macro_rules! routes {
+    ($($name:literal => $handler:expr),* $(,)?) => {{
+        const _: () = $crate::routes::assert_unique(&[$($name),*]);
+        let mut routes = $crate::routes::Routes::new();
+        $(routes.add($name, $handler);)*
+        routes
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, value_ok, Manager};
+
+    fn get(_: &(), _: &Action) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        value_ok("got")
+    }
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    #[test]
+    fn names() {
+        let routes: Routes<()> = routes! {
+            "user.get" => get,
+            "user.create" => |_, _| action_ok(),
+        };
+        assert_eq!(routes.names(), vec!["user.get", "user.create"]);
+        assert_eq!(routes.len(), 2);
+        assert!(str_eq("abc", "abc"));
+        assert!(!str_eq("abc", "abd"));
+    }
+
+    #[test]
+    fn mount() {
+        let mut m = Manager::new("users", ());
+        m.mount(routes! { "user.get" => get, "user.create" => |_, _| action_ok() })
+            .unwrap();
+        let mut a = action("user.get");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("got")));
+    }
+
+    #[test]
+    fn mount_reports_duplicates() {
+        let mut m = Manager::new("users", ());
+        m.on("user.get", |_, _| value_ok("original"));
+        let err = m
+            .mount(routes! { "user.get" => get, "user.list" => |_, _| action_ok() })
+            .unwrap_err();
+        assert_eq!(err.code, "DuplicateAction");
+        assert_eq!(err.details.unwrap()["duplicates"], json!(["user.get"]));
+        // nothing from the table was mounted
+        let mut a = action("user.list");
+        m.do_action(&mut a);
+        assert!(a.has_errors());
+        let mut a = action("user.get");
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!("original")));
+    }
+
+    #[test]
+    fn mount_reports_mut_duplicates() {
+        let mut m = Manager::new("users", ());
+        m.on_mut("user.reset", |_, _| Ok(json!("reset")));
+        let err = m
+            .mount(routes! { "user.reset" => get, "user.list" => |_, _| action_ok() })
+            .unwrap_err();
+        assert_eq!(err.code, "DuplicateAction");
+        assert_eq!(err.details.unwrap()["duplicates"], json!(["user.reset"]));
+        assert!(!m.has_action("user.list"));
+    }
+}
@@ -0,0 +1,165 @@
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// most payload fields tracked per action, anything past this is ignored
+pub const MAX_INFERRED_FIELDS: usize = 256;
+
+/// json schema type name of a value
+pub fn json_type(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[derive(Debug, Default)]
+struct FieldShape {
+    seen: u64,
+    types: BTreeSet<&'static str>,
+}
+
+#[derive(Debug, Default)]
+struct ActionShape {
+    samples: u64,
+    fields: BTreeMap<String, FieldShape>,
+}
+
+/// structural summary of observed payloads per action, only counters and type names are
+/// kept, never values
+pub(crate) struct SchemaInference {
+    sample_limit: u64,
+    actions: HashMap<String, ActionShape>,
+}
+
+impl SchemaInference {
+    pub(crate) fn new(sample_limit: u64) -> Self {
+        SchemaInference {
+            sample_limit,
+            actions: HashMap::new(),
+        }
+    }
+
+    /// counts one payload, payloads past the sample limit are ignored
+    pub(crate) fn observe(&mut self, action: &str, payload: &HashMap<String, Value>) {
+        let shape = self.actions.entry(action.to_owned()).or_default();
+        if shape.samples >= self.sample_limit {
+            return;
+        }
+        shape.samples += 1;
+        for (k, v) in payload {
+            if !shape.fields.contains_key(k) && shape.fields.len() >= MAX_INFERRED_FIELDS {
+                continue;
+            }
+            let field = shape.fields.entry(k.clone()).or_default();
+            field.seen += 1;
+            field.types.insert(json_type(v));
+        }
+    }
+
+    /// a json schema-ish document per action; `x-confidence` is how much of the sample
+    /// limit has been observed and `x-presence` how often each field was present
+    pub(crate) fn schemas(&self) -> HashMap<String, Value> {
+        self.actions
+            .iter()
+            .map(|(name, shape)| {
+                let mut properties = Map::new();
+                let mut required = Vec::new();
+                for (k, f) in &shape.fields {
+                    let types: Vec<&str> = f.types.iter().copied().collect();
+                    let ty = if types.len() == 1 {
+                        json!(types[0])
+                    } else {
+                        json!(types)
+                    };
+                    properties.insert(
+                        k.clone(),
+                        json!({
+                            "type": ty,
+                            "x-presence": f.seen as f64 / shape.samples as f64,
+                        }),
+                    );
+                    if f.seen == shape.samples {
+                        required.push(k.clone());
+                    }
+                }
+                let confidence = if self.sample_limit == 0 {
+                    0.0
+                } else {
+                    shape.samples as f64 / self.sample_limit as f64
+                };
+                let schema = json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                    "x-samples": shape.samples,
+                    "x-confidence": confidence,
+                });
+                (name.clone(), schema)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, Manager};
+
+    fn action(payload: Value) -> Action {
+        let mut a = Action::new("user.update", 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    #[test]
+    fn types_and_optionality() {
+        let mut m = Manager::new("users", ());
+        m.on("user.update", |_, _| action_ok());
+        m.infer_schemas(10);
+        for payload in [
+            json!({"id": 1, "name": "bob", "age": 30}),
+            json!({"id": 2, "name": null, "tags": ["a"]}),
+            json!({"id": 3, "name": "al", "age": 30.5}),
+            json!({"id": 4, "name": "al"}),
+        ] {
+            m.do_action(&mut action(payload));
+        }
+        let schemas = m.inferred_schemas();
+        let s = &schemas["user.update"];
+        assert_eq!(s["x-samples"], json!(4));
+        assert_eq!(s["x-confidence"], json!(0.4));
+        assert_eq!(s["required"], json!(["id", "name"]));
+        assert_eq!(s["properties"]["id"]["type"], json!("integer"));
+        assert_eq!(s["properties"]["name"]["type"], json!(["null", "string"]));
+        assert_eq!(s["properties"]["age"]["type"], json!(["integer", "number"]));
+        assert_eq!(s["properties"]["age"]["x-presence"], json!(0.5));
+        assert_eq!(s["properties"]["tags"]["type"], json!("array"));
+        // values are never kept
+        assert!(!s.to_string().contains("bob"));
+    }
+
+    #[test]
+    fn bounded_by_sample_limit() {
+        let mut inf = SchemaInference::new(2);
+        let p = |v: Value| serde_json::from_value::<HashMap<String, Value>>(v).unwrap();
+        inf.observe("a", &p(json!({"x": 1})));
+        inf.observe("a", &p(json!({"x": 1})));
+        inf.observe("a", &p(json!({"y": "late"})));
+        let s = &inf.schemas()["a"];
+        assert_eq!(s["x-samples"], json!(2));
+        assert!(s["properties"].get("y").is_none());
+    }
+
+    #[test]
+    fn off_by_default() {
+        let mut m = Manager::new("users", ());
+        m.on("user.update", |_, _| action_ok());
+        m.do_action(&mut action(json!({"id": 1})));
+        assert!(m.inferred_schemas().is_empty());
+    }
+}
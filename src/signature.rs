@@ -0,0 +1,372 @@
+//! HMAC-SHA256 signatures over actions, for actions coming from outside
+//! (`crypto` feature)
+//!
+//! The signature covers these bytes, in order, with lengths as big endian u32:
+//!
+//! - `SIGNATURE_VERSION`
+//! - the length of `name`, then `name` as UTF-8
+//! - `id` as a big endian u64
+//! - `token`: a 0 byte when absent, else a 1 byte, its length and the token
+//! - `base64`: the same as `token`
+//! - the length of the payload JSON, then the JSON: compact, object keys sorted
+//!   byte-wise at every level
+//! - `expires_at`: a 0 byte when absent, else a 1 byte and the time as a big endian u64
+//! - `binary`: a 0 byte when absent, else a 1 byte, its length and the bytes
+//! - the number of `attachments`, then for each its `name`, `content_type` and `b64`
+//!   each with its length, and `size` as a big endian u64
+//! - `dry_run`: a 0 byte when absent, else a 1 byte and 0 for false or 1 for true
+//! - `seq`: the same as `expires_at`
+//! - `more`: the same as `dry_run`
+//!
+//! `signature` is the base64url (no padding) encoded tag. Everything else on the
+//! action, like `source` or `correlation_id`, is not covered. Version 1 left out
+//! `expires_at`, `binary` and `attachments`, version 2 `dry_run`, `seq` and `more`.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+
+/// the first bytes signed, changes with the byte layout
+pub const SIGNATURE_VERSION: &[u8] = b"json_action-hmac-sha256-v3";
+
+fn bad_signature(message: &str) -> ActionError {
+    ActionError::new("BadSignature", message)
+}
+
+fn put(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_opt(out: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        None => out.push(0),
+        Some(bytes) => {
+            out.push(1);
+            put(out, bytes);
+        }
+    }
+}
+
+fn put_u64_opt(out: &mut Vec<u8>, n: Option<u64>) {
+    match n {
+        None => out.push(0),
+        Some(n) => {
+            out.push(1);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn put_bool_opt(out: &mut Vec<u8>, b: Option<bool>) {
+    match b {
+        None => out.push(0),
+        Some(b) => out.extend_from_slice(&[1, u8::from(b)]),
+    }
+}
+
+fn canonical_json(v: &Value, out: &mut Vec<u8>) {
+    match v {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push(b'{');
+            for (i, k) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, k).expect("strings always serialize");
+                out.push(b':');
+                canonical_json(&map[k], out);
+            }
+            out.push(b'}');
+        }
+        Value::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                canonical_json(item, out);
+            }
+            out.push(b']');
+        }
+        v => serde_json::to_writer(&mut *out, v).expect("json values always serialize"),
+    }
+}
+
+impl Action {
+    /// the bytes `sign_hmac_sha256` signs, see the module docs for the layout
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut out = SIGNATURE_VERSION.to_vec();
+        put(&mut out, self.name.as_bytes());
+        out.extend_from_slice(&self.id.to_be_bytes());
+        put_opt(&mut out, self.token.as_deref().map(str::as_bytes));
+        put_opt(&mut out, self.base64.as_deref().map(str::as_bytes));
+        let mut payload = Vec::new();
+        let map = self
+            .payload
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        canonical_json(&Value::Object(map), &mut payload);
+        put(&mut out, &payload);
+        put_u64_opt(&mut out, self.expires_at);
+        put_opt(&mut out, self.binary.as_deref());
+        out.extend_from_slice(&(self.attachments.len() as u32).to_be_bytes());
+        for a in &self.attachments {
+            put(&mut out, a.name.as_bytes());
+            put(&mut out, a.content_type.as_bytes());
+            put(&mut out, a.b64.as_bytes());
+            out.extend_from_slice(&a.size.to_be_bytes());
+        }
+        put_bool_opt(&mut out, self.dry_run);
+        put_u64_opt(&mut out, self.seq);
+        put_bool_opt(&mut out, self.more);
+        out
+    }
+
+    fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any length");
+        mac.update(&self.signing_bytes());
+        mac
+    }
+
+    /// stores the signature of the action under `key` in `signature`
+    pub fn sign_hmac_sha256(&mut self, key: &[u8]) {
+        let tag = self.mac(key).finalize().into_bytes();
+        self.signature = Some(URL_SAFE_NO_PAD.encode(tag));
+    }
+
+    /// checks `signature` against `key` in constant time, failing with `BadSignature`
+    /// when it is missing, malformed or does not match
+    pub fn verify_hmac_sha256(&self, key: &[u8]) -> Result<(), ActionError> {
+        self.verify_hmac_sha256_any(&[key])
+    }
+
+    /// `verify_hmac_sha256` accepting a signature by any of `keys`, e.g. while rotating
+    pub fn verify_hmac_sha256_any(&self, keys: &[&[u8]]) -> Result<(), ActionError> {
+        let signature = self
+            .signature
+            .as_deref()
+            .ok_or_else(|| bad_signature(&format!("{} is not signed", self.name)))?;
+        let tag = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| bad_signature("the signature is not base64url"))?;
+        if keys.iter().any(|k| self.mac(k).verify_slice(&tag).is_ok()) {
+            Ok(())
+        } else {
+            Err(bad_signature(&format!(
+                "the signature of {} does not match",
+                self.name
+            )))
+        }
+    }
+}
+
+impl<R> Manager<R> {
+    /// rejects actions not signed with `key` with `BadSignature` before anything else
+    /// runs; calling it again adds another accepted key, for rotating keys
+    pub fn require_signature(&mut self, key: &[u8]) {
+        self.signature_keys_mut().push(key.to_vec());
+    }
+
+    /// forgets the keys of `require_signature`, actions no longer need a signature
+    pub fn clear_signature_keys(&mut self) {
+        self.signature_keys_mut().clear();
+    }
+}
+
+/// checks the action against the keys of a manager, nothing to check without keys
+pub(crate) fn check_signature(action: &Action, keys: &[Vec<u8>]) -> Result<(), ActionError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+    action.verify_hmac_sha256_any(&keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Attachment};
+    use crate::outcome::DispatchOutcome;
+
+    const OLD_KEY: &[u8] = b"old partner key";
+    const NEW_KEY: &[u8] = b"new partner key";
+
+    fn transfer() -> Action {
+        let mut a = Action::new("transfer", 9);
+        a.token = Some("partner-1".to_owned());
+        a.payload = serde_json::from_value(json!({
+            "to": "acct-2",
+            "amount": 10,
+            "meta": { "z": 1, "a": [true, null] }
+        }))
+        .unwrap();
+        a
+    }
+
+    #[test]
+    fn layout() {
+        let mut a = Action::new("n", 1);
+        a.payload = serde_json::from_value(json!({ "b": { "y": 1, "x": 2 }, "a": 0 })).unwrap();
+        let mut expected = SIGNATURE_VERSION.to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 1, b'n']);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 0]);
+        let payload = br#"{"a":0,"b":{"x":2,"y":1}}"#;
+        expected.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        expected.extend_from_slice(payload);
+        expected.extend_from_slice(&[0, 0]);
+        expected.extend_from_slice(&[0, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(a.signing_bytes(), expected);
+
+        a.expires_at = Some(2);
+        a.binary = Some(vec![7]);
+        a.attach("a", "t", b"x");
+        a.dry_run = Some(true);
+        a.seq = Some(3);
+        a.more = Some(false);
+        let tail = [
+            &[1, 0, 0, 0, 0, 0, 0, 0, 2][..],
+            &[1, 0, 0, 0, 1, 7],
+            &[0, 0, 0, 1],
+            &[0, 0, 0, 1, b'a', 0, 0, 0, 1, b't', 0, 0, 0, 4],
+            b"eA==",
+            &[0, 0, 0, 0, 0, 0, 0, 1],
+            &[1, 1],
+            &[1, 0, 0, 0, 0, 0, 0, 0, 3],
+            &[1, 0],
+        ]
+        .concat();
+        assert!(a.signing_bytes().ends_with(&tail));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let mut a = transfer();
+        a.sign_hmac_sha256(NEW_KEY);
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_ok());
+        assert_eq!(
+            a.verify_hmac_sha256(OLD_KEY).unwrap_err().code,
+            "BadSignature"
+        );
+
+        // survives a trip through json
+        let a: Action = serde_json::from_str(&serde_json::to_string(&a).unwrap()).unwrap();
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_ok());
+    }
+
+    #[test]
+    fn tampered_payload() {
+        let mut a = transfer();
+        a.sign_hmac_sha256(NEW_KEY);
+        a.payload.insert("amount".to_owned(), json!(10_000));
+        let err = a.verify_hmac_sha256(NEW_KEY).unwrap_err();
+        assert_eq!(err.code, "BadSignature");
+
+        let mut a = transfer();
+        a.sign_hmac_sha256(NEW_KEY);
+        a.token = None;
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+    }
+
+    #[test]
+    fn tampered_expiry_and_data() {
+        let signed = || {
+            let mut a = transfer().with_ttl(std::time::Duration::from_secs(60));
+            a.binary = Some(vec![1, 2, 3]);
+            a.attach("receipt", "text/plain", b"paid");
+            a.sign_hmac_sha256(NEW_KEY);
+            a
+        };
+        assert!(signed().verify_hmac_sha256(NEW_KEY).is_ok());
+        let mut a = signed();
+        a.expires_at = None;
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+        let mut a = signed();
+        a.binary = Some(vec![9]);
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+        let mut a = signed();
+        a.attachments[0] = Attachment::new("receipt", "text/plain", b"unpaid");
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+        let mut a = signed();
+        a.attachments.clear();
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+    }
+
+    #[test]
+    fn tampered_dry_run() {
+        let mut a = transfer();
+        a.dry_run = Some(true);
+        a.sign_hmac_sha256(NEW_KEY);
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_ok());
+        // a signed simulation can not be replayed for real
+        for dry_run in [None, Some(false)] {
+            a.dry_run = dry_run;
+            assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+        }
+
+        let mut a = transfer();
+        a.seq = Some(1);
+        a.more = Some(true);
+        a.sign_hmac_sha256(NEW_KEY);
+        a.seq = Some(2);
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+        a.seq = Some(1);
+        a.more = Some(false);
+        assert!(a.verify_hmac_sha256(NEW_KEY).is_err());
+    }
+
+    #[test]
+    fn manager_requires_signatures() {
+        let mut m = Manager::new("test", ());
+        m.on("transfer", |_r, _a| value_ok(json!("sent")));
+        m.require_signature(OLD_KEY);
+        m.require_signature(NEW_KEY);
+
+        // signed with either key while rotating
+        for key in &[OLD_KEY, NEW_KEY] {
+            let mut a = transfer();
+            a.sign_hmac_sha256(key);
+            assert_eq!(m.do_action(&mut a), DispatchOutcome::Handled);
+        }
+
+        let mut a = transfer();
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+        assert_eq!(a.errors.unwrap()[0].message, "transfer is not signed");
+
+        let mut a = transfer();
+        a.sign_hmac_sha256(b"someone else");
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+        assert!(a.result.is_none());
+
+        // the old key is retired
+        m.clear_signature_keys();
+        m.require_signature(NEW_KEY);
+        let mut a = transfer();
+        a.sign_hmac_sha256(OLD_KEY);
+        assert_eq!(m.do_action(&mut a), DispatchOutcome::Rejected);
+    }
+
+    #[test]
+    fn if_exists_requires_signatures() {
+        let mut m = Manager::new("test", ());
+        m.on("transfer", |_r, _a| value_ok(json!("sent")));
+        m.require_signature(NEW_KEY);
+        let mut a = transfer();
+        assert_eq!(
+            m.do_action_if_exists(&mut a),
+            Some(DispatchOutcome::Rejected)
+        );
+        assert!(a.result.is_none());
+        assert_eq!(a.errors.unwrap()[0].code, "BadSignature");
+    }
+}
@@ -0,0 +1,166 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+
+/// running min/max/mean of a byte size
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ByteStats {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+    pub mean: f64,
+}
+
+impl ByteStats {
+    fn record(&mut self, bytes: u64) {
+        if self.count == 0 || bytes < self.min {
+            self.min = bytes;
+        }
+        self.max = self.max.max(bytes);
+        self.count += 1;
+        self.total += bytes;
+        self.mean = self.total as f64 / self.count as f64;
+    }
+}
+
+/// serialized sizes seen for one action, results only count successful dispatches
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SizeStats {
+    pub payload: ByteStats,
+    pub result: ByteStats,
+}
+
+/// which side of the dispatch `Manager::top_sizes` ranks by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeMetric {
+    Payload,
+    Result,
+}
+
+/// serialized length of `v` as json, without building the output
+pub(crate) fn json_len<T: Serialize + ?Sized>(v: &T) -> Result<usize, serde_json::Error> {
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, v)?;
+    Ok(counter.0)
+}
+
+struct Counter(usize);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SizeTracker {
+    stats: BTreeMap<String, SizeStats>,
+}
+
+impl SizeTracker {
+    pub(crate) fn payload(&mut self, action: &str, bytes: usize) {
+        self.entry(action).payload.record(bytes as u64);
+    }
+
+    pub(crate) fn result(&mut self, action: &str, bytes: usize) {
+        self.entry(action).result.record(bytes as u64);
+    }
+
+    fn entry(&mut self, action: &str) -> &mut SizeStats {
+        if !self.stats.contains_key(action) {
+            self.stats.insert(action.to_owned(), SizeStats::default());
+        }
+        self.stats.get_mut(action).expect("inserted above")
+    }
+
+    pub(crate) fn snapshot(&self) -> BTreeMap<String, SizeStats> {
+        self.stats.clone()
+    }
+
+    /// the `n` actions with the largest maximum size, largest first
+    pub(crate) fn top(&self, n: usize, by: SizeMetric) -> Vec<(String, SizeStats)> {
+        let pick = |s: &SizeStats| match by {
+            SizeMetric::Payload => s.payload.max,
+            SizeMetric::Result => s.result.max,
+        };
+        let mut all: Vec<(String, SizeStats)> = self
+            .stats
+            .iter()
+            .filter(|(_, s)| pick(s) > 0)
+            .map(|(k, s)| (k.clone(), s.clone()))
+            .collect();
+        all.sort_by(|a, b| pick(&b.1).cmp(&pick(&a.1)).then(a.0.cmp(&b.0)));
+        all.truncate(n);
+        all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager};
+    use crate::error::ActionError;
+    use serde_json::Value;
+
+    fn action(name: &str, payload: Value) -> Action {
+        let mut a = Action::new(name, 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    #[test]
+    fn counts_without_buffering() {
+        let v = json!({"a": [1, 2, 3], "b": "x"});
+        assert_eq!(json_len(&v).unwrap(), serde_json::to_vec(&v).unwrap().len());
+    }
+
+    #[test]
+    fn scripted_mix() {
+        let mut m = Manager::new("test", ());
+        m.on("echo", |_, a| value_ok(&a.payload));
+        m.on("big", |_, _| value_ok("x".repeat(1000)));
+        m.on("fail", |_, _| Err(Box::new(ActionError::new("Fail", ""))));
+        m.track_sizes(true);
+
+        for payload in [json!({}), json!({"k": "vvvvvvvvvv"})] {
+            m.do_action(&mut action("echo", payload));
+        }
+        m.do_action(&mut action("big", json!({})));
+        m.do_action(&mut action("fail", json!({"k": 1})));
+
+        let stats = m.size_stats();
+        let echo = &stats["echo"];
+        assert_eq!(echo.payload.count, 2);
+        assert_eq!(echo.payload.min, 2);
+        assert_eq!(echo.payload.max, 18);
+        assert_eq!(echo.payload.mean, 10.0);
+        assert_eq!(echo.result, echo.payload);
+        assert_eq!(stats["big"].result.max, 1002);
+        assert_eq!(stats["fail"].payload.count, 1);
+        assert_eq!(stats["fail"].result.count, 0);
+
+        let top: Vec<String> = m
+            .top_sizes(2, SizeMetric::Result)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(top, vec!["big", "echo"]);
+        let top = m.top_sizes(1, SizeMetric::Payload);
+        assert_eq!(top[0].0, "echo");
+        serde_json::to_value(&stats).unwrap();
+    }
+
+    #[test]
+    fn off_by_default() {
+        let mut m = Manager::new("test", ());
+        m.on("echo", |_, a| value_ok(&a.payload));
+        m.do_action(&mut action("echo", json!({})));
+        assert!(m.size_stats().is_empty());
+    }
+}
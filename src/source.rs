@@ -0,0 +1,150 @@
+//! where an action came from; the source is set by the code receiving the action (see
+//! `Manager::do_action_from`), never trusted from the client
+
+use crate::validate::glob_match;
+
+pub const WS: &str = "ws";
+pub const HTTP: &str = "http";
+pub const STDIO: &str = "stdio";
+pub const SCHEDULER: &str = "scheduler";
+pub const LOCAL: &str = "local";
+
+/// rules applied to every action arriving from one source, see `Manager::source_policy`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyOverrides {
+    /// action names (globs, see `validate::glob_match`) this source may call, `None`
+    /// allows everything
+    pub allowed_actions: Option<Vec<String>>,
+    /// actions from this source are not counted nor refused by `Manager::rate_limit`,
+    /// for trusted sources like the scheduler
+    pub skip_rate_limit: bool,
+}
+
+impl PolicyOverrides {
+    pub fn allows(&self, action: &str) -> bool {
+        match &self.allowed_actions {
+            Some(allowed) => allowed.iter().any(|p| glob_match(p, action)),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{value_ok, Action, Manager, DESCRIBE_ACTION};
+    use crate::rate_limit::RateLimit;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn manager() -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on_ctx("whoami", |_, _, ctx| value_ok(ctx.source()));
+        m.on("admin.reset", |_, _| value_ok(true));
+        m.source_policy(
+            WS,
+            PolicyOverrides {
+                allowed_actions: Some(vec!["whoami".to_owned(), "user.*".to_owned()]),
+                ..PolicyOverrides::default()
+            },
+        );
+        m
+    }
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    #[test]
+    fn client_source_is_overwritten() {
+        let m = manager();
+        let mut a: Action = serde_json::from_value(json!({
+            "name": "whoami", "id": 1, "token": null, "base64": null,
+            "payload": {}, "result": null, "errors": null, "source": "scheduler"
+        }))
+        .unwrap();
+        m.do_action_from(WS, &mut a);
+        assert_eq!(a.source.as_deref(), Some(WS));
+        assert_eq!(a.result, Some(json!("ws")));
+    }
+
+    #[test]
+    fn client_source_is_ignored() {
+        let mut m = manager();
+        m.source_policy(
+            SCHEDULER,
+            PolicyOverrides {
+                skip_rate_limit: true,
+                ..PolicyOverrides::default()
+            },
+        );
+        m.rate_limit(RateLimit {
+            per_token: Some(1),
+            ..RateLimit::default()
+        });
+        let claimed = || -> Action {
+            let a: Action = serde_json::from_value(json!({
+                "name": "whoami", "id": 1, "token": "t1", "payload": {}, "source": "scheduler"
+            }))
+            .unwrap();
+            assert!(a.source.is_none());
+            a
+        };
+        let mut a = claimed();
+        m.do_action(&mut a);
+        assert_eq!(a.result, Some(json!(null)));
+        // not exempted from the rate limit by the claimed source
+        let mut a = claimed();
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "RateLimited");
+    }
+
+    #[test]
+    fn allow_list_rejects() {
+        let m = manager();
+        let mut a = action("admin.reset");
+        m.do_action_from(WS, &mut a);
+        assert!(a.result.is_none());
+        assert_eq!(a.errors.unwrap()[0].code, "SourceNotAllowed");
+
+        let mut a = action("admin.reset");
+        m.do_action_from(SCHEDULER, &mut a);
+        assert_eq!(a.result, Some(json!(true)));
+    }
+
+    #[test]
+    fn rejected_before_hooks_builtins_and_fallback() {
+        let mut m = manager();
+        let hooks = Arc::new(AtomicUsize::new(0));
+        let counted = hooks.clone();
+        m.use_before(move |_, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        m.on_unknown(|_, a| Ok(json!(a.name)));
+        for name in ["admin.reset", "admin.missing", DESCRIBE_ACTION] {
+            let mut a = action(name);
+            m.do_action_from(WS, &mut a);
+            assert!(a.result.is_none());
+            assert_eq!(a.errors.unwrap()[0].code, "SourceNotAllowed");
+        }
+        assert_eq!(hooks.load(Ordering::SeqCst), 0);
+
+        // allowed names still reach the hooks and the fallback
+        let mut a = action("user.missing");
+        m.do_action_from(WS, &mut a);
+        assert_eq!(a.result, Some(json!("user.missing")));
+        assert_eq!(hooks.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn glob_allow_list() {
+        let p = PolicyOverrides {
+            allowed_actions: Some(vec!["user.*".to_owned()]),
+            ..PolicyOverrides::default()
+        };
+        assert!(p.allows("user.get"));
+        assert!(!p.allows("billing.get"));
+        assert!(PolicyOverrides::default().allows("anything"));
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::action::ActionReply;
+
+/// hands out the server sequence numbers of replies, see `Manager::stamp_replies`
+#[derive(Default)]
+pub(crate) struct ReplyStamp {
+    last: AtomicU64,
+}
+
+impl ReplyStamp {
+    /// sets `server_ts_ms` and the next `server_seq` on the reply, sequence numbers start
+    /// at 1 and are unique even when replies are stamped from several threads
+    pub(crate) fn stamp(&self, reply: &mut ActionReply) {
+        reply.server_seq = Some(self.last.fetch_add(1, Ordering::SeqCst) + 1);
+        reply.server_ts_ms = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64),
+        );
+    }
+
+    /// the sequence number of the last stamped reply, 0 before the first
+    pub(crate) fn current(&self) -> u64 {
+        self.last.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action, Manager};
+    use crate::error::ActionError;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn action(name: &str, id: u64) -> Action {
+        Action::new(name, id)
+    }
+
+    #[test]
+    fn every_reply_is_stamped() {
+        let mut m = Manager::new("test", ());
+        m.on("ok", |_, _| action_ok());
+        m.on("fail", |_, _| Err(ActionError::new("Nope", "nope").into()));
+        assert_eq!(m.current_seq(), 0);
+
+        let (reply, _) = m.handle_with_outcome(action("ok", 1));
+        assert_eq!(reply.server_seq, None);
+        assert_eq!(reply.server_ts_ms, None);
+
+        m.stamp_replies(true);
+        let mut seqs = Vec::new();
+        for name in ["ok", "fail", "missing"] {
+            let (reply, _) = m.handle_with_outcome(action(name, 1));
+            assert!(reply.server_ts_ms.unwrap() > 0);
+            seqs.push(reply.server_seq.unwrap());
+        }
+        let batch = m.do_batch(vec![action("ok", 2), action("missing", 3)]);
+        seqs.extend(batch.iter().map(|r| r.server_seq.unwrap()));
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+        assert_eq!(m.current_seq(), 5);
+
+        let (reply, _) = m.handle_with_outcome(action("ok", 4));
+        assert_eq!(reply.server_seq, Some(6));
+        let json = serde_json::to_value(&reply).unwrap();
+        assert_eq!(json["server_seq"], json!(6));
+    }
+
+    #[test]
+    fn strictly_increasing_across_threads() {
+        let stamp = Arc::new(ReplyStamp::default());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let stamp = stamp.clone();
+                thread::spawn(move || {
+                    let mut seen = Vec::new();
+                    for n in 0..1250 {
+                        let mut reply = action("ok", n).into_reply();
+                        stamp.stamp(&mut reply);
+                        seen.push(reply.server_seq.unwrap());
+                    }
+                    seen
+                })
+            })
+            .collect();
+        let mut all = HashSet::new();
+        for t in threads {
+            let seen = t.join().unwrap();
+            // each thread sees its own replies in order
+            assert!(seen.windows(2).all(|w| w[0] < w[1]));
+            all.extend(seen);
+        }
+        assert_eq!(all.len(), 10_000);
+        assert_eq!(all.iter().max(), Some(&10_000));
+        assert_eq!(stamp.current(), 10_000);
+    }
+}
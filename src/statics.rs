@@ -0,0 +1,281 @@
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::action::{value_ok, Action, Manager};
+use crate::error::ActionError;
+
+/// what a template placeholder resolves to when the action has no such value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingRef {
+    /// the action fails with `TemplateError`
+    #[default]
+    Error,
+    /// the placeholder is replaced with an empty string
+    Empty,
+}
+
+/// a json file loaded on first use and reloaded whenever its mtime changes
+struct StaticFile {
+    path: PathBuf,
+    cached: Mutex<Option<(SystemTime, Arc<Value>)>>,
+}
+
+impl StaticFile {
+    fn file_error(&self, e: impl std::fmt::Display) -> ActionError {
+        ActionError::new(
+            "StaticFileError",
+            &format!("could not load {}: {}", self.path.display(), e),
+        )
+    }
+
+    fn load(&self) -> Result<Arc<Value>, ActionError> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .map_err(|e| self.file_error(e))?;
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((at, v)) = &*cached {
+            if *at == modified {
+                return Ok(v.clone());
+            }
+        }
+        let bytes = fs::read(&self.path).map_err(|e| self.file_error(e))?;
+        let v: Value = serde_json::from_slice(&bytes).map_err(|e| self.file_error(e))?;
+        let v = Arc::new(v);
+        *cached = Some((modified, v.clone()));
+        Ok(v)
+    }
+}
+
+/// the value a placeholder such as `token`, `id`, `name` or `payload.user.id` refers to
+fn lookup(action: &Action, path: &str) -> Option<Value> {
+    let mut parts = path.split('.');
+    match parts.next()? {
+        "token" if path == "token" => action.token.clone().map(Value::String),
+        "id" if path == "id" => Some(Value::from(action.id)),
+        "name" if path == "name" => Some(Value::String(action.name.clone())),
+        "payload" => {
+            let mut v = action.payload.get(parts.next()?)?;
+            for key in parts {
+                v = v.get(key)?;
+            }
+            Some(v.clone())
+        }
+        _ => None,
+    }
+}
+
+fn resolve(action: &Action, path: &str, missing: MissingRef) -> Result<Value, ActionError> {
+    match lookup(action, path) {
+        Some(v) => Ok(v),
+        None => match missing {
+            MissingRef::Error => Err(ActionError::new(
+                "TemplateError",
+                &format!("{} has no value for {{{}}}", action.name, path),
+            )
+            .with_details(json!({ "placeholder": path }))),
+            MissingRef::Empty => Ok(Value::String(String::new())),
+        },
+    }
+}
+
+fn render_str(s: &str, action: &Action, missing: MissingRef) -> Result<Value, ActionError> {
+    // a leaf that is a single placeholder keeps the type of what it refers to
+    if let Some(path) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+        if !path.contains(['{', '}']) {
+            return resolve(action, path, missing);
+        }
+    }
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match resolve(action, &rest[start + 1..end], missing)? {
+            Value::String(v) => out.push_str(&v),
+            v => out.push_str(&v.to_string()),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(Value::String(out))
+}
+
+/// replaces `{...}` placeholders in every string leaf of `template`, object keys are
+/// left alone
+pub fn render_template(
+    template: &Value,
+    action: &Action,
+    missing: MissingRef,
+) -> Result<Value, ActionError> {
+    Ok(match template {
+        Value::String(s) => render_str(s, action, missing)?,
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|v| render_template(v, action, missing))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Object(map) => {
+            let mut out = Map::new();
+            for (k, v) in map {
+                out.insert(k.clone(), render_template(v, action, missing)?);
+            }
+            Value::Object(out)
+        }
+        v => v.clone(),
+    })
+}
+
+impl<R> Manager<R> {
+    /// an action that always replies with `result`
+    pub fn on_static(&mut self, name: &str, result: Value) {
+        let result = Arc::new(result);
+        self.on(name, move |_, _| value_ok(&*result));
+    }
+
+    /// an action replying with the contents of a json file, read on first use and again
+    /// whenever its modification time changes; an unreadable or invalid file fails the
+    /// action with `StaticFileError`
+    pub fn on_static_file<P: AsRef<Path>>(&mut self, name: &str, path: P) {
+        let file = StaticFile {
+            path: path.as_ref().to_owned(),
+            cached: Mutex::new(None),
+        };
+        self.on(name, move |_, _| value_ok(&*file.load()?));
+    }
+
+    /// an action replying with `template` where string leaves have `{payload.field}`,
+    /// `{token}`, `{id}` and `{name}` placeholders filled in from the action; missing
+    /// values fail the action with `TemplateError`
+    pub fn on_template(&mut self, name: &str, template: Value) {
+        self.on_template_with(name, template, MissingRef::Error);
+    }
+
+    pub fn on_template_with(&mut self, name: &str, template: Value, missing: MissingRef) {
+        self.on(name, move |_, action| {
+            value_ok(render_template(&template, action, missing)?)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn action(name: &str, payload: Value) -> Action {
+        let mut a = Action::new(name, 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    fn run(m: &Manager<()>, a: &mut Action) -> Result<Value, Vec<ActionError>> {
+        m.do_action(a);
+        match a.errors.take() {
+            Some(e) => Err(e),
+            None => Ok(a.result.take().unwrap()),
+        }
+    }
+
+    #[test]
+    fn static_result() {
+        let mut m = Manager::new("test", ());
+        m.on_static("caps", json!({"gzip": true}));
+        let mut a = action("caps", json!({}));
+        assert_eq!(run(&m, &mut a).unwrap(), json!({"gzip": true}));
+        let names: Vec<String> = m
+            .list_actions_detailed()
+            .into_iter()
+            .map(|i| i.name)
+            .collect();
+        assert_eq!(names, vec!["caps"]);
+    }
+
+    #[test]
+    fn file_reloads_on_mtime_change() {
+        let path =
+            std::env::temp_dir().join(format!("json_action_static_{}.json", std::process::id()));
+        fs::write(&path, r#"{"v": 1}"#).unwrap();
+        let mut m = Manager::new("test", ());
+        m.on_static_file("manifest", &path);
+        assert_eq!(
+            run(&m, &mut action("manifest", json!({}))).unwrap(),
+            json!({"v": 1})
+        );
+
+        // same mtime, the cached copy is served
+        let at = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, r#"{"v": 2}"#).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(at)
+            .unwrap();
+        assert_eq!(
+            run(&m, &mut action("manifest", json!({}))).unwrap(),
+            json!({"v": 1})
+        );
+
+        let f = fs::File::options().write(true).open(&path).unwrap();
+        f.set_modified(at + Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            run(&m, &mut action("manifest", json!({}))).unwrap(),
+            json!({"v": 2})
+        );
+
+        fs::remove_file(&path).unwrap();
+        let e = run(&m, &mut action("manifest", json!({}))).unwrap_err();
+        assert_eq!(e[0].code, "StaticFileError");
+    }
+
+    #[test]
+    fn template_substitution() {
+        let mut m = Manager::new("test", ());
+        m.on_template(
+            "greet",
+            json!({
+                "text": "hi {payload.user.name}, you are #{id}",
+                "nested": [{"city": "{payload.user.address.city}"}],
+                "count": "{payload.count}",
+                "token": "{token}",
+            }),
+        );
+        m.on_template_with("lenient", json!("[{payload.nope}]"), MissingRef::Empty);
+
+        let mut a = action(
+            "greet",
+            json!({"user": {"name": "bob", "address": {"city": "Oslo"}}, "count": 3}),
+        );
+        a.id = 7;
+        a.token = Some("t1".to_owned());
+        assert_eq!(
+            run(&m, &mut a).unwrap(),
+            json!({
+                "text": "hi bob, you are #7",
+                "nested": [{"city": "Oslo"}],
+                "count": 3,
+                "token": "t1",
+            })
+        );
+
+        let mut a = action("greet", json!({"user": {"name": "bob"}, "count": 3}));
+        let e = run(&m, &mut a).unwrap_err();
+        assert_eq!(e[0].code, "TemplateError");
+        assert_eq!(
+            e[0].details.as_ref().unwrap()["placeholder"],
+            json!("payload.user.address.city")
+        );
+
+        assert_eq!(
+            run(&m, &mut action("lenient", json!({}))).unwrap(),
+            json!("[]")
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! per-action counters kept by every manager, see `Manager::stats`
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::action::{value_ok, Manager};
+use crate::outcome::DispatchOutcome;
+
+/// name of the built-in stats action, see `Manager::enable_stats`
+pub const STATS_ACTION: &str = "__stats";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActionStats {
+    /// how many times the handler ran, dry runs included
+    pub invocations: u64,
+    /// how many of those failed, panics included
+    pub errors: u64,
+}
+
+/// what the stats action answers with
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsReport {
+    pub actions: HashMap<String, ActionStats>,
+    /// actions asking for a name nothing is registered under
+    pub not_found: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Stats {
+    actions: Mutex<HashMap<String, ActionStats>>,
+    not_found: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) fn record(&self, name: &str, outcome: DispatchOutcome) {
+        let failed = match outcome {
+            DispatchOutcome::Handled | DispatchOutcome::DryRun => false,
+            DispatchOutcome::HandlerError => true,
+            DispatchOutcome::NotFound => {
+                self.not_found.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            _ => return,
+        };
+        let mut actions = self.actions.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = match actions.get_mut(name) {
+            Some(s) => s,
+            None => actions.entry(name.to_owned()).or_default(),
+        };
+        stats.invocations += 1;
+        if failed {
+            stats.errors += 1;
+        }
+    }
+
+    fn report<'a>(&self, names: impl Iterator<Item = &'a str>) -> StatsReport {
+        let mut actions: HashMap<String, ActionStats> = names
+            .map(|n| (n.to_owned(), ActionStats::default()))
+            .collect();
+        for (name, stats) in self
+            .actions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            actions.insert(name.clone(), *stats);
+        }
+        StatsReport {
+            actions,
+            not_found: self.not_found.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<R> Manager<R> {
+    /// counters of every registered action, ones which never ran at zero
+    pub fn stats(&self) -> HashMap<String, ActionStats> {
+        self.stats_report().actions
+    }
+
+    /// how many actions asked for a name nothing is registered under
+    pub fn not_found_count(&self) -> u64 {
+        self.action_stats().not_found.load(Ordering::Relaxed)
+    }
+
+    pub fn stats_report(&self) -> StatsReport {
+        self.action_stats().report(self.action_names())
+    }
+
+    /// registers the `__stats` action answering with the `StatsReport`; actions
+    /// registered after it are listed once they ran
+    pub fn enable_stats(&mut self) {
+        let stats: Arc<Stats> = self.action_stats().clone();
+        let mut names: Vec<String> = self.action_names().map(|n| n.to_owned()).collect();
+        names.push(STATS_ACTION.to_owned());
+        self.on(STATS_ACTION, move |_, _| {
+            value_ok(stats.report(names.iter().map(|n| n.as_str())))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{action_ok, Action};
+    use crate::error::ActionError;
+
+    fn action(name: &str) -> Action {
+        Action::new(name, 0)
+    }
+
+    #[test]
+    fn counts_runs_errors_and_misses() {
+        let mut m = Manager::new("test", ());
+        m.on("ok", |_, _| action_ok());
+        m.on("fails", |_, _| Err(ActionError::new("Nope", "").into()));
+        m.on("idle", |_, _| action_ok());
+        m.enable_stats();
+        for name in ["ok", "ok", "fails", "missing", "ok"] {
+            m.do_action(&mut action(name));
+        }
+        let stats = m.stats();
+        assert_eq!(
+            stats["ok"],
+            ActionStats {
+                invocations: 3,
+                errors: 0
+            }
+        );
+        assert_eq!(
+            stats["fails"],
+            ActionStats {
+                invocations: 1,
+                errors: 1
+            }
+        );
+        assert_eq!(stats["idle"], ActionStats::default());
+        assert_eq!(m.not_found_count(), 1);
+
+        let mut a = action(STATS_ACTION);
+        m.do_action(&mut a);
+        let report: StatsReport = serde_json::from_value(a.result.unwrap()).unwrap();
+        assert_eq!(report.actions["ok"].invocations, 3);
+        assert_eq!(report.actions["idle"], ActionStats::default());
+        assert_eq!(report.not_found, 1);
+        assert_eq!(m.stats()[STATS_ACTION].invocations, 1);
+    }
+}
@@ -0,0 +1,142 @@
+//! newline delimited JSON: actions read one per line off any `BufRead`, replies written
+//! one per line
+
+use std::io::{BufRead, Write};
+
+use crate::action::{Action, ActionReply};
+use crate::error::ActionError;
+
+/// actions parsed line by line, see `ndjson`
+pub struct ActionStream<R> {
+    reader: R,
+    line: usize,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+/// the actions in `reader`, one per line. Empty lines are skipped and `\r\n` endings
+/// accepted; a line which is not an action gives an error naming its line number and the
+/// stream goes on with the next one, a read error ends it
+pub fn ndjson<R: BufRead>(reader: R) -> ActionStream<R> {
+    ActionStream {
+        reader,
+        line: 0,
+        buf: Vec::new(),
+        done: false,
+    }
+}
+
+impl<R> ActionStream<R> {
+    /// number of the line read last, counting from 1
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl<R: BufRead> Iterator for ActionStream<R> {
+    type Item = Result<Action, ActionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buf.clear();
+            match self.reader.read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.line += 1;
+                    let line = trim_line(&self.buf);
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    return Some(Action::from_slice(line).map_err(|e| {
+                        ActionError::new(&e.code, &format!("line {}: {}", self.line, e.message))
+                            .with_details(json!({ "line": self.line }))
+                    }));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn trim_line(buf: &[u8]) -> &[u8] {
+    let buf = buf.strip_suffix(b"\n").unwrap_or(buf);
+    buf.strip_suffix(b"\r").unwrap_or(buf)
+}
+
+/// writes `reply` as one line
+pub fn write_ndjson<W: Write>(reply: &ActionReply, mut w: W) -> Result<(), ActionError> {
+    let bytes = reply.to_bytes()?;
+    w.write_all(&bytes)?;
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn good_and_bad_lines() {
+        let input = concat!(
+            r#"{"name":"a","id":1,"token":null,"base64":null,"payload":{},"result":null,"errors":null}"#,
+            "\r\n\n   \r\n",
+            "{not json}\n",
+            r#"{"name":"b","id":2,"token":null,"base64":null,"payload":{},"result":null,"errors":null}"#,
+            "\n\u{ff}\n",
+            r#"{"name":"c","id":3,"token":null,"base64":null,"payload":{},"result":null,"errors":null}"#,
+        );
+        let results: Vec<_> = ndjson(Cursor::new(input.as_bytes())).collect();
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap().name, "a");
+        let err = results[1].as_ref().unwrap_err();
+        assert!(err.message.starts_with("line 4:"), "{}", err.message);
+        assert_eq!(err.details.as_ref().unwrap()["line"], json!(4));
+        assert_eq!(results[2].as_ref().unwrap().name, "b");
+        assert_eq!(results[3].as_ref().unwrap_err().code, "JsonError");
+        // the last line has no newline
+        assert_eq!(results[4].as_ref().unwrap().name, "c");
+
+        // a line which is not UTF-8 at all
+        let raw = b"\xff\xfe\n{}\n".to_vec();
+        let results: Vec<_> = ndjson(Cursor::new(raw)).collect();
+        assert_eq!(results[0].as_ref().unwrap_err().code, "Utf8Error");
+        assert!(results[1]
+            .as_ref()
+            .unwrap_err()
+            .message
+            .starts_with("line 2:"));
+    }
+
+    #[test]
+    fn huge_line() {
+        let mut a = Action::new("bulk", 9);
+        a.payload
+            .insert("blob".to_owned(), json!("x".repeat(4 << 20)));
+        let mut input = a.to_bytes().unwrap().to_vec();
+        input.extend_from_slice(b"\n");
+        let got: Vec<_> = ndjson(Cursor::new(input)).map(Result::unwrap).collect();
+        assert_eq!(got[0].payload["blob"].as_str().unwrap().len(), 4 << 20);
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        let mut out = Vec::new();
+        for id in 1..=3 {
+            let mut a = Action::new("sum", id);
+            a.set_result(json!(id * 2));
+            write_ndjson(&a.into_reply(), &mut out).unwrap();
+        }
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        let ids: Vec<u64> = text
+            .lines()
+            .map(|l| serde_json::from_str::<ActionReply>(l).unwrap().id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::action::{Action, Manager};
+use crate::error::ActionError;
+use crate::outcome::DispatchOutcome;
+
+/// how many worker threads `Manager::do_action_timed` runs at once unless told otherwise,
+/// those left behind by handlers which timed out included
+pub const DEFAULT_TIMED_WORKERS: usize = 64;
+
+/// execution limits of a manager, see `Manager::set_default_timeout`
+pub(crate) struct Timeouts {
+    default: Option<Duration>,
+    per_action: HashMap<String, Duration>,
+    max_workers: usize,
+    /// shared with the workers, which outlive the call when their handler timed out
+    workers: Arc<AtomicUsize>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            default: None,
+            per_action: HashMap::new(),
+            max_workers: DEFAULT_TIMED_WORKERS,
+            workers: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+/// a running worker thread, counted until dropped
+struct Worker(Arc<AtomicUsize>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Timeouts {
+    fn of(&self, name: &str) -> Option<Duration> {
+        self.per_action.get(name).copied().or(self.default)
+    }
+
+    /// counts a worker, failing with a retryable `Overloaded` once `max_workers` run
+    fn worker(&self, manager: &str) -> Result<Worker, ActionError> {
+        let max = self.max_workers;
+        self.workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .map(|_| Worker(Arc::clone(&self.workers)))
+            .map_err(|_| {
+                ActionError::new(
+                    "Overloaded",
+                    &format!("{} already runs {} timed workers", manager, max),
+                )
+                .with_details(json!({ "limit": max, "limited": "timed workers" }))
+                .retryable()
+            })
+    }
+}
+
+/// the retryable `Timeout` error of an action which ran longer than `limit`
+pub fn timeout_error(name: &str, limit: Duration) -> ActionError {
+    let ms = limit.as_millis() as u64;
+    ActionError::new(
+        "Timeout",
+        &format!("{} did not finish within {}ms", name, ms),
+    )
+    .with_details(json!({ "action": name, "timeout_ms": ms }))
+    .retryable()
+}
+
+impl<R> Manager<R> {
+    /// limit for actions without one of their own from `with_timeout`. Only
+    /// `do_action_timed` enforces it, `do_action`, `run`, `do_batch` and the other
+    /// dispatch methods run handlers without a limit
+    pub fn set_default_timeout(&mut self, limit: Duration) {
+        self.timeouts_mut().default = Some(limit);
+    }
+
+    /// limit for the action `name`, over the default one; like the default one it only
+    /// applies to `do_action_timed`
+    pub fn with_timeout(&mut self, name: &str, limit: Duration) {
+        self.timeouts_mut()
+            .per_action
+            .insert(name.to_owned(), limit);
+    }
+
+    /// the limit `do_action_timed` puts on the action `name`
+    pub fn timeout_of(&self, name: &str) -> Option<Duration> {
+        self.timeouts().of(name)
+    }
+
+    /// how many worker threads `do_action_timed` runs at once, `DEFAULT_TIMED_WORKERS`
+    /// unless set
+    pub fn set_timed_workers(&mut self, max: usize) {
+        self.timeouts_mut().max_workers = max;
+    }
+
+    /// how many worker threads of `do_action_timed` are running, abandoned ones included
+    pub fn timed_workers(&self) -> usize {
+        self.timeouts().workers.load(Ordering::SeqCst)
+    }
+}
+
+impl<R: Send + Sync + 'static> Manager<R> {
+    /// `do_action` with the limit of `timeout_of`. The action is dispatched on a worker
+    /// thread, once the limit passes the action gets a retryable `Timeout` error and the
+    /// worker is left behind: handlers can not be stopped, so it runs to completion and
+    /// its result is dropped. At most `set_timed_workers` workers run at once, past that
+    /// actions are shed with a retryable `Overloaded` error. Actions without a limit are
+    /// dispatched on this thread
+    pub fn do_action_timed(self: &Arc<Self>, action: &mut Action) -> DispatchOutcome {
+        let limit = match self.timeout_of(&action.name) {
+            Some(limit) => limit,
+            None => return self.do_action(action),
+        };
+        let worker = match self.timeouts().worker(self.manager_name()) {
+            Ok(worker) => worker,
+            Err(e) => {
+                action.set_error(e);
+                return DispatchOutcome::Shed;
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        let m = Arc::clone(self);
+        let mut work = action.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("{}-{}", self.manager_name(), action.name))
+            .spawn(move || {
+                let _worker = worker;
+                let outcome = m.do_action(&mut work);
+                // the caller stopped waiting when this fails
+                let _ = tx.send((work, outcome));
+            });
+        if let Err(e) = spawned {
+            action.set_error(
+                ActionError::new("SpawnError", "could not start a worker thread")
+                    .with_details(json!({ "cause": e.to_string() }))
+                    .retryable(),
+            );
+            return DispatchOutcome::Shed;
+        }
+        match rx.recv_timeout(limit) {
+            Ok((done, outcome)) => {
+                *action = done;
+                outcome
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                action.set_error(timeout_error(&action.name, limit));
+                DispatchOutcome::HandlerError
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                action.set_error(ActionError::new(
+                    "HandlerPanic",
+                    &format!("{} panicked", action.name),
+                ));
+                DispatchOutcome::HandlerError
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::value_ok;
+    use std::time::Instant;
+
+    #[test]
+    fn late_results_are_dropped() {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        let mut m = Manager::new("test", done_tx);
+        m.on("slow", |done, _a| {
+            thread::sleep(Duration::from_millis(150));
+            let _ = done.send(());
+            value_ok(json!("late"))
+        });
+        m.on("fast", |_r, _a| value_ok(json!("quick")));
+        m.set_default_timeout(Duration::from_millis(20));
+        m.with_timeout("fast", Duration::from_secs(5));
+        let m = Arc::new(m);
+
+        let mut a = Action::new("slow", 1);
+        let started = Instant::now();
+        assert_eq!(m.do_action_timed(&mut a), DispatchOutcome::HandlerError);
+        assert!(started.elapsed() < Duration::from_millis(150));
+        let errors = a.errors.clone().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "Timeout");
+        assert!(errors[0].is_retryable());
+        let details = errors[0].details.clone().unwrap();
+        assert_eq!(details["action"], json!("slow"));
+        assert_eq!(details["timeout_ms"], json!(20));
+
+        // the handler finishes later, the action keeps only the timeout
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(a.result.is_none());
+        assert_eq!(a.errors.as_ref().map(|e| e.len()), Some(1));
+
+        let mut a = Action::new("fast", 2);
+        assert_eq!(m.do_action_timed(&mut a), DispatchOutcome::Handled);
+        assert_eq!(a.result, Some(json!("quick")));
+    }
+
+    #[test]
+    fn without_a_limit_runs_inline() {
+        let mut m = Manager::new("test", ());
+        m.on("name", |_r, _a| {
+            value_ok(json!(thread::current().name().map(|n| n.to_owned())))
+        });
+        let m = Arc::new(m);
+        assert_eq!(m.timeout_of("name"), None);
+        let mut a = Action::new("name", 1);
+        m.do_action_timed(&mut a);
+        let here = thread::current().name().map(|n| n.to_owned());
+        assert_eq!(a.result, Some(json!(here)));
+
+        let mut m = Arc::try_unwrap(m).ok().unwrap();
+        m.with_timeout("name", Duration::from_secs(5));
+        let m = Arc::new(m);
+        let mut a = Action::new("name", 2);
+        m.do_action_timed(&mut a);
+        assert_eq!(a.result, Some(json!("test-name")));
+    }
+
+    #[test]
+    fn workers_are_bounded() {
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let mut m = Manager::new("test", std::sync::Mutex::new(release_rx));
+        m.on("stuck", |release, _a| {
+            let _ = release.lock().unwrap().recv();
+            value_ok(json!("late"))
+        });
+        m.set_default_timeout(Duration::from_millis(10));
+        m.set_timed_workers(1);
+        let m = Arc::new(m);
+
+        let mut a = Action::new("stuck", 1);
+        assert_eq!(m.do_action_timed(&mut a), DispatchOutcome::HandlerError);
+        assert_eq!(m.timed_workers(), 1);
+        let mut a = Action::new("stuck", 2);
+        assert_eq!(m.do_action_timed(&mut a), DispatchOutcome::Shed);
+        let err = &a.errors.unwrap()[0];
+        assert_eq!(err.code, "Overloaded");
+        assert!(err.is_retryable());
+
+        // the abandoned worker finishes and frees its place
+        release_tx.send(()).unwrap();
+        let started = Instant::now();
+        while m.timed_workers() > 0 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(m.timed_workers(), 0);
+    }
+}
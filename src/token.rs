@@ -0,0 +1,164 @@
+use crate::error::ActionError;
+
+/// how the token of an action is written wherever it is stored or logged, the live action
+/// always keeps the real one; see `Manager::token_codec`
+pub trait TokenCodec: Send + Sync {
+    fn encode(&self, token: &str) -> String;
+    fn decode(&self, stored: &str) -> Result<String, ActionError>;
+}
+
+/// tokens are stored as they are
+pub struct Plain;
+
+impl TokenCodec for Plain {
+    fn encode(&self, token: &str) -> String {
+        token.to_owned()
+    }
+
+    fn decode(&self, stored: &str) -> Result<String, ActionError> {
+        Ok(stored.to_owned())
+    }
+}
+
+#[cfg(feature = "crypto")]
+pub use self::crypto::{Encrypted, Masked};
+
+#[cfg(feature = "crypto")]
+mod crypto {
+    use aes_gcm_siv::aead::{Aead, KeyInit};
+    use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    use super::TokenCodec;
+    use crate::error::ActionError;
+
+    /// tokens are replaced by a prefix of their sha256, the same token always masks to the
+    /// same value so entries can still be correlated, but it can not be recovered
+    pub struct Masked;
+
+    const MASKED_PREFIX: &str = "masked:";
+
+    impl TokenCodec for Masked {
+        fn encode(&self, token: &str) -> String {
+            let hash = Sha256::digest(token.as_bytes());
+            let hex: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}{}", MASKED_PREFIX, hex)
+        }
+
+        fn decode(&self, _: &str) -> Result<String, ActionError> {
+            Err(ActionError::new(
+                "TokenCodecError",
+                "masked tokens can not be recovered",
+            ))
+        }
+    }
+
+    /// tokens are encrypted with AES-256-GCM-SIV under a 32 byte key; the nonce is derived
+    /// from the token, so the same token always encrypts to the same value
+    pub struct Encrypted {
+        key: [u8; 32],
+    }
+
+    const ENCRYPTED_PREFIX: &str = "enc:";
+    const NONCE_LEN: usize = 12;
+
+    impl Encrypted {
+        pub fn new(key: [u8; 32]) -> Self {
+            Encrypted { key }
+        }
+
+        fn cipher(&self) -> Aes256GcmSiv {
+            Aes256GcmSiv::new((&self.key).into())
+        }
+    }
+
+    fn codec_error(message: &str) -> ActionError {
+        ActionError::new("TokenCodecError", message)
+    }
+
+    impl TokenCodec for Encrypted {
+        fn encode(&self, token: &str) -> String {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+                .expect("hmac takes keys of any length");
+            mac.update(token.as_bytes());
+            let nonce = mac.finalize().into_bytes();
+            let nonce = Nonce::from_slice(&nonce[..NONCE_LEN]);
+            let mut out = nonce.to_vec();
+            out.extend(
+                self.cipher()
+                    .encrypt(nonce, token.as_bytes())
+                    .expect("tokens are far below the aead size limit"),
+            );
+            format!("{}{}", ENCRYPTED_PREFIX, URL_SAFE_NO_PAD.encode(out))
+        }
+
+        fn decode(&self, stored: &str) -> Result<String, ActionError> {
+            let data = stored
+                .strip_prefix(ENCRYPTED_PREFIX)
+                .and_then(|s| URL_SAFE_NO_PAD.decode(s).ok())
+                .filter(|d| d.len() > NONCE_LEN)
+                .ok_or_else(|| codec_error("not an encrypted token"))?;
+            let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+            let plain = self
+                .cipher()
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| codec_error("the token does not decrypt with this key"))?;
+            String::from_utf8(plain).map_err(|_| codec_error("the decrypted token is not utf-8"))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+    use crate::action::{Action, Manager};
+    use std::sync::Arc;
+
+    const TOKEN: &str = "sk_live_abc123";
+
+    fn action() -> Action {
+        let mut a = Action::new("pay", 0);
+        a.token = Some(TOKEN.to_owned());
+        a
+    }
+
+    #[test]
+    fn masked_never_stores_the_token() {
+        let mut m = Manager::new("test", ());
+        m.token_codec(Arc::new(Masked));
+        let a = action();
+        let stored = serde_json::to_string(&m.for_storage(&a)).unwrap();
+        assert!(!stored.contains(TOKEN));
+        assert_eq!(a.token.as_deref(), Some(TOKEN));
+        // stable, so the entries of one token can still be grouped
+        assert_eq!(m.for_storage(&a).token, m.for_storage(&action()).token);
+        assert!(m.restore_token(&m.for_storage(&a)).is_err());
+    }
+
+    #[test]
+    fn encrypted_round_trips() {
+        let mut m = Manager::new("test", ());
+        m.token_codec(Arc::new(Encrypted::new([7; 32])));
+        let stored = m.for_storage(&action());
+        let json = serde_json::to_string(&stored).unwrap();
+        assert!(!json.contains(TOKEN));
+        assert!(stored.token.as_deref().unwrap().starts_with("enc:"));
+        assert_eq!(
+            m.restore_token(&stored).unwrap().token.as_deref(),
+            Some(TOKEN)
+        );
+
+        let other = Encrypted::new([8; 32]);
+        assert!(other.decode(stored.token.as_deref().unwrap()).is_err());
+        assert!(other.decode("enc:nope").is_err());
+    }
+
+    #[test]
+    fn plain_by_default() {
+        let m = Manager::new("test", ());
+        assert_eq!(m.for_storage(&action()).token.as_deref(), Some(TOKEN));
+    }
+}
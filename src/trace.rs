@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+use crate::action::Action;
+
+/// how many dispatches `Manager::trace_dispatches` keeps unless told otherwise
+pub const DEFAULT_TRACE_CAPACITY: usize = 256;
+
+/// one timed phase of a dispatch
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TraceSpan {
+    pub phase: &'static str,
+    /// offset from the start of the dispatch in microseconds
+    pub start_us: u64,
+    pub duration_us: u64,
+}
+
+/// where the time went during a single dispatch
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DispatchTrace {
+    pub manager: String,
+    pub action: String,
+    pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub spans: Vec<TraceSpan>,
+}
+
+/// a stack frame name with the separators of the folded format, `;` and whitespace,
+/// replaced by `_`
+fn frame(name: &str) -> String {
+    name.replace(|c: char| c == ';' || c.is_whitespace(), "_")
+}
+
+/// renders traces in the folded stack format consumed by inferno and flamegraph.pl,
+/// one `manager;action;phase micros` line per distinct stack; `;` and whitespace in the
+/// names become `_`
+pub fn traces_to_folded(traces: &[DispatchTrace]) -> String {
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for t in traces {
+        for s in &t.spans {
+            let stack = format!("{};{};{}", frame(&t.manager), frame(&t.action), s.phase);
+            *stacks.entry(stack).or_insert(0) += s.duration_us;
+        }
+    }
+    let mut out = String::new();
+    for (stack, us) in stacks {
+        out.push_str(&stack);
+        out.push(' ');
+        out.push_str(&us.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// records spans for one dispatch, does nothing when tracing is off
+pub(crate) struct Tracer {
+    start: Option<Instant>,
+    spans: Vec<TraceSpan>,
+}
+
+impl Tracer {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Tracer {
+            start: if enabled { Some(Instant::now()) } else { None },
+            spans: Vec::new(),
+        }
+    }
+
+    pub(crate) fn span<T, F: FnOnce() -> T>(&mut self, phase: &'static str, f: F) -> T {
+        let start = match self.start {
+            Some(start) => start,
+            None => return f(),
+        };
+        let begin = Instant::now();
+        let out = f();
+        self.spans.push(TraceSpan {
+            phase,
+            start_us: begin.duration_since(start).as_micros() as u64,
+            duration_us: begin.elapsed().as_micros() as u64,
+        });
+        out
+    }
+
+    pub(crate) fn finish(self, manager: &str, action: &Action) -> Option<DispatchTrace> {
+        self.start.map(|_| DispatchTrace {
+            manager: manager.to_owned(),
+            action: action.name.clone(),
+            id: action.id,
+            correlation_id: action.correlation_id.clone(),
+            spans: self.spans,
+        })
+    }
+}
+
+/// ring buffer of the most recent traces
+pub(crate) struct TraceBuffer {
+    capacity: usize,
+    traces: VecDeque<DispatchTrace>,
+}
+
+impl TraceBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        TraceBuffer {
+            capacity,
+            traces: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub(crate) fn push(&mut self, trace: DispatchTrace) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.traces.len() == self.capacity {
+            self.traces.pop_front();
+        }
+        self.traces.push_back(trace);
+    }
+
+    /// the last `n` traces, oldest first
+    pub(crate) fn recent(&self, n: usize) -> Vec<DispatchTrace> {
+        let skip = self.traces.len().saturating_sub(n);
+        self.traces.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Manager;
+
+    fn action(name: &str, id: u64) -> Action {
+        Action::new(name, id)
+    }
+
+    #[test]
+    fn folded_format() {
+        let span = |phase, duration_us| TraceSpan {
+            phase,
+            start_us: 0,
+            duration_us,
+        };
+        let traces = vec![
+            DispatchTrace {
+                manager: "users".to_owned(),
+                action: "get".to_owned(),
+                id: 1,
+                correlation_id: None,
+                spans: vec![span("before", 3), span("handler", 40)],
+            },
+            DispatchTrace {
+                manager: "users".to_owned(),
+                action: "get".to_owned(),
+                id: 2,
+                correlation_id: None,
+                spans: vec![span("handler", 2)],
+            },
+        ];
+        assert_eq!(
+            traces_to_folded(&traces),
+            "users;get;before 3\nusers;get;handler 42\n"
+        );
+
+        let traces = vec![DispatchTrace {
+            manager: "user admin".to_owned(),
+            action: "get;all\tnow".to_owned(),
+            id: 1,
+            correlation_id: None,
+            spans: vec![span("handler", 5)],
+        }];
+        assert_eq!(
+            traces_to_folded(&traces),
+            "user_admin;get_all_now;handler 5\n"
+        );
+    }
+
+    #[test]
+    fn manager_records_phases() {
+        let mut m = Manager::with("users", || ());
+        m.on("get", |_, _| crate::action::action_ok());
+        m.validate_action("get", |_| Ok(()));
+        m.trace_dispatches(true);
+        m.do_action(&mut action("get", 7));
+        let traces = m.recent_traces(10);
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].id, 7);
+        let phases: Vec<&str> = traces[0].spans.iter().map(|s| s.phase).collect();
+        assert_eq!(phases, vec!["resource", "before", "handler", "encode"]);
+
+        m.use_after(|_, _| {});
+        m.do_action(&mut action("get", 8));
+        let traces = m.recent_traces(1);
+        let phases: Vec<&str> = traces[0].spans.iter().map(|s| s.phase).collect();
+        assert_eq!(
+            phases,
+            vec!["resource", "before", "handler", "encode", "after"]
+        );
+        for line in traces_to_folded(&traces).lines() {
+            let (stack, value) = line.rsplit_once(' ').unwrap();
+            assert!(stack.starts_with("users;get;"));
+            value.parse::<u64>().unwrap();
+        }
+    }
+
+    #[test]
+    fn ring_buffer_is_bounded() {
+        let mut m = Manager::new("users", ());
+        m.on("get", |_, _| crate::action::action_ok());
+        m.trace_capacity(3);
+        for id in 0..10 {
+            m.do_action(&mut action("get", id));
+        }
+        let ids: Vec<u64> = m.recent_traces(100).iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![7, 8, 9]);
+        let ids: Vec<u64> = m.recent_traces(2).iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![8, 9]);
+    }
+
+    #[test]
+    fn off_by_default() {
+        let mut m = Manager::new("users", ());
+        m.on("get", |_, _| crate::action::action_ok());
+        m.do_action(&mut action("get", 1));
+        assert!(m.recent_traces(10).is_empty());
+    }
+}
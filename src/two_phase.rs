@@ -0,0 +1,293 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::action::{value_ok, Action, Manager};
+use crate::error::ActionError;
+
+/// how long a confirmation handed out by a prepare step stays valid
+pub const DEFAULT_CONFIRMATION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// payload key the confirm step expects the confirmation in
+pub const CONFIRMATION_KEY: &str = "confirmation";
+
+/// what a prepare step tells the client is about to happen
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrepareSummary {
+    pub summary: String,
+    /// e.g. how many records would be removed
+    #[serde(default)]
+    pub consequences: Value,
+}
+
+/// result of a prepare step, `confirmation` goes into the payload of the confirm step
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrepareReply {
+    #[serde(flatten)]
+    pub summary: PrepareSummary,
+    pub confirmation: String,
+    /// unix time in milliseconds
+    pub expires_at: u64,
+}
+
+/// signs and checks confirmations, shared by every two phase action of a manager
+pub(crate) struct Confirmations {
+    pub(crate) key: Option<Vec<u8>>,
+    pub(crate) ttl: Duration,
+}
+
+pub(crate) type SharedConfirmations = Arc<Mutex<Confirmations>>;
+
+impl Default for Confirmations {
+    fn default() -> Self {
+        Confirmations {
+            key: None,
+            ttl: DEFAULT_CONFIRMATION_TTL,
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// sha256 of the payload without the confirmation, keys in sorted order
+fn payload_hash(payload: &HashMap<String, Value>) -> Result<Vec<u8>, ActionError> {
+    let sorted: BTreeMap<&String, &Value> = payload
+        .iter()
+        .filter(|(k, _)| k.as_str() != CONFIRMATION_KEY)
+        .collect();
+    Ok(Sha256::digest(serde_json::to_vec(&sorted)?).to_vec())
+}
+
+impl Confirmations {
+    fn mac(
+        &self,
+        action: &Action,
+        name: &str,
+        expires_at: u64,
+    ) -> Result<Hmac<Sha256>, ActionError> {
+        let key = self.key.as_ref().ok_or_else(|| {
+            ActionError::new(
+                "ConfirmationKeyMissing",
+                "Manager::confirmation_key must be set to use two phase actions",
+            )
+        })?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key)
+            .map_err(|e| ActionError::new("ConfirmationKeyMissing", &e.to_string()))?;
+        for part in [
+            name.as_bytes(),
+            action.token.as_deref().unwrap_or("").as_bytes(),
+        ] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac.update(&payload_hash(&action.payload)?);
+        mac.update(&expires_at.to_be_bytes());
+        Ok(mac)
+    }
+
+    fn sign(&self, action: &Action, name: &str) -> Result<(String, u64), ActionError> {
+        let expires_at = now_ms() + self.ttl.as_millis() as u64;
+        let tag = self.mac(action, name, expires_at)?.finalize().into_bytes();
+        Ok((
+            format!("{}.{}", expires_at, URL_SAFE_NO_PAD.encode(tag)),
+            expires_at,
+        ))
+    }
+
+    fn verify(&self, action: &Action, name: &str) -> Result<(), ActionError> {
+        let mismatch = || {
+            ActionError::new(
+                "ConfirmationMismatch",
+                &format!(
+                    "the confirmation does not match this {} request, prepare it again",
+                    name
+                ),
+            )
+        };
+        let confirmation = action
+            .payload
+            .get(CONFIRMATION_KEY)
+            .and_then(Value::as_str)
+            .ok_or_else(mismatch)?;
+        let (expires_at, tag) = confirmation.split_once('.').ok_or_else(mismatch)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| mismatch())?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).map_err(|_| mismatch())?;
+        self.mac(action, name, expires_at)?
+            .verify_slice(&tag)
+            .map_err(|_| mismatch())?;
+        if now_ms() >= expires_at {
+            return Err(ActionError::new(
+                "ConfirmationExpired",
+                &format!("the confirmation for {} expired, prepare it again", name),
+            )
+            .with_details(json!({ "expired_at": expires_at })));
+        }
+        Ok(())
+    }
+}
+
+impl<R> Manager<R> {
+    /// secret the confirmations of two phase actions are signed with
+    pub fn confirmation_key(&mut self, key: &[u8]) {
+        self.confirmations().key = Some(key.to_vec());
+    }
+
+    /// how long a confirmation stays valid, `DEFAULT_CONFIRMATION_TTL` unless set
+    pub fn confirmation_ttl(&mut self, ttl: Duration) {
+        self.confirmations().ttl = ttl;
+    }
+
+    fn confirmations(&mut self) -> std::sync::MutexGuard<'_, Confirmations> {
+        self.confirmations_shared()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// registers `name.prepare`, which runs `prepare` and replies with its summary and a
+    /// confirmation, and `name.confirm`, which runs `execute` only when its payload
+    /// carries that confirmation under `CONFIRMATION_KEY`, unexpired and signed for the
+    /// same token and payload; nothing is stored on the server in between
+    pub fn on_two_phase<P, E>(&mut self, name: &str, prepare: P, execute: E)
+    where
+        P: Fn(&R, &Action) -> Result<PrepareSummary, ActionError> + Send + Sync + 'static,
+        E: Fn(&R, &Action) -> Result<Value, ActionError> + Send + Sync + 'static,
+    {
+        let signer = self.confirmations_shared().clone();
+        let base = name.to_owned();
+        self.on(&format!("{}.prepare", name), move |r, action| {
+            let summary = prepare(r, action)?;
+            let signer = signer.lock().unwrap_or_else(|e| e.into_inner());
+            let (confirmation, expires_at) = signer.sign(action, &base)?;
+            value_ok(PrepareReply {
+                summary,
+                confirmation,
+                expires_at,
+            })
+        });
+        let signer = self.confirmations_shared().clone();
+        let base = name.to_owned();
+        self.on(&format!("{}.confirm", name), move |r, action| {
+            signer
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .verify(action, &base)?;
+            let mut action = action.clone();
+            action.payload.remove(CONFIRMATION_KEY);
+            value_ok(execute(r, &action)?)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(name: &str, payload: Value) -> Action {
+        let mut a = Action::new(name, 0);
+        a.token = Some("t1".to_owned());
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    fn manager(ttl: Duration) -> Manager<()> {
+        let mut m = Manager::new("orgs", ());
+        m.confirmation_key(b"secret");
+        m.confirmation_ttl(ttl);
+        m.on_two_phase(
+            "delete-organization",
+            |_, a| {
+                Ok(PrepareSummary {
+                    summary: format!("deletes organization {}", a.payload["org"]),
+                    consequences: json!({"members": 12}),
+                })
+            },
+            |_, a| {
+                assert!(!a.payload.contains_key(CONFIRMATION_KEY));
+                Ok(json!({"deleted": a.payload["org"]}))
+            },
+        );
+        m
+    }
+
+    fn prepare(m: &Manager<()>, payload: Value) -> PrepareReply {
+        let mut a = action("delete-organization.prepare", payload);
+        m.do_action(&mut a);
+        a.from_result().unwrap()
+    }
+
+    fn confirm(m: &Manager<()>, mut payload: Value, confirmation: &str) -> Action {
+        payload[CONFIRMATION_KEY] = json!(confirmation);
+        let mut a = action("delete-organization.confirm", payload);
+        m.do_action(&mut a);
+        a
+    }
+
+    #[test]
+    fn happy_path() {
+        let m = manager(DEFAULT_CONFIRMATION_TTL);
+        let p = prepare(&m, json!({"org": 4}));
+        assert_eq!(p.summary.consequences, json!({"members": 12}));
+        assert!(p.expires_at > now_ms());
+        let a = confirm(&m, json!({"org": 4}), &p.confirmation);
+        assert!(a.errors.is_none());
+        assert_eq!(a.result, Some(json!({"deleted": 4})));
+    }
+
+    #[test]
+    fn expired() {
+        let m = manager(Duration::from_secs(0));
+        let p = prepare(&m, json!({"org": 4}));
+        let a = confirm(&m, json!({"org": 4}), &p.confirmation);
+        assert_eq!(a.errors.unwrap()[0].code, "ConfirmationExpired");
+        assert!(a.result.is_none());
+    }
+
+    #[test]
+    fn payload_changed_between_phases() {
+        let m = manager(DEFAULT_CONFIRMATION_TTL);
+        let p = prepare(&m, json!({"org": 4}));
+        let a = confirm(&m, json!({"org": 5}), &p.confirmation);
+        assert_eq!(a.errors.unwrap()[0].code, "ConfirmationMismatch");
+
+        // another user, or a stretched expiry, does not verify either
+        let mut a = action(
+            "delete-organization.confirm",
+            json!({"org": 4, CONFIRMATION_KEY: p.confirmation}),
+        );
+        a.token = Some("t2".to_owned());
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "ConfirmationMismatch");
+        let (_, tag) = p.confirmation.split_once('.').unwrap();
+        let stretched = format!("{}.{}", p.expires_at + 60_000, tag);
+        let a = confirm(&m, json!({"org": 4}), &stretched);
+        assert_eq!(a.errors.unwrap()[0].code, "ConfirmationMismatch");
+    }
+
+    #[test]
+    fn key_required() {
+        let mut m = Manager::new("orgs", ());
+        m.on_two_phase(
+            "wipe",
+            |_, _| {
+                Ok(PrepareSummary {
+                    summary: "wipes".to_owned(),
+                    consequences: Value::Null,
+                })
+            },
+            |_, _| Ok(Value::Null),
+        );
+        let mut a = action("wipe.prepare", json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "ConfirmationKeyMissing");
+    }
+}
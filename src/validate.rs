@@ -0,0 +1,464 @@
+use serde_json::Value;
+use std::ops::{Bound, RangeBounds};
+
+use crate::action::Action;
+use crate::error::ActionError;
+
+/// a set of checks run against an action before its handler, see `Manager::validate_action`
+pub type Validator = dyn Fn(&Action) -> Result<(), Vec<ActionError>> + Send + Sync + 'static;
+
+fn violation(message: String, details: Value) -> ActionError {
+    ActionError::new("ValidationError", &message).with_details(details)
+}
+
+/// every key must be present in the payload
+pub fn require_keys(action: &Action, keys: &[&str]) -> Result<(), ActionError> {
+    let missing: Vec<&str> = keys
+        .iter()
+        .filter(|k| !action.payload.contains_key(**k))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(violation(
+            format!("missing required fields: {}", missing.join(", ")),
+            json!({"fields": missing, "constraint": "required"}),
+        ))
+    }
+}
+
+/// exactly one of the keys must be present in the payload
+pub fn require_one_of(action: &Action, keys: &[&str]) -> Result<(), ActionError> {
+    let present: Vec<&str> = keys
+        .iter()
+        .filter(|k| action.payload.contains_key(**k))
+        .copied()
+        .collect();
+    if present.len() == 1 {
+        Ok(())
+    } else {
+        Err(violation(
+            format!(
+                "exactly one of {} is required, got {}",
+                keys.join(", "),
+                present.len()
+            ),
+            json!({"fields": keys, "present": present, "constraint": "one_of"}),
+        ))
+    }
+}
+
+/// the field must be a string matching `pattern`, a glob where `*` matches any run of
+/// characters and `?` matches exactly one
+pub fn require_matches(action: &Action, key: &str, pattern: &str) -> Result<(), ActionError> {
+    let matches = match action.payload.get(key) {
+        Some(Value::String(s)) => glob_match(pattern, s),
+        _ => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(violation(
+            format!("{} must be a string matching {}", key, pattern),
+            json!({"field": key, "constraint": "matches", "pattern": pattern}),
+        ))
+    }
+}
+
+/// the field must be an integer inside `range`
+pub fn require_range<B>(action: &Action, key: &str, range: B) -> Result<(), ActionError>
+where
+    B: RangeBounds<i64>,
+{
+    let in_range = match action.payload.get(key).and_then(Value::as_i64) {
+        Some(n) => range.contains(&n),
+        None => false,
+    };
+    if in_range {
+        return Ok(());
+    }
+    let bound = |b: Bound<&i64>| match b {
+        Bound::Included(n) | Bound::Excluded(n) => json!(n),
+        Bound::Unbounded => Value::Null,
+    };
+    let min = bound(range.start_bound());
+    let max = bound(range.end_bound());
+    let inclusive = matches!(range.end_bound(), Bound::Included(_));
+    Err(violation(
+        format!(
+            "{} must be an integer in range {}..{}{}",
+            key,
+            min,
+            if inclusive { "=" } else { "" },
+            max
+        ),
+        json!({"field": key, "constraint": "range", "min": min, "max": max, "max_inclusive": inclusive}),
+    ))
+}
+
+/// `*` matches any run of characters, `?` exactly one, everything else literally
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // position of the last `*` and the text position it is currently matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// the JSON type a `PayloadValidator` expects a field to have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonType {
+    Any,
+    Null,
+    Bool,
+    Number,
+    /// a number without a fraction
+    Integer,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    pub fn name(self) -> &'static str {
+        match self {
+            JsonType::Any => "any",
+            JsonType::Null => "null",
+            JsonType::Bool => "bool",
+            JsonType::Number => "number",
+            JsonType::Integer => "integer",
+            JsonType::String => "string",
+            JsonType::Array => "array",
+            JsonType::Object => "object",
+        }
+    }
+
+    pub fn of(value: &Value) -> JsonType {
+        match value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Bool,
+            Value::Number(n) if n.is_i64() || n.is_u64() => JsonType::Integer,
+            Value::Number(_) => JsonType::Number,
+            Value::String(_) => JsonType::String,
+            Value::Array(_) => JsonType::Array,
+            Value::Object(_) => JsonType::Object,
+        }
+    }
+
+    pub fn matches(self, value: &Value) -> bool {
+        match self {
+            JsonType::Any => true,
+            JsonType::Number => value.is_number(),
+            t => JsonType::of(value) == t,
+        }
+    }
+}
+
+/// declared payload fields checked all at once, every missing or mistyped field gives
+/// its own `ValidationError`
+///
+/// ```ignore
+/// let signup = PayloadValidator::new()
+///     .required("email", JsonType::String)
+///     .required("age", JsonType::Integer)
+///     .optional("tags", JsonType::Array);
+/// m.validate_action("signup", move |a| signup.validate(a));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PayloadValidator {
+    fields: Vec<(String, JsonType, bool)>,
+}
+
+impl PayloadValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `key` must be present and of type `ty`
+    pub fn required(mut self, key: &str, ty: JsonType) -> Self {
+        self.fields.push((key.to_owned(), ty, true));
+        self
+    }
+
+    /// `key` may be left out, when present it must be of type `ty`
+    pub fn optional(mut self, key: &str, ty: JsonType) -> Self {
+        self.fields.push((key.to_owned(), ty, false));
+        self
+    }
+
+    pub fn validate(&self, action: &Action) -> Result<(), Vec<ActionError>> {
+        let mut errors = ErrorCollector::new();
+        for (key, ty, required) in &self.fields {
+            errors.check(check_field(action, key, *ty, *required));
+        }
+        errors.finish()
+    }
+}
+
+fn check_field(
+    action: &Action,
+    key: &str,
+    ty: JsonType,
+    required: bool,
+) -> Result<(), ActionError> {
+    match action.payload.get(key) {
+        None if required => Err(violation(
+            format!("{} is required, expected {}", key, ty.name()),
+            json!({"field": key, "constraint": "required", "expected": ty.name()}),
+        )),
+        Some(v) if !ty.matches(v) => Err(violation(
+            format!(
+                "{} must be {}, got {}",
+                key,
+                ty.name(),
+                JsonType::of(v).name()
+            ),
+            json!({
+                "field": key,
+                "constraint": "type",
+                "expected": ty.name(),
+                "actual": JsonType::of(v).name(),
+            }),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Action {
+    /// every missing key of `keys` as its own `ValidationError`, unlike `require_keys`
+    /// which reports them in one
+    pub fn require_fields(&self, keys: &[&str]) -> Result<(), Vec<ActionError>> {
+        let mut errors = ErrorCollector::new();
+        for key in keys {
+            errors.check(check_field(self, key, JsonType::Any, true));
+        }
+        errors.finish()
+    }
+}
+
+/// gathers the failures of several checks so they can be reported together
+#[derive(Debug, Default)]
+pub struct ErrorCollector {
+    errors: Vec<ActionError>,
+}
+
+impl ErrorCollector {
+    pub fn new() -> Self {
+        ErrorCollector { errors: Vec::new() }
+    }
+
+    pub fn check(&mut self, res: Result<(), ActionError>) -> &mut Self {
+        if let Err(e) = res {
+            self.errors.push(e);
+        }
+        self
+    }
+
+    pub fn errors(&self) -> &[ActionError] {
+        &self.errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn finish(self) -> Result<(), Vec<ActionError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// like `finish` but folds the failures into one error, for handlers which can only
+    /// return a single error
+    pub fn into_result(self) -> Result<(), ActionError> {
+        self.finish().map_err(combine)
+    }
+}
+
+/// folds several errors into one `ValidationError` with the originals in its details
+pub fn combine(mut errors: Vec<ActionError>) -> ActionError {
+    if errors.len() == 1 {
+        return errors.remove(0);
+    }
+    let message = errors
+        .iter()
+        .map(|e| e.message.as_str())
+        .collect::<Vec<&str>>()
+        .join("; ");
+    ActionError::new("ValidationError", &message).with_details(json!({ "errors": errors }))
+}
+
+/// runs every check and collects the failures, evaluates to `Result<(), Vec<ActionError>>`
+///
+/// ```ignore
+/// validate_all!(
+///     require_keys(action, &["name"]),
+///     require_range(action, "age", 0..=150),
+/// )
+/// ```
+#[macro_export]
+macro_rules! validate_all {
+    ($($check:expr),+ $(,)?) => {{
+        let mut collector = $crate::validate::ErrorCollector::new();
+        $(collector.check($check);)+
+        collector.finish()
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Manager;
+
+    fn action(payload: Value) -> Action {
+        let mut a = Action::new("signup", 0);
+        a.payload = serde_json::from_value(payload).unwrap();
+        a
+    }
+
+    #[test]
+    fn keys() {
+        let a = action(json!({"a": 1, "b": null}));
+        assert!(require_keys(&a, &["a", "b"]).is_ok());
+        let e = require_keys(&a, &["a", "c", "d"]).unwrap_err();
+        assert_eq!(e.code, "ValidationError");
+        assert_eq!(e.details.unwrap()["fields"], json!(["c", "d"]));
+    }
+
+    #[test]
+    fn one_of() {
+        assert!(require_one_of(&action(json!({"email": "x"})), &["email", "phone"]).is_ok());
+        let both = action(json!({"email": "x", "phone": "y"}));
+        let e = require_one_of(&both, &["email", "phone"]).unwrap_err();
+        assert_eq!(e.details.unwrap()["present"], json!(["email", "phone"]));
+        assert!(require_one_of(&action(json!({})), &["email", "phone"]).is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let a = action(json!({"email": "bob@example.com", "n": 3}));
+        assert!(require_matches(&a, "email", "*@*.*").is_ok());
+        assert!(require_matches(&a, "email", "?*@example.com").is_ok());
+        assert!(require_matches(&a, "email", "*@example.org").is_err());
+        assert!(require_matches(&a, "n", "*").is_err());
+        assert!(require_matches(&a, "missing", "*").is_err());
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(!glob_match("a?c", "ac"));
+    }
+
+    #[test]
+    fn range() {
+        let a = action(json!({"age": 30, "neg": -1, "float": 1.5}));
+        assert!(require_range(&a, "age", 0..=150).is_ok());
+        assert!(require_range(&a, "age", 0..30).is_err());
+        assert!(require_range(&a, "float", 0..=150).is_err());
+        let e = require_range(&a, "neg", 0..=150).unwrap_err();
+        let d = e.details.unwrap();
+        assert_eq!(d["field"], json!("neg"));
+        assert_eq!(d["max"], json!(150));
+    }
+
+    #[test]
+    fn aggregate() {
+        let a = action(json!({"age": 200}));
+        let errors = validate_all!(
+            require_keys(&a, &["name"]),
+            require_one_of(&a, &["email", "phone"]),
+            require_range(&a, "age", 0..=150),
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 3);
+        let combined = combine(errors);
+        assert_eq!(
+            combined.details.unwrap()["errors"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(validate_all!(require_range(&a, "age", 0..)).is_ok());
+    }
+
+    #[test]
+    fn as_before_middleware() {
+        let mut m = Manager::new("test", ());
+        m.on("signup", |_, _| crate::action::action_ok());
+        m.validate_action("signup", |a| {
+            validate_all!(require_keys(a, &["name"]), require_range(a, "age", 0..=150))
+        });
+        let mut a = action(json!({"age": 200}));
+        m.do_action(&mut a);
+        assert!(a.result.is_none());
+        assert_eq!(a.errors.unwrap().len(), 2);
+        let mut a = action(json!({"name": "bob", "age": 20}));
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+    }
+
+    #[test]
+    fn inside_handler_keeps_code() {
+        let mut m = Manager::new("test", ());
+        m.on("signup", |_, a| {
+            require_keys(a, &["name"])?;
+            crate::action::action_ok()
+        });
+        let mut a = action(json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap()[0].code, "ValidationError");
+    }
+
+    #[test]
+    fn every_field_reported() {
+        let a = action(json!({"age": "thirty", "tags": ["a"], "score": 1.5}));
+        let errors = a.require_fields(&["email", "age", "name"]).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message.contains("email"));
+        assert!(errors[1].message.contains("name"));
+
+        let signup = PayloadValidator::new()
+            .required("email", JsonType::String)
+            .required("age", JsonType::Integer)
+            .optional("tags", JsonType::Array)
+            .optional("nick", JsonType::String)
+            .required("score", JsonType::Number);
+        let errors = signup.validate(&a).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.code == "ValidationError"));
+        assert_eq!(errors[0].details.as_ref().unwrap()["field"], json!("email"));
+        let d = errors[1].details.as_ref().unwrap();
+        assert_eq!(
+            (d["expected"].clone(), d["actual"].clone()),
+            (json!("integer"), json!("string"))
+        );
+        assert_eq!(errors[1].message, "age must be integer, got string");
+
+        let mut m = Manager::new("test", ());
+        m.on("signup", |_, _| crate::action::action_ok());
+        m.validate_action("signup", move |a| signup.validate(a));
+        let mut a = action(json!({}));
+        m.do_action(&mut a);
+        assert_eq!(a.errors.unwrap().len(), 3);
+        let mut a = action(json!({"email": "x", "age": 3, "score": 2}));
+        m.do_action(&mut a);
+        assert!(a.errors.is_none());
+    }
+}
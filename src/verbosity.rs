@@ -0,0 +1,226 @@
+use serde_json::Value;
+use std::collections::VecDeque;
+
+use crate::correlation::CorrelationId;
+use crate::error::ActionError;
+
+/// message clients see in place of the real one when it is hidden
+pub const GENERIC_ERROR_MESSAGE: &str = "the action could not be completed";
+
+/// code clients see in place of the real one for internal errors at `Minimal`
+pub const INTERNAL_ERROR_CODE: &str = "InternalError";
+
+/// error codes the crate produces for failures on the server side, as opposed to
+/// problems with what the client sent
+pub const INTERNAL_ERROR_CODES: &[&str] =
+    &["RunAction", "Boxed::Error", "io::Error", "HandlerPanic"];
+
+/// how many incidents `Manager::find_incident` can look up
+pub const INCIDENT_CAPACITY: usize = 1024;
+
+/// how much of an error reaches the client, see `Manager::error_verbosity`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorVerbosity {
+    /// errors are sent as they are
+    #[default]
+    Full,
+    /// client errors keep code and message but lose the denied detail keys, internal
+    /// errors are reduced to their code and an incident id
+    Standard,
+    /// only codes and an incident id are sent, internal errors all share one code
+    Minimal,
+}
+
+/// the errors of one reply as they were before being shaped for the client
+#[derive(Serialize, Debug, Clone)]
+pub struct Incident {
+    pub id: String,
+    pub action: String,
+    pub action_id: u64,
+    pub errors: Vec<ActionError>,
+}
+
+pub fn is_internal(e: &ActionError) -> bool {
+    INTERNAL_ERROR_CODES.contains(&e.code.as_str())
+}
+
+fn hidden(code: &str, incident: &str) -> ActionError {
+    ActionError::new(code, GENERIC_ERROR_MESSAGE).with_details(json!({ "incident": incident }))
+}
+
+/// the error as the client gets to see it, `None` when it can be sent as is
+fn shape(
+    e: &ActionError,
+    level: ErrorVerbosity,
+    deny: &[String],
+    incident: &str,
+) -> Option<ActionError> {
+    match (level, is_internal(e)) {
+        (ErrorVerbosity::Full, _) => None,
+        (ErrorVerbosity::Standard, false) => match &e.details {
+            Some(Value::Object(map)) if deny.iter().any(|k| map.contains_key(k)) => {
+                let mut e = e.clone();
+                if let Some(Value::Object(map)) = &mut e.details {
+                    map.retain(|k, _| !deny.contains(k));
+                }
+                Some(e)
+            }
+            _ => None,
+        },
+        (ErrorVerbosity::Standard, true) | (ErrorVerbosity::Minimal, false) => {
+            Some(hidden(&e.code, incident))
+        }
+        (ErrorVerbosity::Minimal, true) => Some(hidden(INTERNAL_ERROR_CODE, incident)),
+    }
+}
+
+/// the bounded log of incidents along with the policy producing them
+pub(crate) struct Incidents {
+    pub(crate) level: ErrorVerbosity,
+    pub(crate) deny: Vec<String>,
+    log: VecDeque<Incident>,
+}
+
+impl Incidents {
+    pub(crate) fn new(level: ErrorVerbosity) -> Self {
+        Incidents {
+            level,
+            deny: Vec::new(),
+            log: VecDeque::new(),
+        }
+    }
+
+    /// shapes `errors` in place; when anything had to be hidden the originals are kept
+    /// and logged under a new incident id, which is returned
+    pub(crate) fn shape(
+        &mut self,
+        manager: &str,
+        action: &str,
+        action_id: u64,
+        errors: &mut [ActionError],
+    ) -> Option<String> {
+        if self.level == ErrorVerbosity::Full || errors.is_empty() {
+            return None;
+        }
+        let id = CorrelationId::generate().to_string();
+        let originals = errors.to_vec();
+        let mut hid = false;
+        for e in errors.iter_mut() {
+            if let Some(shaped) = shape(e, self.level, &self.deny, &id) {
+                hid |= shaped.message != e.message;
+                *e = shaped;
+            }
+        }
+        if !hid {
+            return None;
+        }
+        self.record(manager, &id, action, action_id, originals);
+        Some(id)
+    }
+
+    /// logs `errors` under the incident `id` and keeps them for `find`
+    pub(crate) fn record(
+        &mut self,
+        manager: &str,
+        id: &str,
+        action: &str,
+        action_id: u64,
+        errors: Vec<ActionError>,
+    ) {
+        event!(
+            warn,
+            "Manager [{}] incident {}: {} ({}) failed with {}",
+            manager,
+            id,
+            action,
+            action_id,
+            serde_json::to_string(&errors).unwrap_or_default()
+        );
+        if self.log.len() >= INCIDENT_CAPACITY {
+            self.log.pop_front();
+        }
+        self.log.push_back(Incident {
+            id: id.to_owned(),
+            action: action.to_owned(),
+            action_id,
+            errors,
+        });
+    }
+
+    pub(crate) fn find(&self, id: &str) -> Option<Incident> {
+        self.log.iter().find(|i| i.id == id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{Action, ActionReply, Manager};
+
+    fn manager(level: ErrorVerbosity) -> Manager<()> {
+        let mut m = Manager::new("test", ());
+        m.on("client", |_, _| {
+            Err(Box::new(
+                ActionError::new("ValidationError", "age must be positive")
+                    .with_details(json!({"field": "age", "sql": "select 1"})),
+            ))
+        });
+        m.on("internal", |_, _| {
+            Err("connection refused by 10.0.0.3".into())
+        });
+        m.error_verbosity(level);
+        m.deny_error_details(&["sql"]);
+        m
+    }
+
+    fn run(m: &Manager<()>, name: &str) -> ActionReply {
+        let mut a = Action::new(name, 9);
+        m.do_action(&mut a);
+        m.reply(a)
+    }
+
+    fn incident(e: &ActionError) -> &str {
+        e.details.as_ref().unwrap()["incident"].as_str().unwrap()
+    }
+
+    #[test]
+    fn full() {
+        let m = manager(ErrorVerbosity::Full);
+        let e = &run(&m, "client").errors[0];
+        assert_eq!(e.details.as_ref().unwrap()["sql"], json!("select 1"));
+        let e = &run(&m, "internal").errors[0];
+        assert_eq!(e.code, "RunAction");
+        assert_eq!(e.message, "connection refused by 10.0.0.3");
+    }
+
+    #[test]
+    fn standard() {
+        let m = manager(ErrorVerbosity::Standard);
+        let e = &run(&m, "client").errors[0];
+        assert_eq!(e.message, "age must be positive");
+        assert_eq!(e.details, Some(json!({"field": "age"})));
+
+        let e = &run(&m, "internal").errors[0];
+        assert_eq!(e.code, "RunAction");
+        assert_eq!(e.message, GENERIC_ERROR_MESSAGE);
+        let found = m.find_incident(incident(e)).unwrap();
+        assert_eq!(found.action, "internal");
+        assert_eq!(found.action_id, 9);
+        assert_eq!(found.errors[0].message, "connection refused by 10.0.0.3");
+    }
+
+    #[test]
+    fn minimal() {
+        let m = manager(ErrorVerbosity::Minimal);
+        let e = &run(&m, "client").errors[0];
+        assert_eq!(e.code, "ValidationError");
+        assert_eq!(e.message, GENERIC_ERROR_MESSAGE);
+        let found = m.find_incident(incident(e)).unwrap();
+        assert_eq!(found.errors[0].message, "age must be positive");
+
+        let e = &run(&m, "internal").errors[0];
+        assert_eq!(e.code, INTERNAL_ERROR_CODE);
+        assert!(m.find_incident(incident(e)).is_some());
+        assert!(m.find_incident("nope").is_none());
+    }
+}
@@ -0,0 +1,35 @@
+//! compiles against whatever features the crate was built with and checks the gated
+//! parts are there exactly when their feature is on; `cargo run -p xtask` runs this for
+//! every combination
+
+extern crate json_action;
+#[macro_use]
+extern crate serde_json;
+
+use json_action::action::{value_ok, Action, Manager};
+
+#[test]
+fn core_manager_dispatches() {
+    let mut m = Manager::new("core", ());
+    m.on("ping", |_, _| value_ok("pong"));
+    let mut a = Action::new("ping", 0);
+    m.do_action(&mut a);
+    assert_eq!(m.reply(a).result, Some(json!("pong")));
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn crypto_parts() {
+    use json_action::token::{Encrypted, TokenCodec};
+    let codec = Encrypted::new([1; 32]);
+    assert_eq!(codec.decode(&codec.encode("t")).unwrap(), "t");
+    let mut m = Manager::new("crypto", ());
+    m.confirmation_key(b"k");
+}
+
+#[cfg(feature = "compat")]
+#[test]
+fn compat_parts() {
+    let issues = json_action::compat::check_backward::<u32>(&[json!(1), json!("x")]);
+    assert_eq!(issues.len(), 1);
+}
@@ -0,0 +1,49 @@
+//! one manager shared between threads through an `Arc`
+
+extern crate json_action;
+#[macro_use]
+extern crate serde_json;
+
+use json_action::action::{value_ok, Action, Manager};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+fn action(name: &str, n: u64) -> Action {
+    let mut a = Action::new(name, n);
+    a.payload.insert("n".to_owned(), json!(n));
+    a
+}
+
+#[test]
+fn dispatch_from_many_threads() {
+    let mut m = Manager::new("shared", AtomicU64::new(0));
+    m.on("add", |total, a| {
+        let n = a.payload["n"].as_u64().unwrap_or(0);
+        value_ok(total.fetch_add(n, Ordering::SeqCst) + n)
+    });
+    m.on("total", |total, _| value_ok(total.load(Ordering::SeqCst)));
+    let m = Arc::new(m);
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let m = m.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let mut a = action("add", t * 100 + i);
+                    m.do_action(&mut a);
+                    let reply = m.reply(a);
+                    assert!(reply.errors.is_empty());
+                    assert_eq!(reply.id, t * 100 + i);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let mut a = action("total", 0);
+    m.do_action(&mut a);
+    assert_eq!(a.result, Some(json!((0..800u64).sum::<u64>())));
+}
@@ -0,0 +1,113 @@
+//! `cargo run -p xtask [features]`: checks that json_action builds with every supported
+//! feature combination and that `core` alone pulls in none of the optional dependencies
+
+use std::env;
+use std::process::{exit, Command};
+
+/// every combination users are expected to build with
+const COMBOS: &[&str] = &[
+    "core",
+    "core,log",
+    "core,crypto",
+    "core,compat",
+    "core,crypto,compat",
+    "core,zstd-dict",
+    "core,test-util",
+    "core,duplex",
+    "core,msgpack",
+    "core,cbor",
+    "core,tokio-codec",
+];
+
+/// optional dependencies which must not show up in a `core` only build
+const OPTIONAL_DEPS: &[&str] = &[
+    "aes-gcm-siv",
+    "hmac",
+    "sha2",
+    "serde_path_to_error",
+    "zstd",
+    "tokio",
+    "log",
+    "rmp-serde",
+    "ciborium",
+    "tokio-util",
+];
+
+fn cargo() -> Command {
+    Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+}
+
+fn check(features: &str) -> bool {
+    println!(
+        "xtask: cargo check --no-default-features --features {}",
+        features
+    );
+    cargo()
+        .args([
+            "check",
+            "-p",
+            "json_action",
+            "--lib",
+            "--tests",
+            "--no-default-features",
+            "--features",
+        ])
+        .arg(features)
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+/// the dependency tree of a `core` only build must not contain any optional dependency
+fn core_is_minimal() -> bool {
+    let out = match cargo()
+        .args([
+            "tree",
+            "-p",
+            "json_action",
+            "-e",
+            "normal",
+            "--prefix",
+            "none",
+            "--no-default-features",
+            "--features",
+            "core",
+        ])
+        .output()
+    {
+        Ok(out) if out.status.success() => out,
+        _ => {
+            eprintln!("xtask: cargo tree failed");
+            return false;
+        }
+    };
+    let tree = String::from_utf8_lossy(&out.stdout);
+    let found: Vec<&str> = OPTIONAL_DEPS
+        .iter()
+        .filter(|dep| tree.lines().any(|l| l.split(' ').next() == Some(**dep)))
+        .copied()
+        .collect();
+    if !found.is_empty() {
+        eprintln!("xtask: core build depends on {}", found.join(", "));
+    }
+    found.is_empty()
+}
+
+fn main() {
+    let requested: Vec<String> = env::args().skip(1).collect();
+    let combos: Vec<&str> = if requested.is_empty() {
+        COMBOS.to_vec()
+    } else {
+        requested.iter().map(|s| s.as_str()).collect()
+    };
+    let mut failed: Vec<&str> = combos.into_iter().filter(|f| !check(f)).collect();
+    if !core_is_minimal() {
+        failed.push("core dependency tree");
+    }
+    if failed.is_empty() {
+        println!("xtask: all feature checks passed");
+    } else {
+        eprintln!("xtask: failed: {}", failed.join("; "));
+        exit(1);
+    }
+}